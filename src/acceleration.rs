@@ -1,6 +1,12 @@
 
 
 
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Instant;
+use rayon::prelude::*;
+use serde::Serialize;
+
 use crate::prelude::*;
 use crate::json_structs::VertexData;
 use crate::shapes::{HeapAllocatedShape};
@@ -9,139 +15,1264 @@ use crate::bbox::{BBox};
 use crate::interval::{Interval, FloatConst};
 use crate::scene::{HeapAllocatedVerts};
 
+// ====================================================================================================
+// BVH Build Settings
+// ====================================================================================================
+
+/// Splitting strategy used while building the BVH
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+pub enum BVHBuilder {
+    #[default]
+    #[serde(rename = "sah", alias = "SAH")]
+    SAH, // Binned Surface Area Heuristic
+
+    #[serde(rename = "median")]
+    Median, // Split at the median centroid on the largest axis (the original builder)
+}
+
+/// Settings read from "BVH" field of the scene JSON, e.g.
+/// "BVH": { "Builder": "sah", "LeafSize": "4", "BinCount": "12", "Width": "4", "RefitThreshold": "1.3" }
+#[derive(Debug, Clone, Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct BVHSettings {
+    #[serde(rename = "Builder")]
+    pub builder: BVHBuilder,
+
+    #[serde(rename = "LeafSize", deserialize_with = "deser_usize")]
+    #[default = 4]
+    pub leaf_size: usize, // Maximum number of shapes in a leaf
+
+    #[serde(rename = "BinCount", deserialize_with = "deser_usize")]
+    #[default = 12]
+    pub bin_count: usize, // Number of bins per axis for SAH
+
+    #[serde(rename = "Width", deserialize_with = "deser_usize")]
+    #[default = 2]
+    pub width: usize, // Branching factor used in traversal, 2 (binary) or 4 (collapsed, see WideBVHNode)
+
+    #[serde(rename = "RefitThreshold", deserialize_with = "deser_float")]
+    #[default = 1.3]
+    pub refit_threshold: Float, // Refitted BVH is rebuilt if its SAH cost exceeds this times the cost at build, zero disables refitting
+}
+
+impl BVHSettings {
+    /// Fix settings that would break the build, called once the scene JSON is read
+    pub fn validate(&mut self) {
+        // Sanity checks, zero would never terminate the recursion
+        if self.leaf_size == 0 {
+            warn!("BVH leaf size cannot be zero, setting it to 1.");
+            self.leaf_size = 1;
+        }
+        if self.leaf_size > u16::MAX as usize {
+            warn!("BVH leaf size cannot exceed {} (see LinearBVHNode), clamping it.", u16::MAX);
+            self.leaf_size = u16::MAX as usize;
+        }
+        if self.bin_count < 2 {
+            warn!("BVH bin count must be at least 2, setting it to 2.");
+            self.bin_count = 2;
+        }
+        if self.width != 2 && self.width != 4 {
+            warn!("BVH width can be 2 or 4, found {}, setting it to 2.", self.width);
+            self.width = 2;
+        }
+        debug!("BVH settings: {:?}", self);
+    }
+}
+
+/// Acceleration structure of the scene and meshes, read from "Accelerator" field of the scene JSON
+/// or --accelerator=<kind> command line flag, e.g. "Accelerator": "kdtree"
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+pub enum AcceleratorKind {
+    #[default]
+    #[serde(rename = "bvh", alias = "BVH")]
+    BVH, // BVHSubtree, configured with BVHSettings
+
+    #[serde(rename = "kdtree", alias = "KdTree")]
+    KdTree, // SAH kd-tree, see kdtree.rs
+
+    #[serde(rename = "naive")]
+    Naive, // Test every shape, only for debugging the others
+}
+
+impl AcceleratorKind {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "bvh" => Some(Self::BVH),
+            "kdtree" => Some(Self::KdTree),
+            "naive" => Some(Self::Naive),
+            _ => None,
+        }
+    }
+}
+
+// Relative costs used by SAH (see also pbrt-v3, 4.3.2)
+const TRAVERSAL_COST: Float = 1.0;
+const INTERSECTION_COST: Float = 1.0;
+
+
+// Conservative bound on floating point error of slab test, i.e. gamma(3) in pbrt-v3, 3.9.1
+const fn gamma(n: Float) -> Float {
+    let e = Float::EPSILON * 0.5;
+    (n * e) / (1.0 - n * e)
+}
+pub(crate) const ROBUST_SLACK: Float = 1.0 + 2.0 * gamma(3.0);
+
+// Traversal uses a fixed size stack, to keep its size bounded SAH switches to median split
+// (which always halves the items) after MAX_SAH_DEPTH levels.
+const MAX_SAH_DEPTH: usize = 64;
+const TRAVERSAL_STACK_SIZE: usize = 128;
+const WIDE_TRAVERSAL_STACK_SIZE: usize = 3 * TRAVERSAL_STACK_SIZE / 2; // Up to three children are pushed per wide node (half the depth)
+
 // ====================================================================================================
 // Bounding Volume Hierarchy
 // ====================================================================================================
-// Binary tree creation is inspired by:
+// Tree is first built recursively with BuildNode and then flattened into an array
+// of LinearBVHNode in depth-first order, see pbrt-v3, 4.3.4 (Compact BVH For Traversal)
+// Binary tree creation was initially inspired by:
 // https://google.github.io/comprehensive-rust/smart-pointers/exercise.html
 
+/// Node of flattened BVH, 32 bytes so that two nodes fit in a 64 byte cache line.
+/// Bounds are stored as f32, rounded outwards so that they always contain the Float bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[repr(C, align(32))]
+pub struct LinearBVHNode {
+    bounds_min: [f32; 3],
+    bounds_max: [f32; 3],
+    offset: u32, // Leaves: index of first shape in BVHSubtree::shapes, interior: index of second child (first child is next to the node)
+    n_prims: u16, // Zero for interior nodes
+    axis: u8, // Split axis of interior nodes, used to visit the near child first
+    _pad: u8,
+}
+
+const _: () = assert!(std::mem::size_of::<LinearBVHNode>() == 32);
+
+impl LinearBVHNode {
+    fn new(bbox: &BBox, offset: usize, n_prims: usize, axis: u8) -> Self {
+        // Round down min and round up max
+        let lower = |x: Float| { let f = x as f32; if (f as Float) > x { f.next_down() } else { f } };
+        let upper = |x: Float| { let f = x as f32; if (f as Float) < x { f.next_up() } else { f } };
+        Self {
+            bounds_min: [lower(bbox.xmin), lower(bbox.ymin), lower(bbox.zmin)],
+            bounds_max: [upper(bbox.xmax), upper(bbox.ymax), upper(bbox.zmax)],
+            offset: u32::try_from(offset).expect("BVH has too many nodes or shapes for u32 offsets"),
+            n_prims: u16::try_from(n_prims).expect("BVH leaf has too many shapes"),
+            axis,
+            _pad: 0,
+        }
+    }
+
+    #[inline]
+    fn is_leaf(&self) -> bool {
+        self.n_prims > 0
+    }
 
-#[derive(Debug)]
-pub struct BVHNode{
-    pub bbox: BBox,
-    pub left: Option<Arc<BVHNode>>,
-    pub right: Option<Arc<BVHNode>>,
-    pub objects: Vec<HeapAllocatedShape>, // Only leaves have object pointers
+    pub fn bbox(&self) -> BBox {
+        BBox::new(self.bounds_min[0] as Float, self.bounds_max[0] as Float,
+                  self.bounds_min[1] as Float, self.bounds_max[1] as Float,
+                  self.bounds_min[2] as Float, self.bounds_max[2] as Float)
+    }
+
+    #[inline]
+    fn hits(&self, origin: &Vector3, inv_dir: &Vector3, t_min: Float, t_max: Float) -> bool {
+        slab_entry(&self.bounds_min, &self.bounds_max, origin, inv_dir, t_min, t_max).is_some()
+    }
+}
+
+/// Slab test (see slides 03, p.5-6) against [t_min, t_max], inverse direction is precomputed per ray.
+/// Unlike BBox::intersect( ) ray direction does not need to be normalized (local rays of instances are not).
+/// Returns the entry distance if the box is hit.
+#[inline]
+fn slab_entry(bounds_min: &[f32; 3], bounds_max: &[f32; 3], origin: &Vector3, inv_dir: &Vector3, t_min: Float, t_max: Float) -> Option<Float> {
+    let (mut t0, mut t1) = (t_min, t_max);
+    for axis in 0..3 {
+        let mut t_near = (bounds_min[axis] as Float - origin[axis]) * inv_dir[axis];
+        let mut t_far = (bounds_max[axis] as Float - origin[axis]) * inv_dir[axis];
+        if t_near > t_far {
+            std::mem::swap(&mut t_near, &mut t_far);
+        }
+        t_far *= ROBUST_SLACK;
+
+        // NOTE: comparisons with NaN are false, so 0 * inf cases are ignored
+        if t_near > t0 { t0 = t_near; }
+        if t_far < t1 { t1 = t_far; }
+        if t0 > t1 {
+            return None;
+        }
+    }
+    Some(t0)
+}
+
+/// Node of the 4-wide BVH, collapsed from the binary one when BVHSettings::width is 4.
+/// Children of a wide node are the grandchildren of a binary node (or its child if that is a leaf),
+/// stored in [left.left, left.right, right.left, right.right] slots. Their boxes are stored as
+/// structure of arrays so that all four are tested in one pass (SIMD with "simd" cargo feature).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[repr(C, align(64))]
+pub struct WideBVHNode {
+    bounds_min: [[f32; 4]; 3], // [axis][child]
+    bounds_max: [[f32; 4]; 3],
+    offset: [u32; 4], // Leaf children: index of first shape, interior children: index of wide node
+    n_prims: [u16; 4], // Zero for interior children
+    axes: [u8; 3], // Split axes of the binary node and of its two children, to visit children in the binary order
+    valid: u8, // Bitmask of non-empty child slots
 }
 
-/// TODO: Here I only use Subtree, forgot to add wrapper as in comprehensive rust's docs 
+const _: () = assert!(std::mem::size_of::<WideBVHNode>() == 128);
+
+impl WideBVHNode {
+    fn empty() -> Self {
+        Self {
+            bounds_min: [[0.; 4]; 3],
+            bounds_max: [[0.; 4]; 3],
+            offset: [0; 4],
+            n_prims: [0; 4],
+            axes: [0; 3],
+            valid: 0,
+        }
+    }
+
+    fn set_child(&mut self, slot: usize, node: &LinearBVHNode, offset: usize) {
+        for axis in 0..3 {
+            self.bounds_min[axis][slot] = node.bounds_min[axis];
+            self.bounds_max[axis][slot] = node.bounds_max[axis];
+        }
+        self.offset[slot] = u32::try_from(offset).expect("BVH has too many nodes for u32 offsets");
+        self.n_prims[slot] = node.n_prims;
+        self.valid |= 1 << slot;
+    }
+
+    /// Order that binary traversal would visit the children, i.e. near side first and near child within the side
+    #[inline]
+    fn visit_order(&self, dir_is_neg: &[bool; 3]) -> [usize; 4] {
+        let first = dir_is_neg[self.axes[0] as usize] as usize;
+        let side = |s: usize| {
+            let near = dir_is_neg[self.axes[1 + s] as usize] as usize;
+            [2 * s + near, 2 * s + 1 - near]
+        };
+        let (a, b) = (side(first), side(1 - first));
+        [a[0], a[1], b[0], b[1]]
+    }
+
+    /// Slab test of all four children, returns bitmask of hit children and their entry distances.
+    /// Arithmetic is the same as slab_entry( ) per child so results are bit-identical to the binary BVH.
+    #[cfg(feature = "simd")]
+    #[inline]
+    fn hits(&self, origin: &Vector3, inv_dir: &Vector3, t_min: Float, t_max: Float) -> (u8, [Float; 4]) {
+        use wide::{f64x4, CmpGt};
+        let widen = |b: &[f32; 4]| f64x4::new(b.map(|x| x as Float));
+
+        let (mut t0, mut t1) = (f64x4::splat(t_min), f64x4::splat(t_max));
+        for axis in 0..3 {
+            let (o, inv) = (f64x4::splat(origin[axis]), f64x4::splat(inv_dir[axis]));
+            let a = (widen(&self.bounds_min[axis]) - o) * inv;
+            let b = (widen(&self.bounds_max[axis]) - o) * inv;
+            let swap = a.cmp_gt(b);
+            let t_near = swap.blend(b, a);
+            let t_far = swap.blend(a, b) * ROBUST_SLACK;
+
+            // NOTE: cmp_gt is false for NaN, so 0 * inf cases are ignored as in the scalar test
+            t0 = t_near.cmp_gt(t0).blend(t_near, t0);
+            t1 = t1.cmp_gt(t_far).blend(t_far, t1);
+        }
+        // t0 never decreases and t1 never increases, so checking once at the end is the same as early exit per axis
+        let missed = t0.cmp_gt(t1).move_mask() as u8;
+        (!missed & self.valid, t0.to_array())
+    }
+
+    /// Scalar fallback of the SIMD slab test above
+    #[cfg(not(feature = "simd"))]
+    #[inline]
+    fn hits(&self, origin: &Vector3, inv_dir: &Vector3, t_min: Float, t_max: Float) -> (u8, [Float; 4]) {
+        let mut mask = 0;
+        let mut t_entry = [0.; 4];
+        for (slot, entry) in t_entry.iter_mut().enumerate() {
+            if self.valid & (1 << slot) == 0 {
+                continue;
+            }
+            let bounds_min = std::array::from_fn(|axis| self.bounds_min[axis][slot]);
+            let bounds_max = std::array::from_fn(|axis| self.bounds_max[axis][slot]);
+            if let Some(t0) = slab_entry(&bounds_min, &bounds_max, origin, inv_dir, t_min, t_max) {
+                mask |= 1 << slot;
+                *entry = t0;
+            }
+        }
+        (mask, t_entry)
+    }
+}
+
+/// Flattened BVH used both as top-level (Scene3D::bvh) and per mesh (Mesh::bvh).
+/// Cloning is cheap as the nodes and shapes are shared.
 #[derive(Debug, Clone)]
-pub struct BVHSubtree(pub Option<Arc<BVHNode>>);
+pub struct BVHSubtree {
+    nodes: Arc<[LinearBVHNode]>,
+    wide_nodes: Option<Arc<[WideBVHNode]>>, // Used in traversal instead of nodes if BVHSettings::width is 4
+    shapes: Arc<[HeapAllocatedShape]>, // Ordered s.t. each leaf refers to a contiguous range
+    shape_order: Arc<[u32]>, // Index of each shape above in the list given to build( ), to refit or cache the BVH
+    build_cost: Float, // SAH cost right after the build, see refit( )
+}
+
+/// Flattened BVH without the shapes, stored by the mesh cache (see mesh_cache.rs).
+/// Shapes are referred by their index in the list given to BVHSubtree::build( ).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedBVH {
+    nodes: Vec<LinearBVHNode>,
+    wide_nodes: Option<Vec<WideBVHNode>>,
+    shape_order: Vec<u32>,
+    build_cost: Float,
+}
+
+type BuildItem = (u32, BBox, Vector3); // (index of shape, its bbox, bbox centroid)
+
+enum BuildNode {
+    Leaf { bbox: BBox, shapes: Vec<u32> },
+    Interior { bbox: BBox, axis: u8, children: Box<[BuildNode; 2]> },
+}
+
+enum SplitDecision {
+    Leaf,
+    At { axis: usize, bin: usize, cmin: Float, cmax: Float }, // Items whose centroids fall in bins <= bin go to the left child
+}
+
+/// SAH bins of all three axes. Merging bins is exact (min/max and integer sums)
+/// so binning chunks in parallel gives the same result as the serial sweep.
+struct Bins {
+    counts: [Vec<usize>; 3],
+    bounds: [Vec<BBox>; 3],
+}
+
+impl Bins {
+    fn new(n_bins: usize) -> Self {
+        Self {
+            counts: std::array::from_fn(|_| vec![0; n_bins]),
+            bounds: std::array::from_fn(|_| vec![BBox::empty(); n_bins]),
+        }
+    }
+
+    fn add(mut self, items: &[BuildItem], centroid_bbox: &BBox) -> Self {
+        let n_bins = self.counts[0].len();
+        for (_, b, c) in items.iter() {
+            for axis in 0..3 {
+                let i = BVHSubtree::bin_index(c[axis], centroid_bbox.min_at(axis), centroid_bbox.max_at(axis), n_bins);
+                self.counts[axis][i] += 1;
+                self.bounds[axis][i] = self.bounds[axis][i].merge(b);
+            }
+        }
+        self
+    }
+
+    fn merge(mut self, other: Self) -> Self {
+        for axis in 0..3 {
+            for i in 0..self.counts[axis].len() {
+                self.counts[axis][i] += other.counts[axis][i];
+                self.bounds[axis][i] = self.bounds[axis][i].merge(&other.bounds[axis][i]);
+            }
+        }
+        self
+    }
+}
+
+// Nodes with fewer items are built serially, spawning rayon tasks is not worth it below that
+const PARALLEL_THRESHOLD: usize = 4096;
+const PARALLEL_CHUNK_SIZE: usize = 1024;
 
 impl BVHSubtree {
 
-    /// Recursively build nodes in BVH tree
-    #[inline]
-    fn build_nodes(mut items: Vec<(HeapAllocatedShape, BBox, Vector3)>) -> Option<Arc<BVHNode>>
+    /// Recursively build nodes in BVH tree.
+    /// Nodes with at least parallel_threshold items (PARALLEL_THRESHOLD) are built in parallel, which gives
+    /// the identical tree since every step (reductions, stable sort and stable partition) is order independent.
+    fn build_nodes(mut items: Vec<BuildItem>, settings: &BVHSettings, depth: usize, parallel_threshold: usize) -> BuildNode
     {
-            
-        if items.is_empty() { return None; } // Base case
-        let unified_bbox = items.iter().fold(BBox::empty(), |acc, b| acc.merge(&b.1));
+        debug_assert!(!items.is_empty());
+        let n_items = items.len();
+        let parallel = n_items >= parallel_threshold;
+
+        let unified_bbox = if parallel {
+            items.par_iter()
+                 .fold(BBox::empty, |acc, b| acc.merge(&b.1))
+                 .reduce(BBox::empty, |a, b| a.merge(&b))
+        } else {
+            items.iter().fold(BBox::empty(), |acc, b| acc.merge(&b.1))
+        };
 
-        const LEAF_SIZE: usize = 1; // TODO: should we move it inside subtree struct so that it's not a magic constant to set here?
-        if items.len() <= LEAF_SIZE {
-            let node_objects = items.into_iter().map(|(s, _, _)| s).collect(); // NOTE: This is called *consuming*, ownership of items is moved to node_objects but this is fine because we are about to return
-    
-            return Some(Arc::new(BVHNode { bbox: unified_bbox, left: None, right: None, objects: node_objects }));
+        if n_items == 1 {
+            return Self::make_leaf(items, unified_bbox);
         }
 
-        let bboxes: Vec<&BBox> = items.iter().map(|(_, b, _)| b).collect();
-        let (extent_x, extent_y, extent_z) = BBox::get_largest_extents(&bboxes);
-    
+        let split = match settings.builder {
+            BVHBuilder::SAH if depth < MAX_SAH_DEPTH => Self::sah_split(&items, &unified_bbox, settings, parallel),
+            _ => None,
+        };
+
+        // Split items into two
+        let (left_items, right_items, axis) = match split {
+            Some(SplitDecision::Leaf) => return Self::make_leaf(items, unified_bbox),
+            Some(SplitDecision::At { axis, bin, cmin, cmax }) => {
+                let n_bins = settings.bin_count;
+                let goes_left = |item: &BuildItem| Self::bin_index(item.2[axis], cmin, cmax, n_bins) <= bin;
+                // NOTE: Both partitions keep the relative order of items
+                let (left, right): (Vec<BuildItem>, Vec<BuildItem>) = if parallel {
+                    items.into_par_iter().partition(goes_left)
+                } else {
+                    items.into_iter().partition(goes_left)
+                };
+                (left, right, axis)
+            }
+            None => {
+                // Median split (also the fallback when SAH cannot separate the centroids)
+                if n_items <= settings.leaf_size {
+                    return Self::make_leaf(items, unified_bbox);
+                }
+                let axis = Self::median_sort(&mut items, &unified_bbox, parallel);
+                let right = items.split_off(n_items / 2); // Split Vec at middle, remaining items are left
+                (items, right, axis)
+            }
+        };
+        debug_assert!(!left_items.is_empty() && !right_items.is_empty());
+
+        let (left, right) = if parallel {
+            rayon::join(|| Self::build_nodes(left_items, settings, depth + 1, parallel_threshold),
+                        || Self::build_nodes(right_items, settings, depth + 1, parallel_threshold))
+        } else {
+            (Self::build_nodes(left_items, settings, depth + 1, parallel_threshold),
+             Self::build_nodes(right_items, settings, depth + 1, parallel_threshold))
+        };
+
+        BuildNode::Interior { bbox: unified_bbox, axis: axis as u8, children: Box::new([left, right]) }
+    }
+
+    fn make_leaf(items: Vec<BuildItem>, bbox: BBox) -> BuildNode {
+        let shapes = items.into_iter().map(|(i, _, _)| i).collect(); // NOTE: This is called *consuming*, ownership of items is moved to shapes but this is fine because we are about to return
+        BuildNode::Leaf { bbox, shapes }
+    }
+
+    /// Sort items wrt their centroids on the largest extent of bbox and return the axis
+    fn median_sort(items: &mut [BuildItem], bbox: &BBox, parallel: bool) -> usize {
+        let (extent_x, extent_y, extent_z) = (bbox.xmax - bbox.xmin, bbox.ymax - bbox.ymin, bbox.zmax - bbox.zmin);
+
         // Sort items wrt bounding box centroids of the largest extent coordinate 
         // partial_cmp returns Ordering which is used by sort_by( )
         // at first I thought it compares two items but no, it returns Option<Ordering> enum and .unwrap( ) 
         // extracts Ordering, and sort_by( ) decides which Ordering to use, i.e. for ascending order < 
         // see also its signature https://doc.rust-lang.org/std/vec/struct.Vec.html#method.sort_by
-        if (extent_x >= extent_y) && (extent_x >= extent_z) {
-            items.sort_by(|a, b| a.2.x.partial_cmp(&b.2.x).unwrap()); // Sort by bounding box centroids x coordinate
+        let axis = if (extent_x >= extent_y) && (extent_x >= extent_z) {
+            0
         } else if extent_y >= extent_z {
-            items.sort_by(|a, b| a.2.y.partial_cmp(&b.2.y).unwrap()); // Sort by y coordinate
+            1
+        } else {
+            2
+        };
+        // NOTE: Both sorts are stable so they produce the same order
+        if parallel {
+            items.par_sort_by(|a, b| a.2[axis].partial_cmp(&b.2[axis]).unwrap());
         } else {
-            items.sort_by(|a, b| a.2.z.partial_cmp(&b.2.z).unwrap()); // Sort by z coordinate
+            items.sort_by(|a, b| a.2[axis].partial_cmp(&b.2[axis]).unwrap()); // Sort by bounding box centroids on axis
         }
+        axis
+    }
 
-        // Split items into two
-        let middle = items.len() / 2;
-        let right_items = items.split_off(middle); // Split Vec at middle
-        let left_items = items; // Remaining items are left_items
-        let left = Self::build_nodes(left_items);
-        let right = Self::build_nodes(right_items);
+    /// Binned SAH (see pbrt-v3, 4.3.2 and Wald's "On fast construction of SAH-based BVHs")
+    /// Returns None if centroids cannot be binned so that caller falls back to median split.
+    fn sah_split(items: &[BuildItem], bbox: &BBox, settings: &BVHSettings, parallel: bool) -> Option<SplitDecision> {
+
+        let n_bins = settings.bin_count;
+        let n_items = items.len();
 
-        Some(Arc::new(BVHNode { bbox: unified_bbox, left, right, objects: Vec::new() }))
+        // Bins are placed along the centroid bounds, not the actual bounds
+        let point_bbox = |c: &Vector3| BBox::new(c.x, c.x, c.y, c.y, c.z, c.z);
+        let (centroid_bbox, bins) = if parallel {
+            let centroid_bbox = items.par_iter()
+                                     .fold(BBox::empty, |acc, (_, _, c)| acc.merge(&point_bbox(c)))
+                                     .reduce(BBox::empty, |a, b| a.merge(&b));
+            let bins = items.par_chunks(PARALLEL_CHUNK_SIZE)
+                            .map(|chunk| Bins::new(n_bins).add(chunk, &centroid_bbox))
+                            .reduce(|| Bins::new(n_bins), Bins::merge);
+            (centroid_bbox, bins)
+        } else {
+            let centroid_bbox = items.iter().fold(BBox::empty(), |acc, (_, _, c)| acc.merge(&point_bbox(c)));
+            let bins = Bins::new(n_bins).add(items, &centroid_bbox);
+            (centroid_bbox, bins)
+        };
+        let parent_area = bbox.surface_area();
+
+        // (axis, bin index to split after, cost)
+        let mut best: Option<(usize, usize, Float)> = None;
+        for axis in 0..3 {
+            let (cmin, cmax) = (centroid_bbox.min_at(axis), centroid_bbox.max_at(axis));
+            if cmax - cmin <= 0.0 {
+                continue; // All centroids are on the same plane on this axis
+            }
+            let (counts, bounds) = (&bins.counts[axis], &bins.bounds[axis]);
+
+            // Sweep from right to left to accumulate right side areas
+            let mut right_areas = vec![0.0 as Float; n_bins];
+            let mut right_counts = vec![0usize; n_bins];
+            let (mut acc_box, mut acc_count) = (BBox::empty(), 0);
+            for i in (1..n_bins).rev() {
+                if counts[i] > 0 {
+                    acc_box = acc_box.merge(&bounds[i]);
+                    acc_count += counts[i];
+                }
+                right_counts[i - 1] = acc_count;
+                right_areas[i - 1] = if acc_count > 0 { acc_box.surface_area() } else { 0.0 };
+            }
+
+            // Sweep from left to right and evaluate split after bin i
+            let (mut acc_box, mut acc_count) = (BBox::empty(), 0);
+            for i in 0..(n_bins - 1) {
+                if counts[i] > 0 {
+                    acc_box = acc_box.merge(&bounds[i]);
+                    acc_count += counts[i];
+                }
+                if acc_count == 0 || right_counts[i] == 0 {
+                    continue;
+                }
+                let left_area = acc_box.surface_area();
+                let cost = TRAVERSAL_COST + INTERSECTION_COST * (left_area * acc_count as Float + right_areas[i] * right_counts[i] as Float) / parent_area;
+                if best.is_none_or(|(_, _, c)| cost < c) {
+                    best = Some((axis, i, cost));
+                }
+            }
+        }
+
+        let (axis, bin, cost) = best?;
+
+        // Creating a leaf is cheaper than splitting, if allowed by leaf size
+        let leaf_cost = INTERSECTION_COST * n_items as Float;
+        if n_items <= settings.leaf_size && leaf_cost <= cost {
+            return Some(SplitDecision::Leaf);
+        }
+
+        Some(SplitDecision::At { axis, bin, cmin: centroid_bbox.min_at(axis), cmax: centroid_bbox.max_at(axis) })
+    }
+
+    #[inline]
+    fn bin_index(centroid: Float, cmin: Float, cmax: Float, n_bins: usize) -> usize {
+        let b = (n_bins as Float * ((centroid - cmin) / (cmax - cmin))) as usize; // NaN (zero extent) casts to 0
+        b.min(n_bins - 1)
+    }
+
+    /// Flatten the tree in depth-first order, returns index of the node
+    fn flatten(node: BuildNode, nodes: &mut Vec<LinearBVHNode>, shapes: &mut Vec<u32>) -> usize {
+        let idx = nodes.len();
+        match node {
+            BuildNode::Leaf { bbox, shapes: leaf_shapes } => {
+                nodes.push(LinearBVHNode::new(&bbox, shapes.len(), leaf_shapes.len(), 0));
+                shapes.extend(leaf_shapes);
+            }
+            BuildNode::Interior { bbox, axis, children } => {
+                nodes.push(LinearBVHNode::new(&bbox, 0, 0, axis)); // Offset is set after the first child is flattened
+                let [left, right] = *children;
+                Self::flatten(left, nodes, shapes);
+                let second = Self::flatten(right, nodes, shapes);
+                nodes[idx].offset = u32::try_from(second).expect("BVH has too many nodes for u32 offsets");
+            }
+        }
+        idx
+    }
+
+    /// Collapse the binary node at idx and its children into a wide node, returns index of the wide node
+    fn collapse(nodes: &[LinearBVHNode], idx: usize, wide_nodes: &mut Vec<WideBVHNode>) -> usize {
+        let w = wide_nodes.len();
+        wide_nodes.push(WideBVHNode::empty());
+
+        let node = &nodes[idx];
+        let mut slots: [Option<usize>; 4] = [None; 4];
+        if node.is_leaf() {
+            slots[0] = Some(idx); // BVH with a single leaf
+        } else {
+            wide_nodes[w].axes[0] = node.axis;
+            for (side, child) in [idx + 1, node.offset as usize].into_iter().enumerate() {
+                let c = &nodes[child];
+                if c.is_leaf() {
+                    slots[2 * side] = Some(child);
+                } else {
+                    wide_nodes[w].axes[1 + side] = c.axis;
+                    slots[2 * side] = Some(child + 1);
+                    slots[2 * side + 1] = Some(c.offset as usize);
+                }
+            }
+        }
+
+        for (slot, child) in slots.into_iter().enumerate() {
+            if let Some(c) = child {
+                let offset = if nodes[c].is_leaf() { nodes[c].offset as usize } else { Self::collapse(nodes, c, wide_nodes) };
+                wide_nodes[w].set_child(slot, &nodes[c], offset);
+            }
+        }
+        w
     }
 
     /// Build a BVH from a list of shapes using their bounding boxes.
     /// verts needed for get_bbox( ) called inside, since shapes only store indices, 
     /// not the actual verts. 
-    pub fn build(shapes: &Vec<HeapAllocatedShape>, verts: &VertexData, apply_t: bool) -> Self 
+    pub fn build(shapes: &[HeapAllocatedShape], verts: &VertexData, apply_t: bool, settings: &BVHSettings) -> Self
     {
         if shapes.is_empty() {
-            return BVHSubtree(None);
+            return Self::empty();
         }
 
-        // Precompute for sorting: (shape pointer, its bbox, bbox centroid)
-        let mut items: Vec<(HeapAllocatedShape, BBox, Vector3)> = Vec::with_capacity(shapes.len());
-        for s in shapes.iter() {
-            let bbox = s.get_bbox(verts, apply_t);
-            let center = bbox.get_center();
-            items.push((s.clone(), bbox, center)); // clone the pointer, *s doesn't work because "s is behind a shared reference" as rustc states
+        let span = tracing::span!(tracing::Level::INFO, "build_bvh", shapes = shapes.len());
+        let _enter = span.enter();
+        let start = Instant::now();
+
+        // Recursively create nodes and flatten them
+        let root = Self::build_nodes(Self::build_items(shapes, verts, apply_t), settings, 0, PARALLEL_THRESHOLD);
+        let mut nodes = Vec::with_capacity(2 * shapes.len() - 1);
+        let mut shape_order = Vec::with_capacity(shapes.len());
+        Self::flatten(root, &mut nodes, &mut shape_order);
+
+        let bvh = Self::from_parts(nodes, shapes, shape_order, settings, None);
+        info!("Built BVH over {} shapes in {:?}", shapes.len(), start.elapsed());
+        bvh
+    }
+
+    /// Precompute for sorting: (shape index, its bbox, bbox centroid)
+    fn build_items(shapes: &[HeapAllocatedShape], verts: &VertexData, apply_t: bool) -> Vec<BuildItem> {
+        shapes.par_iter()
+              .enumerate()
+              .map(|(i, s)| {
+                  let bbox = s.get_bbox(verts, apply_t);
+                  let center = bbox.get_center();
+                  (u32::try_from(i).expect("BVH has too many shapes for u32 indices"), bbox, center)
+              })
+              .collect()
+    }
+
+    fn empty() -> Self {
+        Self {
+            nodes: Arc::from(Vec::new()),
+            wide_nodes: None,
+            shapes: Arc::from(Vec::new()),
+            shape_order: Arc::from(Vec::new()),
+            build_cost: 0.0,
         }
-        
-        // Recursively create nodes 
-        BVHSubtree(Self::build_nodes(items))
     }
 
-    // helper function to recursively traverse the tree 
-    #[inline]
-    fn walk(node: &Arc<BVHNode>, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts, closest: &mut Option<HitRecord>, early_break: bool) {
-        if !node.bbox.intersect(ray) { return; }  // This is the base case return for recursive helper, not the outer intersect( )!
-                                                  
-        if node.objects.is_empty() {
-            if let Some(l) = &node.left { Self::walk(l, ray, t_interval, vertex_cache, closest, early_break); }
-            if let Some(r) = &node.right { Self::walk(r, ray, t_interval, vertex_cache, closest, early_break); }
+    /// Assemble BVH from flattened nodes, collapsing them if a wide BVH is requested.
+    /// If build_cost is None, nodes are assumed to be freshly built and their SAH cost is used.
+    fn from_parts(nodes: Vec<LinearBVHNode>, shapes: &[HeapAllocatedShape], shape_order: Vec<u32>, settings: &BVHSettings, build_cost: Option<Float>) -> Self {
+        let wide_nodes = if settings.width == 4 {
+            let mut wide_nodes = Vec::with_capacity(nodes.len() / 3 + 1);
+            Self::collapse(&nodes, 0, &mut wide_nodes);
+            debug!("Collapsed {} binary BVH nodes into {} wide nodes", nodes.len(), wide_nodes.len());
+            Some(Arc::from(wide_nodes))
         } else {
-            // Reached to leaf node (remember only leaf nodes have objects) 
-            for obj in &node.objects {
-                if let Some(hit) = obj.intersects_with(ray, t_interval, vertex_cache) { // Note to self: this is where BLAS called
-                    
-                    if let Some(existing) = &closest {
-                        if hit.ray_t < existing.ray_t {
-                            *closest = Some(hit);
-                        }
-                    } else {
-                        *closest = Some(hit);
-                    }
+            None
+        };
+
+        let ordered_shapes: Vec<HeapAllocatedShape> = shape_order.iter().map(|&i| shapes[i as usize].clone()).collect();
+        let mut bvh = Self {
+            nodes: Arc::from(nodes),
+            wide_nodes,
+            shapes: Arc::from(ordered_shapes),
+            shape_order: Arc::from(shape_order),
+            build_cost: 0.0,
+        };
+        bvh.build_cost = build_cost.unwrap_or_else(|| bvh.stats().sah_cost);
+        bvh
+    }
 
+    /// Refit the BVH to shapes that have moved since it was built, e.g. in the next frame of an animation.
+    /// shapes[i] must take the place of the i-th shape given to build( ) (callers make sure of it, see Mesh::setup( )
+    /// and SceneObjects::has_same_layout( )), the tree topology is kept and boxes are recomputed bottom-up.
+    /// The BVH is rebuilt instead if the number of shapes differs,
+    /// or the refitted SAH cost exceeds settings.refit_threshold times the cost at the last build.
+    pub fn refit(&self, shapes: &[HeapAllocatedShape], verts: &VertexData, apply_t: bool, settings: &BVHSettings) -> Self {
+        if shapes.len() != self.shape_order.len() || shapes.is_empty() || settings.refit_threshold <= 0.0 {
+            return Self::build(shapes, verts, apply_t, settings);
+        }
+
+        let span = tracing::span!(tracing::Level::INFO, "refit_bvh", shapes = shapes.len());
+        let _enter = span.enter();
+        let start = Instant::now();
+
+        let shape_boxes: Vec<BBox> = self.shape_order.par_iter()
+                                                     .map(|&i| shapes[i as usize].get_bbox(verts, apply_t))
+                                                     .collect();
+
+        // Children are always placed after their parent in depth-first order, so visiting
+        // the nodes backwards refits both children before their parent
+        let mut nodes = self.nodes.to_vec();
+        let mut boxes = vec![BBox::empty(); nodes.len()];
+        for idx in (0..nodes.len()).rev() {
+            let node = nodes[idx];
+            let bbox = if node.is_leaf() {
+                let first = node.offset as usize;
+                shape_boxes[first..first + node.n_prims as usize].iter().fold(BBox::empty(), |acc, b| acc.merge(b))
+            } else {
+                boxes[idx + 1].merge(&boxes[node.offset as usize])
+            };
+            nodes[idx] = LinearBVHNode::new(&bbox, node.offset as usize, node.n_prims as usize, node.axis);
+            boxes[idx] = bbox;
+        }
+
+        let bvh = Self::from_parts(nodes, shapes, self.shape_order.to_vec(), settings, Some(self.build_cost));
+        let cost = bvh.stats().sah_cost;
+        if cost > settings.refit_threshold * self.build_cost {
+            info!("Refitted BVH SAH cost {:.3} exceeds {} x {:.3}, rebuilding it.", cost, settings.refit_threshold, self.build_cost);
+            return Self::build(shapes, verts, apply_t, settings);
+        }
+        info!("Refitted BVH over {} shapes in {:?} (SAH cost: {:.3}, at build: {:.3})", shapes.len(), start.elapsed(), cost, self.build_cost);
+        bvh
+    }
+
+    /// Convert to cacheable form, see from_cached( )
+    pub fn to_cached(&self) -> CachedBVH {
+        CachedBVH {
+            nodes: self.nodes.to_vec(),
+            wide_nodes: self.wide_nodes.as_ref().map(|w| w.to_vec()),
+            shape_order: self.shape_order.to_vec(),
+            build_cost: self.build_cost,
+        }
+    }
+
+    /// Restore a BVH from the cache over the same shapes it was built for.
+    /// Returns None if the cached BVH does not fit the shapes, e.g. the shape list has changed.
+    pub fn from_cached(cached: CachedBVH, shapes: &[HeapAllocatedShape]) -> Option<Self> {
+        let ordered_shapes = cached.shape_order.iter()
+                                               .map(|&i| shapes.get(i as usize).cloned())
+                                               .collect::<Option<Vec<_>>>()?;
+        if ordered_shapes.len() != shapes.len() {
+            return None;
+        }
+        let leaves_in_range = cached.nodes.iter()
+                                          .filter(|n| n.is_leaf())
+                                          .all(|n| n.offset as usize + n.n_prims as usize <= ordered_shapes.len());
+        if !leaves_in_range {
+            return None;
+        }
+        Some(Self {
+            nodes: Arc::from(cached.nodes),
+            wide_nodes: cached.wide_nodes.map(Arc::from),
+            shapes: Arc::from(ordered_shapes),
+            shape_order: Arc::from(cached.shape_order),
+            build_cost: cached.build_cost,
+        })
+    }
+
+    /// Collect build quality statistics to compare builders
+    pub fn stats(&self) -> BVHStats {
+        let mut stats = BVHStats::default();
+        if let Some(root) = self.nodes.first() {
+            let root_area = root.bbox().surface_area();
+            self.collect_stats(0, 1, root_area, &mut stats);
+        }
+        stats
+    }
+
+    fn collect_stats(&self, idx: usize, depth: usize, root_area: Float, stats: &mut BVHStats) {
+        let node = &self.nodes[idx];
+        stats.n_nodes += 1;
+        stats.max_depth = stats.max_depth.max(depth);
+
+        // Relative probability of hitting this node given that the root is hit
+        let area_ratio = if root_area > 0.0 { node.bbox().surface_area() / root_area } else { 1.0 };
+        if node.is_leaf() {
+            let n = node.n_prims as usize;
+            stats.n_leaves += 1;
+            stats.n_shapes += n;
+            stats.sah_cost += INTERSECTION_COST * n as Float * area_ratio;
+            *stats.leaf_histogram.entry(n).or_insert(0) += 1;
+        } else {
+            stats.sah_cost += TRAVERSAL_COST * area_ratio;
+            self.collect_stats(idx + 1, depth + 1, root_area, stats);
+            self.collect_stats(node.offset as usize, depth + 1, root_area, stats);
+        }
+    }
+
+    #[inline]
+    fn leaf_shapes(&self, offset: u32, n_prims: u16) -> &[HeapAllocatedShape] {
+        let first = offset as usize;
+        &self.shapes[first..first + n_prims as usize]
+    }
+
+    /// Intersect shapes in a leaf, updates closest hit and shrinks t_max to it
+    #[inline]
+    fn intersect_leaf(shapes: &[HeapAllocatedShape], ray: &Ray, t_min: Float, t_max: &mut Float, vertex_cache: &HeapAllocatedVerts,
+                      closest: &mut Option<HitRecord>, early_break: bool) {
+        let interval = Interval::new(t_min, *t_max);
+        for obj in shapes {
+            if let Some(hit) = obj.intersects_with(ray, &interval, vertex_cache) // Note to self: this is where BLAS called
+                && closest.as_ref().is_none_or(|c| hit.ray_t < c.ray_t) {
+                    *t_max = t_max.min(hit.ray_t);
+                    *closest = Some(hit);
                     if early_break {
-                        return;
+                        break;
                     }
-                }
             }
-        } 
+        }
     }
 
     /// Intersect a ray with the BVH. 
     /// Returns true if any hit was found and mutates hitrecord to closest hit.
+    /// Nodes are visited near child first, and nodes beyond the closest hit so far are culled.
     /// TODO: Now this is literally the same as Shape, BVHSubtree itself could impl Shape 
     pub fn intersect(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts, rec: &mut HitRecord, early_break: bool) -> bool {
-        
-        // NOTE: See the following link for this match &self.0 pattern here
-        // https://google.github.io/comprehensive-rust/smart-pointers/solution.html
-        match &self.0 {
-            None => false,
-            Some(root) => { 
-                rec.ray_t = FloatConst::INF; 
-                let mut closest: Option<HitRecord> = None;
-                Self::walk(root, ray, t_interval, vertex_cache, &mut closest, early_break);
-                if let Some(h) = closest {
-                    *rec = h;
-                    true
+        rec.ray_t = FloatConst::INF;
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let closest = if let Some(wide_nodes) = &self.wide_nodes {
+            self.intersect_wide(wide_nodes, ray, t_interval, vertex_cache, early_break)
+        } else {
+            self.intersect_binary(ray, t_interval, vertex_cache, early_break)
+        };
+
+        if let Some(h) = closest {
+            *rec = h;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn intersect_binary(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts, early_break: bool) -> Option<HitRecord> {
+        let inv_dir = ray.direction.recip();
+        let dir_is_neg = [inv_dir.x < 0., inv_dir.y < 0., inv_dir.z < 0.];
+        let mut closest: Option<HitRecord> = None;
+        let mut t_max = t_interval.max; // Shrinks as closer hits are found
+
+        let mut to_visit = [0usize; TRAVERSAL_STACK_SIZE];
+        let mut stack_size = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            if node.hits(&ray.origin, &inv_dir, t_interval.min, t_max) {
+                if node.is_leaf() {
+                    // Reached to leaf node (remember only leaf nodes have shapes)
+                    Self::intersect_leaf(self.leaf_shapes(node.offset, node.n_prims), ray, t_interval.min, &mut t_max, vertex_cache, &mut closest, early_break);
+                    if early_break && closest.is_some() {
+                        break;
+                    }
                 } else {
-                    false
-                }   
+                    // Put the far child on the stack and visit the near one
+                    let (near, far) = if dir_is_neg[node.axis as usize] {
+                        (node.offset as usize, current + 1)
+                    } else {
+                        (current + 1, node.offset as usize)
+                    };
+                    to_visit[stack_size] = far;
+                    stack_size += 1;
+                    current = near;
+                    continue;
+                }
             }
+
+            if stack_size == 0 {
+                break;
+            }
+            stack_size -= 1;
+            current = to_visit[stack_size];
         }
-        
+        closest
     }
-}
\ No newline at end of file
+
+    /// Same as intersect_binary( ) but four children are tested at once. Children are visited in the
+    /// same order as the binary traversal and a child is skipped if a closer hit is found after it is pushed
+    /// (where binary traversal would test its box again), so the same leaves are visited in the same order.
+    fn intersect_wide(&self, wide_nodes: &[WideBVHNode], ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts, early_break: bool) -> Option<HitRecord> {
+        let inv_dir = ray.direction.recip();
+        let dir_is_neg = [inv_dir.x < 0., inv_dir.y < 0., inv_dir.z < 0.];
+        let mut closest: Option<HitRecord> = None;
+        let mut t_max = t_interval.max;
+
+        // (offset, n_prims, entry distance) of children to visit
+        let mut to_visit = [(0u32, 0u16, 0.0 as Float); WIDE_TRAVERSAL_STACK_SIZE];
+        let mut stack_size = 0;
+        let mut current = Some(0);
+        loop {
+            if let Some(idx) = current.take() {
+                let node: &WideBVHNode = &wide_nodes[idx];
+                let (mask, t_entry) = node.hits(&ray.origin, &inv_dir, t_interval.min, t_max);
+                // Push in reverse so that the nearest child is popped first
+                for &slot in node.visit_order(&dir_is_neg).iter().rev() {
+                    if mask & (1 << slot) != 0 {
+                        to_visit[stack_size] = (node.offset[slot], node.n_prims[slot], t_entry[slot]);
+                        stack_size += 1;
+                    }
+                }
+            }
+
+            if stack_size == 0 {
+                break;
+            }
+            stack_size -= 1;
+            let (offset, n_prims, t_entry) = to_visit[stack_size];
+            if t_entry > t_max {
+                continue;
+            }
+            if n_prims > 0 {
+                Self::intersect_leaf(self.leaf_shapes(offset, n_prims), ray, t_interval.min, &mut t_max, vertex_cache, &mut closest, early_break);
+                if early_break && closest.is_some() {
+                    break;
+                }
+            } else {
+                current = Some(offset as usize);
+            }
+        }
+        closest
+    }
+
+    /// Any-hit traversal for shadow rays, returns at the first hit in any leaf without building a HitRecord
+    pub fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        if let Some(wide_nodes) = &self.wide_nodes {
+            return self.occluded_wide(wide_nodes, ray, t_interval, vertex_cache);
+        }
+
+        let inv_dir = ray.direction.recip();
+        let dir_is_neg = [inv_dir.x < 0., inv_dir.y < 0., inv_dir.z < 0.];
+
+        let mut to_visit = [0usize; TRAVERSAL_STACK_SIZE];
+        let mut stack_size = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            if node.hits(&ray.origin, &inv_dir, t_interval.min, t_interval.max) {
+                if node.is_leaf() {
+                    if self.leaf_shapes(node.offset, node.n_prims).iter().any(|obj| obj.occluded(ray, t_interval, vertex_cache)) {
+                        return true;
+                    }
+                } else {
+                    // Order does not matter for correctness but near child is more likely to occlude
+                    let (near, far) = if dir_is_neg[node.axis as usize] {
+                        (node.offset as usize, current + 1)
+                    } else {
+                        (current + 1, node.offset as usize)
+                    };
+                    to_visit[stack_size] = far;
+                    stack_size += 1;
+                    current = near;
+                    continue;
+                }
+            }
+
+            if stack_size == 0 {
+                return false;
+            }
+            stack_size -= 1;
+            current = to_visit[stack_size];
+        }
+    }
+
+    fn occluded_wide(&self, wide_nodes: &[WideBVHNode], ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        let inv_dir = ray.direction.recip();
+        let dir_is_neg = [inv_dir.x < 0., inv_dir.y < 0., inv_dir.z < 0.];
+
+        let mut to_visit = [(0u32, 0u16); WIDE_TRAVERSAL_STACK_SIZE];
+        let mut stack_size = 0;
+        let mut current = Some(0);
+        loop {
+            if let Some(idx) = current.take() {
+                let node: &WideBVHNode = &wide_nodes[idx];
+                let (mask, _) = node.hits(&ray.origin, &inv_dir, t_interval.min, t_interval.max);
+                for &slot in node.visit_order(&dir_is_neg).iter().rev() {
+                    if mask & (1 << slot) != 0 {
+                        to_visit[stack_size] = (node.offset[slot], node.n_prims[slot]);
+                        stack_size += 1;
+                    }
+                }
+            }
+
+            if stack_size == 0 {
+                return false;
+            }
+            stack_size -= 1;
+            let (offset, n_prims) = to_visit[stack_size];
+            if n_prims > 0 {
+                if self.leaf_shapes(offset, n_prims).iter().any(|obj| obj.occluded(ray, t_interval, vertex_cache)) {
+                    return true;
+                }
+            } else {
+                current = Some(offset as usize);
+            }
+        }
+    }
+}
+
+// ====================================================================================================
+// Accelerator interface
+// ====================================================================================================
+
+/// Closest hit and any hit queries over a set of shapes, implemented by BVHSubtree, KdTree and
+/// NaiveAccelerator so that they can be swapped per scene (see AcceleratorKind)
+pub trait Accelerator: fmt::Debug + Send + Sync {
+    /// Find the closest hit within t_interval and write it to rec, returns false if nothing is hit.
+    /// If early_break is set, the first hit found is returned instead of the closest one.
+    fn intersect(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts, rec: &mut HitRecord, early_break: bool) -> bool;
+
+    /// True if any shape blocks the ray within t_interval
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool;
+}
+
+impl Accelerator for BVHSubtree {
+    fn intersect(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts, rec: &mut HitRecord, early_break: bool) -> bool {
+        BVHSubtree::intersect(self, ray, t_interval, vertex_cache, rec, early_break)
+    }
+
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        BVHSubtree::occluded(self, ray, t_interval, vertex_cache)
+    }
+}
+
+/// Iterate over all shapes (previously Scene3D::hit_naive( )), kept as a reference to debug the others
+#[derive(Debug, Clone)]
+pub struct NaiveAccelerator {
+    pub shapes: Arc<[HeapAllocatedShape]>,
+}
+
+impl NaiveAccelerator {
+    pub fn new(shapes: &[HeapAllocatedShape]) -> Self {
+        Self { shapes: Arc::from(shapes) }
+    }
+}
+
+/// Closest hit by testing every shape, also used by Scene3D::hit( ) if the scene accelerator is not built.
+/// If early_break is set, the first hit found is returned instead of the closest one.
+pub fn closest_hit_naive(shapes: &[HeapAllocatedShape], ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts, early_break: bool) -> Option<HitRecord> {
+    // Refers to p.91 of slide 01_b, lines 3-7
+    let mut closest: Option<HitRecord> = None;
+    for shape in shapes {
+        if let Some(hit_record) = shape.intersects_with(ray, t_interval, vertex_cache) {
+            if early_break {
+                return Some(hit_record);
+            }
+            // Update if new hit is closer
+            if closest.as_ref().is_none_or(|c| hit_record.ray_t < c.ray_t) {
+                closest = Some(hit_record);
+            }
+        }
+    }
+    closest
+}
+
+impl Accelerator for NaiveAccelerator {
+    fn intersect(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts, rec: &mut HitRecord, early_break: bool) -> bool {
+        if let Some(h) = closest_hit_naive(&self.shapes, ray, t_interval, vertex_cache, early_break) {
+            *rec = h;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        self.shapes.iter().any(|shape| shape.occluded(ray, t_interval, vertex_cache))
+    }
+}
+
+// ====================================================================================================
+// Build quality report
+// ====================================================================================================
+
+#[derive(Debug, Default, Clone)]
+pub struct BVHStats {
+    pub sah_cost: Float, // Expected cost of a ray that hits the root (relative to TRAVERSAL_COST, INTERSECTION_COST)
+    pub max_depth: usize,
+    pub n_nodes: usize,
+    pub n_leaves: usize,
+    pub n_shapes: usize,
+    pub leaf_histogram: BTreeMap<usize, usize>, // Number of shapes in leaf -> number of such leaves
+}
+
+impl fmt::Display for BVHStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SAH cost: {:.3}, depth: {}, nodes: {}, leaves: {}, shapes: {}, leaf histogram: {{",
+                self.sah_cost, self.max_depth, self.n_nodes, self.n_leaves, self.n_shapes)?;
+        for (i, (size, count)) in self.leaf_histogram.iter().enumerate() {
+            if i > 0 { write!(f, ", ")?; }
+            write!(f, "{}: {}", size, count)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use crate::scene::VertexCache;
+    use crate::shapes::{CommonPrimitiveData, Triangle};
+
+    fn random_point(rng: &mut StdRng, extent: Float) -> Vector3 {
+        Vector3::new(rng.random_range(-extent..extent), rng.random_range(-extent..extent), rng.random_range(-extent..extent))
+    }
+
+    /// n small triangles scattered in [-1, 1]^3 and the vertex cache they refer to
+    pub(crate) fn triangle_soup(n: usize, seed: u64) -> (Vec<HeapAllocatedShape>, HeapAllocatedVerts) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut vertices = vec![Vector3::ZERO]; // Vertex indices start from 1
+        let mut shapes: Vec<HeapAllocatedShape> = Vec::with_capacity(n);
+        for i in 0..n {
+            let center = random_point(&mut rng, 1.);
+            let first = vertices.len();
+            for _ in 0..3 {
+                vertices.push(center + random_point(&mut rng, 0.2));
+            }
+            shapes.push(Arc::new(Triangle {
+                _data: CommonPrimitiveData { _id: i + 1, material_idx: 1, ..Default::default() },
+                vert_indices: [first, first + 1, first + 2],
+                ..Default::default()
+            }));
+        }
+        let vertex_data = VertexData { _data: vertices, _type: String::from("xyz"), ..Default::default() };
+        (shapes, Arc::new(VertexCache { vertex_data, ..Default::default() }))
+    }
+
+    /// Rays from around the soup above towards random directions
+    pub(crate) fn random_rays(n: usize, seed: u64) -> Vec<Ray> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n).map(|_| {
+                  let origin = random_point(&mut rng, 2.);
+                  let target = random_point(&mut rng, 1.);
+                  Ray::new(origin, (target - origin).normalize(), 0.)
+              })
+              .collect()
+    }
+
+    /// Closest hit distance of every ray, None if it misses
+    pub(crate) fn nearest_hits(accelerator: &dyn Accelerator, rays: &[Ray], verts: &HeapAllocatedVerts) -> Vec<Option<Float>> {
+        let interval = Interval::new(1e-6, FloatConst::INF);
+        rays.iter()
+            .map(|ray| {
+                let mut rec = HitRecord::default();
+                accelerator.intersect(ray, &interval, verts, &mut rec, false).then_some(rec.ray_t)
+            })
+            .collect()
+    }
+
+    fn settings(builder: BVHBuilder, width: usize) -> BVHSettings {
+        BVHSettings { builder, width, ..Default::default() }
+    }
+
+    #[test]
+    fn builders_find_same_nearest_hits() {
+        let (shapes, verts) = triangle_soup(300, 1);
+        let rays = random_rays(500, 2);
+        let expected = nearest_hits(&NaiveAccelerator::new(&shapes), &rays, &verts);
+        assert!(expected.iter().filter(|t| t.is_some()).count() > 100, "Too few rays hit the soup to compare");
+
+        for builder in [BVHBuilder::SAH, BVHBuilder::Median] {
+            let bvh = BVHSubtree::build(&shapes, &verts.vertex_data, true, &settings(builder, 2));
+            assert_eq!(nearest_hits(&bvh, &rays, &verts), expected, "{:?} BVH differs from naive", builder);
+        }
+    }
+
+    #[test]
+    fn flattened_layout() {
+        let (shapes, verts) = triangle_soup(200, 3);
+        let bvh = BVHSubtree::build(&shapes, &verts.vertex_data, true, &settings(BVHBuilder::SAH, 2));
+        let nodes = &bvh.nodes;
+
+        // Depth-first order: first child is next to its parent, and leaves cover the shapes in order
+        let mut next_shape = 0;
+        for (idx, node) in nodes.iter().enumerate() {
+            if node.is_leaf() {
+                assert_eq!(node.offset as usize, next_shape);
+                next_shape += node.n_prims as usize;
+            } else {
+                let second = node.offset as usize;
+                assert!(second > idx + 1 && second < nodes.len());
+                for child in [&nodes[idx + 1], &nodes[second]] {
+                    let inside = (0..3).all(|axis| child.bounds_min[axis] >= node.bounds_min[axis] && child.bounds_max[axis] <= node.bounds_max[axis]);
+                    assert!(inside, "Child box is not inside its parent");
+                }
+            }
+        }
+        assert_eq!(next_shape, shapes.len());
+        let stats = bvh.stats();
+        assert_eq!(stats.n_nodes, nodes.len());
+        assert_eq!(stats.n_nodes, 2 * stats.n_leaves - 1);
+
+        // Leaf shapes are the input shapes reordered
+        let mut order = bvh.shape_order.to_vec();
+        order.sort();
+        assert!(order.iter().enumerate().all(|(i, &s)| i == s as usize));
+    }
+
+    #[test]
+    fn wide_visit_order_follows_binary_traversal() {
+        let mut node = WideBVHNode::empty();
+        node.axes = [0, 1, 2]; // Root split on x, its children on y and z
+        assert_eq!(node.visit_order(&[false, false, false]), [0, 1, 2, 3]);
+        assert_eq!(node.visit_order(&[true, false, false]), [2, 3, 0, 1]); // Right side is near
+        assert_eq!(node.visit_order(&[false, true, false]), [1, 0, 2, 3]); // Only left side children swap
+        assert_eq!(node.visit_order(&[true, false, true]), [3, 2, 0, 1]);
+    }
+
+    #[test]
+    fn parallel_build_matches_serial() {
+        let (shapes, verts) = triangle_soup(1000, 4);
+        for builder in [BVHBuilder::SAH, BVHBuilder::Median] {
+            let settings = settings(builder, 2);
+            let flattened = |parallel_threshold: usize| {
+                let items = BVHSubtree::build_items(&shapes, &verts.vertex_data, true);
+                let root = BVHSubtree::build_nodes(items, &settings, 0, parallel_threshold);
+                let (mut nodes, mut order) = (Vec::new(), Vec::new());
+                BVHSubtree::flatten(root, &mut nodes, &mut order);
+                (nodes, order)
+            };
+            // Every node is built in parallel on one side and none on the other
+            assert!(flattened(2) == flattened(usize::MAX), "{:?} parallel build differs from serial", builder);
+        }
+    }
+
+    #[test]
+    fn occluded_agrees_with_intersect() {
+        let (shapes, verts) = triangle_soup(300, 5);
+        let rays = random_rays(500, 6);
+        let naive = NaiveAccelerator::new(&shapes);
+        let binary = BVHSubtree::build(&shapes, &verts.vertex_data, true, &settings(BVHBuilder::SAH, 2));
+        let wide = BVHSubtree::build(&shapes, &verts.vertex_data, true, &settings(BVHBuilder::SAH, 4));
+
+        // Bounded intervals as in shadow rays, so that some rays end before the shapes they would hit
+        let interval = Interval::new(1e-6, 2.);
+        for accelerator in [&naive as &dyn Accelerator, &binary, &wide] {
+            for ray in &rays {
+                let mut rec = HitRecord::default();
+                let hit = accelerator.intersect(ray, &interval, &verts, &mut rec, false);
+                assert_eq!(accelerator.occluded(ray, &interval, &verts), hit);
+            }
+        }
+    }
+
+    #[test]
+    fn wide_and_binary_find_same_hits() {
+        let (shapes, verts) = triangle_soup(500, 7);
+        let rays = random_rays(500, 8);
+        for builder in [BVHBuilder::SAH, BVHBuilder::Median] {
+            let binary = BVHSubtree::build(&shapes, &verts.vertex_data, true, &settings(builder, 2));
+            let wide = BVHSubtree::build(&shapes, &verts.vertex_data, true, &settings(builder, 4));
+            assert!(wide.wide_nodes.is_some() && binary.wide_nodes.is_none());
+            assert_eq!(wide.nodes, binary.nodes, "Wide BVH is collapsed from the same binary tree");
+            assert_eq!(nearest_hits(&wide, &rays, &verts), nearest_hits(&binary, &rays, &verts));
+        }
+    }
+
+    #[test]
+    fn refit_matches_rebuild_after_vertices_move() {
+        let (shapes, verts) = triangle_soup(300, 9);
+        let settings = BVHSettings { refit_threshold: Float::MAX, ..Default::default() }; // Never rebuild
+        let bvh = BVHSubtree::build(&shapes, &verts.vertex_data, true, &settings);
+
+        // Same triangles in the next frame, each vertex moved by a different amount
+        let mut moved = verts.vertex_data.clone();
+        for (i, v) in moved._data.iter_mut().enumerate().skip(1) {
+            *v += Vector3::new(0.3 * (i as Float).sin(), 0.1, -0.2 * (i as Float).cos());
+        }
+        let moved = Arc::new(VertexCache { vertex_data: moved, ..Default::default() });
+
+        let refitted = bvh.refit(&shapes, &moved.vertex_data, true, &settings);
+        let offsets = |b: &BVHSubtree| b.nodes.iter().map(|n| (n.offset, n.n_prims)).collect::<Vec<_>>();
+        assert_eq!(offsets(&refitted), offsets(&bvh), "Refit should keep the tree topology");
+
+        let rays = random_rays(500, 10);
+        let rebuilt = BVHSubtree::build(&shapes, &moved.vertex_data, true, &settings);
+        let expected = nearest_hits(&rebuilt, &rays, &moved);
+        assert_eq!(nearest_hits(&refitted, &rays, &moved), expected);
+        assert_eq!(nearest_hits(&NaiveAccelerator::new(&shapes), &rays, &moved), expected);
+    }
+}
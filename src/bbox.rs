@@ -69,15 +69,16 @@ impl BBox {
 
     /// Merge two bboxes into a single one by
     /// comparing their extents
+    /// NOTE: Does not call new( ) since merging two empty boxes is valid (e.g. in parallel reductions)
     pub fn merge(&self, other: &Self) -> Self {
-        Self::new(
-            self.xmin.min(other.xmin),
-            self.xmax.max(other.xmax),
-            self.ymin.min(other.ymin),
-            self.ymax.max(other.ymax),
-            self.zmin.min(other.zmin),
-            self.zmax.max(other.zmax),
-        )
+        Self {
+            xmin: self.xmin.min(other.xmin),
+            xmax: self.xmax.max(other.xmax),
+            ymin: self.ymin.min(other.ymin),
+            ymax: self.ymax.max(other.ymax),
+            zmin: self.zmin.min(other.zmin),
+            zmax: self.zmax.max(other.zmax),
+        }
     }
 
     pub fn get_largest_extents(bboxes: &Vec<&Self>) -> (Float, Float, Float) {
@@ -94,6 +95,35 @@ impl BBox {
          Vector3::new((self.xmin + self.xmax) * 0.5, (self.ymin + self.ymax) * 0.5, (self.zmin + self.zmax) * 0.5)
     }
 
+    /// Surface area used by SAH, see pbrt-v3, 4.3.2
+    pub fn surface_area(&self) -> Float {
+        let (dx, dy, dz) = (self.xmax - self.xmin, self.ymax - self.ymin, self.zmax - self.zmin);
+        if dx < 0. || dy < 0. || dz < 0. {
+            return 0.; // Empty box
+        }
+        2. * (dx * dy + dx * dz + dy * dz)
+    }
+
+    /// Minimum extent along axis 0, 1, 2 for x, y, z respectively
+    pub fn min_at(&self, axis: usize) -> Float {
+        match axis {
+            0 => self.xmin,
+            1 => self.ymin,
+            2 => self.zmin,
+            _ => panic!("Invalid axis {} for bbox", axis),
+        }
+    }
+
+    /// Maximum extent along axis 0, 1, 2 for x, y, z respectively
+    pub fn max_at(&self, axis: usize) -> Float {
+        match axis {
+            0 => self.xmax,
+            1 => self.ymax,
+            2 => self.zmax,
+            _ => panic!("Invalid axis {} for bbox", axis),
+        }
+    }
+
     pub fn get_sphere_radius(&self) -> Float {
         0.5 * Vector3::new(
             self.xmax - self.xmin,
@@ -129,10 +159,8 @@ impl BBox {
         t1 <= t2 
     }
 
-    /// Transform the bounding box given a 4x4 matrix
-    pub fn transform(&self, matrix: &Matrix4) -> Self {
-        // TODO: why not directly apply transform to points? do we really need to compute new extents?
-        let corners = [
+    fn corners(&self) -> [Vector3; 8] {
+        [
             Vector3::new(self.xmin, self.ymin, self.zmin),
             Vector3::new(self.xmin, self.ymin, self.zmax),
             Vector3::new(self.xmin, self.ymax, self.zmin),
@@ -141,9 +169,13 @@ impl BBox {
             Vector3::new(self.xmax, self.ymin, self.zmax),
             Vector3::new(self.xmax, self.ymax, self.zmin),
             Vector3::new(self.xmax, self.ymax, self.zmax),
-        ];
+        ]
+    }
 
-        let transformed_corners: Vec<Vector3> = corners
+    /// Transform the bounding box given a 4x4 matrix
+    pub fn transform(&self, matrix: &Matrix4) -> Self {
+        // TODO: why not directly apply transform to points? do we really need to compute new extents?
+        let transformed_corners: Vec<Vector3> = self.corners()
             .iter()
             .map(|corner| matrix.transform_point3(*corner))
             .collect();
@@ -165,6 +197,21 @@ impl BBox {
         Self::new(xmin, xmax, ymin, ymax, zmin, zmax)
     }
 
+    /// Bounding box swept by the box moving with the animated transform during ray times [0, 1].
+    /// Boxes at a few times are merged, then padded by the distance a corner can travel halfway between
+    /// them, so that the arcs of rotating corners are covered as well.
+    pub fn transform_animated(&self, motion: &AnimatedTransform) -> Self {
+        const STEPS: usize = 8;
+        let mut swept = Self::empty();
+        for i in 0..=STEPS {
+            swept = swept.merge(&self.transform(&motion.at(i as Float / STEPS as Float).matrix));
+        }
+
+        let max_speed = self.corners().iter().map(|&c| motion.max_speed(c)).fold(0., Float::max);
+        let pad = max_speed / (2 * STEPS) as Float;
+        Self::new(swept.xmin - pad, swept.xmax + pad, swept.ymin - pad, swept.ymax + pad, swept.zmin - pad, swept.zmax + pad)
+    }
+
     /// To be used in motion blur
     pub fn expand_by_motion(&self, motion: Vector3) -> Self {
         if motion.norm_squared() < 1e-10 {
@@ -145,7 +145,13 @@ pub struct Camera {
     pub(crate) transformation_names: Option<String>,
 
     #[serde(skip)]
-    pub(crate) composite_mat: Matrix4,
+    pub(crate) transform: Transform,
+
+    #[serde(rename = "EndTransformations")]
+    pub(crate) end_transformation_names: Option<String>, // Camera moves from Transformations to these during the shutter
+
+    #[serde(skip)]
+    pub(crate) motion: Option<AnimatedTransform>,
 
     #[serde(rename = "Tonemap")]
     pub(crate) tone_maps: SingleOrVec<ToneMap>,
@@ -202,15 +208,18 @@ impl Camera {
         // corrects Up vector if given Up was not perpendicular to
         // Gaze vector.
 
-         self.composite_mat = if self.transformation_names.is_some() {
-                parse_transform_expression(
+         self.transform = if self.transformation_names.is_some() {
+                Transform::new(parse_transform_expression(
                     self.transformation_names.as_deref().unwrap_or(""),
                     transforms,  
-                )
+                ))
         } else {
             debug!("No transformation matrix found for camera, defaulting to Identity...");
-            Matrix4::IDENTITY
+            Transform::IDENTITY
         };
+        self.motion = self.end_transformation_names.as_deref().map(|names| 
+            AnimatedTransform::new(self.transform, Transform::new(parse_transform_expression(names, transforms)))
+        );
 
         if self._type == "lookAt" {
             info!("Found camera _type = lookAt, constructing nearplane...");
@@ -233,10 +242,10 @@ impl Camera {
         self.v = self.w.cross(self.u).normalize();  // directly use corrected up
         
         // Apply transformations
-        self.position = transform_point(&self.composite_mat, &self.position);
-        self.w = transform_dir(&self.composite_mat, &self.w); //.normalize(); -- this doesn't let camera to zoom in under scaling
-        self.u = transform_dir(&self.composite_mat, &self.u); //.normalize();
-        self.v = transform_dir(&self.composite_mat, &self.v);//.normalize();
+        self.position = self.transform.point(&self.position);
+        self.w = self.transform.dir(&self.w); //.normalize(); -- this doesn't let camera to zoom in under scaling
+        self.u = self.transform.dir(&self.u); //.normalize();
+        self.v = self.transform.dir(&self.v);//.normalize();
         
         debug_assert!(approx_zero(self.u.dot(self.w))); 
         debug_assert!(approx_zero(self.v.dot(self.w))); 
@@ -298,7 +307,7 @@ impl Camera {
                                 let p = r.at(t_fd);
                                 let d = (p - s).normalize(); // added this to prevent debug assert failing
                                 let primary_ray = Ray::new(*s, d, time);
-                                rays.push(primary_ray);
+                                rays.push(self.moved(primary_ray));
                             }
                             info!("Sampling primary rays done.");
                         },
@@ -308,7 +317,7 @@ impl Camera {
                             for sample in pixel_samples.iter() {            
                                 let direction = (sample - ray_origin).normalize(); 
                                 let time = random_float();
-                                rays.push(Ray::new(ray_origin, direction, time));
+                                rays.push(self.moved(Ray::new(ray_origin, direction, time)));
                             }
                         },
              _ => { panic!("Unknown aperture type: {}", aperature_type)}
@@ -318,6 +327,14 @@ impl Camera {
     }
 
 
+    /// Rays are generated with the camera at its start transform, this moves them to where the camera
+    /// is at ray time if it has EndTransformations (see numeric::AnimatedTransform)
+    fn moved(&self, ray: Ray) -> Ray {
+        let Some(motion) = &self.motion else { return ray; };
+        let delta = motion.at(ray.time).matrix * self.transform.inverse;
+        Ray::new(transform_point(&delta, &ray.origin), transform_dir(&delta, &ray.direction).normalize(), ray.time)
+    }
+
     pub fn calculate_nearplane_uv(&self, ray: &Ray) -> [Float; 2] {
 
         let d = ray.direction.normalize(); // TODO remove normalize if debug assert passes
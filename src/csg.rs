@@ -0,0 +1,303 @@
+/*
+
+    Constructive Solid Geometry (CSG): union, intersection or difference of
+    closed child shapes.
+
+    Children are Sphere, Box, Torus, Mesh, MeshInstance or other CSG objects,
+    referred by "<Kind>:<_id>" tokens in scene JSON. Objects that are children
+    of a CSG are not rendered by themselves, e.g. a box with a spherical hole:
+
+        "CSG": {
+            "_id": "1",
+            "Operation": "difference",
+            "Children": "Box:1 Sphere:2"
+        }
+
+    Difference subtracts every other child from the first one. Hits of each
+    child are collected along the ray with Shape::all_hits( ), where a front face
+    enters and a back face leaves the child, so the intervals the ray spends
+    inside every child are known. Surface of the CSG is wherever being inside
+    the CSG changes along the ray, hits there keep the material and textures
+    of the child they belong to.
+
+    @date: Oct, 2026
+    @author: Bartu
+*/
+
+use std::str::FromStr;
+use serde::de::{self, Deserializer};
+
+use crate::bbox::{BBox, BBoxable};
+use crate::interval::{FloatConst, Interval};
+use crate::json_structs::VertexData;
+use crate::ray::{Ray, HitRecord};
+use crate::scene::HeapAllocatedVerts;
+use crate::shapes::{HeapAllocatedShape, Shape};
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+pub enum CsgOperation {
+    #[default]
+    #[serde(rename = "union", alias = "Union")]
+    Union,
+
+    #[serde(rename = "intersection", alias = "Intersection")]
+    Intersection,
+
+    #[serde(rename = "difference", alias = "Difference")]
+    Difference, // First child minus the others
+}
+
+impl CsgOperation {
+    /// Whether a point is inside the CSG, given whether it is inside each child
+    fn holds(&self, inside: &[bool]) -> bool {
+        match self {
+            Self::Union => inside.iter().any(|&i| i),
+            Self::Intersection => inside.iter().all(|&i| i),
+            Self::Difference => inside[0] && !inside[1..].iter().any(|&i| i),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CsgChildKind {
+    Sphere,
+    Box,
+    Torus,
+    Mesh,
+    MeshInstance,
+    Csg,
+}
+
+/// "<Kind>:<_id>" token in "Children" field of a CSG, e.g. "Sphere:2"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CsgChildRef {
+    pub kind: CsgChildKind,
+    pub id: usize,
+}
+
+impl FromStr for CsgChildRef {
+    type Err = String;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let (kind, id) = token.split_once(':')
+                              .ok_or_else(|| format!("Expected <Kind>:<_id> for CSG child, found '{}'", token))?;
+        let kind = match kind {
+            "Sphere" => CsgChildKind::Sphere,
+            "Box" => CsgChildKind::Box,
+            "Torus" => CsgChildKind::Torus,
+            "Mesh" => CsgChildKind::Mesh,
+            "MeshInstance" => CsgChildKind::MeshInstance,
+            "CSG" => CsgChildKind::Csg,
+            _ => return Err(format!("CSG child kind '{}' is not supported, expected one of Sphere, Box, Torus, Mesh, MeshInstance, CSG", kind)),
+        };
+        let id = id.parse().map_err(|_| format!("Invalid CSG child id in '{}'", token))?;
+        Ok(Self { kind, id })
+    }
+}
+
+fn deser_csg_children<'de, D>(deserializer: D) -> Result<Vec<CsgChildRef>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.split_whitespace()
+     .map(|token| token.parse().map_err(de::Error::custom))
+     .collect()
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Csg {
+    #[serde(deserialize_with = "deser_usize")]
+    pub _id: usize,
+
+    #[serde(rename = "Operation", default)]
+    pub operation: CsgOperation,
+
+    #[serde(rename = "Children", deserialize_with = "deser_csg_children")]
+    pub child_refs: Vec<CsgChildRef>,
+
+    #[serde(skip)]
+    pub children: Vec<HeapAllocatedShape>, // Resolved from child_refs during scene setup, in the same order
+}
+
+impl Csg {
+    /// Hits on the surface of the CSG within t_interval in increasing t, only the closest one if first_only
+    fn surface_hits(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts, first_only: bool, out: &mut Vec<HitRecord>) {
+        if self.children.is_empty() {
+            return;
+        }
+
+        // Children are traced beyond t_interval.max, otherwise a ray that starts inside a child and
+        // ends before leaving it (e.g. a shadow ray) would look like it is outside
+        let trace_interval = Interval::new(t_interval.min, FloatConst::INF);
+        let mut inside = vec![false; self.children.len()];
+        let mut crossings: Vec<(usize, HitRecord)> = Vec::new();
+        let mut hits = Vec::new();
+        for (i, child) in self.children.iter().enumerate() {
+            hits.clear();
+            child.all_hits(ray, &trace_interval, vertex_cache, &mut hits);
+            inside[i] = hits.first().is_some_and(|h| !h.is_front_face); // Ray starts inside if it leaves first
+            crossings.extend(hits.drain(..).map(|h| (i, h)));
+        }
+        crossings.sort_by(|a, b| a.1.ray_t.total_cmp(&b.1.ray_t));
+
+        let mut was_inside = self.operation.holds(&inside);
+        for (i, mut rec) in crossings {
+            if rec.ray_t > t_interval.max {
+                break;
+            }
+            inside[i] = rec.is_front_face;
+            let is_inside = self.operation.holds(&inside);
+            if is_inside != was_inside {
+                // Normal already faces the ray, only entering the child may differ from entering the CSG
+                // (e.g. leaving a subtracted child enters the CSG)
+                rec.is_front_face = is_inside;
+                out.push(rec);
+                if first_only {
+                    return;
+                }
+            }
+            was_inside = is_inside;
+        }
+    }
+}
+
+impl Shape for Csg {
+    fn intersects_with(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> Option<HitRecord> {
+        let mut hits = Vec::with_capacity(1);
+        self.surface_hits(ray, t_interval, vertex_cache, true, &mut hits);
+        hits.pop()
+    }
+
+    fn all_hits(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts, hits: &mut Vec<HitRecord>) {
+        self.surface_hits(ray, t_interval, vertex_cache, false, hits);
+    }
+}
+
+impl BBoxable for Csg {
+    // Children are in world space already, so apply_t makes no difference
+    fn get_bbox(&self, verts: &VertexData, _apply_t: bool) -> BBox {
+        let mut boxes = self.children.iter().map(|child| child.get_bbox(verts, true));
+        let Some(first) = boxes.next() else {
+            warn!("CSG {} has no children, returning empty bounding box.", self._id);
+            return BBox::empty();
+        };
+
+        match self.operation {
+            CsgOperation::Union => boxes.fold(first, |acc, b| acc.merge(&b)),
+            CsgOperation::Difference => first, // Subtracting never grows the first child
+            CsgOperation::Intersection => {
+                let overlap = boxes.fold(first.clone(), |acc, b| BBox {
+                    xmin: acc.xmin.max(b.xmin), xmax: acc.xmax.min(b.xmax),
+                    ymin: acc.ymin.max(b.ymin), ymax: acc.ymax.min(b.ymax),
+                    zmin: acc.zmin.max(b.zmin), zmax: acc.zmax.min(b.zmax),
+                });
+                // Children do not overlap, there is nothing to hit but keep a valid box for the accelerators
+                if overlap.xmin > overlap.xmax || overlap.ymin > overlap.ymax || overlap.zmin > overlap.zmax { first } else { overlap }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::VertexCache;
+    use crate::shapes::{Cuboid, Sphere};
+
+    /// Vertex cache with the given points, vertex indices start from 1
+    fn vertex_cache(points: &[Vector3]) -> HeapAllocatedVerts {
+        let mut vertex_data = VertexData { _data: vec![Vector3::ZERO], _type: String::from("xyz"), ..Default::default() };
+        vertex_data._data.extend_from_slice(points);
+        Arc::new(VertexCache { vertex_data, ..Default::default() })
+    }
+
+    fn sphere(center_idx: usize, radius: Float) -> HeapAllocatedShape {
+        Arc::new(Sphere { center_idx, radius, ..Default::default() })
+    }
+
+    /// (t, is_front_face) of every surface hit
+    fn surface_hits(csg: &Csg, ray: &Ray, t_interval: &Interval, verts: &HeapAllocatedVerts) -> Vec<(Float, bool)> {
+        let mut hits = Vec::new();
+        csg.surface_hits(ray, t_interval, verts, false, &mut hits);
+        hits.iter().map(|h| (h.ray_t, h.is_front_face)).collect()
+    }
+
+    fn assert_hits(hits: &[(Float, bool)], expected: &[(Float, bool)]) {
+        assert_eq!(hits.len(), expected.len(), "{:?} != {:?}", hits, expected);
+        for (hit, exp) in hits.iter().zip(expected) {
+            assert!((hit.0 - exp.0).abs() < 1e-9 && hit.1 == exp.1, "{:?} != {:?}", hits, expected);
+        }
+    }
+
+    #[test]
+    fn parse_child_refs() {
+        let child: CsgChildRef = "Sphere:12".parse().unwrap();
+        assert_eq!(child, CsgChildRef { kind: CsgChildKind::Sphere, id: 12 });
+        assert_eq!("CSG:3".parse::<CsgChildRef>().unwrap().kind, CsgChildKind::Csg);
+        assert!("Plane:1".parse::<CsgChildRef>().is_err());
+        assert!("Sphere1".parse::<CsgChildRef>().is_err());
+    }
+
+    #[test]
+    fn operations() {
+        assert!(CsgOperation::Union.holds(&[false, true]));
+        assert!(!CsgOperation::Intersection.holds(&[false, true]));
+        assert!(CsgOperation::Difference.holds(&[true, false, false]));
+        assert!(!CsgOperation::Difference.holds(&[true, false, true]));
+    }
+
+    #[test]
+    fn box_minus_sphere() {
+        // Unit box around the origin with a hole of radius 0.5 centered on its +x face
+        let verts = vertex_cache(&[Vector3::splat(-1.), Vector3::ONE, Vector3::X]);
+        let csg = Csg {
+            operation: CsgOperation::Difference,
+            children: vec![Arc::new(Cuboid { min_idx: 1, max_idx: 2, ..Default::default() }), sphere(3, 0.5)],
+            ..Default::default()
+        };
+        let full = Interval::new(1e-6, FloatConst::INF);
+
+        // Enters the box, then leaves the CSG into the hole, box is left inside the sphere
+        let ray = Ray::new(Vector3::new(-3., 0., 0.), Vector3::X, 0.);
+        assert_hits(&surface_hits(&csg, &ray, &full, &verts), &[(2., true), (3.5, false)]);
+        let first = csg.intersects_with(&ray, &full, &verts).unwrap();
+        assert!((first.ray_t - 2.).abs() < 1e-9 && first.is_front_face);
+        let second = csg.intersects_with(&ray, &Interval::new(2.5, FloatConst::INF), &verts).unwrap();
+        assert!(second.normal.abs_diff_eq(-Vector3::X, 1e-9)); // Normal of the sphere, facing the ray
+
+        // Through the box, far from the hole
+        let ray = Ray::new(Vector3::new(-3., 0.9, 0.), Vector3::X, 0.);
+        assert_hits(&surface_hits(&csg, &ray, &full, &verts), &[(2., true), (4., false)]);
+
+        // Starts inside the subtracted sphere, leaving it enters the CSG
+        let ray = Ray::new(Vector3::X, -Vector3::X, 0.);
+        assert_hits(&surface_hits(&csg, &ray, &full, &verts), &[(0.5, true), (2., false)]);
+        assert!(csg.intersects_with(&ray, &Interval::new(1e-6, 0.3), &verts).is_none());
+        assert!(csg.occluded(&ray, &Interval::new(1e-6, 1.), &verts));
+    }
+
+    #[test]
+    fn intersection_of_spheres() {
+        let verts = vertex_cache(&[Vector3::new(-0.5, 0., 0.), Vector3::new(0.5, 0., 0.)]);
+        let csg = Csg {
+            operation: CsgOperation::Intersection,
+            children: vec![sphere(1, 1.), sphere(2, 1.)],
+            ..Default::default()
+        };
+        let full = Interval::new(1e-6, FloatConst::INF);
+
+        // Lens between x = -0.5 and x = 0.5 on the x axis
+        let ray = Ray::new(Vector3::new(-3., 0., 0.), Vector3::X, 0.);
+        assert_hits(&surface_hits(&csg, &ray, &full, &verts), &[(2.5, true), (3.5, false)]);
+
+        // Starting inside the lens, only the exit is hit
+        let ray = Ray::new(Vector3::ZERO, -Vector3::X, 0.);
+        assert_hits(&surface_hits(&csg, &ray, &full, &verts), &[(0.5, false)]);
+
+        // Hits only the first sphere
+        let ray = Ray::new(Vector3::new(-1.2, 3., 0.), -Vector3::Y, 0.);
+        assert!(surface_hits(&csg, &ray, &full, &verts).is_empty());
+    }
+}
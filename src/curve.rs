@@ -0,0 +1,451 @@
+/*
+
+    Curves: cubic Bezier strands for hair, fur and grass.
+
+    A strand is a chain of cubic Bezier segments, given by 3k + 1 control
+    points where consecutive segments share their end points. Width changes
+    linearly from the root to the tip of the strand. "ribbon" strands are
+    flat and always face the ray, "tube" strands are round. e.g.
+
+        "Curve": {
+            "_id": "1", "Material": "2", "Type": "tube",
+            "Width": "0.02 0.002",
+            "ControlPoints": "0 0 0  0 0.3 0  0.1 0.6 0  0.2 0.9 0.1",
+            "_curveFile": "fur.txt"
+        }
+
+    ControlPoints holds a single strand, many strands are loaded from
+    _curveFile (relative to the scene JSON) with a strand per line: control
+    point coordinates, optionally followed by root and tip widths. Empty lines
+    and lines starting with # are skipped.
+
+    Every segment is a separate shape so the scene BVH has a tight box around
+    each of them. Rays are intersected in ray space, where the ray is the +z
+    axis, by subdividing the segment until it is almost a line and finding the
+    closest point of that line to the ray (see pbrt-v3, Section 3.7).
+
+    Rays starting within the width of a strand (e.g. shadow rays from its
+    surface) do not hit that strand again, strands do not shadow themselves.
+
+    @date: Oct, 2026
+    @author: Bartu
+*/
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::path::Path;
+use serde::de::{self, Deserializer};
+
+use crate::bbox::{BBox, BBoxable};
+use crate::interval::Interval;
+use crate::json_structs::VertexData;
+use crate::ray::{Ray, HitRecord};
+use crate::scene::HeapAllocatedVerts;
+use crate::shapes::{valid_t, CommonPrimitiveData, Shape, SurfaceHit};
+use crate::prelude::*;
+
+// Segments are split at most 2^MAX_SUBDIVISION_DEPTH times during intersection
+const MAX_SUBDIVISION_DEPTH: usize = 10;
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+pub enum CurveType {
+    #[default]
+    #[serde(rename = "ribbon", alias = "Ribbon")]
+    Ribbon, // Flat, normal faces the ray
+
+    #[serde(rename = "tube", alias = "Tube")]
+    Tube, // Round, normal points away from the center line
+}
+
+fn deser_curve_width<'de, D>(deserializer: D) -> Result<[Float; 2], D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let widths = deser_float_vec(deserializer)?;
+    match widths[..] {
+        [width] => Ok([width, width]),
+        [root, tip] => Ok([root, tip]),
+        _ => Err(de::Error::custom(format!("Expected 1 or 2 numbers for curve Width, found {}", widths.len()))),
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, SmartDefault)]
+#[serde(default)]
+pub struct Curve {
+    #[serde(flatten)]
+    pub(crate) _data: CommonPrimitiveData,
+
+    #[serde(rename = "Type")]
+    pub kind: CurveType,
+
+    #[serde(rename = "Width", deserialize_with = "deser_curve_width")]
+    #[default([0.01, 0.01])]
+    pub width: [Float; 2], // At the root and the tip, strands in _curveFile can override it
+
+    #[serde(rename = "ControlPoints", deserialize_with = "deser_vertex_data")]
+    pub control_points: Vec<Vector3>,
+
+    #[serde(rename = "_curveFile")]
+    pub curve_file: String,
+
+    #[serde(rename = "MotionBlur", deserialize_with = "deser_vec3")]
+    pub(crate) motionblur: Vector3,
+
+    #[serde(skip)]
+    pub transform: Option<Arc<Transform>>,
+}
+
+#[derive(Debug, Clone)]
+struct Strand {
+    points: Vec<Vector3>,
+    width: [Float; 2],
+}
+
+/// Strands of a curve file, see the top of the file for its format
+fn parse_curve_file(text: &str, default_width: [Float; 2]) -> Result<Vec<Strand>, String> {
+    let mut strands = Vec::new();
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut nums: Vec<Float> = line.split_whitespace()
+                                       .map(|x| x.parse::<Float>().map_err(|e| format!("Line {} of curve file: {}", line_idx + 1, e)))
+                                       .collect::<Result<_, _>>()?;
+        let width = match nums.len() % 3 {
+            0 => default_width,
+            2 => {
+                let tip = nums.pop().unwrap();
+                let root = nums.pop().unwrap();
+                [root, tip]
+            },
+            _ => return Err(format!("Line {} of curve file has {} numbers, expected xyz of control points and optionally two widths", line_idx + 1, nums.len())),
+        };
+        let points = nums.chunks_exact(3).map(|c| Vector3::new(c[0], c[1], c[2])).collect();
+        strands.push(Strand { points, width });
+    }
+    Ok(strands)
+}
+
+impl Curve {
+    /// Bezier segments of every strand, ready to be added to the scene BVH
+    pub fn segments(&self, json_dir: &Path) -> Result<Vec<CurveSegment>, Box<dyn Error>> {
+        let mut strands = Vec::new();
+        if !self.control_points.is_empty() {
+            strands.push(Strand { points: self.control_points.clone(), width: self.width });
+        }
+        if !self.curve_file.is_empty() {
+            let path = json_dir.join(&self.curve_file);
+            let text = std::fs::read_to_string(&path).map_err(|e| format!("Cannot read curve file {:?}: {}", path, e))?;
+            strands.extend(parse_curve_file(&text, self.width)?);
+        }
+
+        let data = Arc::new(self._data.clone());
+        let mut segments = Vec::new();
+        for (strand_idx, strand) in strands.iter().enumerate() {
+            let n_points = strand.points.len();
+            if n_points < 4 || (n_points - 1) % 3 != 0 {
+                warn!("Strand {} of curve {} has {} control points, expected 3k + 1 for k Bezier segments. Skipping it.", strand_idx, self._data._id, n_points);
+                continue;
+            }
+            let n_segments = (n_points - 1) / 3;
+            let width_at = |v: Float| strand.width[0] + (strand.width[1] - strand.width[0]) * v;
+            for i in 0..n_segments {
+                let v_range = [i as Float / n_segments as Float, (i + 1) as Float / n_segments as Float];
+                segments.push(CurveSegment {
+                    data: data.clone(),
+                    kind: self.kind,
+                    cp: strand.points[3 * i..3 * i + 4].try_into().unwrap(),
+                    width: v_range.map(width_at),
+                    v_range,
+                    motionblur: self.motionblur,
+                    transform: self.transform.clone(),
+                });
+            }
+        }
+        info!(">> Curve {} has {} strands with {} Bezier segments", self._data._id, strands.len(), segments.len());
+        Ok(segments)
+    }
+}
+
+// =======================================================================================================
+// Bezier segment (impl Shape + BBoxable)
+// =======================================================================================================
+
+/// Point and derivative of a cubic Bezier curve at u (de Casteljau)
+fn eval_bezier(cp: &[Vector3; 4], u: Float) -> (Vector3, Vector3) {
+    let [a, b, c] = [cp[0].lerp(cp[1], u), cp[1].lerp(cp[2], u), cp[2].lerp(cp[3], u)];
+    let [d, e] = [a.lerp(b, u), b.lerp(c, u)];
+    let derivative = 3. * (e - d);
+    // Derivative vanishes at an end point that coincides with its neighbour control point
+    let derivative = if derivative.length_squared() > 0. { derivative } else { cp[3] - cp[0] };
+    (d.lerp(e, u), derivative)
+}
+
+/// Halves of a cubic Bezier curve split at u = 0.5
+fn split_bezier(cp: &[Vector3; 4]) -> ([Vector3; 4], [Vector3; 4]) {
+    let mid = (cp[0] + 3. * cp[1] + 3. * cp[2] + cp[3]) / 8.;
+    (
+        [cp[0], (cp[0] + cp[1]) / 2., (cp[0] + 2. * cp[1] + cp[2]) / 4., mid],
+        [mid, (cp[1] + 2. * cp[2] + cp[3]) / 4., (cp[2] + cp[3]) / 2., cp[3]],
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct CurveSegment {
+    data: Arc<CommonPrimitiveData>, // Shared by the segments of a Curve
+    kind: CurveType,
+    cp: [Vector3; 4], // Control points in local space
+    width: [Float; 2], // At u = 0 and u = 1
+    v_range: [Float; 2], // Part of the strand, from root (v = 0) to tip (v = 1)
+    motionblur: Vector3,
+    transform: Option<Arc<Transform>>,
+}
+
+/// Closest hit found during CurveSegment::recursive_hit( )
+struct RayCurveHit {
+    z: Float, // Distance along the normalized ray direction
+    u: Float,
+    offset: Float, // Signed distance from the center line, -1 and 1 at the edges
+}
+
+impl CurveSegment {
+    #[inline]
+    fn width_at(&self, u: Float) -> Float {
+        self.width[0] + (self.width[1] - self.width[0]) * u
+    }
+
+    /// Transform at ray time, moving towards EndTransformations of the curve if given
+    #[inline]
+    fn transform_at(&self, time: Float) -> Cow<'_, Transform> {
+        self.data.transform_at(time, self.transform.as_deref())
+    }
+
+    /// Ray in local space, moved by motion blur like AnalyticShape::local_ray( )
+    #[inline]
+    fn local_ray(&self, ray: &Ray, transform: &Transform) -> Ray {
+        let mut ray = ray.clone();
+        ray.origin += self.motionblur * ray.time;
+        ray.to_local(transform)
+    }
+
+    /// Number of halvings until the segment is within 5% of its width to a line, given the ray space control points
+    fn subdivision_depth(&self, cp: &[Vector3; 4]) -> usize {
+        let l0 = (0..2).map(|i| (cp[i] - 2. * cp[i + 1] + cp[i + 2]).abs().max_element())
+                       .fold(0., Float::max);
+        let eps = 0.05 * self.width[0].max(self.width[1]);
+        if l0 <= 0. || eps <= 0. {
+            return 0;
+        }
+        let depth = 0.5 * ((2. as Float).sqrt() * 6. * l0 / (8. * eps)).log2();
+        depth.round().clamp(0., MAX_SUBDIVISION_DEPTH as Float) as usize
+    }
+
+    /// Finds the closest hit of the ray space control points cp, which are [u0, u1] part of the segment, with z
+    /// in [z_min, z_max]. z_max shrinks as hits are found so farther parts are skipped.
+    fn recursive_hit(&self, cp: &[Vector3; 4], (u0, u1): (Float, Float), depth: usize, z_min: Float, z_max: &mut Float, best: &mut Option<RayCurveHit>) {
+        // Curve is inside the convex hull of its control points, ray is the +z axis
+        let half_width = 0.5 * self.width_at(u0).max(self.width_at(u1));
+        let (lo, hi) = cp.iter().fold((Vector3::INFINITY, Vector3::NEG_INFINITY), |(lo, hi), p| (lo.min(*p), hi.max(*p)));
+        if lo.x - half_width > 0. || hi.x + half_width < 0. || lo.y - half_width > 0. || hi.y + half_width < 0.
+            || hi.z + half_width < z_min || lo.z - half_width > *z_max {
+            return;
+        }
+
+        if depth > 0 {
+            let (left, right) = split_bezier(cp);
+            let u_mid = 0.5 * (u0 + u1);
+            self.recursive_hit(&left, (u0, u_mid), depth - 1, z_min, z_max, best);
+            self.recursive_hit(&right, (u_mid, u1), depth - 1, z_min, z_max, best);
+            return;
+        }
+
+        // Almost a line from cp[0] to cp[3] now, points beyond its ends belong to the neighbour parts
+        if (cp[1].y - cp[0].y) * -cp[0].y + cp[0].x * (cp[0].x - cp[1].x) < 0.
+            || (cp[2].y - cp[3].y) * -cp[3].y + cp[3].x * (cp[3].x - cp[2].x) < 0. {
+            return;
+        }
+        let line = (cp[3] - cp[0]).truncate();
+        let length_squared = line.length_squared();
+        if length_squared == 0. {
+            return;
+        }
+        // Closest point of the line to the ray
+        let w = -cp[0].truncate().dot(line) / length_squared;
+        let u = (u0 + w * (u1 - u0)).clamp(u0, u1);
+        let half_width = 0.5 * self.width_at(u);
+        let (center, derivative) = eval_bezier(cp, w.clamp(0., 1.));
+        let dist_squared = center.x * center.x + center.y * center.y;
+        if half_width <= 0. || dist_squared > half_width * half_width || center.z < half_width {
+            return; // Missed, or the ray starts on this strand
+        }
+
+        let z = match self.kind {
+            CurveType::Ribbon => center.z,
+            CurveType::Tube => center.z - (half_width * half_width - dist_squared).sqrt(), // Front of the tube
+        };
+        if z < z_min || z > *z_max {
+            return;
+        }
+        let dist = dist_squared.sqrt() / half_width;
+        let offset = if derivative.x * -center.y + center.x * derivative.y > 0. { dist } else { -dist };
+        *z_max = z;
+        *best = Some(RayCurveHit { z, u, offset });
+    }
+
+    /// Hit in local space, normal is outward for tubes and faces the ray for ribbons
+    fn strand_hit(&self, ray: &Ray, t_interval: &Interval) -> Option<SurfaceHit> {
+        // NOTE: local ray direction is not normalized, but t has to stay the same as in world space
+        let length = ray.direction.length();
+        if length == 0. {
+            return None;
+        }
+        let dir = ray.direction / length;
+        let (x_axis, y_axis) = dir.any_orthonormal_pair();
+        let cp = self.cp.map(|p| {
+            let q = p - ray.origin;
+            Vector3::new(q.dot(x_axis), q.dot(y_axis), q.dot(dir))
+        });
+
+        let mut z_max = t_interval.max * length;
+        let mut best = None;
+        self.recursive_hit(&cp, (0., 1.), self.subdivision_depth(&cp), t_interval.min.max(0.) * length, &mut z_max, &mut best);
+        let hit = best?;
+        let t = hit.z / length;
+        if !valid_t(t, t_interval) {
+            return None;
+        }
+
+        let point = ray.at(t);
+        let (center, along) = eval_bezier(&self.cp, hit.u);
+        let along = along.normalize();
+        let facing = (-dir).reject_from_normalized(along).try_normalize().unwrap_or_else(|| along.any_orthonormal_vector());
+        let normal = match self.kind {
+            CurveType::Ribbon => facing,
+            CurveType::Tube => (point - center).reject_from_normalized(along).try_normalize().unwrap_or(facing),
+        };
+        let v = self.v_range[0] + (self.v_range[1] - self.v_range[0]) * hit.u;
+        // u goes across and v along the strand, B x T is the normal
+        Some(SurfaceHit { t, point, normal, tangent: normal.cross(along), bitangent: along, uv: [0.5 * (hit.offset + 1.), v] })
+    }
+}
+
+impl Shape for CurveSegment {
+    fn intersects_with(&self, ray: &Ray, t_interval: &Interval, _: &HeapAllocatedVerts) -> Option<HitRecord> {
+        let transform = &*self.transform_at(ray.time);
+        let local_ray = self.local_ray(ray, transform);
+        let hit = self.strand_hit(&local_ray, t_interval)?;
+
+        let front_face = local_ray.is_front_face(hit.normal);
+        let normal = if front_face { hit.normal } else { -hit.normal };
+
+        let mut uv = None;
+        let mut tbn = None;
+        if !self.data.texture_idxs.is_empty() {
+            uv = Some(hit.uv);
+            let t_vec = transform.dir(&hit.tangent).normalize();
+            let b_vec = transform.dir(&hit.bitangent).normalize();
+            tbn = Some(Matrix3::from_cols(t_vec, b_vec, transform.normal(&hit.normal)));
+        }
+
+        let mut rec = HitRecord::new_from(local_ray.origin, hit.point, normal, hit.t, self.data.material_idx, front_face, self.data.texture_idxs.clone(), uv, tbn);
+        rec.to_world(transform);
+        rec.strand_tangent = Some(transform.dir(&hit.bitangent).normalize());
+        let motion = self.motionblur * ray.time;
+        rec.entry_point -= motion;
+        rec.hit_point -= motion;
+        Some(rec)
+    }
+
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, _: &HeapAllocatedVerts) -> bool {
+        self.strand_hit(&self.local_ray(ray, &self.transform_at(ray.time)), t_interval).is_some()
+    }
+}
+
+impl BBoxable for CurveSegment {
+    fn get_bbox(&self, _: &VertexData, apply_t: bool) -> BBox {
+        let half_width = 0.5 * self.width[0].max(self.width[1]);
+        let (lo, hi) = self.cp.iter().fold((Vector3::INFINITY, Vector3::NEG_INFINITY), |(lo, hi), p| (lo.min(*p), hi.max(*p)));
+        let (lo, hi) = (lo - half_width, hi + half_width);
+        let local_box = BBox::new(lo.x, hi.x, lo.y, hi.y, lo.z, hi.z);
+        if !apply_t {
+            return local_box;
+        }
+        let world_box = match self.transform.as_deref() {
+            Some(transform) => self.data.world_bbox(&local_box, transform), // Covers EndTransformations too
+            None => local_box,
+        };
+        world_box.expand_by_motion(-self.motionblur)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(kind: CurveType) -> CurveSegment {
+        // Arc in the xy plane from (-1, 0, 0) to (1, 0, 0) bulging up to y = 0.75
+        CurveSegment {
+            data: Arc::new(CommonPrimitiveData::default()),
+            kind,
+            cp: [Vector3::new(-1., 0., 0.), Vector3::new(-1., 1., 0.), Vector3::new(1., 1., 0.), Vector3::new(1., 0., 0.)],
+            width: [0.1, 0.1],
+            v_range: [0., 1.],
+            motionblur: Vector3::ZERO,
+            transform: None,
+        }
+    }
+
+    #[test]
+    fn parse_strands() {
+        let text = "# two strands\n0 0 0 0 1 0 0 2 0 0 3 0\n\n0 0 0 0 1 0 0 2 0 0 3 0 0.5 0.1\n";
+        let strands = parse_curve_file(text, [0.2, 0.2]).unwrap();
+        assert_eq!(strands.len(), 2);
+        assert_eq!(strands[0].width, [0.2, 0.2]);
+        assert_eq!(strands[1].width, [0.5, 0.1]);
+        assert_eq!(strands[1].points[3], Vector3::new(0., 3., 0.));
+        assert!(parse_curve_file("0 0 0 1", [0.2, 0.2]).is_err());
+    }
+
+    #[test]
+    fn hits_ribbon_and_tube() {
+        let t_interval = Interval::positive(1e-6);
+        // Top of the arc, looking down -z
+        let ray = Ray::new(Vector3::new(0., 0.75, 5.), -Vector3::Z, 0.);
+        let ribbon = segment(CurveType::Ribbon).strand_hit(&ray, &t_interval).unwrap();
+        assert!((ribbon.t - 5.).abs() < 1e-6);
+        assert!((ribbon.normal - Vector3::Z).length() < 1e-6);
+        assert!((ribbon.uv[1] - 0.5).abs() < 1e-6);
+
+        let tube = segment(CurveType::Tube).strand_hit(&ray, &t_interval).unwrap();
+        assert!((tube.t - 4.95).abs() < 1e-3);
+
+        // Beside the strand and past its end
+        let beside = Ray::new(Vector3::new(0., 0.9, 5.), -Vector3::Z, 0.);
+        assert!(segment(CurveType::Ribbon).strand_hit(&beside, &t_interval).is_none());
+        let past_end = Ray::new(Vector3::new(1.2, 0., 5.), -Vector3::Z, 0.);
+        assert!(segment(CurveType::Ribbon).strand_hit(&past_end, &t_interval).is_none());
+    }
+
+    #[test]
+    fn moves_with_end_transformations() {
+        // Moves from the origin to x = 3 during the shutter
+        let start = Transform::new(Matrix4::IDENTITY);
+        let end = Transform::new(Matrix4::from_translation(Vector3::new(3., 0., 0.)));
+        let data = CommonPrimitiveData { motion: Some(Arc::new(crate::numeric::AnimatedTransform::new(start, end))), ..Default::default() };
+        let moving = CurveSegment { data: Arc::new(data), transform: Some(Arc::new(start)), ..segment(CurveType::Ribbon) };
+        let verts = HeapAllocatedVerts::default();
+        let t_interval = Interval::positive(1e-6);
+
+        let at_start = Ray::new(Vector3::new(0., 0.75, 5.), -Vector3::Z, 0.);
+        let at_end = Ray::new(Vector3::new(3., 0.75, 5.), -Vector3::Z, 1.);
+        assert!(moving.intersects_with(&at_start, &t_interval, &verts).is_some());
+        assert!(moving.intersects_with(&Ray { time: 1., ..at_start.clone() }, &t_interval, &verts).is_none());
+        let rec = moving.intersects_with(&at_end, &t_interval, &verts).unwrap();
+        assert!((rec.hit_point - Vector3::new(3., 0.75, 0.)).length() < 1e-6);
+        assert!(moving.occluded(&at_end, &t_interval, &verts));
+
+        let bbox = moving.get_bbox(&VertexData::default(), true);
+        assert!(bbox.xmin < -1. && bbox.xmax > 4.);
+    }
+}
@@ -13,8 +13,11 @@
 */
 
 
+use std::collections::HashMap;
+
 use crate::json_structs::{VertexData};
-use crate::{ray::Ray, interval::Interval};
+use crate::shapes::azimuth_u;
+use crate::{ray::Ray, interval::{FloatConst, Interval}};
 use crate::prelude::*;
 
 /// Return true if any of the two verts at the same position
@@ -83,6 +86,28 @@ pub fn get_tri_normal(v1: &Vector3, v2: &Vector3, v3: &Vector3) -> Vector3{
 }
 
 
+/// Ray-triangle intersection algorithm, set per scene with "TriangleIntersection" field of the scene JSON, e.g.
+/// "TriangleIntersection": "watertight"
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+pub enum TriangleIntersection {
+    #[default]
+    #[serde(rename = "moller_trumbore", alias = "MollerTrumbore")]
+    MollerTrumbore,
+
+    #[serde(rename = "watertight", alias = "Watertight")]
+    Watertight, // Rays cannot slip through edges shared by adjacent triangles, see watertight_intersection( )
+}
+
+/// Intersect with the algorithm selected for the scene.
+/// Both return (beta, gamma, t) where beta and gamma are the barycentric weights of the second and third vertices.
+#[inline]
+pub fn triangle_intersection(mode: TriangleIntersection, ray: &Ray, t_interval: &Interval, tri_indices: [usize; 3], verts: &VertexData) -> Option<(Float, Float, Float)> {
+    match mode {
+        TriangleIntersection::MollerTrumbore => moller_trumbore_intersection(ray, t_interval, tri_indices, verts),
+        TriangleIntersection::Watertight => watertight_intersection(ray, t_interval, tri_indices, verts),
+    }
+}
+
 pub fn moller_trumbore_intersection(ray: &Ray, t_interval: &Interval, tri_indices: [usize; 3], verts: &VertexData) -> Option<(Float, Float, Float)> {
     // Based on Möller-Trumbore algorithm
     //
@@ -124,3 +149,490 @@ pub fn moller_trumbore_intersection(ray: &Ray, t_interval: &Interval, tri_indice
     Some((barycentric_u, barycentric_v, t))
 }
 
+/// Watertight ray-triangle intersection, see Woop et al. 2013 "Watertight Ray/Triangle Intersection"
+/// and pbrt-v3, 3.6.2. Vertices are transformed to a space where the ray starts at the origin along +z,
+/// then the edge functions are evaluated in 2D. Since an edge function of a shared edge is computed
+/// from the same two vertices in both triangles, a ray hitting the edge cannot miss both of them.
+/// Unlike moller_trumbore_intersection( ) ray direction does not need to be normalized.
+pub fn watertight_intersection(ray: &Ray, t_interval: &Interval, tri_indices: [usize; 3], verts: &VertexData) -> Option<(Float, Float, Float)> {
+    // Permute axes so that z is the largest component of ray direction
+    let dir_abs = ray.direction.abs();
+    let kz = if dir_abs.x > dir_abs.y && dir_abs.x > dir_abs.z { 0 } else if dir_abs.y > dir_abs.z { 1 } else { 2 };
+    let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+    if ray.direction[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky); // Preserve winding order
+    }
+    if ray.direction[kz] == 0.0 {
+        return None; // Zero direction
+    }
+
+    // Shear coefficients
+    let shear_x = ray.direction[kx] / ray.direction[kz];
+    let shear_y = ray.direction[ky] / ray.direction[kz];
+    let shear_z = 1.0 / ray.direction[kz];
+
+    // Translate vertices wrt ray origin and shear them
+    let [a, b, c] = tri_indices.map(|i| verts[i] - ray.origin);
+    let (ax, ay) = (a[kx] - shear_x * a[kz], a[ky] - shear_y * a[kz]);
+    let (bx, by) = (b[kx] - shear_x * b[kz], b[ky] - shear_y * b[kz]);
+    let (cx, cy) = (c[kx] - shear_x * c[kz], c[ky] - shear_y * c[kz]);
+
+    // Edge functions, i.e. scaled barycentric coordinates of a, b and c
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+
+    // Hit only if all have the same sign (zero is on the edge), both windings are accepted
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+    let determinant = u + v + w;
+    if determinant == 0.0 {
+        return None; // Ray is parallel to the triangle
+    }
+
+    // Scaled distance to the hit point
+    let (az, bz, cz) = (shear_z * a[kz], shear_z * b[kz], shear_z * c[kz]);
+    let t_scaled = u * az + v * bz + w * cz;
+
+    let inverse_determinant = 1.0 / determinant;
+    let t = t_scaled * inverse_determinant;
+    if !t_interval.contains(t) {
+        return None;
+    }
+    Some((v * inverse_determinant, w * inverse_determinant, t))
+}
+
+// =======================================================================================================
+// Subdivision and displacement of triangle meshes
+// =======================================================================================================
+
+/// Triangle mesh with its own vertex list, faces index positions and uvs (aligned with positions)
+#[derive(Debug, Clone, Default)]
+pub struct TriMesh {
+    pub positions: Vec<Vector3>,
+    pub uvs: Vec<Option<[Float; 2]>>,
+    pub faces: Vec<[usize; 3]>,
+}
+
+/// An edge given by its end vertices, with the third vertex of every face sharing it
+struct LoopEdge {
+    a: usize,
+    b: usize,
+    opposite: Vec<usize>,
+}
+
+/// One level of Loop subdivision (Loop, 1987), every triangle is split into four.
+/// Vertices of the input keep their indices and new edge vertices follow them in order of first appearance
+/// in faces, so the result only depends on the input. Edges with other than two faces (boundaries, UV seams
+/// and non-manifold edges) are kept as creases, and uvs are subdivided with the same weights as positions.
+pub fn loop_subdivide(mesh: &TriMesh) -> TriMesh {
+    split_triangles(mesh, true)
+}
+
+/// Split every triangle into four at edge midpoints without moving any vertex, i.e. the surface is unchanged
+pub fn midpoint_subdivide(mesh: &TriMesh) -> TriMesh {
+    split_triangles(mesh, false)
+}
+
+/// Shared by loop_subdivide( ) and midpoint_subdivide( ), smooth selects Loop weights
+fn split_triangles(mesh: &TriMesh, smooth: bool) -> TriMesh {
+    let n_verts = mesh.positions.len();
+
+    let mut edges: Vec<LoopEdge> = Vec::new();
+    let mut edge_ids: HashMap<(usize, usize), usize> = HashMap::new();
+    for face in &mesh.faces {
+        for k in 0..3 {
+            let (a, b, c) = (face[k], face[(k + 1) % 3], face[(k + 2) % 3]);
+            let id = *edge_ids.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                edges.push(LoopEdge { a, b, opposite: Vec::new() });
+                edges.len() - 1
+            });
+            edges[id].opposite.push(c);
+        }
+    }
+
+    let mut neighbours: Vec<Vec<usize>> = vec![Vec::new(); n_verts];
+    let mut crease_neighbours: Vec<Vec<usize>> = vec![Vec::new(); n_verts];
+    for edge in &edges {
+        neighbours[edge.a].push(edge.b);
+        neighbours[edge.b].push(edge.a);
+        if edge.opposite.len() != 2 {
+            crease_neighbours[edge.a].push(edge.b);
+            crease_neighbours[edge.b].push(edge.a);
+        }
+    }
+
+    // Weighted sum of input vertices, uv is missing if any of the vertices is missing it
+    let blend = |terms: &[(usize, Float)]| -> (Vector3, Option<[Float; 2]>) {
+        let mut position = Vector3::ZERO;
+        let mut uv = Some([0.0, 0.0]);
+        for &(i, w) in terms {
+            position += mesh.positions[i] * w;
+            uv = uv.zip(mesh.uvs.get(i).copied().flatten()).map(|(acc, other)| [acc[0] + other[0] * w, acc[1] + other[1] * w]);
+        }
+        (position, uv)
+    };
+
+    let mut out = TriMesh {
+        positions: Vec::with_capacity(n_verts + edges.len()),
+        uvs: Vec::with_capacity(n_verts + edges.len()),
+        faces: Vec::with_capacity(mesh.faces.len() * 4),
+    };
+
+    // Even vertices, moved towards their neighbours
+    for v in 0..n_verts {
+        let creases = &crease_neighbours[v];
+        let ring = &neighbours[v];
+        let mut terms = vec![(v, 1.0)];
+        if smooth && creases.len() == 2 {
+            terms = vec![(v, 0.75), (creases[0], 0.125), (creases[1], 0.125)];
+        }
+        else if smooth && creases.is_empty() && !ring.is_empty() {
+            let k = ring.len() as Float;
+            let c = 0.375 + 0.25 * (2.0 * Float::PI / k).cos();
+            let beta = (0.625 - c * c) / k;
+            terms = vec![(v, 1.0 - k * beta)];
+            terms.extend(ring.iter().map(|&u| (u, beta)));
+        } // Corners, non-manifold vertices and all vertices of midpoint subdivision stay where they are
+
+        let (position, uv) = blend(&terms);
+        out.positions.push(position);
+        out.uvs.push(uv);
+    }
+
+    // Odd vertices, one per edge
+    for edge in &edges {
+        let (position, uv) = match edge.opposite.as_slice() {
+            &[c, d] if smooth => blend(&[(edge.a, 0.375), (edge.b, 0.375), (c, 0.125), (d, 0.125)]),
+            _ => blend(&[(edge.a, 0.5), (edge.b, 0.5)]),
+        };
+        out.positions.push(position);
+        out.uvs.push(uv);
+    }
+
+    let odd = |a: usize, b: usize| n_verts + edge_ids[&(a.min(b), a.max(b))];
+    for &[a, b, c] in &mesh.faces {
+        let (ab, bc, ca) = (odd(a, b), odd(b, c), odd(c, a));
+        out.faces.extend([[a, ab, ca], [ab, b, bc], [ca, bc, c], [ab, bc, ca]]); // Same winding as the input
+    }
+    out
+}
+
+
+impl TriMesh {
+    pub fn longest_edge(&self) -> Float {
+        self.faces.iter()
+                  .flat_map(|&[a, b, c]| [(a, b), (b, c), (c, a)])
+                  .map(|(a, b)| self.positions[a].distance(self.positions[b]))
+                  .fold(0.0, Float::max)
+    }
+
+    /// Area weighted vertex normals, averaged over vertices at the same position so that
+    /// vertices duplicated along UV seams get the same normal
+    pub fn vertex_normals(&self) -> Vec<Vector3> {
+        let mut by_position: HashMap<[u64; 3], Vector3> = HashMap::new();
+        let key = |p: Vector3| [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+        for &[a, b, c] in &self.faces {
+            let (pa, pb, pc) = (self.positions[a], self.positions[b], self.positions[c]);
+            let area_normal = (pb - pa).cross(pc - pa); // Length is twice the area
+            for p in [pa, pb, pc] {
+                *by_position.entry(key(p)).or_insert(Vector3::ZERO) += area_normal;
+            }
+        }
+        self.positions.iter()
+                      .map(|&p| by_position.get(&key(p)).and_then(|n| n.try_normalize()).unwrap_or(Vector3::ZERO))
+                      .collect()
+    }
+}
+
+// =======================================================================================================
+// UV projection
+// =======================================================================================================
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+pub enum UvProjectionKind {
+    #[default]
+    #[serde(rename = "planar", alias = "Planar")]
+    Planar, // Onto the plane perpendicular to Axis
+
+    #[serde(rename = "box", alias = "Box")]
+    Box, // Planar along the axis closest to the normal, e.g. walls and floor of a room at once
+
+    #[serde(rename = "cylindrical", alias = "Cylindrical")]
+    Cylindrical, // Around the y axis through Center, v goes down along it
+
+    #[serde(rename = "spherical", alias = "Spherical")]
+    Spherical, // Around Center with poles on the y axis, same as Sphere
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Default)]
+pub enum ProjectionAxis {
+    #[serde(rename = "x", alias = "X")]
+    X,
+    #[default]
+    #[serde(rename = "y", alias = "Y")]
+    Y,
+    #[serde(rename = "z", alias = "Z")]
+    Z,
+}
+
+/// Texture coordinates generated at hit time for objects without them, set per object in its local space, e.g.
+/// "UVProjection": { "_type": "planar", "Axis": "y", "Scale": "0.5 0.5", "Offset": "0 0" }
+/// uv is scaled, offset and then wrapped to [0, 1) so textures tile.
+#[derive(Debug, Clone, Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct UvProjection {
+    #[serde(rename = "_type")]
+    pub kind: UvProjectionKind,
+
+    #[serde(rename = "Axis")]
+    pub axis: ProjectionAxis, // Only for planar
+
+    #[serde(rename = "Center", deserialize_with = "deser_vec3")]
+    pub center: Vector3, // Only for cylindrical and spherical
+
+    #[serde(rename = "Scale", deserialize_with = "deser_pair")]
+    #[default([1., 1.])]
+    pub scale: [Float; 2],
+
+    #[serde(rename = "Offset", deserialize_with = "deser_pair")]
+    pub offset: [Float; 2],
+}
+
+pub(crate) fn deser_uv_projection<'de, D>(deserializer: D) -> Result<Option<Arc<UvProjection>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Some(Arc::new(UvProjection::deserialize(deserializer)?)))
+}
+
+/// Coordinates on the plane perpendicular to the axis, with tangent and bitangent such that B x T is the axis
+fn planar_frame(axis: ProjectionAxis, p: Vector3) -> ([Float; 2], Vector3, Vector3) {
+    match axis {
+        ProjectionAxis::X => ([-p.z, -p.y], -Vector3::Z, -Vector3::Y),
+        ProjectionAxis::Y => ([p.x, p.z], Vector3::X, Vector3::Z),
+        ProjectionAxis::Z => ([p.x, -p.y], Vector3::X, -Vector3::Y),
+    }
+}
+
+impl UvProjection {
+    /// uv and TBN matrix (see slides 07, pp.10-16) at local point p with unit normal n
+    pub fn project(&self, p: Vector3, n: Vector3) -> ([Float; 2], Matrix3) {
+        let (uv, tangent, bitangent) = match self.kind {
+            UvProjectionKind::Planar => planar_frame(self.axis, p),
+            UvProjectionKind::Box => {
+                let a = n.abs();
+                let (axis, side) = if a.x >= a.y && a.x >= a.z { (ProjectionAxis::X, n.x) }
+                                   else if a.y >= a.z { (ProjectionAxis::Y, n.y) }
+                                   else { (ProjectionAxis::Z, n.z) };
+                let (uv, tangent, bitangent) = planar_frame(axis, p);
+                // Mirror u on the faces looking at -axis, so textures are not flipped there
+                if side < 0. { ([-uv[0], uv[1]], -tangent, bitangent) } else { (uv, tangent, bitangent) }
+            },
+            UvProjectionKind::Cylindrical => {
+                let q = p - self.center;
+                ([azimuth_u(q.x, q.z), -q.y], Vector3::new(q.z, 0., -q.x), -Vector3::Y)
+            },
+            UvProjectionKind::Spherical => {
+                let q = p - self.center;
+                let r = q.length();
+                let theta = if r > 0. { (q.y / r).clamp(-1., 1.).acos() } else { 0. };
+                // dp/dtheta without the trigonometry of Sphere::intersects_with( ), scaled by the distance to the pole axis
+                let rho_squared = q.x * q.x + q.z * q.z;
+                ([azimuth_u(q.x, q.z), theta / Float::PI], Vector3::new(q.z, 0., -q.x), Vector3::new(q.y * q.x, -rho_squared, q.y * q.z))
+            },
+        };
+        let uv = [0, 1].map(|i| (uv[i] * self.scale[i] + self.offset[i]).rem_euclid(1.));
+
+        // Negative scale flips the texture, and tangents are projected on the surface (e.g. at the poles they vanish)
+        let tangent = (tangent * self.scale[0].signum()).reject_from_normalized(n)
+                                                         .try_normalize()
+                                                         .unwrap_or_else(|| n.any_orthonormal_vector());
+        let bitangent = (bitangent * self.scale[1].signum()).reject_from_normalized(n)
+                                                             .reject_from_normalized(tangent)
+                                                             .try_normalize()
+                                                             .unwrap_or_else(|| tangent.cross(n));
+        (uv, Matrix3::from_cols(tangent, bitangent, n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*; // access to the outer scope
+    use crate::interval::FloatConst;
+
+    fn vertex_data(verts: &[Vector3]) -> VertexData {
+        VertexData { _data: verts.to_vec(), ..Default::default() }
+    }
+
+    fn ray_towards(target: Vector3, offset: Vector3) -> Ray {
+        Ray { origin: target + offset, direction: -offset.normalize(), time: 0. }
+    }
+
+    // Planar quad (parallelogram) split along its diagonal a-c, vertices are not axis aligned on purpose.
+    // NOTE: for a non-planar quad the ray can graze the crease from the side where both triangles
+    // project onto the same side of the edge, and then missing both is correct.
+    const QUAD: [Vector3; 4] = [
+        Vector3::new(0.13, 0.27, -3.71),
+        Vector3::new(1.39, -0.41, -2.93),
+        Vector3::new(1.07, 1.53, -2.17),
+        Vector3::new(0.13 + 1.07 - 1.39, 0.27 + 1.53 + 0.41, -3.71 - 2.17 + 2.93),
+    ];
+    const QUAD_TRIS: [[usize; 3]; 2] = [[0, 1, 2], [0, 2, 3]];
+
+    #[test]
+    fn test_watertight_shared_edge() {
+        let verts = vertex_data(&QUAD);
+        let t_interval = Interval::positive(1e-10);
+        let offsets = [Vector3::new(0.3, -0.7, 5.1), Vector3::new(-2.3, 1.9, 0.7), Vector3::new(0.01, 0.02, -4.)];
+        for offset in offsets {
+            for k in 1..200 {
+                let s = k as Float / 200.;
+                let target = QUAD[0] + s * (QUAD[2] - QUAD[0]);
+                let ray = ray_towards(target, offset);
+                let n_hits = QUAD_TRIS.iter().filter(|&&tri| watertight_intersection(&ray, &t_interval, tri, &verts).is_some()).count();
+                assert!(n_hits > 0, "Ray slipped through shared edge at s = {}, offset = {}", s, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn test_watertight_shared_vertex() {
+        // Closed fan of triangles around the center vertex (index 0)
+        let center = Vector3::new(0.31, -0.17, 1.23);
+        let n_fan = 7;
+        let mut fan = vec![center];
+        for i in 0..n_fan {
+            let angle = 2. * Float::PI * i as Float / n_fan as Float;
+            fan.push(center + Vector3::new(angle.cos(), angle.sin(), 0.1 * angle.sin()));
+        }
+        let verts = vertex_data(&fan);
+        let tris: Vec<[usize; 3]> = (0..n_fan).map(|i| [0, 1 + i, 1 + (i + 1) % n_fan]).collect();
+
+        let t_interval = Interval::positive(1e-10);
+        for offset in [Vector3::new(0., 0., 3.), Vector3::new(0.7, -1.3, 2.9), Vector3::new(-0.2, 0.4, -1.1)] {
+            let ray = ray_towards(center, offset);
+            let hit = tris.iter().find_map(|&tri| watertight_intersection(&ray, &t_interval, tri, &verts));
+            let (_, _, t) = hit.unwrap_or_else(|| panic!("Ray slipped through shared vertex, offset = {}", offset));
+            assert!((t - offset.length()).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_watertight_matches_moller_trumbore() {
+        // Same barycentric coordinates and distance as the default intersection for inner hits
+        let verts = vertex_data(&QUAD);
+        let t_interval = Interval::positive(1e-10);
+        let target = 0.2 * QUAD[0] + 0.5 * QUAD[1] + 0.3 * QUAD[2];
+        let ray = ray_towards(target, Vector3::new(0.4, 0.2, 2.5));
+
+        let (beta, gamma, t) = moller_trumbore_intersection(&ray, &t_interval, QUAD_TRIS[0], &verts).unwrap();
+        let (w_beta, w_gamma, w_t) = watertight_intersection(&ray, &t_interval, QUAD_TRIS[0], &verts).unwrap();
+        assert!((beta - 0.5).abs() < 1e-9 && (gamma - 0.3).abs() < 1e-9);
+        assert!((beta - w_beta).abs() < 1e-9);
+        assert!((gamma - w_gamma).abs() < 1e-9);
+        assert!((t - w_t).abs() < 1e-9);
+
+        // Non-normalized direction (e.g. local rays of scaled instances) gives t in units of direction
+        let scaled = Ray { origin: ray.origin, direction: ray.direction * 2., time: 0. };
+        let (s_beta, s_gamma, s_t) = watertight_intersection(&scaled, &t_interval, QUAD_TRIS[0], &verts).unwrap();
+        assert!((s_beta - beta).abs() < 1e-9 && (s_gamma - gamma).abs() < 1e-9);
+        assert!((2. * s_t - t).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_watertight_miss() {
+        let verts = vertex_data(&QUAD);
+        let t_interval = Interval::positive(1e-10);
+        let outside = QUAD[1] + 0.1 * (QUAD[1] - QUAD[3]);
+        let ray = ray_towards(outside, Vector3::new(0.4, 0.2, 2.5));
+        assert!(QUAD_TRIS.iter().all(|&tri| watertight_intersection(&ray, &t_interval, tri, &verts).is_none()));
+
+        // Triangle behind the ray
+        let target = (QUAD[0] + QUAD[1] + QUAD[2]) / 3.;
+        let behind = Ray { origin: target + Vector3::new(0., 0., 2.), direction: Vector3::Z, time: 0. };
+        assert!(watertight_intersection(&behind, &t_interval, QUAD_TRIS[0], &verts).is_none());
+    }
+
+    #[test]
+    fn test_loop_subdivide_closed_mesh() {
+        // Tetrahedron, every edge is shared by two faces
+        let tetra = TriMesh {
+            positions: vec![Vector3::new(1., 1., 1.), Vector3::new(1., -1., -1.), Vector3::new(-1., 1., -1.), Vector3::new(-1., -1., 1.)],
+            uvs: vec![None; 4],
+            faces: vec![[0, 1, 2], [0, 3, 1], [0, 2, 3], [1, 3, 2]],
+        };
+        let fine = loop_subdivide(&tetra);
+        assert_eq!(fine.positions.len(), 4 + 6);
+        assert_eq!(fine.faces.len(), 16);
+        assert!(fine.uvs.iter().all(|uv| uv.is_none()));
+
+        // Smoothing shrinks the corners, and the result is closed again
+        assert!(fine.positions[..4].iter().all(|p| p.length() < 3f64.sqrt()));
+        let again = loop_subdivide(&fine);
+        assert_eq!(again.positions.len(), fine.positions.len() + fine.faces.len() * 3 / 2);
+    }
+
+    #[test]
+    fn test_loop_subdivide_boundary() {
+        // A single triangle is all boundary, so it stays flat and uvs follow positions
+        let tri = TriMesh {
+            positions: vec![Vector3::new(0., 0., 0.), Vector3::new(1., 0., 0.), Vector3::new(0., 1., 0.)],
+            uvs: vec![Some([0., 0.]), Some([1., 0.]), Some([0., 1.])],
+            faces: vec![[0, 1, 2]],
+        };
+        let fine = loop_subdivide(&tri);
+        assert_eq!(fine.positions.len(), 6);
+        assert_eq!(fine.faces.len(), 4);
+        assert!(fine.positions.iter().all(|p| p.z == 0.));
+        for (p, uv) in fine.positions.iter().zip(&fine.uvs) {
+            let uv = uv.unwrap();
+            assert!((p.x - uv[0]).abs() < 1e-12 && (p.y - uv[1]).abs() < 1e-12);
+        }
+        assert_eq!(fine.positions[3], Vector3::new(0.5, 0., 0.)); // First edge is 0-1
+    }
+
+    #[test]
+    fn test_uv_projection() {
+        let planar = UvProjection { scale: [0.5, 0.5], offset: [0.25, 0.], ..Default::default() };
+        let (uv, tbn) = planar.project(Vector3::new(3., 0., -1.), Vector3::Y);
+        assert_eq!(uv, [0.75, 0.5]); // Wrapped to [0, 1)
+        assert!((tbn.y_axis.cross(tbn.x_axis) - Vector3::Y).length() < 1e-12);
+
+        // Every face of a box has B x T along its outward normal
+        let box_projection = UvProjection { kind: UvProjectionKind::Box, ..Default::default() };
+        for n in [Vector3::X, -Vector3::X, Vector3::Y, -Vector3::Y, Vector3::Z, -Vector3::Z] {
+            let (uv, tbn) = box_projection.project(0.3 * n + Vector3::splat(0.1), n);
+            assert!(uv.iter().all(|&x| (0. ..1.).contains(&x)));
+            assert!((tbn.y_axis.cross(tbn.x_axis) - n).length() < 1e-12, "normal {}", n);
+        }
+
+        let spherical = UvProjection { kind: UvProjectionKind::Spherical, ..Default::default() };
+        let n = Vector3::new(1., 1., 0.).normalize();
+        let (uv, tbn) = spherical.project(n, n);
+        assert!((uv[1] - 0.25).abs() < 1e-12);
+        assert!((tbn.y_axis.cross(tbn.x_axis) - n).length() < 1e-12);
+    }
+
+    #[test]
+    fn test_midpoint_subdivide_keeps_shape() {
+        // Unlike Loop subdivision corners stay where they are, so edges halve at every level
+        let tetra = TriMesh {
+            positions: vec![Vector3::new(1., 1., 1.), Vector3::new(1., -1., -1.), Vector3::new(-1., 1., -1.), Vector3::new(-1., -1., 1.)],
+            uvs: vec![None; 4],
+            faces: vec![[0, 1, 2], [0, 3, 1], [0, 2, 3], [1, 3, 2]],
+        };
+        let fine = midpoint_subdivide(&tetra);
+        assert_eq!(fine.positions[..4], tetra.positions[..]);
+        assert_eq!(fine.faces.len(), 16);
+        assert!((fine.longest_edge() - tetra.longest_edge() / 2.).abs() < 1e-12);
+
+        // Normals at the corners point away from the center
+        let normals = fine.vertex_normals();
+        for (p, n) in fine.positions.iter().zip(&normals).take(4) {
+            assert!((n.length() - 1.).abs() < 1e-12);
+            assert!((n.dot(p.normalize()) - 1.).abs() < 1e-12);
+        }
+    }
+}
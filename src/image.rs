@@ -12,7 +12,7 @@ use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use crate::{json_structs::SingleOrVec, ray::HitRecord};
 use crate::prelude::*;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Default)]
 pub struct Textures {
     #[serde(rename = "Images")]
     pub images: Option<TextureImages>, // WARNING: I assume Image _id corresponds to its index in the Images vector
@@ -79,7 +79,7 @@ fn perlin_interp(x: Float) -> Float {
 }
 
 // Scale determines frequency of perlin noise
-fn perlin_noise(xyz: Vector3, scale: Float, noise_conversion: &NoiseConversion) -> Float {
+pub(crate) fn perlin_noise(xyz: Vector3, scale: Float, noise_conversion: &NoiseConversion) -> Float {
         let mut n_prime: Float = 0.; // notation in p.49
         
         let x: Float = xyz[0] * scale; //* perlin_texmap.noise_scale;
@@ -161,7 +161,8 @@ impl Textures {
     /// return the color of the corresponding texture pixel from the texture (image or procedural).
     /// TODO: should we use hashmaps instead of vecs to avoid the assumption described above?
     /// uv: texture coordinates (currently only uv is supported, I am not sure how to generalize it atm) 
-    pub fn tex_from_map(&self, texmap_idx: usize, uv: [Float; 2], interpolation: &Interpolation, apply_normalization: bool, xyz: Vector3) -> Vector3 {
+    /// variation: random value in [0, 1) of the instance that is hit (see instancer.rs), only used by variation maps
+    pub fn tex_from_map(&self, texmap_idx: usize, uv: [Float; 2], interpolation: &Interpolation, apply_normalization: bool, xyz: Vector3, variation: Float) -> Vector3 {
         
         let texmap = self.texture_maps.all_ref()[texmap_idx];
         match texmap {
@@ -172,8 +173,8 @@ impl Textures {
 
                 let image = &images.data[image_texmap.image_index];
 
-                debug_assert!(uv[0] <= 1.0 && uv[1] <= 1.0, "Failed condition (u, v) <= 1, found uv : ({}, {})", uv[0], uv[1]);
-                debug_assert!(uv[0] >= 0.0 && uv[1] >= 0.0, "Failed (u, v) >= 0, found uv : ({}, {})", uv[0], uv[1]);
+                // Texture coordinates out of [0, 1] tile, i.e. in OBJ files
+                let uv = uv.map(|x| if (0.0..=1.0).contains(&x) { x } else { x.rem_euclid(1.) });
 
                 let (col, row) = (uv[0] * image.width as Float, uv[1] * image.height as Float); // image coordinate (see slides 06, p.8)
                 let color = image.interpolate(row, col, interpolation);
@@ -208,6 +209,9 @@ impl Textures {
                     checker_texmap.white
                 }
             },
+            TextureMap::Variation(variation_texmap) => {
+                variation_texmap.min_color.lerp(variation_texmap.max_color, variation)
+            },
             _ => {
                 todo!("I am not ready to get texel color of this texmap type '{:?}' yet...", texmap);
             }
@@ -217,6 +221,42 @@ impl Textures {
 
     
 
+    /// Alpha masks of image texture maps indexed by texture map index (see tex_from_map( )), None for
+    /// other texture maps and images without transparent pixels. Triangles look them up during intersection.
+    pub fn alpha_masks(&self) -> Vec<Option<AlphaMask>> {
+        self.texture_maps.all_ref().iter().map(|texmap| match texmap {
+            TextureMap::Image(image_texmap) => {
+                let img = &self.images.as_ref()?.data[image_texmap.image_index];
+                img.alphas.iter().any(|&a| a < 1.).then(|| AlphaMask::new(img.alphas.clone(), img.width, img.height, image_texmap.alpha_threshold))
+            },
+            _ => None,
+        }).collect()
+    }
+
+    /// Height of a displacement map scaled by BumpFactor, i.e. gray level of the image at uv divided by Normalizer
+    /// or Perlin noise at position xyz. Image maps give zero height to vertices without uv.
+    pub fn displacement_height(&self, texmap: &TextureMap, uv: Option<[Float; 2]>, xyz: Vector3) -> Float {
+        match texmap {
+            TextureMap::Image(image_texmap) => {
+                let Some([u, v]) = uv else { return 0.; };
+                let images = self.images.as_ref().expect("Image texture is required but no Images section found");
+                let img = &images.data[image_texmap.image_index];
+                // Clamped as in get_bump_mapping( ) since uv = 1 is on the border of the image
+                let row = (v.clamp(0., 1.) * img.height as Float).min((img.height - 1) as Float);
+                let col = (u.clamp(0., 1.) * img.width as Float).min((img.width - 1) as Float);
+                let c = img.interpolate(row, col, &image_texmap.interpolation);
+                (c.x + c.y + c.z) / 3. / image_texmap.normalizer * image_texmap.bump_factor
+            },
+            TextureMap::Perlin(perlin_texmap) => {
+                perlin_octave(perlin_texmap.num_octaves, xyz, perlin_texmap.noise_scale, &perlin_texmap.noise_conversion) * perlin_texmap.bump_factor
+            },
+            _ => {
+                warn!("Texture map {:?} cannot be used for displacement, ignoring it.", texmap);
+                0.
+            }
+        }
+    }
+
     pub fn get_bump_mapping(&self, texmap: &TextureMap, hit_record: &HitRecord) -> Vector3 {
         match texmap {
             TextureMap::Image(image_texmap) => {
@@ -332,7 +372,7 @@ impl Textures {
 
 
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Default)]
 //#[serde(default)]
 pub struct TextureImages {
     #[serde(rename = "Image")] 
@@ -369,6 +409,29 @@ impl TextureImages {
     }
 }
 
+impl Textures {
+    /// Append an image texture map replacing kd with the image at path, i.e. map_Kd of OBJ materials (see obj.rs).
+    /// Returns the id of the texture map, images are read later by TextureImages::setup( ).
+    pub fn push_diffuse_image(&mut self, path: &Path) -> usize {
+        let images = self.images.get_or_insert_with(TextureImages::default);
+        let image_id = images.raw_images.iter().map(|h| h._id).max().unwrap_or(0) + 1;
+        images.raw_images.push(TextureImageHelper { _data: path.to_string_lossy().into_owned(), _id: image_id });
+
+        let texmap_id = self.texture_maps.len() + 1;
+        self.texture_maps.push(TextureMap::Image(ImageTexmap {
+            _id: texmap_id,
+            image_index: image_id - 1,
+            interpolation: Interpolation::Bilinear,
+            decal_mode: DecalMode::ReplaceKd,
+            normalizer: 255.,
+            bump_factor: 1.,
+            max_edge_length: 0.,
+            alpha_threshold: 0.5,
+        }));
+        texmap_id
+    }
+}
+
 // See https://serde.rs/enum-representations.html for internally tagged representation
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "_type", rename_all = "lowercase")] // content = "content", 
@@ -376,6 +439,7 @@ pub enum TextureMap {
     Image(ImageTexmap),
     Perlin(PerlinTexmap),
     Checkerboard(CheckerTexmap),
+    Variation(VariationTexmap),
     Empty,
 }
 
@@ -393,6 +457,7 @@ impl TextureMap {
             TextureMap::Image(img) => Some(&img.decal_mode),
             TextureMap::Perlin(perlin) => Some(&perlin.decal_mode),
             TextureMap::Checkerboard(checker) => Some(&checker.decal_mode),
+            TextureMap::Variation(variation) => Some(&variation.decal_mode),
             TextureMap::Empty => None,
         }
     }
@@ -402,6 +467,7 @@ impl TextureMap {
             TextureMap::Image(img) => img._id - 1, // Assuming id starts from 1 and index assumes 0 start
             TextureMap::Perlin(perlin) => perlin.id - 1,
             TextureMap::Checkerboard(checker) => checker._id - 1,
+            TextureMap::Variation(variation) => variation._id - 1,
             TextureMap::Empty => panic!("Empty TextureMap received, index unknown!"),
         }
     }
@@ -412,6 +478,20 @@ impl TextureMap {
             _ => None,
         }
     }
+
+    /// Variation maps color instances of an Instancer, they do not need texture coordinates
+    pub fn is_variation(&self) -> bool {
+        matches!(self, TextureMap::Variation(_))
+    }
+
+    /// Longest edge allowed in meshes displaced by this texture map, None if it is not a displacement map
+    pub fn displacement_edge_length(&self) -> Option<Float> {
+        match self {
+            TextureMap::Image(img) if matches!(img.decal_mode, DecalMode::Displacement) => Some(img.max_edge_length),
+            TextureMap::Perlin(perlin) if matches!(perlin.decal_mode, DecalMode::Displacement) => Some(perlin.max_edge_length),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -463,6 +543,47 @@ impl<'de> Deserialize<'de> for CheckerTexmap {
 
 
 
+/// Color between MinColor and MaxColor picked by the random variation of the instance that is hit,
+/// so that copies placed by an Instancer differ (see instancer.rs). Other shapes get MinColor.
+#[derive(Debug, Clone)]
+pub struct VariationTexmap {
+    _id: usize,
+    min_color: Vector3,
+    max_color: Vector3,
+    decal_mode: DecalMode,
+}
+
+impl<'de> Deserialize<'de> for VariationTexmap {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize, SmartDefault)]
+        #[serde(rename_all = "PascalCase")]
+        #[serde(default)]
+        struct Helper {
+            #[serde(rename = "_id", deserialize_with = "deser_usize")]
+            _id: usize,
+            #[serde(deserialize_with = "deser_vec3")]
+            min_color: Vector3,
+            #[serde(deserialize_with = "deser_vec3")]
+            #[default(Vector3::ONE)]
+            max_color: Vector3,
+            #[default = "replace_kd"]
+            decal_mode: String,
+        }
+        let h = Helper::deserialize(deserializer)?;
+        Ok(VariationTexmap {
+            _id: h._id,
+            min_color: h.min_color,
+            max_color: h.max_color,
+            decal_mode: parse_decal(&h.decal_mode).unwrap(),
+        })
+    }
+}
+
+
+
 #[derive(Debug, Clone)]
 struct ImageTexmap {
    
@@ -472,6 +593,8 @@ struct ImageTexmap {
     decal_mode: DecalMode,    
     normalizer: Float,
     bump_factor: Float, 
+    max_edge_length: Float,
+    alpha_threshold: Float,
 }
 
 impl<'de> Deserialize<'de> for ImageTexmap {
@@ -497,6 +620,13 @@ impl<'de> Deserialize<'de> for ImageTexmap {
             #[serde(deserialize_with = "deser_float")]
             #[default = 1.0]
             bump_factor: Float, // TODO: is it safe to assume 1 here?
+
+            #[serde(deserialize_with = "deser_float")]
+            max_edge_length: Float, // Only for displacement, 0 displaces the vertices of the mesh without splitting
+
+            #[serde(deserialize_with = "deser_float")]
+            #[default = 0.5]
+            alpha_threshold: Float, // Only for alpha masks, pixels with less alpha are cut out
         }
         debug!("Calling helper deserializer for 'Image' type texture map...");
         let h = Helper::deserialize(deserializer)?;
@@ -509,6 +639,8 @@ impl<'de> Deserialize<'de> for ImageTexmap {
             interpolation: parse_interp(&h.interpolation).unwrap(),
             normalizer: h.normalizer,
             bump_factor: h.bump_factor,
+            max_edge_length: h.max_edge_length,
+            alpha_threshold: h.alpha_threshold,
         })
     }
 }
@@ -523,6 +655,7 @@ struct PerlinTexmap {
     noise_scale: Float,
     bump_factor: Float,
     num_octaves: usize,
+    max_edge_length: Float,
 }
 
 impl<'de> Deserialize<'de> for PerlinTexmap {
@@ -552,6 +685,9 @@ impl<'de> Deserialize<'de> for PerlinTexmap {
             #[serde(deserialize_with = "deser_usize")]
             #[default = 1]
             num_octaves: usize,
+
+            #[serde(deserialize_with = "deser_float")]
+            max_edge_length: Float, // Only for displacement, see ImageTexmap
         }
 
         let h = Helper::deserialize(deserializer)?;
@@ -563,6 +699,7 @@ impl<'de> Deserialize<'de> for PerlinTexmap {
             noise_scale: h.noise_scale,
             bump_factor: h.bump_factor,
             num_octaves: h.num_octaves,
+            max_edge_length: h.max_edge_length,
         })
     }
 }
@@ -590,6 +727,7 @@ pub(crate) enum DecalMode {
     ReplaceNormal,
     BumpNormal,
     ReplaceAll,
+    Displacement, // Moves mesh vertices at setup instead of changing shading, see Mesh::refine( )
 }
 
 
@@ -621,12 +759,38 @@ impl Interpolation {
 }
 
 
+/// Alpha channel of an image texture map, used to cut out triangles where it is transparent
+/// (see "AlphaMask" in CommonPrimitiveData and Textures::alpha_masks( ))
+#[derive(Debug, Clone)]
+pub struct AlphaMask {
+    alphas: Arc<Vec<Float>>,
+    width: usize,
+    height: usize,
+    threshold: Float,
+}
+
+impl AlphaMask {
+    /// alphas are given row by row, width * height of them
+    pub(crate) fn new(alphas: Arc<Vec<Float>>, width: usize, height: usize, threshold: Float) -> Self {
+        debug_assert_eq!(alphas.len(), width * height);
+        Self { alphas, width, height, threshold }
+    }
+
+    /// True if the nearest pixel to uv has less alpha than AlphaThreshold, uv tiles like texture coordinates
+    pub fn is_transparent(&self, uv: [Float; 2]) -> bool {
+        let col = ((uv[0].rem_euclid(1.) * self.width as Float) as usize).min(self.width - 1);
+        let row = ((uv[1].rem_euclid(1.) * self.height as Float) as usize).min(self.height - 1);
+        self.alphas[row * self.width + col] < self.threshold
+    }
+}
+
 /// ImageData is meant to be used while saving the final rendered image
 #[derive(Debug, Clone, SmartDefault)]
 pub struct ImageData {
     // WARNING: Currently width and height is assumed to represent number of pixels,
     // not accepting a measure like centimeters, that'd require DPI as well
     pub colors : Vec<Vector3>, // Vector of RGB per pixel
+    alphas: Arc<Vec<Float>>, // Alpha per pixel in [0, 1] for images read from file, empty otherwise (shared with AlphaMask)
     width : usize, 
     height: usize,
     name: String, // TODO: width, height, name info actually is stored under camera as well
@@ -645,16 +809,18 @@ impl ImageData {
         let width = width as usize;
         let height = height as usize;
 
-        // WARNING: Asusmes 8 bit RGB
-        let rgb = img.to_rgb8();
+        // WARNING: Asusmes 8 bit RGBA (images without alpha channel are opaque)
+        let rgba = img.to_rgba8();
 
         let mut pixel_colors = Vec::with_capacity(width * height);
-        for chunk in rgb.as_raw().chunks_exact(3) {
+        let mut alphas = Vec::with_capacity(width * height);
+        for chunk in rgba.as_raw().chunks_exact(4) {
             pixel_colors.push(Vector3::new(
                 chunk[0] as Float,
                 chunk[1] as Float,
                 chunk[2] as Float,
             ));
+            alphas.push(chunk[3] as Float / 255.);
         }
 
         // WARNING: Assumes the name is not the path of the image! 
@@ -667,6 +833,7 @@ impl ImageData {
         debug!("Loading ImageData from {}... with dimensions ({}, {})", path.display(), width, height);
         Self {
             colors: pixel_colors,
+            alphas: Arc::new(alphas),
             width,
             height,
             name,
@@ -700,6 +867,7 @@ impl ImageData {
             width,
             height,
             name,
+            ..Default::default()
         }
     }
 
@@ -714,6 +882,7 @@ impl ImageData {
             width,
             height,
             name,
+            ..Default::default()
         }
     }
 
@@ -953,4 +1122,29 @@ pub fn jittered_sampling(n_samples: usize, width: usize, height: usize, nearplan
     }
     
     samples
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alpha_mask_tiles_and_keeps_threshold_opaque() {
+        // 2x2 image, transparent at top left, alpha exactly at the threshold at bottom right
+        let mask = AlphaMask::new(Arc::new(vec![0., 1., 1., 0.5]), 2, 2, 0.5);
+        assert!(mask.is_transparent([0.25, 0.25]));
+        assert!(!mask.is_transparent([0.75, 0.25]));
+        assert!(!mask.is_transparent([0.25, 0.75]));
+        assert!(!mask.is_transparent([0.75, 0.75])); // Not less than the threshold
+
+        // uv outside [0, 1] repeats the image
+        assert!(mask.is_transparent([1.25, -0.75]));
+        assert!(mask.is_transparent([-1.75, 3.25]));
+        assert!(!mask.is_transparent([-0.25, 2.25]));
+        assert!(mask.is_transparent([1., 1.])); // Wraps to the top left pixel, not out of bounds
+
+        let mask = AlphaMask::new(Arc::new(vec![0., 1., 1., 0.5]), 2, 2, 0.5 + 1e-9);
+        assert!(mask.is_transparent([0.75, 0.75]));
+    }
+}
@@ -0,0 +1,359 @@
+/*
+
+    Instancer: many copies of a mesh for forests, crowds or grass, without
+    a MeshInstance object per copy. e.g.
+
+        "Instancer": {
+            "_id": "1", "_baseMeshId": "2", "Material": "3", "Textures": "4",
+            "_pointFile": "trees.csv",
+            "Scatter": { "_surfaceMeshId": "1", "Count": "5000", "ScaleRange": "0.8 1.2",
+                         "RandomRotation": "true", "AlignToNormal": "false" },
+            "Seed": "7"
+        }
+
+    Copies are placed at the points of _pointFile (relative to the scene
+    JSON) and/or scattered uniformly over the surface of another Mesh.
+
+    Point files are CSV (commas or spaces) with an optional header naming the
+    columns: x, y, z, rx, ry, rz (rotations in degrees around x, then y, then
+    z axis), scale (uniform) and material (overrides Material of the copy).
+    Only x, y, z are required, without a header columns are in this order.
+    Lines starting with # are skipped. PLY files give them as properties of
+    their vertices with the same names.
+
+    Scattered copies are rotated around their up (y) axis randomly if
+    RandomRotation is set, and their up axis is turned along the surface
+    normal if AlignToNormal is set. Transformations of the instancer are
+    applied to the points, then the copies are instanced as MeshInstance
+    (M_copy * M_base, or M_copy if _resetTransform is set).
+
+    Every copy shares the base mesh and its BVH, and gets a random value in
+    [0, 1) that "variation" texture maps turn into a color (see image.rs).
+    Seed makes scattering and the variation repeatable.
+
+    @date: Oct, 2026
+    @author: Bartu
+*/
+
+use std::error::Error;
+use std::path::Path;
+use bevy_math::EulerRot;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::interval::FloatConst;
+use crate::json_structs::{SingleOrVec, VertexData};
+use crate::mesh::{Mesh, MeshInstanceField};
+use crate::prelude::*;
+
+#[derive(Debug, Deserialize, Clone, SmartDefault)]
+#[serde(default)]
+pub struct ScatterSettings {
+    #[serde(rename = "_surfaceMeshId", deserialize_with = "deser_usize")]
+    pub surface_mesh_id: usize,
+
+    #[serde(rename = "Count", deserialize_with = "deser_usize")]
+    pub count: usize,
+
+    #[serde(rename = "ScaleRange", deserialize_with = "deser_pair")]
+    #[default([1., 1.])]
+    pub scale_range: [Float; 2],
+
+    #[serde(rename = "RandomRotation", deserialize_with = "deser_bool")]
+    #[default = true]
+    pub random_rotation: bool,
+
+    #[serde(rename = "AlignToNormal", deserialize_with = "deser_bool")]
+    pub align_to_normal: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, SmartDefault)]
+#[serde(default)]
+pub struct Instancer {
+    #[serde(deserialize_with = "deser_usize")]
+    pub _id: usize,
+
+    #[serde(rename = "_baseMeshId", deserialize_with = "deser_usize")]
+    pub base_mesh_id: usize,
+
+    #[serde(rename = "_pointFile")]
+    pub point_file: String,
+
+    #[serde(rename = "Scatter")]
+    pub scatter: Option<ScatterSettings>,
+
+    #[serde(rename = "Seed", deserialize_with = "deser_usize")]
+    pub seed: usize,
+
+    #[serde(rename = "Material", deserialize_with = "deser_opt_usize")]
+    pub material_id: Option<usize>,
+
+    #[serde(rename = "Textures", deserialize_with = "deser_usize_vec")]
+    pub texture_idxs: Vec<usize>, // Textures of the base mesh if not given
+
+    #[serde(rename = "_resetTransform", deserialize_with = "deser_bool")]
+    pub reset_transform: bool,
+
+    #[serde(rename = "Transformations")]
+    pub transformation_names: Option<String>,
+
+    #[serde(skip)]
+    pub transform: Transform,
+}
+
+/// Placement of a copy, relative to the instancer
+#[derive(Debug, Clone, PartialEq)]
+struct InstancePoint {
+    position: Vector3,
+    rotation: Quaternion,
+    scale: Float,
+    material: Option<usize>,
+}
+
+impl InstancePoint {
+    fn new(position: Vector3) -> Self {
+        Self { position, rotation: Quaternion::IDENTITY, scale: 1., material: None }
+    }
+
+    fn matrix(&self) -> Matrix4 {
+        Matrix4::from_scale_rotation_translation(Vector3::splat(self.scale), self.rotation, self.position)
+    }
+}
+
+/// Columns of a point file by name, see the top of the file
+#[derive(Debug, Default)]
+struct PointColumns {
+    position: [Float; 3],
+    angles: [Float; 3], // Degrees
+    scale: Option<Float>,
+    material: Option<usize>,
+}
+
+impl PointColumns {
+    fn set(&mut self, name: &str, value: Float) -> Result<(), String> {
+        match name {
+            "x" => self.position[0] = value,
+            "y" => self.position[1] = value,
+            "z" => self.position[2] = value,
+            "rx" => self.angles[0] = value,
+            "ry" => self.angles[1] = value,
+            "rz" => self.angles[2] = value,
+            "scale" => self.scale = Some(value),
+            "material" if value.is_nan() || value < 1. => return Err(format!("Material id {} in point file, ids start from 1", value)),
+            "material" => self.material = Some(value as usize),
+            other => return Err(format!("Unknown point file column '{}'", other)),
+        }
+        Ok(())
+    }
+
+    fn to_point(&self) -> InstancePoint {
+        let [rx, ry, rz] = self.angles.map(Float::to_radians);
+        InstancePoint {
+            position: Vector3::from_array(self.position),
+            rotation: Quaternion::from_euler(EulerRot::ZYX, rz, ry, rx), // Rz * Ry * Rx
+            scale: self.scale.unwrap_or(1.),
+            material: self.material,
+        }
+    }
+}
+
+const DEFAULT_COLUMNS: [&str; 8] = ["x", "y", "z", "rx", "ry", "rz", "scale", "material"];
+
+fn parse_point_csv(text: &str) -> Result<Vec<InstancePoint>, String> {
+    let mut columns: Vec<String> = DEFAULT_COLUMNS.iter().map(|c| c.to_string()).collect();
+    let mut points = Vec::new();
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(|c: char| c == ',' || c.is_whitespace()).filter(|f| !f.is_empty()).collect();
+        if fields.is_empty() {
+            continue; // Only separators, e.g. ",,"
+        }
+        if points.is_empty() && fields[0].parse::<Float>().is_err() {
+            columns = fields.iter().map(|f| f.to_ascii_lowercase()).collect(); // Header
+            continue;
+        }
+        if fields.len() < 3 || fields.len() > columns.len() {
+            return Err(format!("Line {} of point file has {} columns, expected 3 to {}", line_idx + 1, fields.len(), columns.len()));
+        }
+        let mut row = PointColumns::default();
+        for (name, field) in columns.iter().zip(fields) {
+            let value = field.parse::<Float>().map_err(|e| format!("Line {} of point file: {}", line_idx + 1, e))?;
+            row.set(name, value)?;
+        }
+        points.push(row.to_point());
+    }
+    Ok(points)
+}
+
+#[derive(Deserialize)]
+struct PlyPoints {
+    vertex: Vec<PlyPoint>,
+}
+
+#[derive(Deserialize)]
+struct PlyPoint {
+    x: f32,
+    y: f32,
+    z: f32,
+    #[serde(default)]
+    rx: f32,
+    #[serde(default)]
+    ry: f32,
+    #[serde(default)]
+    rz: f32,
+    #[serde(default)]
+    scale: Option<f32>,
+    #[serde(default)]
+    material: Option<f32>,
+}
+
+fn parse_point_ply(bytes: &[u8]) -> Result<Vec<InstancePoint>, Box<dyn Error>> {
+    let ply: PlyPoints = serde_ply::from_reader(bytes)?;
+    let points = ply.vertex.iter().map(|p| {
+        let mut row = PointColumns {
+            position: [p.x, p.y, p.z].map(|v| v as Float),
+            angles: [p.rx, p.ry, p.rz].map(|v| v as Float),
+            scale: p.scale.map(|s| s as Float),
+            material: None,
+        };
+        if let Some(material) = p.material {
+            row.set("material", material as Float)?; // Checks the id
+        }
+        Ok(row.to_point())
+    }).collect::<Result<_, String>>()?;
+    Ok(points)
+}
+
+/// World space triangles of a mesh that is set up (see Mesh::setup( ))
+fn world_triangles(mesh: &Mesh, verts: &VertexData) -> Vec<[Vector3; 3]> {
+    (0..mesh.faces.len_tris()).map(|i| mesh.faces.get_tri_indices(i).map(|idx| mesh.transform.point(&verts[idx]))).collect()
+}
+
+/// Points distributed uniformly over the area of the triangles
+fn scatter_on_triangles(triangles: &[[Vector3; 3]], settings: &ScatterSettings, rng: &mut StdRng) -> Vec<InstancePoint> {
+    let mut total_area = 0.;
+    let cumulative_areas: Vec<Float> = triangles.iter().map(|[a, b, c]| {
+        total_area += 0.5 * (b - a).cross(c - a).length();
+        total_area
+    }).collect();
+    if total_area <= 0. {
+        warn!("Surface mesh {} has no area to scatter instances on.", settings.surface_mesh_id);
+        return Vec::new();
+    }
+
+    let [min_scale, max_scale] = settings.scale_range;
+    (0..settings.count).map(|_| {
+        let target = rng.random::<Float>() * total_area;
+        let [a, b, c] = triangles[cumulative_areas.partition_point(|&area| area <= target).min(triangles.len() - 1)];
+        // Uniform barycentric coordinates (see pbrt, Section 13.6.5)
+        let (psi1, psi2) = (rng.random::<Float>().sqrt(), rng.random::<Float>());
+        let position = a * (1. - psi1) + b * (psi1 * (1. - psi2)) + c * (psi1 * psi2);
+
+        let mut point = InstancePoint::new(position);
+        if settings.align_to_normal {
+            let normal = (b - a).cross(c - a).normalize();
+            point.rotation = Quaternion::from_rotation_arc(Vector3::Y, normal);
+        }
+        if settings.random_rotation {
+            point.rotation *= Quaternion::from_rotation_y(2. * Float::PI * rng.random::<Float>());
+        }
+        point.scale = min_scale + (max_scale - min_scale) * rng.random::<Float>();
+        point
+    }).collect()
+}
+
+impl Instancer {
+    /// Copies of the base mesh as mesh instances, ready to be added to the scene BVH.
+    /// Meshes must be set up before, since copies share them and the surface is scattered over their triangles.
+    pub fn instances(&self, json_dir: &Path, meshes: &SingleOrVec<Mesh>, verts: &VertexData) -> Result<Vec<MeshInstanceField>, Box<dyn Error>> {
+        let find_mesh = |id: usize| meshes.iter().find(|m| m._id == id).ok_or_else(|| format!("Instancer {} refers mesh {} which is not found", self._id, id));
+        let base_mesh = find_mesh(self.base_mesh_id)?;
+        let mut rng = StdRng::seed_from_u64(self.seed as u64);
+
+        let mut points = Vec::new();
+        if !self.point_file.is_empty() {
+            let path = json_dir.join(&self.point_file);
+            let bytes = std::fs::read(&path).map_err(|e| format!("Cannot read point file {:?}: {}", path, e))?;
+            if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("ply")) {
+                points.extend(parse_point_ply(&bytes)?);
+            } else {
+                points.extend(parse_point_csv(&String::from_utf8(bytes)?)?);
+            }
+        }
+        if let Some(scatter) = &self.scatter {
+            let triangles = world_triangles(find_mesh(scatter.surface_mesh_id)?, verts);
+            points.extend(scatter_on_triangles(&triangles, scatter, &mut rng));
+        }
+        if points.is_empty() {
+            warn!("Instancer {} has neither _pointFile nor Scatter, it has no copies.", self._id);
+        }
+
+        // A single base mesh (and its BVH) for every copy, unlike MeshInstance objects
+        let shared_mesh = Arc::new(base_mesh.clone());
+        let texture_idxs = if self.texture_idxs.is_empty() { &base_mesh.texture_idxs } else { &self.texture_idxs };
+        Ok(points.iter().map(|point| {
+            let mut instance = MeshInstanceField {
+                _id: self._id,
+                base_mesh_id: self.base_mesh_id,
+                reset_transform: self.reset_transform,
+                material_id: point.material.or(self.material_id),
+                texture_idxs: texture_idxs.clone(),
+                transform: Transform::new(self.transform.matrix * point.matrix()),
+                variation: Some(rng.random::<Float>()),
+                base_mesh: Some(shared_mesh.clone()),
+                ..Default::default()
+            };
+            instance.update_composite();
+            instance
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_point_csv_columns() {
+        let points = parse_point_csv("# trees\nx,z,y,ry,material\n1,2,3,90,4\n0 0 0\n").unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].position, Vector3::new(1., 3., 2.));
+        assert_eq!(points[0].material, Some(4));
+        assert!((points[0].rotation * Vector3::X).abs_diff_eq(-Vector3::Z, 1e-12));
+        assert_eq!(points[1], InstancePoint::new(Vector3::ZERO));
+
+        // Default columns without a header
+        let points = parse_point_csv("1 2 3 0 0 0 2.5").unwrap();
+        assert_eq!(points[0].scale, 2.5);
+        assert!(parse_point_csv("1 2").is_err());
+
+        // Lines with only separators are skipped, material ids start from 1
+        assert_eq!(parse_point_csv("1,2,3\n,,\n , \n").unwrap().len(), 1);
+        assert!(parse_point_csv("x,y,z,material\n1,2,3,0\n").is_err());
+        assert!(parse_point_csv("x,y,z,material\n1,2,3,-2\n").is_err());
+    }
+
+    #[test]
+    fn test_scatter_is_on_surface_and_repeatable() {
+        // Unit square in the xz plane as two triangles facing +y
+        let triangles = [
+            [Vector3::ZERO, Vector3::Z, Vector3::X],
+            [Vector3::X, Vector3::Z, Vector3::new(1., 0., 1.)],
+        ];
+        let settings = ScatterSettings { count: 500, scale_range: [0.5, 2.], align_to_normal: true, ..Default::default() };
+        let points = scatter_on_triangles(&triangles, &settings, &mut StdRng::seed_from_u64(3));
+        assert_eq!(points.len(), 500);
+        for point in points.iter() {
+            let p = point.position;
+            assert!(p.y.abs() < 1e-12 && (0. ..=1.).contains(&p.x) && (0. ..=1.).contains(&p.z), "Found {}", p);
+            assert!((point.rotation * Vector3::Y).abs_diff_eq(Vector3::Y, 1e-9));
+            assert!((0.5..=2.).contains(&point.scale));
+        }
+        // Both halves of the square get about half of the points
+        let lower = points.iter().filter(|point| point.position.x + point.position.z < 1.).count();
+        assert!((200..300).contains(&lower), "Found {} points in the lower half", lower);
+        assert_eq!(points, scatter_on_triangles(&triangles, &settings, &mut StdRng::seed_from_u64(3)));
+    }
+}
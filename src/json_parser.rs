@@ -579,6 +579,8 @@ fn parse_single_material(value: serde_json::Value) -> HeapAllocMaterial {
         "mirror" => Box::new(MirrorMaterial::new_from(&value)),
         "dielectric" => Box::new(DielectricMaterial::new_from(&value)),
         "conductor" => Box::new(ConductorMaterial::new_from(&value)),
+        "hair" => Box::new(HairMaterial::new_from(&value)),
+        "null" => Box::new(NullMaterial::new_from(&value)),
         // Add more materials here
 
         other => {
@@ -667,6 +669,7 @@ pub(crate) fn parse_decal(s: &str) -> Result<DecalMode, String> {
         "replace_normal" => Ok(DecalMode::ReplaceNormal),
         "bump_normal" => Ok(DecalMode::BumpNormal),
         "replace_all" => Ok(DecalMode::ReplaceAll),
+        "displacement" => Ok(DecalMode::Displacement),
 
         other => Err(format!("Unknown decal mode: {}", other)),
     }
@@ -0,0 +1,367 @@
+/*
+
+    SAH kd-tree, alternative to BVHSubtree to benchmark against it.
+    Selected with "Accelerator": "kdtree" in the scene JSON or
+    --accelerator=kdtree on the command line (see AcceleratorKind).
+
+    Unlike BVH, space is split instead of shapes, so a shape overlapping
+    both sides of a split plane is referred by both children. Build and
+    traversal follow pbrt-v3, 4.4 (Kd-Tree Accelerator).
+
+    @date: Oct, 2026
+    @author: Bartu
+*/
+use std::time::Instant;
+use rayon::prelude::*;
+
+use crate::prelude::*;
+use crate::acceleration::{Accelerator, ROBUST_SLACK};
+use crate::bbox::BBox;
+use crate::interval::{FloatConst, Interval};
+use crate::json_structs::VertexData;
+use crate::ray::{HitRecord, Ray};
+use crate::scene::HeapAllocatedVerts;
+use crate::shapes::HeapAllocatedShape;
+
+// SAH costs of pbrt-v3, intersection is much more expensive than traversal
+// so that nodes are split until there are only a few shapes in leaves
+const TRAVERSAL_COST: Float = 1.0;
+const INTERSECTION_COST: Float = 80.0;
+const EMPTY_BONUS: Float = 0.5; // Discount of splits that cut off empty space
+const MAX_PRIMS: usize = 1; // Leaves are created without evaluating splits below this
+const MAX_BAD_REFINES: usize = 3; // Splits whose cost is higher than not splitting, allowed along a path
+const TRAVERSAL_STACK_SIZE: usize = 64;
+
+const LEAF: u8 = 3; // Axis of leaf nodes
+
+#[derive(Debug, Clone, Copy)]
+struct KdNode {
+    split: Float, // Position of split plane on axis, unused for leaves
+    offset: u32, // Leaves: index of first shape in KdTree::shape_indices, interior: index of the above child (below child is next to the node)
+    n_prims: u32, // Zero for interior nodes
+    axis: u8, // Split axis, LEAF for leaves
+}
+
+impl KdNode {
+    #[inline]
+    fn is_leaf(&self) -> bool {
+        self.axis == LEAF
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BoundEdge {
+    t: Float,
+    shape: u32,
+    is_start: bool,
+}
+
+/// Cloning is cheap as the nodes and shapes are shared.
+#[derive(Debug, Clone)]
+pub struct KdTree {
+    nodes: Arc<[KdNode]>,
+    shape_indices: Arc<[u32]>, // Indices into shapes, each leaf refers to a contiguous range
+    shapes: Arc<[HeapAllocatedShape]>,
+    bounds: BBox,
+}
+
+impl KdTree {
+    /// Build kd-tree over shapes using their bounding boxes, see BVHSubtree::build( ) for verts and apply_t
+    pub fn build(shapes: &[HeapAllocatedShape], verts: &VertexData, apply_t: bool) -> Self {
+        let span = tracing::span!(tracing::Level::INFO, "build_kdtree", shapes = shapes.len());
+        let _enter = span.enter();
+        let start = Instant::now();
+
+        let shape_bounds: Vec<BBox> = shapes.par_iter().map(|s| s.get_bbox(verts, apply_t)).collect();
+        let bounds = shape_bounds.iter().fold(BBox::empty(), |acc, b| acc.merge(b));
+
+        let mut builder = KdTreeBuilder {
+            shape_bounds: &shape_bounds,
+            nodes: Vec::new(),
+            shape_indices: Vec::new(),
+            edges: Vec::with_capacity(2 * shapes.len()),
+        };
+        if !shapes.is_empty() {
+            // Depth limit of pbrt-v3
+            let max_depth = (8.0 + 1.3 * (shapes.len() as Float).log2()).round() as usize;
+            let all: Vec<u32> = (0..shapes.len() as u32).collect();
+            builder.build_node(&bounds, all, max_depth, 0);
+        }
+
+        let tree = Self {
+            nodes: Arc::from(builder.nodes),
+            shape_indices: Arc::from(builder.shape_indices),
+            shapes: Arc::from(shapes),
+            bounds,
+        };
+        let n_leaves = tree.nodes.iter().filter(|n| n.is_leaf()).count();
+        info!("Built kd-tree over {} shapes in {:?} (nodes: {}, leaves: {}, shape references: {})",
+              shapes.len(), start.elapsed(), tree.nodes.len(), n_leaves, tree.shape_indices.len());
+        tree
+    }
+
+    #[inline]
+    fn leaf_shapes(&self, node: &KdNode) -> impl Iterator<Item = &HeapAllocatedShape> {
+        let first = node.offset as usize;
+        self.shape_indices[first..first + node.n_prims as usize].iter().map(|&i| &self.shapes[i as usize])
+    }
+
+    /// Parametric range of the ray inside the tree bounds, clipped to [t_min, t_max]
+    fn clip(&self, ray: &Ray, inv_dir: &Vector3, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        let (mut t0, mut t1) = (t_min, t_max);
+        for axis in 0..3 {
+            let mut t_near = (self.bounds.min_at(axis) - ray.origin[axis]) * inv_dir[axis];
+            let mut t_far = (self.bounds.max_at(axis) - ray.origin[axis]) * inv_dir[axis];
+            if t_near > t_far {
+                std::mem::swap(&mut t_near, &mut t_far);
+            }
+            t_far *= ROBUST_SLACK;
+
+            // NOTE: comparisons with NaN are false, so 0 * inf cases are ignored
+            if t_near > t0 { t0 = t_near; }
+            if t_far < t1 { t1 = t_far; }
+            if t0 > t1 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+
+    /// Front to back traversal, calls visit_leaf( ) with each leaf the ray passes through and the ray's
+    /// entry distance to it. Stops when visit_leaf( ) returns true.
+    #[inline]
+    fn traverse(&self, ray: &Ray, t_interval: &Interval, mut visit_leaf: impl FnMut(&KdNode, Float) -> bool) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let inv_dir = ray.direction.recip();
+        let Some((t_min, t_max)) = self.clip(ray, &inv_dir, t_interval.min, t_interval.max) else {
+            return;
+        };
+
+        // (node, t_min, t_max) of far children to visit
+        let mut to_visit = [(0usize, 0.0 as Float, 0.0 as Float); TRAVERSAL_STACK_SIZE];
+        let mut stack_size = 0;
+        let (mut current, mut t_min, mut t_max) = (0, t_min, t_max);
+        loop {
+            let node = &self.nodes[current];
+            if node.is_leaf() {
+                if visit_leaf(node, t_min) {
+                    return;
+                }
+                if stack_size == 0 {
+                    return;
+                }
+                stack_size -= 1;
+                (current, t_min, t_max) = to_visit[stack_size];
+                continue;
+            }
+
+            // Children in the order ray passes through them
+            let axis = node.axis as usize;
+            let t_plane = (node.split - ray.origin[axis]) * inv_dir[axis];
+            let below_first = ray.origin[axis] < node.split || (ray.origin[axis] == node.split && ray.direction[axis] <= 0.0);
+            let (first, second) = if below_first { (current + 1, node.offset as usize) } else { (node.offset as usize, current + 1) };
+
+            // NOTE: t_plane is NaN if the ray lies on the plane, then only the first child is visited
+            if t_plane > t_max || t_plane <= 0.0 || t_plane.is_nan() {
+                current = first;
+            } else if t_plane < t_min {
+                current = second;
+            } else {
+                to_visit[stack_size] = (second, t_plane, t_max);
+                stack_size += 1;
+                current = first;
+                t_max = t_plane;
+            }
+        }
+    }
+}
+
+impl Accelerator for KdTree {
+    fn intersect(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts, rec: &mut HitRecord, early_break: bool) -> bool {
+        rec.ray_t = FloatConst::INF;
+        let mut closest: Option<HitRecord> = None;
+        let mut t_max = t_interval.max;
+
+        self.traverse(ray, t_interval, |node, t_entry| {
+            // Leaves are visited in order, so no closer hit can be found after the closest hit so far
+            if t_max < t_entry {
+                return true;
+            }
+            let interval = Interval::new(t_interval.min, t_max);
+            for obj in self.leaf_shapes(node) {
+                if let Some(hit) = obj.intersects_with(ray, &interval, vertex_cache)
+                    && closest.as_ref().is_none_or(|c| hit.ray_t < c.ray_t) {
+                        t_max = t_max.min(hit.ray_t);
+                        closest = Some(hit);
+                        if early_break {
+                            return true;
+                        }
+                }
+            }
+            false
+        });
+
+        if let Some(h) = closest {
+            *rec = h;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        let mut blocked = false;
+        self.traverse(ray, t_interval, |node, _| {
+            blocked = self.leaf_shapes(node).any(|obj| obj.occluded(ray, t_interval, vertex_cache));
+            blocked
+        });
+        blocked
+    }
+}
+
+// ====================================================================================================
+// Build
+// ====================================================================================================
+
+struct KdTreeBuilder<'a> {
+    shape_bounds: &'a [BBox],
+    nodes: Vec<KdNode>,
+    shape_indices: Vec<u32>,
+    edges: Vec<BoundEdge>, // Reused across nodes
+}
+
+impl KdTreeBuilder<'_> {
+    fn make_leaf(&mut self, shapes: &[u32]) {
+        self.nodes.push(KdNode {
+            split: 0.0,
+            offset: u32::try_from(self.shape_indices.len()).expect("kd-tree has too many shape references for u32 offsets"),
+            n_prims: shapes.len() as u32,
+            axis: LEAF,
+        });
+        self.shape_indices.extend_from_slice(shapes);
+    }
+
+    /// Recursively build nodes in depth-first order, see pbrt-v3, 4.4.2
+    fn build_node(&mut self, bounds: &BBox, shapes: Vec<u32>, depth: usize, mut bad_refines: usize) {
+        let n = shapes.len();
+        if n <= MAX_PRIMS || depth == 0 {
+            self.make_leaf(&shapes);
+            return;
+        }
+
+        // Try the axis with the largest extent first, then the others if no split is found
+        let extent = [bounds.xmax - bounds.xmin, bounds.ymax - bounds.ymin, bounds.zmax - bounds.zmin];
+        let inv_total_area = 1.0 / bounds.surface_area();
+        let old_cost = INTERSECTION_COST * n as Float;
+        let mut axis = if extent[0] > extent[1] && extent[0] > extent[2] { 0 } else if extent[1] > extent[2] { 1 } else { 2 };
+
+        // (axis, index of split edge, cost)
+        let mut best: Option<(usize, usize, Float)> = None;
+        for _ in 0..3 {
+            self.edges.clear();
+            for &i in &shapes {
+                let b = &self.shape_bounds[i as usize];
+                self.edges.push(BoundEdge { t: b.min_at(axis), shape: i, is_start: true });
+                self.edges.push(BoundEdge { t: b.max_at(axis), shape: i, is_start: false });
+            }
+            // Start edges come first at the same position so that touching shapes are not counted on both sides
+            self.edges.sort_by(|a, b| a.t.partial_cmp(&b.t).unwrap().then(b.is_start.cmp(&a.is_start)));
+
+            // Sweep the edges and evaluate SAH at each of them
+            let (other0, other1) = ((axis + 1) % 3, (axis + 2) % 3);
+            let (mut n_below, mut n_above) = (0, n);
+            for (i, edge) in self.edges.iter().enumerate() {
+                if !edge.is_start {
+                    n_above -= 1;
+                }
+                if edge.t > bounds.min_at(axis) && edge.t < bounds.max_at(axis) {
+                    let cross_area = extent[other0] * extent[other1];
+                    let side_length = extent[other0] + extent[other1];
+                    let below_area = 2.0 * (cross_area + (edge.t - bounds.min_at(axis)) * side_length);
+                    let above_area = 2.0 * (cross_area + (bounds.max_at(axis) - edge.t) * side_length);
+                    let (p_below, p_above) = (below_area * inv_total_area, above_area * inv_total_area);
+                    let bonus = if n_above == 0 || n_below == 0 { EMPTY_BONUS } else { 0.0 };
+                    let cost = TRAVERSAL_COST + INTERSECTION_COST * (1.0 - bonus) * (p_below * n_below as Float + p_above * n_above as Float);
+                    if best.is_none_or(|(_, _, c)| cost < c) {
+                        best = Some((axis, i, cost));
+                    }
+                }
+                if edge.is_start {
+                    n_below += 1;
+                }
+            }
+
+            if best.is_some() {
+                break;
+            }
+            axis = (axis + 1) % 3;
+        }
+
+        // NOTE: edges are of the best axis since the loop above stops at the first axis with a split
+        let Some((axis, split_edge, cost)) = best else {
+            self.make_leaf(&shapes);
+            return;
+        };
+        if cost > old_cost {
+            bad_refines += 1;
+        }
+        if (cost > 4.0 * old_cost && n < 16) || bad_refines == MAX_BAD_REFINES {
+            self.make_leaf(&shapes);
+            return;
+        }
+
+        // Shapes that start before the split edge go below, and shapes that end after it go above
+        let split = self.edges[split_edge].t;
+        let below: Vec<u32> = self.edges[..split_edge].iter().filter(|e| e.is_start).map(|e| e.shape).collect();
+        let above: Vec<u32> = self.edges[split_edge + 1..].iter().filter(|e| !e.is_start).map(|e| e.shape).collect();
+
+        let (mut bounds_below, mut bounds_above) = (bounds.clone(), bounds.clone());
+        set_axis(&mut bounds_below, axis, None, Some(split));
+        set_axis(&mut bounds_above, axis, Some(split), None);
+
+        let idx = self.nodes.len();
+        self.nodes.push(KdNode { split, offset: 0, n_prims: 0, axis: axis as u8 }); // Offset is set after the below child is built
+        drop(shapes); // Not needed anymore, free it before going deeper
+        self.build_node(&bounds_below, below, depth - 1, bad_refines);
+        self.nodes[idx].offset = u32::try_from(self.nodes.len()).expect("kd-tree has too many nodes for u32 offsets");
+        self.build_node(&bounds_above, above, depth - 1, bad_refines);
+    }
+}
+
+fn set_axis(bbox: &mut BBox, axis: usize, min: Option<Float>, max: Option<Float>) {
+    let (lo, hi) = match axis {
+        0 => (&mut bbox.xmin, &mut bbox.xmax),
+        1 => (&mut bbox.ymin, &mut bbox.ymax),
+        _ => (&mut bbox.zmin, &mut bbox.zmax),
+    };
+    if let Some(min) = min { *lo = min; }
+    if let Some(max) = max { *hi = max; }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acceleration::{BVHSettings, BVHSubtree, NaiveAccelerator};
+    use crate::acceleration::tests::{nearest_hits, random_rays, triangle_soup};
+
+    #[test]
+    fn same_nearest_hits_as_naive_and_bvh() {
+        let (shapes, verts) = triangle_soup(300, 21);
+        let rays = random_rays(500, 22);
+        let expected = nearest_hits(&NaiveAccelerator::new(&shapes), &rays, &verts);
+        assert!(expected.iter().filter(|t| t.is_some()).count() > 100, "Too few rays hit the soup to compare");
+
+        let kdtree = KdTree::build(&shapes, &verts.vertex_data, true);
+        let bvh = BVHSubtree::build(&shapes, &verts.vertex_data, true, &BVHSettings::default());
+        assert_eq!(nearest_hits(&kdtree, &rays, &verts), expected);
+        assert_eq!(nearest_hits(&bvh, &rays, &verts), expected);
+
+        // Shadow rays agree as well
+        let interval = Interval::new(1e-6, 2.);
+        for ray in &rays {
+            assert_eq!(kdtree.occluded(ray, &interval, &verts), NaiveAccelerator::new(&shapes).occluded(ray, &interval, &verts));
+        }
+    }
+}
@@ -1,15 +1,24 @@
 
 pub mod ray;
 pub mod acceleration;
+pub mod kdtree;
 pub mod bbox;
 pub mod mesh;
+pub mod mesh_cache;
+pub mod obj;
+pub mod instancer;
 pub mod image;
 pub mod scene;
 pub mod camera;
 pub mod shapes;
+pub mod csg;
+pub mod sdf;
+pub mod curve;
 pub mod numeric;
 pub mod interval;
 pub mod material;
+pub mod medium;
+pub mod volume;
 pub mod renderer;
 pub mod geometry;
 pub mod json_structs;
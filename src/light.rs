@@ -371,24 +371,25 @@ pub struct PointLight {
     pub(crate) transformation_names: Option<String>,
 
     #[serde(skip)]
-    pub(crate) composite_mat: Matrix4,
+    pub(crate) transform: Option<Arc<Transform>>, // boxed to keep LightKind variants small
 }
 
 impl PointLight {
 
     pub fn setup(&mut self, transforms: &Transformations) {
-        self.composite_mat = 
+        let transform = 
         if self.transformation_names.is_some() 
         {
-            parse_transform_expression(
+            Transform::new(parse_transform_expression(
                 self.transformation_names.as_deref().unwrap_or(""),
                 transforms,  
-            )
+            ))
         } else {
             debug!("No transformation matrix found for point light '{}', defaulting to Identity...", self._id);
-            Matrix4::IDENTITY
+            Transform::IDENTITY
         };
 
-        self.position = transform_point(&self.composite_mat, &self.position);
+        self.position = transform.point(&self.position);
+        self.transform = Some(Arc::new(transform));
     }
 }
@@ -14,6 +14,7 @@ use std::{env, path::Path, path::PathBuf};
 use fury_tracer::*; // lib.rs mods
 use crate::prelude::*; 
 use crate::scene::Scene3D;
+use crate::acceleration::AcceleratorKind;
 
 fn main()  -> Result<(), Box<dyn std::error::Error>> {
 
@@ -21,7 +22,31 @@ fn main()  -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init(); 
 
     // Parse args
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // --accelerator=<bvh|kdtree|naive> overrides "Accelerator" of every scene JSON
+    let mut accelerator: Option<AcceleratorKind> = None;
+    args.retain(|arg| match arg.strip_prefix("--accelerator=") {
+        Some(name) => {
+            accelerator = AcceleratorKind::parse(name);
+            if accelerator.is_none() {
+                error!("Unknown accelerator '{}', expected one of bvh, kdtree, naive", name);
+                std::process::exit(1);
+            }
+            false
+        }
+        None => true,
+    });
+
+    // --bvh-leaf-size=<n> and --bvh-bin-count=<n> override "LeafSize" and "BinCount" of "BVH" in every scene JSON
+    let bvh_overrides = BVHOverrides {
+        leaf_size: take_usize_flag(&mut args, "--bvh-leaf-size="),
+        bin_count: take_usize_flag(&mut args, "--bvh-bin-count="),
+    };
+
+    // --frames renders a folder as an animation, consecutive frames refit BVHs of the previous one
+    let frames = args.iter().any(|arg| arg == "--frames");
+    args.retain(|arg| arg != "--frames");
 
     // If quick test mode on, use input output arguments for .png images
     if std::env::var("QUICK_PNG").is_ok() {
@@ -50,22 +75,29 @@ fn main()  -> Result<(), Box<dyn std::error::Error>> {
         } else if args.len() == 2 {
             &args[1]
         } else {
-            error!("Usage: {} <filename>.json or <path/to/folder>", args[0]);
+            error!("Usage: {} [--accelerator=<bvh|kdtree|naive>] [--bvh-leaf-size=<n>] [--bvh-bin-count=<n>] [--frames] <filename>.json or <path/to/folder>", args[0]);
             std::process::exit(1);
         };
         
         let path = Path::new(&input_path);
         if path.is_file() {
             // Scenario 1: input contains JSON file
-            read_json_and_render(&path.to_str().unwrap().to_string())?; // TODO: Perhaps I should make these functions accept path directly
+            read_json_and_render(&path.to_str().unwrap().to_string(), None, accelerator, &bvh_overrides)?; // TODO: Perhaps I should make these functions accept path directly
         } else if path.is_dir() {
             // Scenario 2: input is a directory, explore all .jsons recursively
-            for entry in WalkDir::new(path).into_iter().filter_map(Result::ok) {
+            // Sorted by name so that frames of an animation are rendered in order. With --frames, each frame refits
+            // BVHs of the previous one if both are in the same folder and their names differ only in the frame number
+            let mut previous: Option<(Scene3D, PathBuf)> = None;
+            for entry in WalkDir::new(path).sort_by_file_name().into_iter().filter_map(Result::ok) {
                 let entry_path = entry.path();
                 let is_json = entry_path.extension().map(|s| s == "json").unwrap_or(false);
                 if entry_path.is_file() && is_json {
                     info!("Rendering JSON: {:?}", entry_path);
-                    read_json_and_render(&entry_path.to_str().unwrap().to_string())?;
+                    let previous_frame = previous.as_ref()
+                                                 .filter(|(_, previous_path)| frames && is_next_frame(previous_path, entry_path))
+                                                 .map(|(scene, _)| scene);
+                    let scene = read_json_and_render(&entry_path.to_str().unwrap().to_string(), previous_frame, accelerator, &bvh_overrides)?;
+                    previous = scene.filter(|_| frames).map(|scene| (scene, entry_path.to_path_buf()));
                 }
             }
         } else {
@@ -77,8 +109,38 @@ fn main()  -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// BVH settings given on the command line, applied over the ones in JSON
+struct BVHOverrides {
+    leaf_size: Option<usize>,
+    bin_count: Option<usize>,
+}
+
+/// Removes every <prefix><n> argument and returns the last n, exits if it is not a number
+fn take_usize_flag(args: &mut Vec<String>, prefix: &str) -> Option<usize> {
+    let mut value = None;
+    args.retain(|arg| match arg.strip_prefix(prefix) {
+        Some(n) => {
+            value = Some(n.parse().unwrap_or_else(|_| {
+                error!("Expected a number for {}, found '{}'", prefix.trim_end_matches('='), n);
+                std::process::exit(1);
+            }));
+            false
+        }
+        None => true,
+    });
+    value
+}
+
+/// Frames of an animation are in the same folder and named with the same prefix before the frame number, e.g. spin_001.json
+fn is_next_frame(previous: &Path, path: &Path) -> bool {
+    let prefix = |p: &Path| p.file_stem().map(|s| s.to_string_lossy().trim_end_matches(|c: char| c.is_ascii_digit()).to_string());
+    previous.parent() == path.parent() && prefix(previous) == prefix(path)
+}
+
 /// Helper function for main() 
-fn read_json_and_render(json_path: &String) -> Result<(), Box<dyn std::error::Error>>  {
+/// Returns the rendered 3D scene, if given to the next call its BVHs are refitted (for frame sequences)
+/// Accelerator and BVH settings given on the command line override the ones in JSON
+fn read_json_and_render(json_path: &String, previous: Option<&Scene3D>, accelerator: Option<AcceleratorKind>, bvh_overrides: &BVHOverrides) -> Result<Option<Scene3D>, Box<dyn std::error::Error>>  {
     // Parse JSON
     debug!("Loading scene from {}...", json_path);
     let root = parse_json795(json_path).map_err(|e| {
@@ -88,8 +150,18 @@ fn read_json_and_render(json_path: &String) -> Result<(), Box<dyn std::error::Er
 
     let json_path = Path::new(json_path).canonicalize()?;
     // HOMEWORK PARTS 3D Renders:
-    if let Some(scene_3d_contents) = root.scene_3d {
-        let scene = Scene3D::new_from(scene_3d_contents, &json_path); 
+    if let Some(mut scene_3d_contents) = root.scene_3d {
+        if let Some(accelerator) = accelerator {
+            scene_3d_contents.accelerator = accelerator;
+        }
+        // Checked by BVHSettings::validate( ) together with the JSON settings during scene setup
+        if let Some(leaf_size) = bvh_overrides.leaf_size {
+            scene_3d_contents.bvh_settings.leaf_size = leaf_size;
+        }
+        if let Some(bin_count) = bvh_overrides.bin_count {
+            scene_3d_contents.bvh_settings.bin_count = bin_count;
+        }
+        let scene = Scene3D::new_from_previous(scene_3d_contents, &json_path, previous); 
         //Box::new(scene3d)
         // UPDATE: If environment variable is given, just load the json, print it and exit. ---------------------------------------------------------
         if std::env::var("JUST_LOAD").is_ok() {
@@ -107,6 +179,7 @@ fn read_json_and_render(json_path: &String) -> Result<(), Box<dyn std::error::Er
                 eprintln!("Failed to save {}: {}", imagefolder, e);
             }
         }
+        return Ok(Some(scene));
     // PROJECT PART 2D Renders:
     } else if let Some(mut scene2d) = root.scene_2d {
         scene2d.setup(&json_path);
@@ -128,7 +201,7 @@ fn read_json_and_render(json_path: &String) -> Result<(), Box<dyn std::error::Er
     };
     
 
-    Ok(())
+    Ok(None)
 }
 
 
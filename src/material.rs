@@ -7,6 +7,8 @@
         - Mirror
         - Conductor (TBI)
         - Dielectric (TBI)
+        - Hair (Kajiya-Kay, for curves)
+        - Null (invisible boundary of a participating medium, see medium.rs)
 
     @date: Oct, 2025
     @author: Bartu
@@ -19,6 +21,7 @@ use serde::{Deserialize, de::DeserializeOwned};
 
 use crate::interval::FloatConst;
 use crate::ray::{Ray, HitRecord}; 
+use crate::medium::Medium;
 use crate::prelude::*;
 use crate::brdf;
 
@@ -104,6 +107,9 @@ pub trait Material : Debug + Send + Sync  {
     fn interact(&self, ray_in: &Ray, hit_record: &HitRecord, epsilon: Float, does_reflect: bool) -> Option<(Ray, Vector3)>; //(Ray, attenuation) --> Used for Ray Tracing requiring Shadow Rays, deterministically reflecting refracting rays if needed (e.g. in Mirror materials) and Diffuse material does not spawn a ray
     fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord, epsilon: Float, importance_sampling: bool) -> Option<(Ray, Vector3)>; // Intended to be used for Path Tracing, spawning rays randomly
     // TODO: Should we merge interact( ) and scatter( )?
+
+    /// Medium filling the closed shape with this material, if any
+    fn medium(&self) -> Option<Medium> { None }
 }
 
 pub type HeapAllocMaterial = Box<dyn Material>; // Box, Rc, Arc -> Probably will be Arc when we use rayon
@@ -374,6 +380,10 @@ pub struct DielectricMaterial {
    
     #[serde(rename = "AbsorptionCoefficient", deserialize_with = "deser_vec3")]
     pub absorption_coeff: Vector3,
+    #[serde(rename = "ScatteringCoefficient", deserialize_with = "deser_vec3")]
+    pub scattering_coeff: Vector3, // Non-zero fills the interior with a scattering medium instead of Beer's law absorption
+    #[serde(rename = "Anisotropy", deserialize_with = "deser_float")]
+    pub anisotropy: Float,
     #[serde(rename = "RefractionIndex", deserialize_with = "deser_float")]
     pub refraction_index: Float,
     #[serde(rename = "Roughness", deserialize_with = "deser_float")]
@@ -394,6 +404,8 @@ impl Default for DielectricMaterial {
                 },
             mirror_rf: Vector3::new(0.5, 0.5, 0.5),
             absorption_coeff: Vector3::new(0.01, 0.01, 0.01),
+            scattering_coeff: Vector3::ZERO,
+            anisotropy: 0.0,
             refraction_index: 1.5,
             roughness: 0.0,
         }
@@ -528,7 +540,7 @@ impl DielectricMaterial {
         
         let ray = Ray::new(hit_record.hit_point - n * epsilon, refracted_direction, ray_in.time); // Apply epsilon in negative normal direction!
         let mut attenuation = frd.f_t * Vector3::ONE;
-        if !hit_record.is_front_face {
+        if !hit_record.is_front_face && self.medium().is_none() { // Otherwise renderer accounts for the medium
             // Attenuate as it goes out of object 
             // assumes glass object is empty
             let distance = (hit_record.entry_point - hit_record.hit_point).norm(); 
@@ -572,6 +584,10 @@ impl Material for DielectricMaterial {
         Some((0.0, self.refraction_index))
     }
 
+    fn medium(&self) -> Option<Medium> {
+        (self.scattering_coeff != Vector3::ZERO).then(|| Medium::new(self.absorption_coeff, self.scattering_coeff, self.anisotropy))
+    }
+
     fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord, epsilon: Float, _: bool) -> Option<(Ray, Vector3)> {
         
         let mut fresnel = FresnelData::default();
@@ -756,3 +772,204 @@ impl Material for ConductorMaterial {
         self.reflect(ray_in, hit_record, epsilon)
     }
 }
+
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+/// 
+/// NULL
+/// 
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Boundary that rays pass through unchanged, only used to hold a medium (e.g. smoke) without a glass surface
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct NullMaterial {
+    #[serde(deserialize_with = "deser_usize")]
+    pub _id: usize,
+
+    #[serde(flatten)]
+    pub brdf_common: ReflectanceParams, // Unused, required by reflectance_data( )
+
+    #[serde(rename = "AbsorptionCoefficient", deserialize_with = "deser_vec3")]
+    pub absorption_coeff: Vector3,
+    #[serde(rename = "ScatteringCoefficient", deserialize_with = "deser_vec3")]
+    pub scattering_coeff: Vector3,
+    #[serde(rename = "Anisotropy", deserialize_with = "deser_float")]
+    pub anisotropy: Float,
+}
+
+impl NullMaterial {
+    fn pass_through(&self, ray_in: &Ray, hit_record: &HitRecord, epsilon: Float) -> Option<(Ray, Vector3)> {
+        // Normal faces against the ray, so the origin is shifted to the other side
+        let ray = Ray::new(hit_record.hit_point - hit_record.normal * epsilon, ray_in.direction, ray_in.time);
+        Some((ray, Vector3::ONE))
+    }
+}
+
+impl Material for NullMaterial {
+
+    fn get_type(&self) -> &str {
+        "null"
+    }
+
+    fn brdf(&self) -> Option<usize> {
+        None
+    }
+
+    fn reflectance_data(&self) -> &ReflectanceParams {
+        &self.brdf_common
+    }
+
+    fn get_fresnel_indices(&self) -> Option<(Float, Float)> {
+        None
+    }
+
+    fn interact(&self, ray_in: &Ray, hit_record: &HitRecord, epsilon: Float, _: bool) -> Option<(Ray, Vector3)> {
+        self.pass_through(ray_in, hit_record, epsilon)
+    }
+
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord, epsilon: Float, _: bool) -> Option<(Ray, Vector3)> {
+        self.pass_through(ray_in, hit_record, epsilon)
+    }
+
+    fn medium(&self) -> Option<Medium> {
+        let sigma_t = self.absorption_coeff + self.scattering_coeff;
+        (sigma_t != Vector3::ZERO).then(|| Medium::new(self.absorption_coeff, self.scattering_coeff, self.anisotropy))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+/// 
+/// HAIR
+/// 
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HairMaterial {
+    #[serde(deserialize_with = "deser_usize")]
+    pub _id: usize,
+
+    #[serde(flatten)]
+    pub brdf_common: ReflectanceParams,
+}
+
+impl HairMaterial {
+    /// Strand direction at the hit, shapes other than curves get an arbitrary tangent
+    pub fn tangent(hit_record: &HitRecord) -> Vector3 {
+        hit_record.strand_tangent.unwrap_or_else(|| get_onb(&hit_record.normal).0)
+    }
+}
+
+impl Material for HairMaterial {
+
+    fn setup(&mut self) {
+        if self.brdf_common.degamma {
+            self.brdf_common.apply_degamma();
+            self.brdf_common.degamma = false;
+        }
+    }
+
+    fn get_type(&self) -> &str {
+        "hair"
+    }
+
+    fn reflectance_data(&self) -> &ReflectanceParams {
+        &self.brdf_common
+    }
+
+    fn brdf(&self) -> Option<usize> {
+        None // BRDFs need a normal, strands are shaded by kajiya_kay( ) instead
+    }
+
+    fn interact(&self, _: &Ray, _: &HitRecord, _: Float, _: bool) -> Option<(Ray, Vector3)> {
+        warn!("Hair material assumed to only use shadow rays, rays are not meant to be scattered here.");
+        None
+    }
+
+    fn get_fresnel_indices(&self) -> Option<(Float, Float)> {
+        None
+    }
+
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord, epsilon: Float, _: bool) -> Option<(Ray, Vector3)> {
+        // Strands scatter to both sides, so sample the whole sphere uniformly
+        let z = 1. - 2. * random_float();
+        let r = (1. - z * z).max(0.).sqrt();
+        let phi = 2. * Float::PI * random_float();
+        let scattered_dir = Vector3::new(r * phi.cos(), r * phi.sin(), z);
+        let pdf = 1. / (4. * Float::PI);
+
+        // Offset along the scattered direction since it may go through the strand (see curve.rs)
+        let scattered_ray = Ray::new(hit_record.hit_point + scattered_dir * epsilon, scattered_dir.normalize(), ray_in.time);
+        let attenuation = kajiya_kay(scattered_dir, -ray_in.direction, Self::tangent(hit_record), &self.brdf_common) / pdf;
+        Some((scattered_ray, attenuation))
+    }
+}
+
+/// Kajiya-Kay hair model (Kajiya and Kay, 1989) for a strand with unit tangent t. Strands have no
+/// single normal, so the cosine term is included: diffuse falls off with sin(t, wi) and specular peaks
+/// on the cone of mirror directions around the strand. PhongExponent sets the width of that cone.
+pub fn kajiya_kay(wi: Vector3, wo: Vector3, t: Vector3, params: &ReflectanceParams) -> Vector3 {
+    let cos_i = wi.dot(t).clamp(-1., 1.);
+    let cos_o = wo.dot(t).clamp(-1., 1.);
+    let (sin_i, sin_o) = ((1. - cos_i * cos_i).sqrt(), (1. - cos_o * cos_o).sqrt());
+    // cos(theta_o - (pi - theta_i)), wo on the cone where the angle to t is pi - theta_i
+    let cone = (sin_i * sin_o - cos_i * cos_o).max(0.);
+    params.diffuse_rf * sin_i / Float::PI + params.specular_rf * cone.powf(params.phong_exponent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hair_params(diffuse: Float, specular: Float) -> ReflectanceParams {
+        ReflectanceParams {
+            diffuse_rf: Vector3::splat(diffuse),
+            specular_rf: Vector3::splat(specular),
+            phong_exponent: 50.,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn kajiya_kay_specular_peaks_on_mirror_cone() {
+        let t = Vector3::X;
+        let params = hair_params(0., 1.);
+        let theta_i: Float = 0.7;
+        let wi = Vector3::new(theta_i.cos(), theta_i.sin(), 0.);
+        // Every wo at angle pi - theta_i to the strand, i.e. around the cone
+        let phis: [Float; 4] = [0., 1., 2.5, 4.];
+        for phi in phis {
+            let wo = Vector3::new(-theta_i.cos(), theta_i.sin() * phi.cos(), theta_i.sin() * phi.sin());
+            assert!(kajiya_kay(wi, wo, t, &params).abs_diff_eq(Vector3::ONE, 1e-12));
+        }
+        // Off the cone it falls off
+        let theta_o: Float = Float::PI - theta_i + 0.2;
+        let wo = Vector3::new(theta_o.cos(), theta_o.sin(), 0.);
+        assert!(kajiya_kay(wi, wo, t, &params).max_element() < 0.5);
+    }
+
+    #[test]
+    fn kajiya_kay_diffuse_vanishes_along_tangent() {
+        let t = Vector3::new(1., 2., -1.).normalize();
+        let params = hair_params(1., 0.);
+        let wo = Vector3::Y;
+        assert!(kajiya_kay(t, wo, t, &params).abs_diff_eq(Vector3::ZERO, 1e-12));
+        assert!(kajiya_kay(-t, wo, t, &params).abs_diff_eq(Vector3::ZERO, 1e-12));
+        // Largest perpendicular to the strand
+        let perpendicular = get_onb(&t).0;
+        assert!(kajiya_kay(perpendicular, wo, t, &params).abs_diff_eq(Vector3::splat(1. / Float::PI), 1e-12));
+    }
+
+    #[test]
+    fn hair_scatter_gives_unit_directions() {
+        let hair = HairMaterial { _id: 1, brdf_common: hair_params(0.8, 0.5) };
+        let hit_record = HitRecord { strand_tangent: Some(Vector3::Y), normal: Vector3::Z, ..Default::default() };
+        let ray_in = Ray::new(Vector3::new(0., 0., 2.), -Vector3::Z, 0.);
+        for _ in 0..1000 {
+            let (ray, attenuation) = hair.scatter(&ray_in, &hit_record, 1e-4, false).unwrap();
+            assert!((ray.direction.length() - 1.).abs() < 1e-12);
+            assert!(attenuation.is_finite() && attenuation.min_element() >= 0.);
+        }
+    }
+}
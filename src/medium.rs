@@ -0,0 +1,145 @@
+/*
+
+    Homogeneous participating media, i.e. murky water, jade or smoke
+    filling a closed boundary mesh. The medium belongs to the material
+    of the boundary (dielectric or "null"), a ray that hits the boundary
+    from inside has travelled through the medium (no nested media).
+
+    Materials describe it with:
+        "AbsorptionCoefficient": "0.1 0.2 0.3",
+        "ScatteringCoefficient": "1 1 1",
+        "Anisotropy": "0.3"  (Henyey-Greenstein g, positive scatters forward)
+
+    Path tracer samples free-flight distances to scatter inside the
+    medium (see renderer::path_trace( )), ray tracer only applies its
+    extinction along the rays.
+
+    @date: Oct, 2026
+    @author: Bartu
+*/
+
+use crate::interval::FloatConst;
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Medium {
+    pub absorption: Vector3, // sigma_a
+    pub scattering: Vector3, // sigma_s
+    pub g: Float,            // Anisotropy of Henyey-Greenstein phase function in (-1, 1)
+}
+
+impl Medium {
+    pub fn new(absorption: Vector3, scattering: Vector3, g: Float) -> Self {
+        if g.abs() >= 1. {
+            warn!("Anisotropy {} of medium must be in (-1, 1), clamping it.", g);
+        }
+        Self { absorption, scattering, g: g.clamp(-0.99, 0.99) }
+    }
+
+    fn extinction(&self) -> Vector3 {
+        self.absorption + self.scattering
+    }
+
+    /// Fraction of extinction that is scattering rather than absorption
+    pub fn albedo(&self) -> Vector3 {
+        let sigma_t = self.extinction();
+        Vector3::select(sigma_t.cmpgt(Vector3::ZERO), self.scattering / sigma_t, Vector3::ZERO)
+    }
+
+    /// Fraction of light passing through distance in the medium (Beer's law with sigma_t = sigma_a + sigma_s)
+    pub fn transmittance(&self, distance: Float) -> Vector3 {
+        (-self.extinction() * distance).exp()
+    }
+
+    /// Samples a free-flight distance along a ray that leaves the medium at max_distance.
+    /// Returns the distance if the ray scatters before leaving, together with the throughput weight
+    /// (transmittance times sigma_s if it scatters, divided by the pdf). A color channel is picked
+    /// uniformly for the exponential distribution and the pdf averages them (see pbrt, ch. 15.2).
+    pub fn sample_distance(&self, max_distance: Float, psi1: Float, psi2: Float) -> (Option<Float>, Vector3) {
+        let sigma_t = self.extinction();
+        let channel = ((psi1 * 3.) as usize).min(2);
+        let distance = if sigma_t[channel] > 0. { -(1. - psi2).ln() / sigma_t[channel] } else { Float::INFINITY };
+
+        let scatters = distance < max_distance;
+        let distance = distance.min(max_distance);
+        let transmittance = self.transmittance(distance);
+        let density = if scatters { sigma_t * transmittance } else { transmittance };
+        let pdf = density.element_sum() / 3.;
+        if pdf <= 0. {
+            return (None, Vector3::ZERO);
+        }
+
+        let weight = if scatters { self.scattering * transmittance / pdf } else { transmittance / pdf };
+        (scatters.then_some(distance), weight)
+    }
+
+    /// Henyey-Greenstein phase function for the angle between incoming and scattered ray directions
+    pub fn phase(&self, cos_theta: Float) -> Float {
+        let g = self.g;
+        let denom = 1. + g * g - 2. * g * cos_theta;
+        (1. - g * g) / (4. * Float::PI * denom * denom.sqrt())
+    }
+
+    /// Scattered direction for a ray travelling along direction, sampled proportional to phase( ),
+    /// so that the throughput does not change.
+    pub fn sample_phase(&self, direction: Vector3, psi1: Float, psi2: Float) -> Vector3 {
+        let g = self.g;
+        let cos_theta = if g.abs() < 1e-3 {
+            1. - 2. * psi1
+        } else {
+            let s = (1. - g * g) / (1. - g + 2. * g * psi1);
+            (1. + g * g - s * s) / (2. * g)
+        }.clamp(-1., 1.);
+        let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+        let phi = 2. * Float::PI * psi2;
+
+        let (u, v) = get_onb(&direction);
+        (cos_theta * direction + sin_theta * (phi.cos() * u + phi.sin() * v)).normalize()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_sampling_matches_anisotropy() {
+        // Mean cosine of Henyey-Greenstein is g, and the phase function integrates to one
+        let medium = Medium::new(Vector3::ZERO, Vector3::ONE, 0.6);
+        let direction = Vector3::new(1., 2., -0.5).normalize();
+        let n = 200;
+        let (mut mean_cos, mut integral) = (0., 0.);
+        for i in 0..n {
+            for j in 0..n {
+                let (psi1, psi2) = ((i as Float + 0.5) / n as Float, (j as Float + 0.5) / n as Float);
+                let w = medium.sample_phase(direction, psi1, psi2);
+                assert!(w.is_normalized());
+                mean_cos += w.dot(direction);
+                integral += medium.phase(1. - 2. * psi1) * 4. * Float::PI; // Uniform sphere samples
+            }
+        }
+        let count = (n * n) as Float;
+        assert!((mean_cos / count - 0.6).abs() < 1e-3, "Mean cosine {}", mean_cos / count);
+        assert!((integral / count - 1.).abs() < 1e-2, "Phase integral {}", integral / count);
+    }
+
+    #[test]
+    fn test_distance_sampling_is_unbiased() {
+        // Expected weight of passing through equals transmittance, with a colored medium
+        let medium = Medium::new(Vector3::new(0.1, 0.5, 1.), Vector3::new(0.5, 0.2, 0.), 0.);
+        let max_distance = 1.5;
+        let n = 400;
+        let mut passed = Vector3::ZERO;
+        for i in 0..n {
+            for j in 0..n {
+                let (psi1, psi2) = ((i as Float + 0.5) / n as Float, (j as Float + 0.5) / n as Float);
+                if let (None, weight) = medium.sample_distance(max_distance, psi1, psi2) {
+                    passed += weight;
+                }
+            }
+        }
+        let passed = passed / (n * n) as Float;
+        assert!(passed.abs_diff_eq(medium.transmittance(max_distance), 1e-2), "Found {}, expected {}", passed, medium.transmittance(max_distance));
+    }
+}
@@ -7,16 +7,21 @@ UPDATE: Acceleration structure added Mesh::bvh
 
 */
 use bevy_math::NormedVectorSpace;
+use std::borrow::Cow;
+use std::collections::HashMap;
 
 
 use crate::json_structs::{FaceType, SingleOrVec, VertexData, TexCoordData};
-use crate::geometry::{get_tri_normal, is_degenerate_triangle};
+use crate::geometry::{deser_uv_projection, get_tri_normal, is_degenerate_triangle, loop_subdivide, midpoint_subdivide, TriMesh, UvProjection};
+use crate::image::{TextureMap, Textures};
 use crate::shapes::{CommonPrimitiveData, EmissiveShape, Shape, Triangle};
 use crate::ray::{Ray, HitRecord};
 use crate::interval::{FloatConst, Interval};
 use crate::bbox::{BBoxable, BBox};
 use crate::scene::{HeapAllocatedVerts};
-use crate::acceleration::BVHSubtree;
+use crate::acceleration::{Accelerator, AcceleratorKind, BVHSettings, BVHSubtree, CachedBVH, NaiveAccelerator};
+use crate::kdtree::KdTree;
+use crate::obj::{deser_obj_materials, ObjMesh};
 use crate::shapes::ShapeList;
 
 use crate::prelude::*;
@@ -45,11 +50,26 @@ pub struct MeshInstanceField {
     #[serde(rename = "Transformations")]
     pub(crate) transformation_names: String,
 
+    #[serde(rename = "EndTransformations", default)]
+    pub(crate) end_transformation_names: Option<String>, // M_instance at the end of the shutter
+
     #[serde(rename = "MotionBlur", deserialize_with = "deser_vec3", default)]
     pub(crate) motionblur: Vector3, // translational
 
     #[serde(skip)]
-    pub(crate) matrix: Matrix4, // WARNING: This should apply its M_instance on M_base
+    pub(crate) transform: Transform, // M_instance, WARNING: This should apply its M_instance on M_base
+
+    #[serde(skip)]
+    pub(crate) end_transform: Option<Transform>, // M_instance given by EndTransformations
+
+    #[serde(skip)]
+    pub(crate) composite: Transform, // M_instance * M_base (or M_instance if reset_transform), cached by update_composite( )
+
+    #[serde(skip)]
+    pub(crate) motion: Option<AnimatedTransform>, // Composite from start to end, cached by update_composite( )
+
+    #[serde(skip)]
+    pub(crate) variation: Option<Float>, // Random value in [0, 1) given to instances of an Instancer (see instancer.rs)
 
     #[serde(skip)]
     pub base_mesh: Option<Arc<Mesh>>, // wrapped around Option to prevent default mesh construction
@@ -76,6 +96,25 @@ impl MeshInstanceField {
             warn!("Couldn't find base mesh id {} ", self.base_mesh_id);
         }
     }
+
+    /// Cache composite transform once base mesh is resolved, so that it is not recomputed per ray
+    pub fn update_composite(&mut self) {
+        let composite = |instance: &Transform| match self.base_mesh.as_deref() {
+            Some(base_mesh) if !self.reset_transform => instance.compose(&base_mesh.transform), // M_instance * M_base
+            _ => *instance,
+        };
+        self.composite = composite(&self.transform);
+        self.motion = self.end_transform.as_ref().map(|end| AnimatedTransform::new(self.composite, composite(end)));
+        debug!("Composite transform for mesh instance '{}' is {}", self._id, self.composite.matrix);
+    }
+
+    /// Composite transform at ray time, see Mesh::transform_at( )
+    fn composite_at(&self, time: Float) -> Cow<'_, Transform> {
+        match &self.motion {
+            Some(motion) => Cow::Owned(motion.at(time)),
+            None => Cow::Borrowed(&self.composite),
+        }
+    }
 }
 
 
@@ -105,6 +144,10 @@ impl Shape for LightMesh {
         }
         
     }
+
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        self.data.occluded(ray, t_interval, vertex_cache)
+    }
 }
 
 impl EmissiveShape for LightMesh {
@@ -124,8 +167,8 @@ impl EmissiveShape for LightMesh {
         let radius_local = bbox.get_sphere_radius();
         
         // Transform center and radius to world space
-        let center_world = crate::prelude::transform_point(&self.data.matrix, &center_local);
-        let max_scale = crate::numeric::max_scale(&self.data.matrix, true);
+        let center_world = self.data.transform.point(&center_local);
+        let max_scale = crate::numeric::max_scale(&self.data.transform.matrix, true);
         let radius_world = radius_local * max_scale;
         
         // Sample direction using solid angle around bounding sphere (same as LightSphere)
@@ -169,6 +212,17 @@ impl BBoxable for LightMesh {
     }
 }
 
+// Every split multiplies the number of faces by four, see Mesh::refine( )
+const MAX_DISPLACEMENT_SPLITS: usize = 8;
+
+/// Material and texture maps of the faces from first_face up to the next group, i.e. usemtl groups of OBJ files (see obj.rs)
+#[derive(Debug, Clone)]
+pub struct FaceGroup {
+    pub first_face: usize,
+    pub material_idx: usize,
+    pub texture_idxs: Vec<usize>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[derive(SmartDefault)]
 #[serde(default)]
@@ -183,6 +237,12 @@ pub struct Mesh {
     #[serde(rename = "Textures", deserialize_with = "deser_usize_vec")]
     pub texture_idxs: Vec<usize>,
 
+    #[serde(rename = "UVProjection", deserialize_with = "deser_uv_projection")]
+    pub uv_projection: Option<Arc<UvProjection>>, // For meshes without texture coordinates, see geometry::UvProjection
+
+    #[serde(rename = "AlphaMask", deserialize_with = "deser_opt_usize")]
+    pub alpha_mask: Option<usize>, // See CommonPrimitiveData::alpha_mask
+
     #[serde(rename = "Faces")]
     pub faces: FaceType,
 
@@ -190,22 +250,46 @@ pub struct Mesh {
     #[default = "flat"]
     pub _shading_mode: String,
 
+    #[serde(rename = "_subdivisionLevel", deserialize_with = "deser_usize")]
+    pub _subdivision_level: usize, // Levels of Loop subdivision applied in setup( ), 0 keeps faces as they are
+
+    #[serde(rename = "_objFile")]
+    pub _obj_file: String, // Wavefront OBJ file relative to the json, used instead of Faces (see obj.rs)
+
+    #[serde(rename = "_objMaterials", deserialize_with = "deser_obj_materials")]
+    pub obj_materials: HashMap<String, usize>, // Scene material ids of usemtl names in _objFile
+
+    #[serde(rename = "_useMtl", deserialize_with = "deser_bool")]
+    pub use_mtl: bool, // Generate materials of other usemtl names from .mtl files
+
     #[serde(rename = "Transformations", default)]
     pub transformation_names: Option<String>,
 
+    #[serde(rename = "EndTransformations", default)]
+    pub end_transformation_names: Option<String>, // Transformations at the end of the shutter, see numeric::AnimatedTransform
+
     #[serde(rename = "MotionBlur", deserialize_with = "deser_vec3", default)]
     pub(crate) motionblur: Vector3, // translational
 
     #[serde(skip)]
-    pub matrix: Matrix4,
+    pub transform: Transform,
 
-    //#[serde(skip)]
-    //pub inv_matrix: Arc<Matrix4>,
+    #[serde(skip)]
+    pub motion: Option<AnimatedTransform>, // From transform to EndTransformations, set in scene setup
+
+    #[serde(skip)]
+    pub(crate) obj: Option<Arc<ObjMesh>>, // Read from _objFile before the scene objects are set up, see obj::load_obj( )
+    #[serde(skip)]
+    pub(crate) face_groups: Vec<FaceGroup>, // Sorted by first_face, faces before the first group use material_idx and texture_idxs
 
     #[serde(skip)]
     pub triangles: ShapeList,
     #[serde(skip)]
     pub bvh: Option<BVHSubtree>,
+    #[serde(skip)]
+    pub(crate) previous_frame: Option<(BVHSubtree, Vec<usize>)>, // BVH and faces of this mesh in the previous frame, see SceneObjects::reuse_mesh_bvhs( )
+    #[serde(skip)]
+    pub accelerator: Option<Arc<dyn Accelerator>>, // Used instead of bvh if set, see setup_accelerator( )
 }
 
 impl Mesh {
@@ -213,7 +297,62 @@ impl Mesh {
     /// Given global vertex data and id_offset, 
     /// Populate self.triangles with a vector, and
     /// return the vector of the created triangles.
-    pub fn setup(&mut self, verts: &VertexData, id_offset: usize) -> Vec<Triangle> {
+    /// If the mesh has the BVH of the previous frame (see SceneObjects::reuse_mesh_bvhs( )) with the same faces,
+    /// it is refitted to the triangles instead of building a new one. BVH is not built if bvh_settings is None,
+    /// i.e. another accelerator is used (see setup_accelerator( )).
+    /// Subdivided or displaced vertices are appended to verts and uv_coords (see refine( )).
+    pub fn setup(&mut self, verts: &mut VertexData, uv_coords: &mut Vec<Option<[Float; 2]>>, textures: Option<&Textures>, id_offset: usize, bvh_settings: Option<&BVHSettings>) -> Vec<Triangle> {
+        let previous_frame = self.previous_frame.take();
+        let triangles = self.setup_triangles(verts, uv_coords, textures, id_offset);
+        let Some(bvh_settings) = bvh_settings else {
+            return triangles;
+        };
+
+        // Build BVH for acceleration, refit needs the same faces (compared after vertex offsets are applied) so
+        // that its leaves still group nearby triangles
+        let bvh = match previous_frame.filter(|(_, faces)| *faces == self.faces._data) {
+            Some((previous, _)) => previous.refit(&self.triangles, verts, false, bvh_settings),
+            None => BVHSubtree::build(&self.triangles, verts, false, bvh_settings),
+        };
+        info!(">> Mesh {} BVH ({:?}): {}", self._id, bvh_settings.builder, bvh.stats());
+        self.bvh = Some(bvh);
+
+        triangles
+    }
+
+    /// Same as setup( ) but the BVH is restored from the mesh cache instead of being built.
+    /// Falls back to building it if the cached BVH does not fit the triangles.
+    pub fn setup_cached(&mut self, verts: &mut VertexData, uv_coords: &mut Vec<Option<[Float; 2]>>, textures: Option<&Textures>, id_offset: usize, bvh_settings: &BVHSettings, cached_bvh: CachedBVH) -> Vec<Triangle> {
+        let triangles = self.setup_triangles(verts, uv_coords, textures, id_offset);
+
+        match BVHSubtree::from_cached(cached_bvh, &self.triangles) {
+            Some(bvh) => {
+                info!(">> Mesh {} BVH (cached): {}", self._id, bvh.stats());
+                self.bvh = Some(bvh);
+            }
+            None => {
+                warn!("Cached BVH of mesh {} does not match its triangles, rebuilding it.", self._id);
+                let bvh = BVHSubtree::build(&self.triangles, verts, false, bvh_settings);
+                self.bvh = Some(bvh);
+            }
+        }
+
+        triangles
+    }
+
+    /// Build the acceleration structure of given kind over self.triangles, must be called after setup( ).
+    /// BVH itself is built in setup( ) since the mesh cache and refitting depend on it, for other kinds it is
+    /// only built (doubling the build time) if the mesh is stored in the mesh cache.
+    pub fn setup_accelerator(&mut self, kind: AcceleratorKind, verts: &VertexData) {
+        self.accelerator = match kind {
+            AcceleratorKind::BVH => None,
+            AcceleratorKind::KdTree => Some(Arc::new(KdTree::build(&self.triangles, verts, false))),
+            AcceleratorKind::Naive => Some(Arc::new(NaiveAccelerator::new(&self.triangles))),
+        };
+    }
+
+    /// Populate self.triangles, see setup( )
+    fn setup_triangles(&mut self, verts: &mut VertexData, uv_coords: &mut Vec<Option<[Float; 2]>>, textures: Option<&Textures>, id_offset: usize) -> Vec<Triangle> {
 
         // Apply vertex offset to faces._data
         // subsequent uses of faces._data will have correct indices
@@ -224,6 +363,8 @@ impl Mesh {
             }
         }
 
+        self.refine(verts, uv_coords, textures);
+
         let triangles: Vec<Triangle> = self.to_triangles(verts, id_offset);
         
         self.triangles = triangles.clone()
@@ -231,13 +372,99 @@ impl Mesh {
                                   .map(|tri| Arc::new(tri) as Arc<dyn Shape>)
                                   .collect();
 
-        // Build BVH for acceleration
-        self.bvh = Some(BVHSubtree::build(&self.triangles, verts,false));
-
         triangles
     }
 
 
+    /// Texture maps of the mesh with "displacement" decal mode, together with their MaxEdgeLength
+    fn displacement_maps<'a>(&self, textures: Option<&'a Textures>) -> Vec<(&'a TextureMap, Float)> {
+        let Some(textures) = textures else { return Vec::new(); };
+        let texture_maps = textures.texture_maps.all_ref();
+        self.texture_idxs.iter()
+                         .filter_map(|&id| texture_maps.get(id.wrapping_sub(1)).copied()) // Texture map ids start from 1
+                         .filter_map(|texmap| texmap.displacement_edge_length().map(|len| (texmap, len)))
+                         .collect()
+    }
+
+    pub fn has_displacement(&self, textures: Option<&Textures>) -> bool {
+        !self.displacement_maps(textures).is_empty()
+    }
+
+    /// Refine faces with _subdivision_level levels of Loop subdivision (see geometry::loop_subdivide( )), then if the
+    /// mesh has displacement maps, split them until no edge is longer than the smallest MaxEdgeLength and move
+    /// vertices along their normals by the sum of map heights. Unlike bump mapping this changes silhouettes too.
+    /// NOTE: vertices duplicated along UV seams move together for Perlin maps but image heights may open cracks there.
+    /// Vertices of the refined mesh and their uv coordinates are appended to the scene's and faces refer them
+    /// afterwards, original vertices are left as they are since other objects may use them.
+    fn refine(&mut self, verts: &mut VertexData, uv_coords: &mut Vec<Option<[Float; 2]>>, textures: Option<&Textures>) {
+        let displacement_maps = self.displacement_maps(textures);
+        if self._subdivision_level == 0 && displacement_maps.is_empty() {
+            return;
+        }
+
+        // Vertex offset is already baked into faces, texture offset is applied as in to_triangles( )
+        let vertex_offset = self.faces._vertex_offset.unwrap_or(0);
+        let tex_offset = self.faces._texture_offset.unwrap_or(0);
+
+        let mut cage = TriMesh::default();
+        let mut local_ids: HashMap<usize, usize> = HashMap::new();
+        for i in 0..self.faces.len_tris() {
+            let face = self.faces.get_tri_indices(i).map(|idx| *local_ids.entry(idx).or_insert_with(|| {
+                let tex_idx = (idx as isize - vertex_offset + tex_offset) as usize;
+                cage.positions.push(verts[idx]);
+                cage.uvs.push(uv_coords.get(tex_idx).copied().flatten());
+                cage.positions.len() - 1
+            }));
+            cage.faces.push(face);
+        }
+
+        let n_cage_faces = cage.faces.len();
+        for _ in 0..self._subdivision_level {
+            cage = loop_subdivide(&cage);
+        }
+        if self._subdivision_level > 0 {
+            info!(">> Mesh {} is subdivided {} times, from {} to {} faces", self._id, self._subdivision_level, n_cage_faces, cage.faces.len());
+        }
+
+        if let Some(textures) = textures && !displacement_maps.is_empty() {
+            let max_edge_length = displacement_maps.iter()
+                                                   .map(|&(_, len)| len)
+                                                   .filter(|&len| len > 0.)
+                                                   .fold(Float::INFINITY, Float::min);
+            let mut n_splits = 0;
+            while n_splits < MAX_DISPLACEMENT_SPLITS && cage.longest_edge() > max_edge_length {
+                cage = midpoint_subdivide(&cage);
+                n_splits += 1;
+            }
+            if cage.longest_edge() > max_edge_length {
+                warn!("Mesh {} still has edges longer than MaxEdgeLength {} after splitting its faces {} times.", self._id, max_edge_length, n_splits);
+            }
+
+            let normals = cage.vertex_normals();
+            for ((p, uv), n) in cage.positions.iter_mut().zip(&cage.uvs).zip(normals) {
+                let height: Float = displacement_maps.iter().map(|&(texmap, _)| textures.displacement_height(texmap, *uv, *p)).sum();
+                *p += n * height;
+            }
+            info!(">> Mesh {} is displaced with {} faces ({} splits)", self._id, cage.faces.len(), n_splits);
+        }
+
+        // Every face is split into four consecutive faces, so face groups keep their faces
+        let split_factor = cage.faces.len() / n_cage_faces.max(1);
+        for group in self.face_groups.iter_mut() {
+            group.first_face *= split_factor;
+        }
+
+        // Texture offset makes uv index of the appended vertices point at the appended uvs, in case
+        // uv_coords and vertex data are not of the same length
+        let first_vertex = verts._data.len();
+        let first_uv = uv_coords.len();
+        verts._data.extend(cage.positions);
+        uv_coords.extend(cage.uvs);
+        self.faces._data = cage.faces.iter().flatten().map(|&i| first_vertex + i).collect();
+        self.faces._vertex_offset = None;
+        self.faces._texture_offset = Some(first_uv as isize - first_vertex as isize);
+    }
+
     /// Helper function to convert a Mesh into individual Triangles
     fn to_triangles(&self, verts: &VertexData, id_offset: usize) -> Vec<Triangle> {
         
@@ -264,11 +491,19 @@ impl Mesh {
             
             let [v1, v2, v3] = vert_offseted_face_indices.map(|i| verts[i]);
 
+            let (material_idx, texture_idxs) = match self.face_groups.partition_point(|g| g.first_face <= i) {
+                0 => (self.material_idx, &self.texture_idxs),
+                k => (self.face_groups[k - 1].material_idx, &self.face_groups[k - 1].texture_idxs),
+            };
             let cpd = CommonPrimitiveData{
                 _id: id_offset + i, 
-                material_idx: self.material_idx,
+                material_idx,
                 transformation_names: None, 
-                texture_idxs: self.texture_idxs.clone(),
+                texture_idxs: texture_idxs.clone(),
+                uv_projection: self.uv_projection.clone(),
+                alpha_mask: self.alpha_mask,
+                end_transformation_names: None,
+                motion: None,
             };
 
 
@@ -294,7 +529,7 @@ impl Mesh {
                 vert_indices: vert_offseted_face_indices,
                 is_smooth: self._shading_mode.eq_ignore_ascii_case("smooth"),
                 normal: get_tri_normal(&v1, &v2, &v3),
-                matrix: None, //Some(Arc::new(self.matrix)), // NOTE: here it is ok to .clone( ) because it just increases Arc's counter, not cloning the whole data
+                transform: None, //Some(Arc::new(self.transform)), // NOTE: here it is ok to .clone( ) because it just increases Arc's counter, not cloning the whole data
                 texture_indices, // WARNING: DO NOT CONFUSE IT WITH TEXTUREMAP IDS which is also named texture_idxs in CommonPrimitiveData
                 //_texture_offset: tex_offset,
                 //_vertex_offset: vert_offset,
@@ -322,7 +557,16 @@ impl Mesh {
     }
 
     fn _intersect_bvh(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> Option<HitRecord> {
-         if let Some(bvh) = &self.bvh {
+         if let Some(accelerator) = &self.accelerator {
+                let mut closest = HitRecord::default();
+                if accelerator.intersect(ray, t_interval, vertex_cache, &mut closest, false) {
+                    Some(closest)
+                }
+                else {
+                    None
+                }
+            }
+            else if let Some(bvh) = &self.bvh {
                 let mut closest = HitRecord::default();    
                 if bvh.intersect(ray, t_interval, vertex_cache, &mut closest, false) { // Early break: false for BLAS (adding it to BLAS didn't improve results, only cluttered my intersect( ) functions in impl Shape trait)
                     Some(closest)
@@ -338,6 +582,19 @@ impl Mesh {
     }
 
 
+    fn _occluded_bvh(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        if let Some(accelerator) = &self.accelerator {
+            accelerator.occluded(ray, t_interval, vertex_cache)
+        }
+        else if let Some(bvh) = &self.bvh {
+            bvh.occluded(ray, t_interval, vertex_cache)
+        }
+        else {
+            warn!("Intersecting naively.... this shouldn't happen.");
+            self.triangles.iter().any(|tri| tri.occluded(ray, t_interval, vertex_cache))
+        }
+    }
+
     fn intersect(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> Option<HitRecord> {
         
         // Motion blur (Note: normally we inverse transform the ray along translation but here I add it first, it is transformed to inverse in the next step tgogether with object transformation since they have the same logic)
@@ -345,14 +602,14 @@ impl Mesh {
         ray.origin += self.motionblur * ray.time;
 
         // Transform ray to local space
-        let inv_matrix = self.matrix.inverse();
-        let local_ray = ray.inverse_transform(&inv_matrix);
+        let transform = self.transform_at(ray.time);
+        let local_ray = ray.to_local(&transform);
 
         // Intersect in local space
         let rec = self._intersect_bvh(&local_ray, t_interval, vertex_cache);
 
         rec.map(|mut r| {
-            r.to_world(&self.matrix);
+            r.to_world(&transform);
             r.ray_t = (r.hit_point - ray.origin).length(); //TODO: it's so easy to forget it, how to refactor?
             r
         }) // Added to reduce if let verbosity but it didn't reduce nesting above...
@@ -385,12 +642,24 @@ impl Mesh {
         let mut local_box = BBox::new_from(&xint, &yint, &zint);
         local_box = local_box.expand_by_motion(self.motionblur);
         if apply_t {
-            debug!("Applying transform for TLAS {}", self.matrix);
-           local_box.transform(&self.matrix)
+            debug!("Applying transform for TLAS {}", self.transform.matrix);
+            match &self.motion {
+                Some(motion) => local_box.transform_animated(motion),
+                None => local_box.transform(&self.transform.matrix),
+            }
         } else {
             local_box
         }
     }
+
+    /// Transform at ray time if the mesh has EndTransformations, otherwise its transform.
+    /// BVH of the mesh is in local space, so only the ray is moved.
+    fn transform_at(&self, time: Float) -> Cow<'_, Transform> {
+        match &self.motion {
+            Some(motion) => Cow::Owned(motion.at(time)),
+            None => Cow::Borrowed(&self.transform),
+        }
+    }
 }
 
 
@@ -400,6 +669,15 @@ impl Shape for Mesh {
     
         self.intersect(ray, t_interval, vertex_cache)
     }
+
+    /// Shadow ray test in local space, see intersect( )
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        let mut ray = ray.clone();
+        ray.origin += self.motionblur * ray.time;
+
+        let local_ray = ray.to_local(&self.transform_at(ray.time));
+        self._occluded_bvh(&local_ray, t_interval, vertex_cache)
+    }
 }
 
 impl BBoxable for Mesh {
@@ -422,42 +700,33 @@ impl Shape for MeshInstanceField {
         ray.origin += self.motionblur * ray.time;
 
         let base_mesh = self.base_mesh.as_deref().unwrap();
-        
-        if self.reset_transform {
-            let inv_instance = self.matrix.inverse();
-            let local_ray = ray.inverse_transform(&inv_instance);
-            
-            // Intersect without applying base mesh's transform
-            if let Some(mut hit) = base_mesh._intersect_bvh(&local_ray, t_interval, vertex_cache) {
-                hit.material = self.material_id.unwrap_or(self.base_mesh.clone().unwrap().material_idx);
-                hit.textures = self.texture_idxs.clone();
-                hit.to_world(&self.matrix);  // this transforms normals and hitpoints p.53
-                hit.ray_t = (hit.hit_point - ray.origin).length(); //TODO: it's so easy to forget it, how to refactor?
-
-                Some(hit)
-            } else {
-                None
-            }
-        } 
-        else {
-            // Compose transforms: inv(M_instance * M_base)
-            let composite_matrix = self.matrix * base_mesh.matrix;
-            let inv_composite = composite_matrix.inverse();
-            let local_ray = ray.inverse_transform(&inv_composite);
-            
-            // Intersect with BVH 
-            if let Some(mut hit) = base_mesh._intersect_bvh(&local_ray, t_interval, vertex_cache) {
-                hit.material = self.material_id.unwrap_or(self.base_mesh.clone().unwrap().material_idx);
-                hit.textures = self.texture_idxs.clone();
-                hit.to_world(&composite_matrix);  // this transforms normals and hitpoints p.53
-                hit.ray_t = (hit.hit_point - ray.origin).length(); //TODO: it's so easy to forget it, how to refactor?
-
-                Some(hit)
-            } else {
-                None
-            }
+
+        // Composite is inv(M_instance * M_base), or inv(M_instance) to intersect without applying base mesh's transform
+        let composite = self.composite_at(ray.time);
+        let local_ray = ray.to_local(&composite);
+
+        // Intersect with BVH 
+        if let Some(mut hit) = base_mesh._intersect_bvh(&local_ray, t_interval, vertex_cache) {
+            hit.material = self.material_id.unwrap_or(hit.material); // Triangles have the material of the base mesh or its face group
+            hit.textures = self.texture_idxs.clone();
+            hit.instance_variation = self.variation;
+            hit.to_world(&composite);  // this transforms normals and hitpoints p.53
+            hit.ray_t = (hit.hit_point - ray.origin).length(); //TODO: it's so easy to forget it, how to refactor?
+
+            Some(hit)
+        } else {
+            None
         }
     }
+
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        let mut ray = ray.clone();
+        ray.origin += self.motionblur * ray.time;
+
+        let base_mesh = self.base_mesh.as_deref().unwrap();
+        let local_ray = ray.to_local(&self.composite_at(ray.time));
+        base_mesh._occluded_bvh(&local_ray, t_interval, vertex_cache)
+    }
 }
 
 
@@ -469,14 +738,11 @@ impl BBoxable for MeshInstanceField {
             
             let mut local_box = base_mesh.get_bbox(verts, false);
             local_box = local_box.expand_by_motion(self.motionblur);
-            let mut composite = self.matrix;
-
-            if !self.reset_transform{
-                composite *= base_mesh.matrix;
-            }
-            
             if apply_t {
-                    local_box.transform(&composite)
+                match &self.motion {
+                    Some(motion) => local_box.transform_animated(motion),
+                    None => local_box.transform(&self.composite.matrix),
+                }
             } 
             else {
                     local_box
@@ -0,0 +1,172 @@
+/*
+
+    On-disk cache of meshes loaded from PLY files.
+
+    Parsing a PLY file and building its BVH is repeated at every run, which
+    is slow for large meshes when only camera or material settings change.
+    If MESH_CACHE_DIR environment variable is set, parsed vertices, UVs, faces,
+    vertex normals and the built BVH of every PLY mesh are stored in that
+    directory, keyed by a hash of the PLY file contents and the BVH settings.
+    Any change in the PLY file or settings gives a different key, so stale
+    entries are never read (they are not deleted either, clear the directory
+    to reclaim the space).
+
+    e.g. $ MESH_CACHE_DIR=./cache cargo run --release ./path/to/your.json
+
+    @date: Oct, 2026
+    @author: Bartu
+*/
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use serde::Serialize;
+
+use crate::prelude::*;
+use crate::json_structs::FaceType;
+use crate::acceleration::{BVHSettings, CachedBVH};
+
+// Bump whenever CachedMesh or BVH node layout changes, so that old entries are not read
+const CACHE_VERSION: u32 = 2;
+
+/// Everything that is read from a PLY file and computed from it during mesh setup
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedMesh {
+    pub vertices: Vec<Vector3>,
+    pub uv_coords: Vec<Option<[Float; 2]>>,
+    pub faces: Option<Vec<usize>>, // Indices into vertices above (not shifted by the scene's vertex count)
+    pub vertex_normals: Vec<Vector3>, // Aligned with vertices
+    pub bvh: CachedBVH,
+}
+
+#[derive(Debug, Clone)]
+pub struct MeshCache {
+    dir: PathBuf,
+}
+
+impl MeshCache {
+    /// Cache is enabled only if MESH_CACHE_DIR is set, directory is created if it doesn't exist
+    pub fn from_env() -> Option<Self> {
+        let dir = PathBuf::from(std::env::var_os("MESH_CACHE_DIR")?);
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            warn!("Could not create mesh cache directory {:?} ({}), mesh cache is disabled.", dir, e);
+            return None;
+        }
+        info!("Using mesh cache directory {:?}", dir);
+        Some(Self { dir })
+    }
+
+    /// Key of a mesh given the contents of its PLY file. Face offsets and subdivision level are part
+    /// of the key since they are baked into the triangles the BVH is built over.
+    pub fn key(ply_bytes: &[u8], faces: &FaceType, subdivision_level: usize, settings: &BVHSettings) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&CACHE_VERSION.to_le_bytes());
+        hasher.update(ply_bytes);
+        // Debug output is enough to tell the settings apart, it is only hashed
+        let params = format!("{:?}|{}|{}|{}|{:?}|{:?}|{}", settings.builder, settings.leaf_size, settings.bin_count, settings.width,
+                                                     faces._vertex_offset, faces._texture_offset, subdivision_level);
+        hasher.update(params.as_bytes());
+        hasher.finalize().to_hex().to_string()
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.bin", key))
+    }
+
+    /// Returns None on a cache miss or if the entry cannot be read
+    pub fn load(&self, key: &str) -> Option<CachedMesh> {
+        let path = self.path(key);
+        let file = File::open(&path).ok()?;
+        match bincode::deserialize_from(BufReader::new(file)) {
+            Ok(mesh) => {
+                debug!("Mesh cache hit: {:?}", path);
+                Some(mesh)
+            }
+            Err(e) => {
+                warn!("Could not read mesh cache entry {:?} ({}), rebuilding it.", path, e);
+                None
+            }
+        }
+    }
+
+    /// Failing to store is not an error, the mesh is just rebuilt at the next run
+    pub fn store(&self, key: &str, mesh: &CachedMesh) {
+        let path = self.path(key);
+        // Write to a temporary file first so that concurrent runs never read a partially written entry
+        let tmp_path = self.dir.join(format!("{}.{}.tmp", key, std::process::id()));
+        let result = File::create(&tmp_path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                bincode::serialize_into(&mut writer, mesh).map_err(|e| e.to_string())?;
+                writer.flush().map_err(|e| e.to_string())
+            })
+            .and_then(|_| std::fs::rename(&tmp_path, &path).map_err(|e| e.to_string()));
+
+        match result {
+            Ok(_) => debug!("Stored mesh cache entry {:?}", path),
+            Err(e) => {
+                warn!("Could not store mesh cache entry {:?} ({})", path, e);
+                let _ = std::fs::remove_file(&tmp_path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acceleration::{BVHBuilder, BVHSubtree};
+    use crate::acceleration::tests::{nearest_hits, random_rays, triangle_soup};
+
+    #[test]
+    fn store_and_load_round_trip() {
+        let dir = std::env::temp_dir().join(format!("mesh_cache_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = MeshCache { dir: dir.clone() };
+
+        let (shapes, verts) = triangle_soup(200, 11);
+        let bvh = BVHSubtree::build(&shapes, &verts.vertex_data, true, &BVHSettings::default());
+        let mesh = CachedMesh {
+            vertices: verts.vertex_data._data.clone(),
+            uv_coords: vec![Some([0.25, 0.5]), None],
+            faces: Some((1..verts.vertex_data._data.len()).collect()),
+            vertex_normals: vec![Vector3::Y; verts.vertex_data._data.len()],
+            bvh: bvh.to_cached(),
+        };
+        let key = MeshCache::key(b"ply", &FaceType::default(), 0, &BVHSettings::default());
+        assert!(cache.load(&key).is_none());
+        cache.store(&key, &mesh);
+        let loaded = cache.load(&key).expect("Stored entry should be read back");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.vertices, mesh.vertices);
+        assert_eq!(loaded.uv_coords, mesh.uv_coords);
+        assert_eq!(loaded.faces, mesh.faces);
+        assert_eq!(loaded.vertex_normals, mesh.vertex_normals);
+
+        // Restored BVH finds the same hits as the one it was stored from
+        let restored = BVHSubtree::from_cached(loaded.bvh, &shapes).expect("Cached BVH fits its shapes");
+        let rays = random_rays(200, 12);
+        assert_eq!(nearest_hits(&restored, &rays, &verts), nearest_hits(&bvh, &rays, &verts));
+        assert!(BVHSubtree::from_cached(bvh.to_cached(), &shapes[1..]).is_none());
+    }
+
+    #[test]
+    fn key_changes_with_settings() {
+        let faces = FaceType::default();
+        let settings = BVHSettings::default();
+        let key = MeshCache::key(b"ply", &faces, 0, &settings);
+        assert_eq!(key, MeshCache::key(b"ply", &faces, 0, &settings));
+
+        assert_ne!(key, MeshCache::key(b"other ply", &faces, 0, &settings));
+        assert_ne!(key, MeshCache::key(b"ply", &faces, 1, &settings)); // Subdivision level
+        let offset_faces = FaceType { _vertex_offset: Some(3), ..Default::default() };
+        assert_ne!(key, MeshCache::key(b"ply", &offset_faces, 0, &settings));
+        for changed in [BVHSettings { builder: BVHBuilder::Median, ..Default::default() },
+                        BVHSettings { leaf_size: 1, ..Default::default() },
+                        BVHSettings { bin_count: 16, ..Default::default() },
+                        BVHSettings { width: 4, ..Default::default() }] {
+            assert_ne!(key, MeshCache::key(b"ply", &faces, 0, &changed), "Key ignores {:?}", changed);
+        }
+    }
+}
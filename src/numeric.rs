@@ -14,7 +14,7 @@
     @author: Bartu
 */
 
-use bevy_math::{DMat2, DMat3, DMat4, DVec2, DVec3, DVec4};
+use bevy_math::{DMat2, DMat3, DMat4, DQuat, DVec2, DVec3, DVec4};
 use crate::prelude::*;
 use std::sync::atomic;
 
@@ -26,6 +26,7 @@ pub type Matrix4 = DMat4;
 pub type Vector4 = DVec4;
 pub type Matrix2 = DMat2;
 pub type Vector2 = DVec2;
+pub type Quaternion = DQuat;
 
 //#[derive(Clone, Copy, Debug, Default)]
 //pub struct Vector3(pub DVec3); // To declare a type and use impl traits on this type
@@ -93,6 +94,130 @@ pub fn transform_normal(mat: &Matrix4, n: &Vector3) -> Vector3 {
     Vector3::new(r.x, r.y, r.z).normalize()
 }
 
+/// Transformation matrix together with its inverse and normal matrix,
+/// computed once at scene setup so that intersection routines do not call .inverse( ) per ray.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub matrix: Matrix4,
+    pub inverse: Matrix4,
+    pub normal_matrix: Matrix3, // Inverse transpose of the upper 3x3 (see slides 04, p.53)
+}
+
+impl Transform {
+    pub const IDENTITY: Self = Self {
+        matrix: Matrix4::IDENTITY,
+        inverse: Matrix4::IDENTITY,
+        normal_matrix: Matrix3::IDENTITY,
+    };
+
+    pub fn new(matrix: Matrix4) -> Self {
+        Self {
+            matrix,
+            inverse: matrix.inverse(),
+            normal_matrix: Matrix3::from_mat4(matrix).inverse().transpose(),
+        }
+    }
+
+    /// Returns self * other, i.e. other is applied first
+    pub fn compose(&self, other: &Self) -> Self {
+        Self::new(self.matrix * other.matrix)
+    }
+
+    #[inline]
+    pub fn point(&self, v: &Vector3) -> Vector3 {
+        transform_point(&self.matrix, v)
+    }
+
+    #[inline]
+    pub fn dir(&self, v: &Vector3) -> Vector3 {
+        transform_dir(&self.matrix, v)
+    }
+
+    /// Transformed normal, normalized
+    #[inline]
+    pub fn normal(&self, n: &Vector3) -> Vector3 {
+        (self.normal_matrix * *n).normalize()
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl From<Matrix4> for Transform {
+    fn from(matrix: Matrix4) -> Self {
+        Self::new(matrix)
+    }
+}
+
+/// Rigid body motion blur: an object (or camera) moves from its start transform at ray time 0 to
+/// its end transform at ray time 1. Both are decomposed into translation, rotation and scale so that
+/// rotations are interpolated along the arc (slerp) instead of shearing the matrix. Shear is not kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnimatedTransform {
+    pub start: Transform,
+    pub end: Transform,
+    translations: [Vector3; 2],
+    rotations: [Quaternion; 2],
+    scales: [Vector3; 2],
+    has_shear: bool, // Either end, see at( )
+}
+
+impl AnimatedTransform {
+    pub fn new(start: Transform, end: Transform) -> Self {
+        let (s0, r0, t0) = start.matrix.to_scale_rotation_translation();
+        let (s1, r1, t1) = end.matrix.to_scale_rotation_translation();
+        let mut has_shear = false;
+        for (matrix, (s, r, t)) in [(start.matrix, (s0, r0, t0)), (end.matrix, (s1, r1, t1))] {
+            if !Matrix4::from_scale_rotation_translation(s, r, t).abs_diff_eq(matrix, 1e-6) {
+                warn!("Motion blur transform {} has shear, it is interpolated without it.", matrix);
+                has_shear = true;
+            }
+        }
+        Self {
+            start,
+            end,
+            translations: [t0, t1],
+            rotations: [r0, r1],
+            scales: [s0, s1],
+            has_shear,
+        }
+    }
+
+    /// Transform at ray time in [0, 1]. It is called per ray, so the inverse and normal matrix are
+    /// built from the interpolated parts instead of inverting the matrix as Transform::new( ) does.
+    pub fn at(&self, time: Float) -> Transform {
+        let translation = self.translations[0].lerp(self.translations[1], time);
+        let rotation = self.rotations[0].slerp(self.rotations[1], time);
+        let scale = self.scales[0].lerp(self.scales[1], time);
+        let matrix = Matrix4::from_scale_rotation_translation(scale, rotation, translation);
+        if self.has_shear {
+            return Transform::new(matrix);
+        }
+
+        // M = T R S, so M^-1 = S^-1 R^-1 T^-1 and the normal matrix (R S)^-T = R S^-1
+        let inv_scale = Matrix3::from_diagonal(scale.recip());
+        let inv_linear = inv_scale * Matrix3::from_quat(rotation.inverse());
+        Transform {
+            matrix,
+            inverse: Matrix4::from_mat3_translation(inv_linear, -(inv_linear * translation)),
+            normal_matrix: Matrix3::from_quat(rotation) * inv_scale,
+        }
+    }
+
+    /// Upper bound of the speed of local point p, i.e. |d/dt at(t).point(p)| for every t in [0, 1]
+    /// Rotation moves p by its scaled distance to the origin times the angle, which is at most the total angle per unit time.
+    pub fn max_speed(&self, p: Vector3) -> Float {
+        let angle = self.rotations[0].angle_between(self.rotations[1]);
+        let scaled_length = (self.scales[0] * p).length().max((self.scales[1] * p).length()); // Largest at either end since scale is lerped
+        (self.translations[1] - self.translations[0]).length()
+            + angle * scaled_length
+            + ((self.scales[1] - self.scales[0]) * p).length()
+    }
+}
+
 pub fn get_onb(normal: &Vector3) -> (Vector3, Vector3) {
     // See slides 05, p.96
     debug_assert!(normal.is_normalized(), "normal is not normalized: normal = {}", normal); 
@@ -117,6 +242,98 @@ pub fn get_onb(normal: &Vector3) -> (Vector3, Vector3) {
     (u, v)
 }
 
+//////////////////////////////////////////////////////////////////////////
+/// Polynomial roots
+//////////////////////////////////////////////////////////////////////////
+pub const MAX_POLYNOMIAL_DEGREE: usize = 4;
+const ROOT_MAX_ITERATIONS: usize = 64;
+
+/// Evaluate c[0] + c[1] t + ... + c[n] t^n (Horner's method)
+#[inline]
+pub fn eval_polynomial(coeffs: &[Float], t: Float) -> Float {
+    coeffs.iter().rev().fold(0.0, |acc, &c| acc * t + c)
+}
+
+/// Real roots of c[0] + c[1] t + ... + c[n] t^n within [lo, hi], written to roots in increasing order,
+/// returns how many are found. Roots of the derivative split the range into monotonic pieces, each
+/// piece has at most one root which is found by Newton iterations safeguarded with bisection.
+/// Unlike the closed form (Ferrari's) solution of quartics, it does not lose precision when roots
+/// are close to each other, e.g. at the silhouette of a torus.
+/// NOTE: Roots where the polynomial touches zero without changing its sign are found only if exact.
+pub fn polynomial_roots(coeffs: &[Float], lo: Float, hi: Float, roots: &mut [Float; MAX_POLYNOMIAL_DEGREE]) -> usize {
+    // Vanishing leading coefficients reduce the degree
+    let n = coeffs.iter().rposition(|&c| c != 0.0).map_or(0, |i| i + 1);
+    let coeffs = &coeffs[..n];
+    assert!(n <= MAX_POLYNOMIAL_DEGREE + 1, "Polynomial degree {} is not supported", n - 1);
+
+    match n {
+        0 | 1 => 0, // Constant
+        2 => {
+            let t = -coeffs[0] / coeffs[1];
+            if lo <= t && t <= hi {
+                roots[0] = t;
+                1
+            } else {
+                0
+            }
+        }
+        _ => {
+            let mut deriv = [0.0; MAX_POLYNOMIAL_DEGREE];
+            for i in 1..n {
+                deriv[i - 1] = i as Float * coeffs[i];
+            }
+            let deriv = &deriv[..n - 1];
+            let mut critical = [0.0; MAX_POLYNOMIAL_DEGREE];
+            let n_critical = polynomial_roots(deriv, lo, hi, &mut critical);
+
+            let mut count = 0;
+            let mut push = |t: Float, roots: &mut [Float; MAX_POLYNOMIAL_DEGREE]| {
+                if count == 0 || roots[count - 1] != t {
+                    roots[count] = t;
+                    count += 1;
+                }
+            };
+
+            let (mut x0, mut f0) = (lo, eval_polynomial(coeffs, lo));
+            for x1 in critical[..n_critical].iter().copied().chain(std::iter::once(hi)) {
+                let f1 = eval_polynomial(coeffs, x1);
+                if f0 == 0.0 {
+                    push(x0, roots);
+                } else if f1 != 0.0 && (f0 < 0.0) != (f1 < 0.0) {
+                    push(refine_root(coeffs, deriv, x0, x1, f0), roots);
+                }
+                (x0, f0) = (x1, f1);
+            }
+            if f0 == 0.0 {
+                push(hi, roots);
+            }
+            count
+        }
+    }
+}
+
+/// Root of a monotonic polynomial in [lo, hi] given that it changes sign there, f_lo is its value at lo
+fn refine_root(coeffs: &[Float], deriv: &[Float], mut lo: Float, mut hi: Float, f_lo: Float) -> Float {
+    let mut x = 0.5 * (lo + hi);
+    for _ in 0..ROOT_MAX_ITERATIONS {
+        let fx = eval_polynomial(coeffs, x);
+        if fx == 0.0 {
+            return x;
+        }
+        // Shrink the bracket, then take the Newton step if it stays inside (NaN does not)
+        if (fx < 0.0) == (f_lo < 0.0) { lo = x; } else { hi = x; }
+        let mut next = x - fx / eval_polynomial(deriv, x);
+        if !(next > lo && next < hi) {
+            next = 0.5 * (lo + hi);
+        }
+        if (next - x).abs() <= Float::EPSILON * x.abs().max(1.0) {
+            return next;
+        }
+        x = next;
+    }
+    x
+}
+
 //////////////////////////////////////////////////////////////////////////
 /// Assert utils
 //////////////////////////////////////////////////////////////////////////
@@ -128,3 +345,105 @@ pub fn debug_assert_orthonormality(u: &Vector3, v: &Vector3, n: &Vector3) {
     debug_assert!(v.is_normalized());
     debug_assert!(n.is_normalized());
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interval::FloatConst;
+
+    fn roots_of(coeffs: &[Float], lo: Float, hi: Float) -> Vec<Float> {
+        let mut roots = [0.0; MAX_POLYNOMIAL_DEGREE];
+        let n = polynomial_roots(coeffs, lo, hi, &mut roots);
+        roots[..n].to_vec()
+    }
+
+    #[test]
+    fn quartic_roots_in_order() {
+        // (t - 1)(t - 2)(t - 3)(t - 4) = t^4 - 10t^3 + 35t^2 - 50t + 24
+        let roots = roots_of(&[24.0, -50.0, 35.0, -10.0, 1.0], -10.0, 10.0);
+        assert_eq!(roots.len(), 4);
+        for (root, expected) in roots.iter().zip([1.0, 2.0, 3.0, 4.0]) {
+            assert!((root - expected).abs() < 1e-12, "Found root {}, expected {}", root, expected);
+        }
+
+        // Range excludes some of them
+        assert_eq!(roots_of(&[24.0, -50.0, 35.0, -10.0, 1.0], 1.5, 3.5).len(), 2);
+    }
+
+    #[test]
+    fn close_quartic_roots() {
+        // (t - 1)(t - 1 - e)(t^2 + 1) has two close real roots where the closed form solution struggles
+        let e = 1e-6;
+        let (a, b) = (1.0, 1.0 + e);
+        let coeffs = [a * b, -(a + b), a * b + 1.0, -(a + b), 1.0];
+        let roots = roots_of(&coeffs, 0.0, 2.0);
+        assert_eq!(roots.len(), 2);
+        // Values are flat around them, so accuracy is limited by rounding of the coefficients
+        assert!((roots[0] - a).abs() < 1e-8 && (roots[1] - b).abs() < 1e-8, "Found roots {:?}", roots);
+    }
+
+    #[test]
+    fn animated_transform_rotates_along_arc() {
+        let start = Transform::new(Matrix4::from_translation(Vector3::new(1., 0., 0.)));
+        let end = Transform::new(Matrix4::from_rotation_translation(Quaternion::from_rotation_y(Float::PI), Vector3::new(3., 0., 0.)));
+        let motion = AnimatedTransform::new(start, end);
+        assert!(motion.at(0.).matrix.abs_diff_eq(start.matrix, 1e-12));
+        assert!(motion.at(1.).matrix.abs_diff_eq(end.matrix, 1e-12));
+
+        // Halfway rotated by 90 degrees rather than collapsing to a lerped matrix
+        let p = Vector3::X;
+        assert!(motion.at(0.5).point(&p).abs_diff_eq(Vector3::new(2., 0., -1.), 1e-12));
+
+        // Cached matrices are the same as inverting the interpolated matrix, with non-uniform scale too
+        let end = Transform::new(Matrix4::from_scale_rotation_translation(Vector3::new(2., 0.5, 3.), Quaternion::from_rotation_x(1.), Vector3::Y));
+        let scaling = AnimatedTransform::new(start, end);
+        for time in [0., 0.3, 1.] {
+            let transform = scaling.at(time);
+            let expected = Transform::new(transform.matrix);
+            assert!(transform.inverse.abs_diff_eq(expected.inverse, 1e-12));
+            assert!(transform.normal_matrix.abs_diff_eq(expected.normal_matrix, 1e-12));
+        }
+
+        // Distance travelled between close times is bounded by max_speed( )
+        let dt = 1e-3;
+        for i in 0..1000 {
+            let t = i as Float * dt;
+            let step = (motion.at(t + dt).point(&p) - motion.at(t).point(&p)).length();
+            assert!(step <= motion.max_speed(p) * dt + 1e-12);
+        }
+    }
+
+    #[test]
+    fn cached_inverse_and_normal_matrix() {
+        // Non-uniform scale, so normals are not transformed like directions
+        let matrix = Matrix4::from_scale_rotation_translation(Vector3::new(2., 0.5, 1.),
+                                                               Quaternion::from_rotation_z(0.3),
+                                                               Vector3::new(1., -2., 3.));
+        let transform = Transform::new(matrix);
+        assert!((transform.matrix * transform.inverse).abs_diff_eq(Matrix4::IDENTITY, 1e-12));
+        let p = Vector3::new(0.3, -1.2, 4.);
+        assert!(transform_point(&transform.inverse, &transform.point(&p)).abs_diff_eq(p, 1e-12));
+
+        // Transformed normal stays perpendicular to transformed tangents of the surface
+        let (n, tangent) = (Vector3::new(1., 1., 0.).normalize(), Vector3::new(1., -1., 0.));
+        let n_world = transform.normal(&n);
+        assert!((n_world.length() - 1.).abs() < 1e-12);
+        assert!(n_world.dot(transform.dir(&tangent)).abs() < 1e-12);
+        assert!(n_world.dot(transform.dir(&Vector3::Z)).abs() < 1e-12);
+
+        // Composition applies the right hand side first and keeps the cached matrices consistent
+        let shift = Transform::new(Matrix4::from_translation(Vector3::X));
+        let composed = shift.compose(&transform);
+        assert!(composed.point(&p).abs_diff_eq(shift.point(&transform.point(&p)), 1e-12));
+        assert!((composed.matrix * composed.inverse).abs_diff_eq(Matrix4::IDENTITY, 1e-12));
+        assert!(composed.normal_matrix.abs_diff_eq(transform.normal_matrix, 1e-12)); // Translation does not change normals
+    }
+
+    #[test]
+    fn lower_degree_and_no_roots() {
+        assert_eq!(roots_of(&[-4.0, 0.0, 1.0, 0.0, 0.0], -5.0, 5.0), vec![-2.0, 2.0]); // Leading zeros are dropped
+        assert!(roots_of(&[1.0, 0.0, 1.0], -5.0, 5.0).is_empty());
+        assert!(roots_of(&[3.0], -5.0, 5.0).is_empty());
+    }
+}
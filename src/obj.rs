@@ -0,0 +1,334 @@
+/*
+
+    Wavefront OBJ meshes, given to a Mesh with _objFile instead of Faces, e.g.
+
+        "Mesh": {
+            "_id": "1", "Material": "1", "_shadingMode": "smooth",
+            "_objFile": "models/chair.obj",
+            "_objMaterials": "Wood:3 Metal:4",
+            "_useMtl": "true"
+        }
+
+    Positions, texture coordinates and normals of the file are read, polygons
+    are triangulated as fans. Since OBJ faces index them separately, a vertex
+    is created for every distinct v/vt/vn triplet so that they can be appended
+    to the scene's vertex data like PLY vertices. Normals of the file replace
+    the computed vertex normals (used by smooth shading) if every vertex has one.
+
+    Faces after "usemtl <name>" use the scene material that _objMaterials maps
+    the name to. Other names get a material generated from the .mtl files of
+    "mtllib" if _useMtl is set: Ka, Kd, Ks and Ns give a diffuse material, or
+    a conductor mirroring Ks for illum 3 and 5 (Kd if Pm is at least 0.5, with
+    roughness Pr). map_Kd is added as an image texture map replacing kd.
+    Remaining faces use Material and Textures of the mesh.
+
+    Generated materials and texture maps are appended after the ones in the
+    JSON, so they are set up before the scene objects (see Scene3DJSON).
+
+    @date: Oct, 2026
+    @author: Bartu
+*/
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use serde::de::{self, Deserializer};
+use serde_json::json;
+
+use crate::image::Textures;
+use crate::mesh::{FaceGroup, Mesh};
+use crate::scene::SceneMaterials;
+use crate::prelude::*;
+
+/// "<usemtl name>:<material id>" tokens in "_objMaterials" field of a Mesh, e.g. "Wood:3"
+pub(crate) fn deser_obj_materials<'de, D>(deserializer: D) -> Result<HashMap<String, usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.split_whitespace()
+     .map(|token| {
+         let (name, id) = token.rsplit_once(':').ok_or_else(|| de::Error::custom(format!("Expected <name>:<material id> in _objMaterials, found '{}'", token)))?;
+         let id = id.parse::<usize>().map_err(de::Error::custom)?;
+         Ok((name.to_string(), id))
+     })
+     .collect()
+}
+
+/// Triangulated OBJ file with a vertex per distinct v/vt/vn triplet of its faces
+#[derive(Debug, Default)]
+pub struct ObjMesh {
+    pub positions: Vec<Vector3>,
+    pub uvs: Vec<Option<[Float; 2]>>,
+    pub normals: Option<Vec<Vector3>>, // None unless every vertex has a normal
+    pub faces: Vec<[usize; 3]>,
+    pub groups: Vec<(String, usize)>, // usemtl name and the first face after it
+    pub material_libs: Vec<String>,
+}
+
+/// Index of an OBJ vertex attribute, starting from 1 or negative to count back from the last one
+fn obj_index(token: &str, count: usize, line_idx: usize) -> Result<usize, String> {
+    let idx: isize = token.parse().map_err(|e| format!("Line {} of OBJ file: {}", line_idx + 1, e))?;
+    let resolved = if idx < 0 { count as isize + idx } else { idx - 1 };
+    if resolved < 0 || resolved >= count as isize {
+        return Err(format!("Line {} of OBJ file refers to index {} but there are {}", line_idx + 1, idx, count));
+    }
+    Ok(resolved as usize)
+}
+
+pub fn parse_obj(text: &str) -> Result<ObjMesh, String> {
+    let mut positions: Vec<Vector3> = Vec::new();
+    let mut tex_coords: Vec<[Float; 2]> = Vec::new();
+    let mut normals: Vec<Vector3> = Vec::new();
+
+    let mut obj = ObjMesh::default();
+    let mut vertex_normals: Vec<Option<Vector3>> = Vec::new();
+    let mut vertex_ids: HashMap<(usize, Option<usize>, Option<usize>), usize> = HashMap::new();
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else { continue; };
+        let mut numbers = |n: usize| -> Result<Vec<Float>, String> {
+            let nums: Vec<Float> = tokens.by_ref()
+                                         .take(n)
+                                         .map(|x| x.parse::<Float>().map_err(|e| format!("Line {} of OBJ file: {}", line_idx + 1, e)))
+                                         .collect::<Result<_, _>>()?;
+            Ok(nums)
+        };
+
+        match keyword {
+            "v" => match numbers(3)?[..] {
+                [x, y, z] => positions.push(Vector3::new(x, y, z)),
+                _ => return Err(format!("Line {} of OBJ file has a vertex without xyz", line_idx + 1)),
+            },
+            "vt" => match numbers(2)?[..] {
+                [u, v] => tex_coords.push([u, 1. - v]), // OBJ v goes up from the bottom of images
+                [u] => tex_coords.push([u, 1.]),
+                _ => return Err(format!("Line {} of OBJ file has a texture coordinate without u", line_idx + 1)),
+            },
+            "vn" => match numbers(3)?[..] {
+                [x, y, z] => normals.push(Vector3::new(x, y, z).normalize_or_zero()),
+                _ => return Err(format!("Line {} of OBJ file has a normal without xyz", line_idx + 1)),
+            },
+            "f" => {
+                let mut face = Vec::new();
+                for corner in tokens {
+                    let mut parts = corner.split('/');
+                    let position = obj_index(parts.next().unwrap_or(""), positions.len(), line_idx)?;
+                    let tex_coord = parts.next().filter(|t| !t.is_empty()).map(|t| obj_index(t, tex_coords.len(), line_idx)).transpose()?;
+                    let normal = parts.next().filter(|t| !t.is_empty()).map(|t| obj_index(t, normals.len(), line_idx)).transpose()?;
+                    face.push(*vertex_ids.entry((position, tex_coord, normal)).or_insert_with(|| {
+                        obj.positions.push(positions[position]);
+                        obj.uvs.push(tex_coord.map(|t| tex_coords[t]));
+                        vertex_normals.push(normal.map(|n| normals[n]));
+                        obj.positions.len() - 1
+                    }));
+                }
+                if face.len() < 3 {
+                    warn!("Line {} of OBJ file has a face with {} vertices, skipping it.", line_idx + 1, face.len());
+                    continue;
+                }
+                obj.faces.extend((1..face.len() - 1).map(|k| [face[0], face[k], face[k + 1]]));
+            },
+            "usemtl" => {
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                if obj.groups.last().is_some_and(|(_, first_face)| *first_face == obj.faces.len()) {
+                    obj.groups.pop(); // Has no faces
+                }
+                obj.groups.push((name, obj.faces.len()));
+            },
+            "mtllib" => obj.material_libs.extend(tokens.map(String::from)),
+            _ => {}, // Objects, groups, smoothing groups, lines and comments
+        }
+    }
+
+    if vertex_normals.iter().all(Option::is_some) {
+        obj.normals = Some(vertex_normals.into_iter().flatten().collect());
+    } else if vertex_normals.iter().any(Option::is_some) {
+        warn!("Some faces of OBJ file have no normals, vertex normals are computed instead.");
+    }
+    Ok(obj)
+}
+
+/// A material of an .mtl file, only what the scene materials can represent
+#[derive(Debug, Clone, SmartDefault)]
+pub struct MtlMaterial {
+    pub name: String,
+    pub ambient: Vector3,
+    #[default(Vector3::ONE)]
+    pub diffuse: Vector3,
+    pub specular: Vector3,
+    #[default = 1.]
+    pub shininess: Float,
+    pub illum: usize,
+    pub metallic: Float,
+    pub roughness: Float,
+    pub diffuse_map: Option<String>,
+}
+
+pub fn parse_mtl(text: &str) -> Vec<MtlMaterial> {
+    let mut materials: Vec<MtlMaterial> = Vec::new();
+    for line in text.lines() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let Some((&keyword, args)) = tokens.split_first() else { continue; };
+        if keyword == "newmtl" {
+            materials.push(MtlMaterial { name: args.join(" "), ..Default::default() });
+            continue;
+        }
+        let Some(material) = materials.last_mut() else { continue; };
+
+        let nums: Vec<Float> = args.iter().map_while(|x| x.parse().ok()).collect();
+        let color = || match nums[..] {
+            [r, g, b, ..] => Some(Vector3::new(r, g, b)),
+            [c] => Some(Vector3::splat(c)),
+            _ => None, // i.e. spectral or xyz colors
+        };
+        match keyword {
+            "Ka" => material.ambient = color().unwrap_or(material.ambient),
+            "Kd" => material.diffuse = color().unwrap_or(material.diffuse),
+            "Ks" => material.specular = color().unwrap_or(material.specular),
+            "Ns" => material.shininess = nums.first().copied().unwrap_or(material.shininess),
+            "illum" => material.illum = nums.first().map_or(material.illum, |&i| i as usize),
+            "Pm" => material.metallic = nums.first().copied().unwrap_or(material.metallic),
+            "Pr" => material.roughness = nums.first().copied().unwrap_or(material.roughness),
+            "map_Kd" => material.diffuse_map = args.last().map(|file| file.to_string()), // Options like -s come before the file name
+            _ => {},
+        }
+    }
+    materials
+}
+
+impl MtlMaterial {
+    /// Scene material in the JSON format, see json_parser::parse_material( )
+    pub fn to_json(&self, id: usize) -> serde_json::Value {
+        let rgb = |c: Vector3| json!([c.x, c.y, c.z]);
+        let mut value = json!({
+            "_id": id,
+            "_type": "diffuse",
+            "AmbientReflectance": rgb(self.ambient),
+            "DiffuseReflectance": rgb(self.diffuse),
+            "SpecularReflectance": rgb(self.specular),
+            "PhongExponent": self.shininess.max(1.),
+        });
+        let mirror = if self.metallic >= 0.5 { Some(self.diffuse) } else if matches!(self.illum, 3 | 5) { Some(self.specular) } else { None };
+        if let Some(mirror) = mirror {
+            value["_type"] = json!("conductor");
+            value["MirrorReflectance"] = rgb(mirror);
+            value["Roughness"] = json!(self.roughness);
+        }
+        value
+    }
+}
+
+/// Read _objFile of the mesh and resolve materials of its usemtl groups, appending the generated
+/// materials and texture maps to the scene's. Faces are added to the scene vertex data later
+/// in the scene objects setup, from Mesh::obj.
+pub fn load_obj(mesh: &mut Mesh, json_dir: &Path, materials: &mut SceneMaterials, textures: &mut Option<Textures>) -> Result<(), Box<dyn Error>> {
+    let path = json_dir.join(&mesh._obj_file);
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("Cannot read OBJ file {:?}: {}", path, e))?;
+    let obj = parse_obj(&text)?;
+    info!(">> Mesh {} read {} vertices and {} faces from {:?}", mesh._id, obj.positions.len(), obj.faces.len(), path);
+
+    // Files of mtllib and map_Kd are relative to the OBJ file
+    let obj_dir = path.parent().unwrap_or(json_dir);
+    let mut library: HashMap<String, MtlMaterial> = HashMap::new();
+    if mesh.use_mtl {
+        for lib in obj.material_libs.iter() {
+            let lib_path = obj_dir.join(lib);
+            match std::fs::read_to_string(&lib_path) {
+                Ok(text) => library.extend(parse_mtl(&text).into_iter().map(|m| (m.name.clone(), m))),
+                Err(e) => warn!("Cannot read material library {:?} of mesh {}: {}", lib_path, mesh._id, e),
+            }
+        }
+    }
+
+    let mut resolved: HashMap<&str, FaceGroup> = HashMap::new();
+    mesh.face_groups = Vec::with_capacity(obj.groups.len());
+    for (name, first_face) in obj.groups.iter() {
+        let group = resolved.entry(name).or_insert_with(|| {
+            let mut texture_idxs = mesh.texture_idxs.clone();
+            let material_idx = if let Some(&id) = mesh.obj_materials.get(name) {
+                id
+            } else if let Some(mtl) = library.get(name) {
+                if let Some(map) = &mtl.diffuse_map {
+                    let textures = textures.get_or_insert_with(Textures::default);
+                    texture_idxs.push(textures.push_diffuse_image(&obj_dir.join(map)));
+                }
+                materials.push_value(mtl.to_json(materials.data.len() + 1))
+            } else {
+                if mesh.use_mtl {
+                    warn!("Material '{}' of mesh {} is not found in its material libraries, using Material {}.", name, mesh._id, mesh.material_idx);
+                }
+                mesh.material_idx
+            };
+            FaceGroup { first_face: 0, material_idx, texture_idxs }
+        });
+        mesh.face_groups.push(FaceGroup { first_face: *first_face, ..group.clone() });
+    }
+
+    mesh.obj = Some(Arc::new(obj));
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_obj_polygons_and_triplets() {
+        let text = "
+            mtllib scene.mtl
+            v 0 0 0
+            v 1 0 0
+            v 1 1 0
+            v 0 1 0
+            vt 0 0
+            vt 1 1
+            vn 0 0 1
+            usemtl Red
+            f 1/1/1 2/1/1 3/1/1 4/1/1
+            usemtl Blue
+            f -4/2/1 -2/2/1 -1/2/1
+        ";
+        let obj = parse_obj(text).unwrap();
+        assert_eq!(obj.material_libs, vec!["scene.mtl"]);
+        // Quad is split into two triangles, the second face uses another vt so its vertices are new
+        assert_eq!(obj.faces, vec![[0, 1, 2], [0, 2, 3], [4, 5, 6]]);
+        assert_eq!(obj.positions.len(), 7);
+        assert_eq!(obj.positions[5], Vector3::new(1., 1., 0.));
+        assert_eq!(obj.uvs[0], Some([0., 1.]));
+        assert_eq!(obj.uvs[4], Some([1., 0.]));
+        assert_eq!(obj.normals.as_deref(), Some(&[Vector3::Z; 7][..]));
+        assert_eq!(obj.groups, vec![("Red".to_string(), 0), ("Blue".to_string(), 2)]);
+
+        assert!(parse_obj("v 0 0 0\nf 1 2 3").is_err());
+    }
+
+    #[test]
+    fn test_mtl_materials() {
+        let text = "
+            newmtl Wood
+            Ka 0.1 0.1 0.1
+            Kd 0.6 0.4 0.2
+            Ns 50
+            map_Kd -s 2 2 textures/wood.png
+
+            newmtl Chrome
+            Kd 0.1 0.1 0.1
+            Ks 0.9 0.9 0.9
+            illum 3
+        ";
+        let materials = parse_mtl(text);
+        assert_eq!(materials.len(), 2);
+        assert_eq!(materials[0].diffuse, Vector3::new(0.6, 0.4, 0.2));
+        assert_eq!(materials[0].diffuse_map.as_deref(), Some("textures/wood.png"));
+
+        let wood = materials[0].to_json(1);
+        assert_eq!(wood["_type"], "diffuse");
+        assert_eq!(wood["PhongExponent"], 50.);
+        let chrome = materials[1].to_json(2);
+        assert_eq!(chrome["_type"], "conductor");
+        assert_eq!(chrome["MirrorReflectance"], json!([0.9, 0.9, 0.9]));
+    }
+}
@@ -7,6 +7,7 @@
 use bevy_math::{NormedVectorSpace, VectorSpace};
 use rand::random;
 
+use crate::medium::Medium;
 use crate::prelude::*;
 
 
@@ -84,6 +85,12 @@ impl Ray {
          self.direction.dot(normal) <= 0.0 
     }
 
+    /// Ray in local space of the given transform
+    #[inline]
+    pub fn to_local(&self, transform: &Transform) -> Ray {
+        self.inverse_transform(&transform.inverse)
+    }
+
     #[inline]
     pub fn inverse_transform(&self, inv_matrix: &Matrix4) -> Ray {
         // slides 04, p.50
@@ -114,6 +121,9 @@ pub struct HitRecord {
     pub textures: Vec<usize>,
     pub texture_uv: Option<[Float; 2]>,
     pub tbn_matrix: Option<Matrix3>, // Tangent space matric (TBN matrix in slides 07 pp.10-16)
+    pub strand_tangent: Option<Vector3>, // Unit direction of a curve strand at the hit point, for hair shading (see curve.rs)
+    pub medium: Option<Medium>, // Set if the hit is a collision inside a heterogeneous volume instead of a surface (see volume.rs)
+    pub instance_variation: Option<Float>, // Random value in [0, 1) of the mesh instance that is hit, for variation texture maps (see instancer.rs)
 
     pub radiance: Option<Vector3>,
     pub emissive_ptr: Option<Arc<dyn crate::shapes::EmissiveShape>>,
@@ -141,6 +151,9 @@ impl HitRecord {
             textures: texs,
             texture_uv: uv,
             tbn_matrix: tbn,
+            strand_tangent: None,
+            medium: None,
+            instance_variation: None,
             radiance: None,
             emissive_ptr: None,
             emissive_shape_id: None,
@@ -149,19 +162,16 @@ impl HitRecord {
 
     
     #[inline]
-    pub fn to_world(&mut self, mat4: &Matrix4) {
+    pub fn to_world(&mut self, transform: &Transform) {
         // WARNING: What about entry point??? <-------------------------
-        self.entry_point = transform_point(mat4, &self.entry_point);
+        self.entry_point = transform.point(&self.entry_point);
 
         // Slides 04, p.51
         // Transform hit point to world space
-        self.hit_point = transform_point(mat4, &self.hit_point);
+        self.hit_point = transform.point(&self.hit_point);
         // Transform normal to world space
-        // WARNING: for normal only use upper 3x3, see p.53 
-        // TODO: Cache?
-        let mat3 = Matrix3::from_mat4(*mat4); 
-        let inv_transpose = mat3.inverse().transpose();
-        self.normal = (inv_transpose * self.normal).normalize();
+        // WARNING: for normal only use upper 3x3, see p.53 (cached in Transform::normal_matrix)
+        self.normal = transform.normal(&self.normal);
     }
 }
 
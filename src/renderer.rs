@@ -19,7 +19,8 @@ use std::ops::Mul;
 use std::{self, time::Instant};
 
 use crate::brdf;
-use crate::material::{HeapAllocMaterial, ReflectanceParams};
+use crate::material::{kajiya_kay, HairMaterial, HeapAllocMaterial, ReflectanceParams};
+use crate::medium::Medium;
 use crate::ray::{HitRecord, Ray};
 use crate::light::{LightKind};
 use crate::scene::{Layer2D, Scene2D, Scene3D};
@@ -35,9 +36,13 @@ pub fn update_brdf_and_get_normal(textures: &Textures, texmap_ids: &Vec<usize>,
     for texmap_id in  texmap_ids{
         let texmap = &textures.texture_maps.as_slice()[*texmap_id - 1]; // TODO: I am not sure if as_slice( ) is still relevant here, it resolved a rustc error before I change the implementation though            
         
-        let uv = &hit_record.texture_uv.expect("Texture coordinates (u, v) is not written to hitrecord.");
+        let uv = match hit_record.texture_uv {
+            None if texmap.is_variation() => [0., 0.], // Does not depend on uv
+            uv => uv.expect("Texture coordinates (u, v) is not written to hitrecord."),
+        };
         let interpolation = texmap.interpolation().unwrap_or(&Interpolation::DEFAULT); //
-        let tex_color = textures.tex_from_map(texmap_id - 1, *uv, interpolation, true, hit_record.hit_point);
+        let variation = hit_record.instance_variation.unwrap_or(0.);
+        let tex_color = textures.tex_from_map(texmap_id - 1, uv, interpolation, true, hit_record.hit_point, variation);
         if let Some(decal_mode) = texmap.decal_mode() {
             match decal_mode {
                 // Update BRDF ----------------------------------------------------------
@@ -52,7 +57,7 @@ pub fn update_brdf_and_get_normal(textures: &Textures, texmap_ids: &Vec<usize>,
                 // Update hitrecord normal ----------------------------------------------
                 DecalMode::ReplaceNormal => { 
                                              // TODO: better solution than "apply_normalization" parameter in retrieving colors...? 
-                                             let tex_color = textures.tex_from_map(texmap_id - 1, hit_record.texture_uv.unwrap(), texmap.interpolation().unwrap(), false, hit_record.hit_point);
+                                             let tex_color = textures.tex_from_map(texmap_id - 1, hit_record.texture_uv.unwrap(), texmap.interpolation().unwrap(), false, hit_record.hit_point, variation);
                                              let dir = ImageData::color_to_direction(tex_color);
                                              perturbed_normal = hit_record.tbn_matrix.unwrap() * dir;
                                              debug_assert!(perturbed_normal.is_normalized());
@@ -62,6 +67,7 @@ pub fn update_brdf_and_get_normal(textures: &Textures, texmap_ids: &Vec<usize>,
                         debug_assert!(!perturbed_normal.is_nan(), "Found perturbed normal: {}", perturbed_normal);
                         debug_assert!(perturbed_normal.is_normalized(), "Found perturbed normal not normalized: {}", perturbed_normal);
                 },
+                DecalMode::Displacement => {}, // Already applied to mesh vertices at setup (see Mesh::refine( ))
                 DecalMode::ReplaceBackground => {todo!("Found replacebackground decalibration mode! This is implemented elsewhere in the renderer. (check textureOffset in json)");},
                 _ => { debug!("Unexpeced decalibration mode {:?}...", decal_mode); }
             }
@@ -96,12 +102,19 @@ pub fn shade_diffuse(scene: &Scene3D, hit_record: &mut HitRecord, ray_in: &Ray)
     
     let brdf_id = mat.brdf();
     let scene_brdfs = &scene.data.brdfs;
+    // Reflection including the cosine term, hair is shaded by its strand tangent instead of the normal
+    let strand = (mat.get_type() == "hair").then(|| HairMaterial::tangent(hit_record));
+    let reflect = |w_i: Vector3, w_o: Vector3, n: Vector3| match strand {
+        Some(t) => kajiya_kay(w_i, w_o, t, &material_params),
+        None => brdf::eval_brdf(brdf_id, mat, scene_brdfs, w_i, w_o, n, &material_params) * w_i.dot(n).max(0.),
+    };
     
     let mut color = material_params.ambient() * scene.data.lights.ambient_light; 
     for light in scene.data.lights.all_shadow_rayable().iter() {
             
         let (shadow_ray, interval) = get_shadow_ray(&light, hit_record, ray_in, scene.data.shadow_ray_epsilon);
-        if scene.hit_bvh(&shadow_ray, &interval, true).is_none() {
+        let transmittance = shadow_transmittance(scene, &shadow_ray, &interval);
+        if transmittance != Vector3::ZERO {
 
             // Note: below assert might fail in bump or normal mapping case once the normals are updated:
             debug_assert!( (hit_record.is_front_face && hit_record.normal.dot(ray_in.direction) < 1e-6) || (!hit_record.is_front_face && hit_record.normal.dot(ray_in.direction) > -1e-6), "Found front_face = {} and normal dot ray_in direction = {}", hit_record.is_front_face, hit_record.normal.dot(ray_in.direction) );
@@ -113,9 +126,7 @@ pub fn shade_diffuse(scene: &Scene3D, hit_record: &mut HitRecord, ray_in: &Ray)
             let w_o = -ray_in.direction;
             //color += material_params.diffuse(w_i, n) * irradiance;
             //color += material_params.specular(w_o, w_i, n) * irradiance;
-            let cos_theta = w_i.dot(n).max(0.);
-            let reflection_comp = brdf::eval_brdf(brdf_id, mat, scene_brdfs, w_i, w_o, n, &material_params);
-            color +=  reflection_comp * cos_theta * irradiance;
+            color += reflect(w_i, w_o, n) * irradiance * transmittance;
         }
     }
 
@@ -133,9 +144,7 @@ pub fn shade_diffuse(scene: &Scene3D, hit_record: &mut HitRecord, ray_in: &Ray)
             let n = hit_record.normal;
             //color += radiance * material_params.diffuse(w_i, n);
             //color += radiance * material_params.specular(w_o, w_i, n); 
-            let cos_theta = w_i.dot(n).max(0.);
-            let reflection_comp = brdf::eval_brdf(brdf_id, mat, scene_brdfs, w_i, w_o, n, &material_params);
-            color +=  reflection_comp * cos_theta * radiance;
+            color += reflect(w_i, w_o, n) * radiance;
 
         }
     }
@@ -164,30 +173,19 @@ pub fn shade_diffuse(scene: &Scene3D, hit_record: &mut HitRecord, ray_in: &Ray)
         );
 
         // Intersection test for this shadow ray in the scene
-        if intersect_object_light(scene, &shadow_ray, object_light) {
+        let transmittance = object_light_transmittance(scene, &shadow_ray, object_light);
+        if transmittance != Vector3::ZERO {
             // Evaluate BRDF
             let w_o = -ray_in.direction;
             let n = hit_record.normal;
-            let cos_theta = w_i.dot(n).max(0.0);
-
-            if cos_theta <= 0.0 {
+            if strand.is_none() && w_i.dot(n) <= 0.0 {
                 continue;
             }
 
-            let reflection = brdf::eval_brdf(
-                brdf_id,
-                mat,
-                scene_brdfs,
-                w_i,
-                w_o,
-                n,
-                &material_params,
-            );
-
             let radiance = object_light.radiance();
 
             // TODO: distance attenuation?
-            color += reflection * cos_theta * radiance / pdf;
+            color += reflect(w_i, w_o, n) * radiance * transmittance / pdf;
         }
 
         }
@@ -195,25 +193,80 @@ pub fn shade_diffuse(scene: &Scene3D, hit_record: &mut HitRecord, ray_in: &Ray)
     color
 }
 
-// Return radiance
-fn intersect_object_light(scene: &Scene3D, shadow_ray: &Ray, object_light: &Arc<dyn EmissiveShape>) -> bool {
+/// Fraction of light arriving along the shadow ray. It passes through "null" boundaries, attenuated by
+/// the media inside them, any other surface blocks it. Scenes without them only test occlusion.
+/// Heterogeneous volumes are ratio tracked (see volume.rs).
+fn shadow_transmittance(scene: &Scene3D, shadow_ray: &Ray, interval: &Interval) -> Vector3 {
+    let mut transmittance = Vector3::ONE;
+    if !scene.data.materials.has_null {
+        if scene.occluded(shadow_ray, interval) {
+            return Vector3::ZERO;
+        }
+    } else {
+        let mut interval = *interval;
+        while let Some(hit) = scene.hit(shadow_ray, &interval, false) {
+            // Collisions in volumes are skipped, they are tracked below
+            if hit.medium.is_none() {
+                let mat = &scene.data.materials.data[hit.material - 1];
+                if mat.get_type() != "null" {
+                    return Vector3::ZERO;
+                }
+                if let Some(medium) = mat.medium().filter(|_| !hit.is_front_face) {
+                    transmittance *= medium.transmittance(hit.ray_t - interval.min);
+                }
+            }
+            interval.min = hit.ray_t + scene.data.intersection_test_epsilon;
+        }
+    }
+
+    for volume in scene.data.objects.volumes.iter() {
+        transmittance *= volume.transmittance(shadow_ray, interval);
+    }
+    transmittance
+}
+
+/// Light from point, area and object lights scattered towards the ray at point p inside the medium
+fn shade_medium(scene: &Scene3D, medium: &Medium, p: Vector3, ray_in: &Ray) -> Vector3 {
+    let mut radiance = Vector3::ZERO;
+    for light in scene.data.lights.all_shadow_rayable().iter() {
+        let (dir, distance) = light.get_shadow_direction_and_distance(&p);
+        let shadow_ray = Ray::new(p, dir, ray_in.time);
+        let interval = Interval::new(0.0, distance);
+        let transmittance = shadow_transmittance(scene, &shadow_ray, &interval);
+        if transmittance != Vector3::ZERO {
+            radiance += medium.phase(ray_in.direction.dot(dir)) * light.irradiance(&shadow_ray, &interval) * transmittance;
+        }
+    }
+
+    // Same sampling as in shade_diffuse( ), without the cosine term
+    for object_light in scene.data.objects.emissive_shapes.iter() {
+        let sample = object_light.sample_from_bsphere(&scene.data.vertex_data, p, random_float(), random_float());
+        if sample.pdf <= 0.0 {
+            continue;
+        }
+        let shadow_ray = Ray::new(p, sample.direction, ray_in.time);
+        let transmittance = object_light_transmittance(scene, &shadow_ray, object_light);
+        if transmittance != Vector3::ZERO {
+            radiance += medium.phase(ray_in.direction.dot(sample.direction)) * object_light.radiance() * transmittance / sample.pdf;
+        }
+    }
+    radiance
+}
+
+/// Fraction of the object light's radiance arriving along the shadow ray, zero if the ray misses the light.
+/// Surfaces before the light are handled by shadow_transmittance( ), so null boundaries and volumes attenuate it.
+fn object_light_transmittance(scene: &Scene3D, shadow_ray: &Ray, object_light: &Arc<dyn EmissiveShape>) -> Vector3 {
     
     // Use a much smaller interval min to catch intersections close to the ray origin
     // This is important for transformed lights where the intersection might be very close
     let shadow_interval = Interval::new(1e-6, FloatConst::INF);
     
-    if let Some(shadow_hit) = scene.hit_bvh(
-            &shadow_ray,
-            &shadow_interval,
-            false,
-        ) {
-            // Accept only if we hit this emissive object by comparing IDs
-            if let Some(hit_shape_id) = shadow_hit.emissive_shape_id {
-                let object_light_id = object_light.shape_id();
-                return hit_shape_id == object_light_id;
-            }
-    }
-    false
+    let Some(light_hit) = object_light.intersects_with(shadow_ray, &shadow_interval, &scene.vertex_cache) else {
+        return Vector3::ZERO;
+    };
+    // Stop just before the light so that it does not block itself
+    let interval = Interval::new(shadow_interval.min, light_hit.ray_t - scene.data.intersection_test_epsilon);
+    shadow_transmittance(scene, shadow_ray, &interval)
 }
 
 pub fn ray_trace(ray_in: &Ray, scene: &Scene3D, cam: &Camera, max_depth: usize, depth: usize) -> Vector3 { 
@@ -225,12 +278,17 @@ pub fn ray_trace(ray_in: &Ray, scene: &Scene3D, cam: &Camera, max_depth: usize,
    }
    
    let t_interval = Interval::positive(scene.data.intersection_test_epsilon);
-   if let Some(mut hit_record) = scene.hit_bvh(ray_in, &t_interval, false) {
+   if let Some(mut hit_record) = scene.hit(ray_in, &t_interval, false) {
 
         if let Some(rad) = hit_record.radiance {
             return rad; // Object lights overrive radiance field in hitrecord
         }
 
+        // Collision inside a heterogeneous volume (see volume.rs), only single scattering of the lights
+        if let Some(medium) = hit_record.medium {
+            return medium.albedo() * shade_medium(scene, &medium, hit_record.hit_point, ray_in);
+        }
+
 
         let mat: &HeapAllocMaterial = &scene.data.materials.data[hit_record.material - 1];
                 
@@ -238,7 +296,7 @@ pub fn ray_trace(ray_in: &Ray, scene: &Scene3D, cam: &Camera, max_depth: usize,
         let mat_type = mat.get_type();
         let epsilon = scene.data.intersection_test_epsilon;  
         color += match mat_type{ 
-            "diffuse" => {
+            "diffuse" | "hair" => {
                 shade_diffuse(scene, &mut hit_record, ray_in)
             },
             "mirror" | "conductor" => { 
@@ -269,12 +327,23 @@ pub fn ray_trace(ray_in: &Ray, scene: &Scene3D, cam: &Camera, max_depth: usize,
                 }
                 tot_radiance
             },
+            "null" => {
+                match mat.interact(ray_in, &hit_record, epsilon, false) {
+                    Some((passed_ray, _)) => ray_trace(&passed_ray, scene, cam, max_depth, depth + 1),
+                    None => Vector3::ZERO,
+                }
+            },
             _ => {
                 // WARNING: Below does not panic when json has unknown material because parser defaults it to Diffuse (however it does panic if you make a typo or not implement shading function)
                 panic!(">> Unknown material type '{}'! Shading function for this material is missing.", mat_type); 
             },
         };
 
+        // Ray tracing does not scatter inside media, light is only attenuated along the ray (see medium.rs)
+        if let Some(medium) = mat.medium().filter(|_| !hit_record.is_front_face) {
+            color *= medium.transmittance(hit_record.ray_t);
+        }
+
         color
    }
    else {
@@ -299,19 +368,50 @@ pub fn path_trace(ray_in: &Ray, scene: &Scene3D, cam: &Camera, max_depth: usize,
     }
 
     let t_interval = Interval::positive(scene.data.intersection_test_epsilon);
-    if let Some(mut hit_record) = scene.hit_bvh(ray_in, &t_interval, false) {
+    if let Some(mut hit_record) = scene.hit(ray_in, &t_interval, false) {
         
         if let Some(rad) = hit_record.radiance {
             return throughput * rad;
         }
-        
-        let mat: &HeapAllocMaterial = &scene.data.materials.data[hit_record.material - 1];
+
         let epsilon = scene.data.intersection_test_epsilon;     
         let mut radiance = Vector3::ZERO;
 
+        // Scattering event inside a medium instead of the surface, either a collision in a heterogeneous volume
+        // (see volume.rs) or sampled in the medium of the shape if the ray hits it from inside (see medium.rs)
+        let mut medium_weight = Vector3::ONE;
+        let medium_event = match hit_record.medium {
+            Some(medium) => Some((hit_record.hit_point, medium, medium.albedo())),
+            None => {
+                let mat = &scene.data.materials.data[hit_record.material - 1];
+                mat.medium().filter(|_| !hit_record.is_front_face).and_then(|medium| {
+                    let (scatter_distance, weight) = medium.sample_distance(hit_record.ray_t, random_float(), random_float());
+                    medium_weight = weight;
+                    scatter_distance.map(|distance| (ray_in.at(distance), medium, weight))
+                })
+            },
+        };
+        if let Some((p, medium, weight)) = medium_event {
+            if cam.renderer_params.nee {
+                radiance += throughput * weight * shade_medium(scene, &medium, p, ray_in);
+            }
+            // Phase function is sampled exactly, so it does not change the throughput
+            let scattered_ray = Ray::new(p, medium.sample_phase(ray_in.direction, random_float(), random_float()), ray_in.time);
+            let indirect = path_trace(&scattered_ray, scene, cam, max_depth, depth + 1, throughput);
+            throughput = throughput.mul(weight);
+            radiance += throughput * indirect;
+
+            if cam.renderer_params.russian_roulette && depth > 0 {
+                radiance /= rr_probability;
+            }
+            return radiance;
+        }
+
+        let mat: &HeapAllocMaterial = &scene.data.materials.data[hit_record.material - 1];
+
         // Direct lighting (NEE)
         // TODO: THIS IS NOT HOW NEE WORKS I SHOULD'VE SAMPLED A LIGHT SOURCE; NOT CONNECTING THEM ALL
-        if cam.renderer_params.nee && hit_record.is_front_face { // TODO: I added is_front_face assuming a material could be dielectric but is it correct..?
+        if cam.renderer_params.nee && hit_record.is_front_face && mat.get_type() != "null" { // TODO: I added is_front_face assuming a material could be dielectric but is it correct..?
             let direct = shade_diffuse(scene, &mut hit_record, ray_in);
             radiance += throughput * direct;
         }
@@ -347,7 +447,7 @@ pub fn path_trace(ray_in: &Ray, scene: &Scene3D, cam: &Camera, max_depth: usize,
             radiance /= rr_probability;
         }
        
-        radiance
+        radiance * medium_weight
     } else {
         sample_background(ray_in, scene, cam)
     }
@@ -403,6 +503,7 @@ fn sample_background(ray_in: &Ray, scene: &Scene3D, cam: &Camera) -> Vector3 {
                                 interpolation,
                                 true, 
                                 Vector3::ZERO, 
+                                0.,
                             );
                             //let bg_color = Vector3::Y * 255.;
                             return bg_color * 255.; // TODO: WARNING THIS IS ERROR PRONE. Background image was returned in range [0, 1] but that appears black, so scale it back
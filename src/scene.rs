@@ -11,22 +11,31 @@
     @date: 2 Oct, 2025
     @author: Bartu
 */
-use std::{path::Path, io::BufReader, error::Error, fs::File};
+use std::{path::Path, error::Error, collections::{HashMap, HashSet}};
 use bevy_math::NormedVectorSpace;
 use image::Pixel;
 use rand::random; // traits needed for norm_squared( ) 
 
 use crate::brdf::BRDFs;
-use crate::image::{ImageData, Textures};
+use crate::image::{AlphaMask, ImageData, Textures};
 use crate::material::{*};
 use crate::pixel::PixelData;
 use crate::shapes::{*};
 use crate::mesh::{LightMesh, Mesh, MeshInstanceField};
+use crate::csg::{Csg, CsgChildKind, CsgChildRef};
+use crate::sdf::SdfShape;
+use crate::curve::Curve;
+use crate::volume::Volume;
+use crate::instancer::Instancer;
+use crate::mesh_cache::{CachedMesh, MeshCache};
+use crate::obj::{self, ObjMesh};
 use crate::json_structs::{*};
 use crate::camera::{Cameras};
 use crate::interval::{Interval, FloatConst};
 use crate::ray::{Ray, HitRecord};
-use crate::acceleration::BVHSubtree;
+use crate::acceleration::{Accelerator, AcceleratorKind, BVHSettings, BVHSubtree, NaiveAccelerator, closest_hit_naive};
+use crate::kdtree::KdTree;
+use crate::geometry::TriangleIntersection;
 use crate::{light::*, numeric};
 use crate::prelude::*; // TODO: Excuse me but what's the point of prelude if there are so many use crate::yet_another_mod above?
 
@@ -249,6 +258,13 @@ pub struct Scene3DJSON {
 
     #[serde(rename = "BRDFs")]
     pub brdfs: BRDFs,
+
+    #[serde(rename = "BVH")]
+    pub bvh_settings: BVHSettings,
+
+    pub triangle_intersection: TriangleIntersection,
+
+    pub accelerator: AcceleratorKind,
     
 }
 
@@ -259,6 +275,10 @@ impl Scene3DJSON {
 
         // 1- Convert materials serde_json values to actual structs
         self.materials.finalize();
+
+        // 1b- Read OBJ meshes, materials and texture maps of their .mtl files are appended to the scene's (see obj.rs)
+        let json_dir = jsonpath.parent().unwrap_or(Path::new("."));
+        self.objects.load_obj_meshes(json_dir, &mut self.materials, &mut self.textures)?;
         //for m in &self.materials.materials { // TODO: refactor that ambigious call materials.materials( )
         //    debug!("Material {}: {:#?}", m.get_type(), m);
         //}
@@ -289,7 +309,11 @@ impl Scene3DJSON {
         }
         
         // 6 - Get cache per vertex (objects.setup appends PLY data to vertex_data)
-        let cache = self.objects.setup_and_get_cache(&mut self.vertex_data, &self.tex_coord_data, jsonpath)?;
+        self.bvh_settings.validate(); // Mesh BVHs are built during objects setup
+        let mut cache = self.objects.setup_and_get_cache(&mut self.vertex_data, &self.tex_coord_data, self.textures.as_ref(), &self.bvh_settings, self.accelerator, jsonpath)?;
+        cache.triangle_intersection = self.triangle_intersection;
+        debug!("Triangle intersection: {:?}", cache.triangle_intersection);
+        cache.alpha_masks = self.textures.as_ref().map(Textures::alpha_masks).unwrap_or_default();
 
         // 7 - Setup scene lights transforms
         self.lights.setup(&self.transformations);
@@ -306,7 +330,8 @@ pub struct Scene3D
     pub data: Box<Scene3DJSON>, // Now owns the data instead of borrowing
 
     pub vertex_cache: HeapAllocatedVerts,
-    pub bvh: Option<BVHSubtree>,
+    pub bvh: Option<BVHSubtree>, // Kept separately to refit it in the next frame
+    pub accelerator: Option<Arc<dyn Accelerator>>, // Top-level structure queried by hit( ) and occluded( ), the BVH above or another kind
 }
 
 
@@ -315,66 +340,83 @@ impl Scene3D
 //    T: Shape + BBoxable + 'static,
     { 
     pub fn new_from(scene_json: Scene3DJSON, jsonpath: &Path) -> Self {
+        Self::new_from_previous(scene_json, jsonpath, None)
+    }
+
+    /// Same as new_from( ) but for the next frame of an animation, BVHs of the previous frame
+    /// are refitted instead of being built from scratch (see BVHSubtree::refit( ))
+    pub fn new_from_previous(scene_json: Scene3DJSON, jsonpath: &Path, previous: Option<&Scene3D>) -> Self {
+
+        let span = tracing::span!(tracing::Level::INFO, "build_scene");
+        let _enter = span.enter();
 
         let mut scene_json = scene_json;
+        // Top-level BVH is refitted only if the previous frame has the same objects
+        let previous = previous.filter(|p| p.data.objects.has_same_layout(&scene_json.objects));
+        if let Some(previous) = previous {
+            scene_json.objects.reuse_mesh_bvhs(&previous.data.objects);
+        }
         let cache = scene_json.setup_and_get_cache(jsonpath).unwrap(); 
 
         let mut scene = Self {
             data: Box::new(scene_json),
             vertex_cache: Arc::new(cache),
-            bvh: None,
+            bvh: previous.and_then(|p| p.bvh.clone()),
+            accelerator: None,
         };
-        scene.build_bvh();
+        scene.build_accelerator();
         scene
     }
 
-    /// Build top-tevel BVH for scene
-    pub fn build_bvh(&mut self) {
+    /// Build top-level acceleration structure of the kind given in scene JSON
+    pub fn build_accelerator(&mut self) {
         let shapes = &self.data.objects.bboxable_shapes;
         let verts = &self.vertex_cache.vertex_data;
-        self.bvh = Some(BVHSubtree::build(shapes, verts, true)); // Apply object's transformation for top-level BVH
-    }
-
-    /// Iterate over all shapes to find the closest hit
-    pub fn hit_naive(&self, ray: &Ray, t_interval: &Interval, early_break: bool) -> Option<HitRecord>{
-        // Refers to p.91 of slide 01_b, lines 3-7
-        let mut rec: Option<HitRecord> = None;
-        let mut t_min: Float = FloatConst::INF;
-        for shape in self.data.objects.bboxable_shapes.iter() { 
-            if let Some(hit_record) = shape.intersects_with(ray, t_interval, &self.vertex_cache){
-
-                if early_break { 
-                    return Some(hit_record);
-                }
-
-                // Update if new hit is closer 
-                if t_min > hit_record.ray_t { 
-                    t_min = hit_record.ray_t;
-                    rec = Some(hit_record);
-                }
+        let accelerator: Arc<dyn Accelerator> = match self.data.accelerator {
+            AcceleratorKind::BVH => {
+                self.build_bvh();
+                Arc::new(self.bvh.clone().expect("BVH is built above"))
             }
-        }
-        rec
+            AcceleratorKind::KdTree => Arc::new(KdTree::build(shapes, verts, true)),
+            AcceleratorKind::Naive => Arc::new(NaiveAccelerator::new(shapes)),
+        };
+        info!(">> Top-level accelerator: {:?}", self.data.accelerator);
+        self.accelerator = Some(accelerator);
     }
 
+    /// Build top-tevel BVH for scene, or refit the existing one if there is any
+    pub fn build_bvh(&mut self) {
+        let shapes = &self.data.objects.bboxable_shapes;
+        let verts = &self.vertex_cache.vertex_data;
+        let settings = &self.data.bvh_settings;
+        // Apply object's transformation for top-level BVH
+        let bvh = match &self.bvh {
+            Some(previous) => previous.refit(shapes, verts, true, settings),
+            None => BVHSubtree::build(shapes, verts, true, settings),
+        };
+        info!(">> Top-level BVH ({:?}): {}", settings.builder, bvh.stats());
+        self.bvh = Some(bvh);
+    }
 
     // TODO: Is it better hitrecord a mutable input parameter rather than returning Option<HitRecord>?  
-    pub fn hit_bvh(&self, ray: &Ray, t_interval: &Interval, early_break: bool)
+    pub fn hit(&self, ray: &Ray, t_interval: &Interval, early_break: bool)
     -> Option<HitRecord> 
     {
-        // 1. BVH hit first with bounding boxable shapes
+        // 1. Accelerator hit first with bounding boxable shapes
         let mut best = None;
         let mut best_t = FloatConst::INF;
 
-        if let Some(bvh) = &self.bvh {
+        if let Some(accelerator) = &self.accelerator {
             let mut rec = HitRecord::default();
-            if bvh.intersect(ray, t_interval, &self.vertex_cache, &mut rec, early_break) {
+            if accelerator.intersect(ray, t_interval, &self.vertex_cache, &mut rec, early_break) {
                 best_t = rec.ray_t;
                 best = Some(rec);
             }
         }
-        else {
-            best = self.hit_naive(ray, t_interval, early_break);
+        else if let Some(rec) = closest_hit_naive(&self.data.objects.bboxable_shapes, ray, t_interval, &self.vertex_cache, early_break) {
+            // Accelerator is not built yet, test every shape
+            best_t = rec.ray_t;
+            best = Some(rec);
         }
 
         // 2. Test planes (looping over all planes)
@@ -392,6 +434,17 @@ impl Scene3D
 
         best
     }
+
+    /// Shadow ray test, true if anything in the scene blocks the ray within t_interval
+    pub fn occluded(&self, ray: &Ray, t_interval: &Interval) -> bool {
+        let blocked_by_accelerator = if let Some(accelerator) = &self.accelerator {
+            accelerator.occluded(ray, t_interval, &self.vertex_cache)
+        } else {
+            self.data.objects.bboxable_shapes.iter().any(|shape| shape.occluded(ray, t_interval, &self.vertex_cache))
+        };
+
+        blocked_by_accelerator || self.data.objects.unbboxable_shapes.iter().any(|plane| plane.occluded(ray, t_interval, &self.vertex_cache))
+    }
 }
 
 
@@ -479,6 +532,9 @@ pub struct SceneMaterials {
 
     #[serde(skip)]
     pub data: Vec<HeapAllocMaterial>,
+
+    #[serde(skip)]
+    pub has_null: bool, // Shadow rays can pass through "null" boundaries only if there is one (see renderer::shadow_transmittance( ))
 }
 
 impl SceneMaterials {
@@ -492,6 +548,17 @@ impl SceneMaterials {
                             m
                         })
                         .collect();
+        self.has_null = self.data.iter().any(|m| m.get_type() == "null");
+    }
+
+    /// Parse and set up a material given as a JSON value after finalize( ), i.e. materials of OBJ files (see obj.rs).
+    /// Returns its id, materials are referred by their index in data starting from 1.
+    pub fn push_value(&mut self, value: serde_json::Value) -> usize {
+        for mut m in parse_material(value) {
+            m.setup();
+            self.data.push(m);
+        }
+        self.data.len()
     }
 
     pub fn all(&mut self) -> &Vec<HeapAllocMaterial> {
@@ -514,6 +581,24 @@ pub struct SceneObjects {
     pub spheres: SingleOrVec<Sphere>,
     #[serde(rename = "Plane")]
     pub planes: SingleOrVec<Plane>,
+    #[serde(rename = "Cylinder")]
+    pub cylinders: SingleOrVec<Cylinder>,
+    #[serde(rename = "Disk")]
+    pub disks: SingleOrVec<Disk>,
+    #[serde(rename = "Cone")]
+    pub cones: SingleOrVec<Cone>,
+    #[serde(rename = "Quad")]
+    pub quads: SingleOrVec<Quad>,
+    #[serde(rename = "Box")]
+    pub boxes: SingleOrVec<Cuboid>,
+    #[serde(rename = "Torus")]
+    pub tori: SingleOrVec<Torus>,
+    #[serde(rename = "SdfShape")]
+    pub sdf_shapes: SingleOrVec<SdfShape>,
+    #[serde(rename = "Curve")]
+    pub curves: SingleOrVec<Curve>,
+    #[serde(rename = "Volume")]
+    pub volumes: SingleOrVec<Volume>,
     #[serde(rename = "Mesh")]
     pub meshes: SingleOrVec<Mesh>,
     
@@ -521,10 +606,18 @@ pub struct SceneObjects {
     pub light_meshes: SingleOrVec<LightMesh>,
     #[serde(rename = "LightSphere")]
     pub light_spheres: SingleOrVec<LightSphere>,
+    #[serde(rename = "LightBox")]
+    pub light_boxes: SingleOrVec<LightBox>,
 
     #[serde(rename = "MeshInstance")]
     pub mesh_instances: SingleOrVec<MeshInstanceField>,
 
+    #[serde(rename = "Instancer")]
+    pub instancers: SingleOrVec<Instancer>,
+
+    #[serde(rename = "CSG")]
+    pub csgs: SingleOrVec<Csg>,
+
     #[serde(skip)]
     pub bboxable_shapes: ShapeList, 
     #[serde(skip)]
@@ -556,7 +649,12 @@ fn resolve_all_mesh_instances(
         for other in left.iter().chain(rest.iter()) {
             if other._id == mint.base_mesh_id {
                 mint.base_mesh = other.base_mesh.clone();
-                mint.matrix *= other.matrix; // TODO: is this the correct order?
+                // End transform is composed the same way, following the motion of the other instance if it moves
+                if mint.end_transform.is_some() || other.end_transform.is_some() {
+                    let end = mint.end_transform.unwrap_or(mint.transform);
+                    mint.end_transform = Some(end.compose(other.end_transform.as_ref().unwrap_or(&other.transform)));
+                }
+                mint.transform = mint.transform.compose(&other.transform); // TODO: is this the correct order?
                 debug!("Mesh instance {} refers base mesh instance {} ", mint._id, mint.base_mesh.clone().unwrap()._id);
                 break;
             }
@@ -567,136 +665,385 @@ fn resolve_all_mesh_instances(
             panic!("Could not resolve base mesh id {}", mint.base_mesh_id);
         }
     }
+
+    for mint in slice.iter_mut() {
+        mint.update_composite();
+    }
+}
+
+/// Resolve children of every CSG in the order of scene objects, nested CSGs are resolved before
+/// the CSGs referring them. Meshes and mesh instances must be set up before.
+fn resolve_all_csgs(objects: &SceneObjects) -> Result<Vec<Arc<Csg>>, Box<dyn Error>> {
+    let csgs = objects.csgs.all_ref();
+    let mut ids = HashSet::with_capacity(csgs.len());
+    if let Some(csg) = csgs.iter().find(|csg| !ids.insert(csg._id)) {
+        return Err(format!("CSG _id {} is used more than once", csg._id).into());
+    }
+    let mut resolved: HashMap<usize, Arc<Csg>> = HashMap::new();
+
+    while resolved.len() < csgs.len() {
+        let mut progressed = false;
+        for csg in &csgs {
+            if resolved.contains_key(&csg._id) {
+                continue;
+            }
+            let mut children = Vec::with_capacity(csg.child_refs.len());
+            for child in &csg.child_refs {
+                match find_csg_child(objects, child, &resolved)? {
+                    Some(shape) => children.push(shape),
+                    None => break, // Nested CSG is not resolved yet
+                }
+            }
+            if children.len() == csg.child_refs.len() {
+                debug!("CSG {} ({:?}) has {} children", csg._id, csg.operation, children.len());
+                resolved.insert(csg._id, Arc::new(Csg { children, ..(*csg).clone() }));
+                progressed = true;
+            }
+        }
+        if !progressed {
+            return Err("CSG objects refer each other in a cycle".into());
+        }
+    }
+
+    Ok(csgs.iter().map(|csg| resolved[&csg._id].clone()).collect())
 }
 
+/// Ok(None) if the child is a CSG that is not resolved yet
+fn find_csg_child(objects: &SceneObjects, child: &CsgChildRef, resolved: &HashMap<usize, Arc<Csg>>) -> Result<Option<HeapAllocatedShape>, Box<dyn Error>> {
+    let id = child.id;
+    let shape = match child.kind {
+        CsgChildKind::Sphere => objects.spheres.iter().find(|s| s._data._id == id).map(|s| Arc::new(s.clone()) as HeapAllocatedShape),
+        CsgChildKind::Box => objects.boxes.iter().find(|s| s._data._id == id).map(|s| Arc::new(s.clone()) as HeapAllocatedShape),
+        CsgChildKind::Torus => objects.tori.iter().find(|s| s._data._id == id).map(|s| Arc::new(s.clone()) as HeapAllocatedShape),
+        CsgChildKind::Mesh => objects.meshes.iter().find(|m| m._id == id).map(|m| Arc::new(m.clone()) as HeapAllocatedShape),
+        CsgChildKind::MeshInstance => objects.mesh_instances.iter().find(|m| m._id == id).map(|m| Arc::new(m.clone()) as HeapAllocatedShape),
+        CsgChildKind::Csg => {
+            if objects.csgs.iter().any(|csg| csg._id == id) {
+                return Ok(resolved.get(&id).map(|csg| csg.clone() as HeapAllocatedShape));
+            }
+            None
+        }
+    };
+    match shape {
+        Some(shape) => Ok(Some(shape)),
+        None => Err(format!("CSG child {:?} {} is not found in scene objects", child.kind, id).into()),
+    }
+}
 
 fn setup_single_mesh_transform(mesh: &mut Mesh,  transforms: &Transformations) {
-    mesh.matrix = if mesh.transformation_names.is_some() {
-        parse_transform_expression(
+    mesh.transform = if mesh.transformation_names.is_some() {
+        Transform::new(parse_transform_expression(
             mesh.transformation_names.as_deref().unwrap_or(""),
             transforms,  
-        )
+        ))
     } else {
         debug!("Mesh '{}'s transformation is not given, defaulting to Identity.", mesh._id);
-        Matrix4::IDENTITY  // Default to identity if no transform is given
+        Transform::IDENTITY  // Default to identity if no transform is given
     };
-    debug!("Composite transform for mesh '{}' is {}", mesh._id, mesh.matrix);
+    debug!("Composite transform for mesh '{}' is {}", mesh._id, mesh.transform.matrix);
+}
+
+/// Motion from the start transform to the one given by "EndTransformations", if any (see numeric::AnimatedTransform)
+fn motion_transform(start: &Transform, end_names: Option<&str>, transforms: &Transformations) -> Option<AnimatedTransform> {
+    end_names.map(|names| AnimatedTransform::new(*start, Transform::new(parse_transform_expression(names, transforms))))
+}
+
+/// Lights are sampled at their start transform and planes are unbounded, so EndTransformations are not
+/// supported for them. Warns and drops the field so that it is not mistaken for motion later.
+fn ignore_end_transformations(kind: &str, id: usize, end_names: &mut Option<String>) {
+    if end_names.take().is_some() {
+        warn!("EndTransformations of {} {} are ignored, only meshes, mesh instances, triangles, spheres, analytic shapes, SDF shapes, curves and cameras can move with them.", kind, id);
+    }
+}
+
+type CachedNormals = (usize, Vec<Vector3>); // (index of first vertex, vertex normals) of a mesh read from the mesh cache
+
+/// Scene data that is the same for the setup of every mesh
+struct MeshSetupContext<'a> {
+    textures: Option<&'a Textures>,
+    bvh_settings: &'a BVHSettings,
+    mesh_cache: Option<&'a MeshCache>,
+    accelerator: AcceleratorKind,
 }
 
+impl MeshSetupContext<'_> {
+    /// Settings of the mesh BVH built in Mesh::setup( ), None if the mesh uses another accelerator
+    fn mesh_bvh_settings(&self) -> Option<&BVHSettings> {
+        (self.accelerator == AcceleratorKind::BVH).then_some(self.bvh_settings)
+    }
+}
+
+/// If the mesh cache is enabled, PLY meshes are read from it when possible (see mesh_cache.rs).
+/// Returns vertex normals of a cached mesh together with the index of its first vertex,
+/// so that they are copied into the scene's normals instead of being recomputed.
 fn unnecessarily_long_setup_function_for_scene_meshes(
     mesh: &mut Mesh, 
     json_dir: &Path,
     verts: &mut VertexData,
     all_triangles: &mut Vec<Triangle>,
     uv_coords: &mut Vec<Option<[Float; 2]>>,
-    tot_mesh_faces: &mut usize,
-) -> Result<(), Box<dyn Error>> 
+    ctx: &MeshSetupContext,
+) -> Result<Option<CachedNormals>, Box<dyn Error>> 
 {
-    if !mesh.faces._ply_file.is_empty() {
+    let (textures, bvh_settings) = (ctx.textures, ctx.bvh_settings);
+    if let Some(obj) = mesh.obj.take() {
+        return Ok(setup_obj_mesh(mesh, &obj, verts, all_triangles, uv_coords, ctx));
+    }
 
-                
-                let ply_file = &mesh.faces._ply_file;
-                let ply_path = json_dir.join(ply_file);
+    if mesh.faces._ply_file.is_empty() {
+        // For vertex cache, get the triangles in a single mesh 
+        // TODO: this is done because we have global vertex_data
+        let offset = verts._data.len();
+        let triangles: Vec<Triangle> = mesh.setup(verts, uv_coords, textures, offset, ctx.mesh_bvh_settings());
+        all_triangles.extend(triangles);
+        return Ok(None);
+    }
 
-                if ply_path.exists() {
-                    debug!("PLY file exists: {:?}", ply_path);
-                } else {
-                    error!("PLY file NOT found at: {:?}", ply_path);
-                }
+    let ply_file = &mesh.faces._ply_file;
+    let ply_path = json_dir.join(ply_file);
 
-                debug!("Loading mesh {} from PLY file path: {:?}", mesh._id, ply_path);
+    if ply_path.exists() {
+        debug!("PLY file exists: {:?}", ply_path);
+    } else {
+        error!("PLY file NOT found at: {:?}", ply_path);
+    }
 
-                let file = File::open(ply_path)?;
-                let reader = BufReader::new(file);
-                let plymesh: PlyMesh = serde_ply::from_reader(reader)?;
-                let old_vertex_count = verts._data.len();
-                // Append loaded ply to vertexdata
-                for vert in &plymesh.vertex {
-                    verts._data.push(Vector3::new(vert.x as Float, vert.y as Float, vert.z as Float));
-                
-                    let uv = match (vert.u, vert.v) {
-                        (Some(u), Some(v)) => {
-                            Some([u as Float , v as Float])
-                        },
-                        _ => None,
-                    };
-                    uv_coords.push(uv);
-                }
-                // Shift faces._data by offset
-                mesh.faces._type = String::from("triangle");
-                if let Some(faces) = &plymesh.face {
-                    mesh.faces._data = faces
-                        .iter()
-                        .flat_map(|f| f.vertex_indices.clone()) // each face is a list of 3 indices
-                        .map(|idx| idx + old_vertex_count)      // shift by existing vertices
-                        .collect();
-                    //info!(">> Mesh {} has {} faces.", mesh._id, mesh.faces._data.len());
-                    *tot_mesh_faces += mesh.faces._data.len();
-                }
-                else {
-                    warn!("PLY mesh {} has no face data!", mesh._id);
-                }
-            }
+    debug!("Loading mesh {} from PLY file path: {:?}", mesh._id, ply_path);
+
+    // Read the whole file since cache key is the hash of its contents
+    let ply_bytes = std::fs::read(ply_path)?;
+    // Displaced vertices depend on texture images as well, which are not part of the cache key
+    let mesh_cache = ctx.mesh_cache.filter(|_| !mesh.has_displacement(textures));
+    let cache_key = mesh_cache.map(|_| MeshCache::key(&ply_bytes, &mesh.faces, mesh._subdivision_level, bvh_settings));
+    let old_vertex_count = verts._data.len();
+    mesh.faces._type = String::from("triangle");
 
-            // For vertex cache, get the triangles in a single mesh 
-            // TODO: this is done because we have global vertex_data
-            let offset = verts._data.len();
-            let triangles: Vec<Triangle> = mesh.setup(verts, offset);
-            all_triangles.extend(triangles.into_iter());
+    if let Some(cached) = mesh_cache.zip(cache_key.as_deref()).and_then(|(cache, key)| cache.load(key)) {
+        verts._data.extend(cached.vertices);
+        uv_coords.extend(cached.uv_coords);
+        set_ply_faces(mesh, cached.faces.as_deref(), old_vertex_count);
 
-            Ok(())
+        let offset = verts._data.len();
+        mesh.setup_cached(verts, uv_coords, textures, offset, bvh_settings, cached.bvh);
+        return Ok(Some((old_vertex_count, cached.vertex_normals)));
+    }
+
+    let plymesh: PlyMesh = serde_ply::from_reader(ply_bytes.as_slice())?;
+    // Append loaded ply to vertexdata
+    let vertices: Vec<Vector3> = plymesh.vertex.iter().map(|vert| Vector3::new(vert.x as Float, vert.y as Float, vert.z as Float)).collect();
+    let ply_uv_coords: Vec<Option<[Float; 2]>> = plymesh.vertex.iter().map(|vert| match (vert.u, vert.v) {
+        (Some(u), Some(v)) => Some([u as Float, v as Float]),
+        _ => None,
+    }).collect();
+    verts._data.extend_from_slice(&vertices);
+    uv_coords.extend_from_slice(&ply_uv_coords);
+
+    let faces: Option<Vec<usize>> = plymesh.face.map(|faces| {
+        faces.iter()
+             .flat_map(|f| f.vertex_indices.clone()) // each face is a list of 3 indices
+             .collect()
+    });
+    set_ply_faces(mesh, faces.as_deref(), old_vertex_count);
+
+    let offset = verts._data.len();
+    // Cache entries always have a BVH, so it is built for other accelerators as well
+    let setup_bvh_settings = if mesh_cache.is_some() { Some(bvh_settings) } else { ctx.mesh_bvh_settings() };
+    let triangles: Vec<Triangle> = mesh.setup(verts, uv_coords, textures, offset, setup_bvh_settings);
+
+    if let (Some(cache), Some(key)) = (mesh_cache, cache_key) {
+        let vertex_normals = VertexCache::build_normals_in_range(&verts._data[old_vertex_count..], old_vertex_count, &triangles);
+        let bvh = mesh.bvh.as_ref().expect("BVH is built in Mesh::setup( )").to_cached();
+        cache.store(&key, &CachedMesh { vertices, uv_coords: ply_uv_coords, faces, vertex_normals, bvh });
+    }
+    all_triangles.extend(triangles);
+
+    Ok(None)
+}
+
+/// Append vertices of a mesh read by obj::load_obj( ) like PLY vertices (not cached in the mesh cache).
+/// Returns the normals given in the OBJ file, so that they are copied into the scene's normals as in cached meshes.
+fn setup_obj_mesh(
+    mesh: &mut Mesh,
+    obj: &ObjMesh,
+    verts: &mut VertexData,
+    all_triangles: &mut Vec<Triangle>,
+    uv_coords: &mut Vec<Option<[Float; 2]>>,
+    ctx: &MeshSetupContext,
+) -> Option<CachedNormals>
+{
+    let old_vertex_count = verts._data.len();
+    mesh.faces._type = String::from("triangle");
+    mesh.faces._data = obj.faces.iter().flatten().map(|idx| idx + old_vertex_count).collect();
+    mesh.faces._vertex_offset = None;
+    mesh.faces._texture_offset = Some(uv_coords.len() as isize - old_vertex_count as isize);
+    verts._data.extend_from_slice(&obj.positions);
+    uv_coords.extend_from_slice(&obj.uvs);
+
+    let offset = verts._data.len();
+    all_triangles.extend(mesh.setup(verts, uv_coords, ctx.textures, offset, ctx.mesh_bvh_settings()));
+    obj.normals.clone().map(|normals| (old_vertex_count, normals))
+}
+
+/// Set mesh faces from PLY face indices, shifted by the number of vertices before the PLY vertices
+fn set_ply_faces(mesh: &mut Mesh, faces: Option<&[usize]>, old_vertex_count: usize) {
+    if let Some(faces) = faces {
+        mesh.faces._data = faces.iter()
+                                .map(|idx| idx + old_vertex_count) // shift by existing vertices
+                                .collect();
+    }
+    else {
+        warn!("PLY mesh {} has no face data!", mesh._id);
+    }
 }
 
 impl SceneObjects {
 
+    /// Give meshes the BVHs of the meshes with the same id in previous frame, so that
+    /// Mesh::setup( ) refits them instead of building new ones if their faces are the same
+    pub fn reuse_mesh_bvhs(&mut self, previous: &SceneObjects) {
+        let previous_frame = |prev: &Mesh| prev.bvh.clone().map(|bvh| (bvh, prev.faces._data.clone()));
+        for mesh in self.meshes.iter_mut() {
+            if let Some(prev) = previous.meshes.iter().find(|m| m._id == mesh._id) {
+                mesh.previous_frame = previous_frame(prev);
+            }
+        }
+        for lightmesh in self.light_meshes.iter_mut() {
+            if let Some(prev) = previous.light_meshes.iter().find(|m| m.data._id == lightmesh.data._id) {
+                lightmesh.data.previous_frame = previous_frame(&prev.data);
+            }
+        }
+    }
+
+    /// True if both have the same number of objects of every kind, so that the top-level BVH of
+    /// one frame can be refitted to the next one (see Scene3D::new_from_previous( ))
+    pub fn has_same_layout(&self, other: &SceneObjects) -> bool {
+        let counts = |o: &SceneObjects| [o.triangles.len(), o.spheres.len(), o.planes.len(), o.cylinders.len(), o.disks.len(),
+                                         o.cones.len(), o.quads.len(), o.boxes.len(), o.tori.len(), o.sdf_shapes.len(),
+                                         o.curves.len(), o.volumes.len(), o.meshes.len(), o.light_meshes.len(), o.light_spheres.len(),
+                                         o.light_boxes.len(), o.mesh_instances.len(), o.instancers.len(), o.csgs.len()];
+        counts(self) == counts(other)
+    }
+
+    /// Read _objFile of meshes before materials and texture images are used, see obj::load_obj( )
+    fn load_obj_meshes(&mut self, json_dir: &Path, materials: &mut SceneMaterials, textures: &mut Option<Textures>) -> Result<(), Box<dyn Error>> {
+        let light_meshes = self.light_meshes.iter_mut().map(|lightmesh| &mut lightmesh.data);
+        for mesh in self.meshes.iter_mut().chain(light_meshes).filter(|mesh| !mesh._obj_file.is_empty()) {
+            obj::load_obj(mesh, json_dir, materials, textures)?;
+        }
+        Ok(())
+    }
+
     fn setup_transforms(&mut self, transforms: &Transformations) { // TODO: What's the deal with setting matrices within scene? these could be impl in shapes.rs 
 
         for mesh in self.meshes.iter_mut() {
             setup_single_mesh_transform(mesh, transforms);
+            mesh.motion = motion_transform(&mesh.transform, mesh.end_transformation_names.as_deref(), transforms);
         }
         for lightmesh in self.light_meshes.iter_mut() {
             setup_single_mesh_transform(&mut lightmesh.data, transforms);
+            ignore_end_transformations("LightMesh", lightmesh.data._id, &mut lightmesh.data.end_transformation_names);
         }
 
         for mint in self.mesh_instances.iter_mut() {
-            mint.matrix = parse_transform_expression(
+            mint.transform = Transform::new(parse_transform_expression(
                     mint.transformation_names.as_str(),
                     transforms,  
-            );
-            debug!("Composite transform for mesh '{}' is {}", mint._id, mint.matrix);
+            ));
+            mint.end_transform = mint.end_transformation_names.as_deref().map(|names| Transform::new(parse_transform_expression(names, transforms)));
+            debug!("Instance transform for mesh '{}' is {}", mint._id, mint.transform.matrix);
         }
 
+        // Motion of shapes with EndTransformations, starting from their transform set just before
+        let primitive_motion = |data: &CommonPrimitiveData, start: &Option<Arc<Transform>>| start.as_deref()
+                .and_then(|start| motion_transform(start, data.end_transformation_names.as_deref(), transforms))
+                .map(Arc::new);
+
         for tri in self.triangles.iter_mut() {
             debug!("Setting up transforms for mesh._id '{}'", tri._data._id.clone());
-            tri.matrix = Some(Arc::new(parse_transform_expression(
+            tri.transform = Some(Arc::new(Transform::new(parse_transform_expression(
                     tri._data.transformation_names.as_deref().unwrap_or(""),
                     transforms,  
-            )));
+            ))));
+            tri._data.motion = primitive_motion(&tri._data, &tri.transform);
         }
 
         for sphere in self.spheres.iter_mut() {
-            sphere.matrix = Some(Arc::new(parse_transform_expression(
+            sphere.transform = Some(Arc::new(Transform::new(parse_transform_expression(
                 sphere._data.transformation_names.as_deref().unwrap_or(""), 
-                transforms)));
+                transforms))));
+            sphere._data.motion = primitive_motion(&sphere._data, &sphere.transform);
+        }
+
+        // Analytic shapes (see shapes::AnalyticShape)
+        let primitive_transform = |data: &CommonPrimitiveData| Some(Arc::new(Transform::new(parse_transform_expression(
+                data.transformation_names.as_deref().unwrap_or(""),
+                transforms))));
+        for cylinder in self.cylinders.iter_mut() {
+            cylinder.transform = primitive_transform(&cylinder._data);
+            cylinder._data.motion = primitive_motion(&cylinder._data, &cylinder.transform);
+        }
+        for disk in self.disks.iter_mut() {
+            disk.transform = primitive_transform(&disk._data);
+            disk._data.motion = primitive_motion(&disk._data, &disk.transform);
+        }
+        for cone in self.cones.iter_mut() {
+            cone.transform = primitive_transform(&cone._data);
+            cone._data.motion = primitive_motion(&cone._data, &cone.transform);
+        }
+        for quad in self.quads.iter_mut() {
+            quad.transform = primitive_transform(&quad._data);
+            quad._data.motion = primitive_motion(&quad._data, &quad.transform);
+        }
+        for cuboid in self.boxes.iter_mut() {
+            cuboid.transform = primitive_transform(&cuboid._data);
+            cuboid._data.motion = primitive_motion(&cuboid._data, &cuboid.transform);
+        }
+        for torus in self.tori.iter_mut() {
+            torus.transform = primitive_transform(&torus._data);
+            torus._data.motion = primitive_motion(&torus._data, &torus.transform);
+        }
+        for sdf in self.sdf_shapes.iter_mut() {
+            sdf.transform = primitive_transform(&sdf._data);
+            sdf._data.motion = primitive_motion(&sdf._data, &sdf.transform);
+        }
+        for curve in self.curves.iter_mut() {
+            curve.transform = primitive_transform(&curve._data);
+            curve._data.motion = primitive_motion(&curve._data, &curve.transform);
+        }
+        for volume in self.volumes.iter_mut() {
+            volume.transform = Some(Arc::new(Transform::new(parse_transform_expression(
+                volume.transformation_names.as_deref().unwrap_or(""),
+                transforms))));
+        }
+        for instancer in self.instancers.iter_mut() {
+            instancer.transform = Transform::new(parse_transform_expression(
+                instancer.transformation_names.as_deref().unwrap_or(""),
+                transforms));
+        }
+        for light_box in self.light_boxes.iter_mut() {
+            light_box.data.transform = primitive_transform(&light_box.data._data);
+            ignore_end_transformations("LightBox", light_box.data._data._id, &mut light_box.data._data.end_transformation_names);
         }
 
         
         for light_sphere in self.light_spheres.iter_mut() {
-            light_sphere.data.matrix = Some(Arc::new(parse_transform_expression(
+            light_sphere.data.transform = Some(Arc::new(Transform::new(parse_transform_expression(
                 light_sphere.data._data.transformation_names.as_deref().unwrap_or(""), 
-                transforms)));
+                transforms))));
+            ignore_end_transformations("LightSphere", light_sphere.data._data._id, &mut light_sphere.data._data.end_transformation_names);
         }
 
         for plane in self.planes.iter_mut() {
             debug!("Setting up transforms for mesh._id '{}'", plane._data._id.clone());
-            plane.matrix = Some(Arc::new(parse_transform_expression(
+            plane.transform = Some(Arc::new(Transform::new(parse_transform_expression(
                     plane._data.transformation_names.as_deref().unwrap_or(""),
                     transforms,  
-            )));
+            ))));
+            ignore_end_transformations("Plane", plane._data._id, &mut plane._data.end_transformation_names);
         }
     }
 
-    pub fn setup_and_get_cache(&mut self, verts: &mut VertexData, texture_coords: &Option<TexCoordData>, jsonpath: &Path) -> Result<VertexCache, Box<dyn Error>> {
+    pub fn setup_and_get_cache(&mut self, verts: &mut VertexData, texture_coords: &Option<TexCoordData>, textures: Option<&Textures>, bvh_settings: &BVHSettings, accelerator: AcceleratorKind, jsonpath: &Path) -> Result<VertexCache, Box<dyn Error>> {
         // NOTE: Vec::extend( ) pushes a collection of data all at once, 
         // if you have a single object to push, then use Vec::push( )
 
@@ -704,6 +1051,10 @@ impl SceneObjects {
         let mut unbboxable_shapes: ShapeList = Vec::new();
         let mut emissive_shapes: EmissiveShapeList = Vec::new();
         let mut all_triangles: Vec<Triangle> = self.triangles.all();
+
+        // Children of CSG objects are only intersected through them (see csg.rs)
+        let csg_children: HashSet<CsgChildRef> = self.csgs.iter().flat_map(|csg| csg.child_refs.iter().copied()).collect();
+        let standalone = |kind: CsgChildKind, id: usize| !csg_children.contains(&CsgChildRef { kind, id });
         
         // Initiate uv_coords from given texture coords or if not available with a new vector
         let mut uv_coords: Vec<Option<[Float; 2]>> = if let Some(tc) = texture_coords {
@@ -723,7 +1074,14 @@ impl SceneObjects {
         }
 
         bboxable_shapes.extend(self.triangles.all().into_iter().map(|t| Arc::new(t) as HeapAllocatedShape));
-        bboxable_shapes.extend(self.spheres.all().into_iter().map(|s| Arc::new(s) as HeapAllocatedShape));
+        bboxable_shapes.extend(self.spheres.all().into_iter().filter(|s| standalone(CsgChildKind::Sphere, s._data._id)).map(|s| Arc::new(s) as HeapAllocatedShape));
+        bboxable_shapes.extend(self.cylinders.all().into_iter().map(|s| Arc::new(s) as HeapAllocatedShape));
+        bboxable_shapes.extend(self.disks.all().into_iter().map(|s| Arc::new(s) as HeapAllocatedShape));
+        bboxable_shapes.extend(self.cones.all().into_iter().map(|s| Arc::new(s) as HeapAllocatedShape));
+        bboxable_shapes.extend(self.quads.all().into_iter().map(|s| Arc::new(s) as HeapAllocatedShape));
+        bboxable_shapes.extend(self.boxes.all().into_iter().filter(|s| standalone(CsgChildKind::Box, s._data._id)).map(|s| Arc::new(s) as HeapAllocatedShape));
+        bboxable_shapes.extend(self.tori.all().into_iter().filter(|s| standalone(CsgChildKind::Torus, s._data._id)).map(|s| Arc::new(s) as HeapAllocatedShape));
+        bboxable_shapes.extend(self.sdf_shapes.all().into_iter().map(|s| Arc::new(s) as HeapAllocatedShape));
         
         // Assign nonces to light spheres and add them to emissive shapes
         for light_sphere in self.light_spheres.iter_mut() {
@@ -731,6 +1089,11 @@ impl SceneObjects {
             bboxable_shapes.push(Arc::new(light_sphere.clone()) as HeapAllocatedShape);
             emissive_shapes.push(Arc::new(light_sphere.clone()) as Arc<dyn EmissiveShape>);
         }
+        for light_box in self.light_boxes.iter_mut() {
+            light_box.nonce = numeric::next_uuid();
+            bboxable_shapes.push(Arc::new(light_box.clone()) as HeapAllocatedShape);
+            emissive_shapes.push(Arc::new(light_box.clone()) as Arc<dyn EmissiveShape>);
+        }
 
         unbboxable_shapes.extend(self.planes.all().into_iter().map(|p| Arc::new(p) as HeapAllocatedShape));
 
@@ -738,15 +1101,41 @@ impl SceneObjects {
         let json_dir = Path::new(jsonpath)
                     .parent()
                     .unwrap_or(Path::new("."));
+        // Every Bezier segment of curves is a separate shape (see curve.rs), _curveFile is relative to the json as well
+        for curve in self.curves.iter() {
+            bboxable_shapes.extend(curve.segments(json_dir)?.into_iter().map(|s| Arc::new(s) as HeapAllocatedShape));
+        }
+        // Volumes are kept in the scene objects as well, shadow rays go through them (see renderer::shadow_transmittance( ))
+        for volume in self.volumes.iter_mut() {
+            volume.load_grid(json_dir)?;
+            bboxable_shapes.push(Arc::new(volume.clone()) as HeapAllocatedShape);
+        }
         // Convert meshes: UPDATE: do not convert it into individual triangles
         let mut tot_mesh_faces: usize = 0;
+        let mesh_cache = MeshCache::from_env();
+        let mesh_ctx = MeshSetupContext { textures, bvh_settings, mesh_cache: mesh_cache.as_ref(), accelerator };
+        let mut cached_normals: Vec<CachedNormals> = Vec::new();
         for mesh in self.meshes.iter_mut() {
-            unnecessarily_long_setup_function_for_scene_meshes(mesh, json_dir, verts, &mut all_triangles, &mut uv_coords, &mut tot_mesh_faces)?;            
-            bboxable_shapes.push(Arc::new(mesh.clone()) as HeapAllocatedShape);
+            if let Some(normals) = unnecessarily_long_setup_function_for_scene_meshes(mesh, json_dir, verts, &mut all_triangles, &mut uv_coords, &mesh_ctx)? {
+                cached_normals.push(normals);
+            }
+            if !mesh.faces._ply_file.is_empty() || !mesh._obj_file.is_empty() {
+                tot_mesh_faces += mesh.faces._data.len();
+            }
+            mesh.setup_accelerator(accelerator, verts);
+            if standalone(CsgChildKind::Mesh, mesh._id) {
+                bboxable_shapes.push(Arc::new(mesh.clone()) as HeapAllocatedShape);
+            }
         }
 
         for lightmesh in self.light_meshes.iter_mut() {
-            unnecessarily_long_setup_function_for_scene_meshes(&mut lightmesh.data, json_dir, verts, &mut all_triangles, &mut uv_coords, &mut tot_mesh_faces)?;
+            if let Some(normals) = unnecessarily_long_setup_function_for_scene_meshes(&mut lightmesh.data, json_dir, verts, &mut all_triangles, &mut uv_coords, &mesh_ctx)? {
+                cached_normals.push(normals);
+            }
+            if !lightmesh.data.faces._ply_file.is_empty() || !lightmesh.data._obj_file.is_empty() {
+                tot_mesh_faces += lightmesh.data.faces._data.len();
+            }
+            lightmesh.data.setup_accelerator(accelerator, verts);
             
             // Assign random nonce
             lightmesh.nonce = numeric::next_uuid(); // rand::random::<u64>();
@@ -761,18 +1150,34 @@ impl SceneObjects {
         resolve_all_mesh_instances(mesh_instances, meshes);
 
         // Push all mesh instances to scene shapes -----------------
-        for mint in self.mesh_instances.iter() { 
+        for mint in self.mesh_instances.iter().filter(|mint| standalone(CsgChildKind::MeshInstance, mint._id)) { 
             debug!("Before pushing into all_shapes, Mesh instance {} referes base mesh {} ", mint._id, mint.base_mesh.clone().unwrap()._id);
             bboxable_shapes.push(Arc::new(mint.clone()) as HeapAllocatedShape);
         }
+        for instancer in self.instancers.iter() {
+            let instances = instancer.instances(json_dir, &self.meshes, verts)?;
+            info!(">> Instancer {} placed {} copies of mesh {}", instancer._id, instances.len(), instancer.base_mesh_id);
+            bboxable_shapes.extend(instances.into_iter().map(|mint| Arc::new(mint) as HeapAllocatedShape));
+        }
+
+        // CSG objects refer the shapes set up above
+        for csg in resolve_all_csgs(self)? {
+            if standalone(CsgChildKind::Csg, csg._id) {
+                bboxable_shapes.push(csg as HeapAllocatedShape);
+            }
+        }
 
         info!(">> There are {} vertices in the scene (excluding {} instance mesh). Meshes have {} faces in total.", verts._data.len(), self.mesh_instances.len(), tot_mesh_faces);
         self.bboxable_shapes = bboxable_shapes;
         self.unbboxable_shapes = unbboxable_shapes;
         self.emissive_shapes = emissive_shapes;
-        let normals_cache = VertexCache::build_normals(verts, &all_triangles);
+        let mut normals_cache = VertexCache::build_normals(verts, &all_triangles);
+        // Triangles of cached meshes are not in all_triangles, their normals are copied instead
+        for (first, normals) in cached_normals {
+            normals_cache[first..first + normals.len()].copy_from_slice(&normals);
+        }
         
-        let cache = VertexCache { vertex_data: verts.clone(), vertex_normals: normals_cache, uv_coords }; 
+        let cache = VertexCache { vertex_data: verts.clone(), vertex_normals: normals_cache, uv_coords, ..Default::default() }; 
         Ok(cache)
     }
 
@@ -791,6 +1196,8 @@ pub struct VertexCache {
     pub vertex_data: VertexData,
     pub vertex_normals: Vec<Vector3>,
     pub uv_coords: Vec<Option<[Float;2]>>,
+    pub triangle_intersection: TriangleIntersection, // Not vertex data but every triangle intersection needs it, and the cache is passed to all of them
+    pub alpha_masks: Vec<Option<AlphaMask>>, // Indexed by texture map index, same reason as above (see Triangle::is_cut_out( ))
 }
 
 impl Default for VertexCache {
@@ -800,6 +1207,8 @@ impl Default for VertexCache {
             vertex_data: VertexData::default(),
             vertex_normals: Vec::new(),
             uv_coords: Vec::new(),
+            triangle_intersection: TriangleIntersection::default(),
+            alpha_masks: Vec::new(),
         }
     }
 }
@@ -859,27 +1268,31 @@ impl VertexCache {
     }
 
     pub fn build_normals(verts: &VertexData, triangles: &[Triangle]) -> Vec<Vector3> {
+        Self::build_normals_in_range(&verts._data, 0, triangles)
+    }
+
+    /// Normals of vertices[first..first + positions.len()], triangles outside of that range are skipped.
+    /// Used by the mesh cache to get normals of a single PLY mesh, which are the same as build_normals( )
+    /// as long as no other triangle refers to its vertices.
+    pub fn build_normals_in_range(positions: &[Vector3], first: usize, triangles: &[Triangle]) -> Vec<Vector3> {
         // Compute per-vertex normal from neighbouring triangles
-        let vertex_data = verts;//.clone();
-        let mut vertex_normals: Vec<Vector3> = vec![Vector3::ZERO; vertex_data._data.len()];
+        let mut vertex_normals: Vec<Vector3> = vec![Vector3::ZERO; positions.len()];
         for tri in triangles.iter() {
-            let indices = tri.vert_indices;
-            // Check if indices are in bounds of vertex_data
-            if indices.iter().any(|&i| i >= vertex_data._data.len()) {
+            // Check if indices are in bounds of positions
+            if tri.vert_indices.iter().any(|&i| i < first || i - first >= positions.len()) {
                 continue;
             }
-            let v1 = vertex_data._data[indices[0]];
-            let v2 = vertex_data._data[indices[1]];
-            let v3 = vertex_data._data[indices[2]];
+            let indices = tri.vert_indices.map(|i| i - first);
+            let v1 = positions[indices[0]];
+            let v2 = positions[indices[1]];
+            let v3 = positions[indices[2]];
             let edge_ab = v2 - v1;
             let edge_ac = v3 - v1;
             let face_n = edge_ab.cross(edge_ac); // Be careful, not normalized yet, to preserve area contribution from each face
 
             // Sum the area-weighted face normals 
             for &idx in &indices {
-                if idx < vertex_normals.len() {
-                    vertex_normals[idx] += face_n;
-                }
+                vertex_normals[idx] += face_n;
             }
         }
 
@@ -892,3 +1305,42 @@ impl VertexCache {
         vertex_normals
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(_id: usize, base_mesh_id: usize, translation: Vector3, end_translation: Option<Vector3>) -> MeshInstanceField {
+        MeshInstanceField {
+            _id,
+            base_mesh_id,
+            transform: Transform::new(Matrix4::from_translation(translation)),
+            end_transform: end_translation.map(|t| Transform::new(Matrix4::from_translation(t))),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn nested_mesh_instance_motion() {
+        let base = Mesh { _id: 1, transform: Transform::new(Matrix4::from_translation(Vector3::Z)), ..Default::default() };
+        let meshes = SingleOrVec::Single(base);
+        // Instance 3 moves up relative to instance 2, which is fixed at x = 1; instance 4 follows 2 that moves along x
+        let mut instances = SingleOrVec::Multiple(vec![
+            instance(2, 1, Vector3::X, None),
+            instance(3, 2, Vector3::Y, Some(2. * Vector3::Y)),
+            instance(5, 1, Vector3::ZERO, Some(Vector3::X)),
+            instance(4, 5, Vector3::Y, None),
+        ]);
+        resolve_all_mesh_instances(&mut instances, &meshes);
+
+        let origin_at = |mint: &MeshInstanceField, time: Float| match &mint.motion {
+            Some(motion) => motion.at(time).point(&Vector3::ZERO),
+            None => mint.composite.point(&Vector3::ZERO),
+        };
+        let all = instances.all();
+        assert!(origin_at(&all[1], 0.).abs_diff_eq(Vector3::new(1., 1., 1.), 1e-12));
+        assert!(origin_at(&all[1], 1.).abs_diff_eq(Vector3::new(1., 2., 1.), 1e-12)); // Keeps the offset of instance 2
+        assert!(origin_at(&all[3], 0.).abs_diff_eq(Vector3::new(0., 1., 1.), 1e-12));
+        assert!(origin_at(&all[3], 1.).abs_diff_eq(Vector3::new(1., 1., 1.), 1e-12));
+    }
+}
@@ -0,0 +1,444 @@
+/*
+
+    Signed distance field (SDF) shapes, rendered by sphere tracing.
+
+    Distance function is a tree of "_type"d nodes: round_box, capsule and
+    torus primitives combined with smooth_union and repeat (domain repetition)
+    nodes. Displacement optionally moves the surface outward by Perlin noise
+    (see image::perlin_noise( )). Bounding box cannot be derived from a
+    distance function in general, so it is given by BoundsMin and BoundsMax
+    in local space, rays are only marched within it. e.g.
+
+        "SdfShape": {
+            "_id": "1", "Material": "1",
+            "BoundsMin": "-2 -0.5 -2", "BoundsMax": "2 0.5 2",
+            "Distance": { "_type": "repeat", "Period": "1 0 1",
+                "Child": { "_type": "smooth_union", "Smoothness": "0.2", "Children": [
+                    { "_type": "round_box", "HalfSize": "0.3 0.2 0.3", "Radius": "0.05" },
+                    { "_type": "capsule", "A": "0 0 0", "B": "0 0.4 0", "Radius": "0.1" } ] } },
+            "Displacement": { "Amplitude": "0.02", "Scale": "8" }
+        }
+
+    Sphere tracing steps by the distance to the surface, which is only safe if
+    the distance never overestimates it. Smooth union and displacement can
+    break that, set StepScale below 1 if rays miss parts of the surface.
+
+    See https://iquilezles.org/articles/distfunctions/ for the distance functions.
+
+    @date: Oct, 2026
+    @author: Bartu
+*/
+
+use crate::bbox::BBox;
+use crate::image::{perlin_noise, NoiseConversion};
+use crate::interval::Interval;
+use crate::json_structs::VertexData;
+use crate::ray::Ray;
+use crate::shapes::{azimuth_u, valid_t, AnalyticShape, CommonPrimitiveData, SurfaceHit};
+use crate::prelude::*;
+
+// =======================================================================================================
+// Distance function nodes
+// =======================================================================================================
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "_type", rename_all = "snake_case")]
+pub enum SdfNode {
+    RoundBox(SdfRoundBox),
+    Capsule(SdfCapsule),
+    Torus(SdfTorus),
+    SmoothUnion(SdfSmoothUnion),
+    Repeat(SdfRepeat),
+    Empty,
+}
+
+impl Default for SdfNode {
+    fn default() -> Self {
+        debug!("Default for SdfNode called. Setting to Empty...");
+        SdfNode::Empty
+    }
+}
+
+/// Box with rounded edges, HalfSize includes the rounding Radius
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+#[serde(default)]
+pub struct SdfRoundBox {
+    #[serde(deserialize_with = "deser_vec3")]
+    pub center: Vector3,
+    #[serde(deserialize_with = "deser_vec3")]
+    pub half_size: Vector3,
+    #[serde(deserialize_with = "deser_float")]
+    pub radius: Float,
+}
+
+/// Segment from A to B, thickened by Radius
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+#[serde(default)]
+pub struct SdfCapsule {
+    #[serde(deserialize_with = "deser_vec3")]
+    pub a: Vector3,
+    #[serde(deserialize_with = "deser_vec3")]
+    pub b: Vector3,
+    #[serde(deserialize_with = "deser_float")]
+    pub radius: Float,
+}
+
+/// Torus around y axis, same parameters as shapes::Torus
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+#[serde(default)]
+pub struct SdfTorus {
+    #[serde(deserialize_with = "deser_vec3")]
+    pub center: Vector3,
+    #[serde(deserialize_with = "deser_float")]
+    pub major_radius: Float,
+    #[serde(deserialize_with = "deser_float")]
+    pub minor_radius: Float,
+}
+
+/// Union of the children blended within Smoothness distance, 0 gives the plain union
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+#[serde(default)]
+pub struct SdfSmoothUnion {
+    #[serde(deserialize_with = "deser_float")]
+    pub smoothness: Float,
+    pub children: Vec<SdfNode>,
+}
+
+/// Infinite copies of the child, Period apart along every axis with a nonzero Period
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+#[serde(default)]
+pub struct SdfRepeat {
+    #[serde(deserialize_with = "deser_vec3")]
+    pub period: Vector3,
+    pub child: Box<SdfNode>,
+}
+
+impl SdfNode {
+    /// Signed distance from local point p, negative inside
+    pub fn distance(&self, p: Vector3) -> Float {
+        match self {
+            SdfNode::RoundBox(b) => {
+                let q = (p - b.center).abs() - b.half_size + Vector3::splat(b.radius);
+                q.max(Vector3::ZERO).length() + q.max_element().min(0.0) - b.radius
+            }
+            SdfNode::Capsule(c) => {
+                let pa = p - c.a;
+                let ba = c.b - c.a;
+                let len_sqrd = ba.dot(ba);
+                let h = if len_sqrd > 0.0 { (pa.dot(ba) / len_sqrd).clamp(0.0, 1.0) } else { 0.0 };
+                (pa - ba * h).length() - c.radius
+            }
+            SdfNode::Torus(t) => {
+                let p = p - t.center;
+                let q = (p.x * p.x + p.z * p.z).sqrt() - t.major_radius;
+                (q * q + p.y * p.y).sqrt() - t.minor_radius
+            }
+            SdfNode::SmoothUnion(u) => {
+                u.children.iter()
+                          .map(|child| child.distance(p))
+                          .reduce(|a, b| smooth_min(a, b, u.smoothness))
+                          .unwrap_or(Float::INFINITY)
+            }
+            SdfNode::Repeat(r) => {
+                // Move p into the copy around the origin on repeated axes
+                let cell = |x: Float, period: Float| if period > 0.0 { x - period * (x / period).round() } else { x };
+                let q = Vector3::new(cell(p.x, r.period.x), cell(p.y, r.period.y), cell(p.z, r.period.z));
+                r.child.distance(q)
+            }
+            SdfNode::Empty => Float::INFINITY,
+        }
+    }
+}
+
+/// Polynomial smooth minimum, blends a and b where they are closer than k
+#[inline]
+fn smooth_min(a: Float, b: Float, k: Float) -> Float {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+    let h = (k - (a - b).abs()).max(0.0) / k;
+    a.min(b) - h * h * k * 0.25
+}
+
+// =======================================================================================================
+// SDF shape
+// =======================================================================================================
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+#[serde(default)]
+pub struct SdfDisplacement {
+    #[serde(deserialize_with = "deser_float")]
+    pub amplitude: Float, // Largest outward displacement, negative moves the surface inward
+    #[serde(deserialize_with = "deser_float")]
+    pub scale: Float, // Noise frequency as in Perlin texture maps
+}
+
+#[derive(Debug, Deserialize, Clone, SmartDefault)]
+#[serde(default)]
+pub struct SdfShape {
+    #[serde(flatten)]
+    pub(crate) _data: CommonPrimitiveData,
+
+    #[serde(rename = "BoundsMin", deserialize_with = "deser_vec3")]
+    pub bounds_min: Vector3, // Local space
+    #[serde(rename = "BoundsMax", deserialize_with = "deser_vec3")]
+    pub bounds_max: Vector3,
+
+    #[serde(rename = "Distance")]
+    pub distance: SdfNode,
+
+    #[serde(rename = "Displacement")]
+    pub displacement: Option<SdfDisplacement>,
+
+    #[serde(rename = "MaxSteps", deserialize_with = "deser_usize")]
+    #[default = 256]
+    pub max_steps: usize,
+
+    #[serde(rename = "StepScale", deserialize_with = "deser_float")]
+    #[default = 1.0]
+    pub step_scale: Float, // Shrinks every step, below 1 if the distance overestimates (see the top of the file)
+
+    #[serde(rename = "Epsilon", deserialize_with = "deser_float")]
+    #[default = 1e-4]
+    pub epsilon: Float, // Ray hits the surface closer than that, also the finite difference step of normals
+
+    #[serde(rename = "MotionBlur", deserialize_with = "deser_vec3")]
+    pub(crate) motionblur: Vector3,
+
+    #[serde(skip)]
+    pub transform: Option<Arc<Transform>>,
+}
+
+impl SdfShape {
+    /// Signed distance in local space, including displacement
+    fn distance(&self, p: Vector3) -> Float {
+        let d = self.distance.distance(p);
+        match &self.displacement {
+            Some(disp) => d - disp.amplitude * perlin_noise(p, disp.scale, &NoiseConversion::Linear),
+            None => d,
+        }
+    }
+
+    /// Outward normal as the gradient of the distance (central differences)
+    fn normal(&self, p: Vector3) -> Vector3 {
+        let h = self.epsilon;
+        let dx = Vector3::new(h, 0.0, 0.0);
+        let dy = Vector3::new(0.0, h, 0.0);
+        let dz = Vector3::new(0.0, 0.0, h);
+        let gradient = Vector3::new(
+            self.distance(p + dx) - self.distance(p - dx),
+            self.distance(p + dy) - self.distance(p - dy),
+            self.distance(p + dz) - self.distance(p - dz),
+        );
+        gradient.try_normalize().unwrap_or(Vector3::Y)
+    }
+
+    /// Where the ray enters and leaves the bounding box, as ray parameters
+    fn bounds_interval(&self, ray: &Ray) -> Option<(Float, Float)> {
+        let (mut t_enter, mut t_exit) = (Float::NEG_INFINITY, Float::INFINITY);
+        for axis in 0..3 {
+            let inv_d = 1.0 / ray.direction[axis];
+            let t0 = (self.bounds_min[axis] - ray.origin[axis]) * inv_d;
+            let t1 = (self.bounds_max[axis] - ray.origin[axis]) * inv_d;
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+        (t_enter <= t_exit).then_some((t_enter, t_exit))
+    }
+}
+
+impl AnalyticShape for SdfShape {
+    fn data(&self) -> &CommonPrimitiveData { &self._data }
+    fn transform(&self) -> Option<&Transform> { self.transform.as_deref() }
+    fn motionblur(&self) -> Vector3 { self.motionblur }
+
+    fn surface_hit(&self, ray: &Ray, t_interval: &Interval, _verts: &VertexData) -> Option<SurfaceHit> {
+        let (t_enter, t_exit) = self.bounds_interval(ray)?;
+        let t_end = t_exit.min(t_interval.max);
+        let mut t = t_enter.max(t_interval.min).max(0.0);
+        if t > t_end {
+            return None;
+        }
+
+        // Local ray direction is not normalized, distances are divided by its length to step in t.
+        // A ray starting inside marches on the negated distance to find where it leaves.
+        let dir_len = ray.direction.length();
+        let sign = if self.distance(ray.at(t)) < 0.0 { -1.0 } else { 1.0 };
+        let mut hit_t = None;
+        // Secondary rays start on the surface they leave, so a hit counts only after the march
+        // leaves the epsilon band around it, or if the ray crosses the surface while still in it
+        let mut left_band = false;
+        for _ in 0..self.max_steps {
+            let d = sign * self.distance(ray.at(t));
+            if d < 0.0 || (left_band && d < self.epsilon) {
+                hit_t = Some(t);
+                break;
+            }
+            left_band |= d >= self.epsilon;
+            t += self.step_scale * d.max(self.epsilon) / dir_len;
+            if t > t_end {
+                break;
+            }
+        }
+        let t = hit_t.filter(|&t| valid_t(t, t_interval))?;
+        let p = ray.at(t);
+        let normal = self.normal(p);
+
+        // Cylindrical uv around the center of bounds, v grows downwards as Cylinder
+        let center = (self.bounds_min + self.bounds_max) * 0.5;
+        let height = (self.bounds_max.y - self.bounds_min.y).max(Float::EPSILON);
+        let around = Vector3::new(p.z - center.z, 0.0, center.x - p.x);
+        let tangent = (around - normal * normal.dot(around)).try_normalize().unwrap_or_else(|| normal.any_orthonormal_vector());
+        Some(SurfaceHit {
+            t,
+            point: p,
+            normal,
+            tangent,
+            bitangent: tangent.cross(normal), // B x T is the outward normal as in the other shapes
+            uv: [azimuth_u(p.x - center.x, p.z - center.z), (self.bounds_max.y - p.y) / height],
+        })
+    }
+
+    fn local_bbox(&self, _verts: &VertexData) -> BBox {
+        let (min, max) = (self.bounds_min.min(self.bounds_max), self.bounds_min.max(self.bounds_max));
+        BBox::new(min.x, max.x, min.y, max.y, min.z, max.z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interval::FloatConst;
+    use crate::scene::HeapAllocatedVerts;
+    use crate::shapes::Shape;
+
+    /// Unit sphere at the origin (a capsule with A = B) in bounds of half size 2
+    fn unit_sphere() -> SdfShape {
+        SdfShape {
+            bounds_min: Vector3::splat(-2.0),
+            bounds_max: Vector3::splat(2.0),
+            distance: SdfNode::Capsule(SdfCapsule { a: Vector3::ZERO, b: Vector3::ZERO, radius: 1.0 }),
+            ..Default::default()
+        }
+    }
+
+    fn hit(sdf: &SdfShape, origin: Vector3, direction: Vector3) -> Option<SurfaceHit> {
+        sdf.surface_hit(&Ray::new(origin, direction.normalize(), 0.0), &Interval::positive(1e-6), &VertexData::default())
+    }
+
+    #[test]
+    fn primitive_distances() {
+        let round_box = SdfNode::RoundBox(SdfRoundBox { center: Vector3::ZERO, half_size: Vector3::ONE, radius: 0.25 });
+        assert!((round_box.distance(Vector3::new(2.0, 0.0, 0.0)) - 1.0).abs() < 1e-12);
+        assert!((round_box.distance(Vector3::ZERO) + 1.0).abs() < 1e-12);
+
+        let capsule = SdfNode::Capsule(SdfCapsule { a: Vector3::ZERO, b: Vector3::Y, radius: 0.5 });
+        assert!((capsule.distance(Vector3::new(0.0, 3.0, 0.0)) - 1.5).abs() < 1e-12);
+
+        let torus = SdfNode::Torus(SdfTorus { center: Vector3::ZERO, major_radius: 2.0, minor_radius: 0.5 });
+        assert!((torus.distance(Vector3::new(2.0, 0.0, 0.0)) + 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn repeat_and_smooth_union() {
+        let capsule = SdfNode::Capsule(SdfCapsule { a: Vector3::ZERO, b: Vector3::ZERO, radius: 0.5 });
+        let repeat = SdfNode::Repeat(SdfRepeat { period: Vector3::new(3.0, 0.0, 0.0), child: Box::new(capsule.clone()) });
+        assert!((repeat.distance(Vector3::new(6.0, 1.0, 0.0)) - 0.5).abs() < 1e-12);
+
+        // Smooth union never gives more than the plain union
+        let union = SdfNode::SmoothUnion(SdfSmoothUnion { smoothness: 0.5, children: vec![capsule.clone(), repeat] });
+        let p = Vector3::new(1.5, 0.0, 0.0);
+        assert!(union.distance(p) < capsule.distance(p));
+    }
+
+    #[test]
+    fn sphere_traces_known_sphere() {
+        let sdf = unit_sphere();
+        let h = hit(&sdf, Vector3::new(-5.0, 0.0, 0.0), Vector3::X).unwrap();
+        assert!((h.t - 4.0).abs() < sdf.epsilon);
+        assert!(h.normal.abs_diff_eq(-Vector3::X, 1e-6));
+
+        // Finite difference normals follow the surface off the axes too
+        let target = Vector3::new(1.0, 1.0, -1.0).normalize();
+        let h = hit(&sdf, target * 3.0, -target).unwrap();
+        assert!((h.t - 2.0).abs() < sdf.epsilon);
+        assert!(h.normal.abs_diff_eq(target, 1e-6));
+        assert!(h.uv.iter().all(|x| (0.0..=1.0).contains(x)));
+        assert!(h.bitangent.cross(h.tangent).normalize().abs_diff_eq(h.normal, 1e-9));
+
+        assert!(hit(&sdf, Vector3::new(-5.0, 1.5, 0.0), Vector3::X).is_none());
+
+        // Starting inside, the ray marches on the negated distance to where it leaves, normal still points out
+        let h = hit(&sdf, Vector3::new(0.0, 0.5, 0.0), Vector3::Y).unwrap();
+        assert!((h.t - 0.5).abs() < sdf.epsilon);
+        assert!(h.normal.abs_diff_eq(Vector3::Y, 1e-6));
+    }
+
+    #[test]
+    fn secondary_rays_leave_the_surface() {
+        let sdf = unit_sphere();
+        let p = Vector3::new(0.0, 1.0, 0.0);
+
+        // Reflected ray leaving the surface, offset by less than Epsilon
+        assert!(hit(&sdf, p + 1e-6 * Vector3::Y, Vector3::new(1.0, 1.0, 0.0)).is_none());
+        assert!(hit(&sdf, p + 1e-6 * Vector3::Y, Vector3::Y).is_none());
+
+        // Refracted ray entering the surface leaves it on the other side
+        let h = hit(&sdf, p - 1e-6 * Vector3::Y, -Vector3::Y).unwrap();
+        assert!((h.t - 2.0).abs() < sdf.epsilon);
+        assert!(h.normal.abs_diff_eq(-Vector3::Y, 1e-6));
+
+        // Ray starting within Epsilon of the surface but heading into it still hits it
+        let h = hit(&sdf, p + 5e-5 * Vector3::Y, -Vector3::Y).unwrap();
+        assert!(h.t < 2.0 * sdf.epsilon && h.normal.abs_diff_eq(Vector3::Y, 1e-6));
+    }
+
+    #[test]
+    fn marches_only_within_bounds() {
+        // Copies every 3 units along x, bounds keep only the one at the origin
+        let mut sdf = unit_sphere();
+        sdf.bounds_min = Vector3::splat(-1.5);
+        sdf.bounds_max = Vector3::splat(1.5);
+        sdf.distance = SdfNode::Repeat(SdfRepeat { period: Vector3::new(3.0, 0.0, 0.0), child: Box::new(sdf.distance.clone()) });
+
+        let h = hit(&sdf, Vector3::new(-10.0, 0.0, 0.0), Vector3::X).unwrap();
+        assert!((h.t - 9.0).abs() < sdf.epsilon); // Not the copy at x = -3
+        assert!(hit(&sdf, Vector3::new(-10.0, 0.0, 3.0), Vector3::X).is_none()); // Misses the bounds
+    }
+
+    #[test]
+    fn step_scale_and_max_steps() {
+        // From 4 units away, one full step lands on the sphere and the next step finds it
+        let mut sdf = unit_sphere();
+        sdf.max_steps = 2;
+        assert!(hit(&sdf, Vector3::new(-5.0, 0.0, 0.0), Vector3::X).is_some());
+        sdf.max_steps = 1;
+        assert!(hit(&sdf, Vector3::new(-5.0, 0.0, 0.0), Vector3::X).is_none());
+
+        // Half steps need more of them but land at the same place
+        sdf.step_scale = 0.5;
+        sdf.max_steps = 2;
+        assert!(hit(&sdf, Vector3::new(-5.0, 0.0, 0.0), Vector3::X).is_none());
+        sdf.max_steps = 256;
+        let h = hit(&sdf, Vector3::new(-5.0, 0.0, 0.0), Vector3::X).unwrap();
+        assert!((h.t - 4.0).abs() < 2.0 * sdf.epsilon);
+    }
+
+    #[test]
+    fn ray_t_is_kept_under_scaling() {
+        // Sphere of radius 2 in world space, local rays are not normalized
+        let mut sdf = unit_sphere();
+        sdf.transform = Some(Arc::new(Transform::new(Matrix4::from_scale(Vector3::splat(2.0)))));
+        let ray = Ray::new(Vector3::new(-10.0, 0.0, 0.0), Vector3::X, 0.0);
+        let rec = sdf.intersects_with(&ray, &Interval::new(1e-6, FloatConst::INF), &HeapAllocatedVerts::default()).unwrap();
+        assert!((rec.ray_t - 8.0).abs() < 2.0 * sdf.epsilon);
+        assert!((rec.hit_point - Vector3::new(-2.0, 0.0, 0.0)).length() < 2.0 * sdf.epsilon);
+        assert!(rec.normal.abs_diff_eq(-Vector3::X, 1e-6));
+    }
+}
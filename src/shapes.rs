@@ -1,6 +1,6 @@
 /*
 
-    Declare primitives: Triangle, Sphere, Plane
+    Declare primitives: Triangle, Sphere, Plane, Cylinder, Disk, Cone, Quad, Box (Cuboid), Torus
     
 
     @date: Oct, 2025
@@ -8,9 +8,9 @@
 */
 
 use bevy_math::NormedVectorSpace;
-use std::{fmt::Debug};
+use std::{borrow::Cow, fmt::Debug};
 
-use crate::geometry::{get_tri_normal, moller_trumbore_intersection};
+use crate::geometry::{deser_uv_projection, get_tri_normal, triangle_intersection, UvProjection};
 
 use crate::bbox::{BBox, BBoxable};
 use crate::ray::{Ray, HitRecord}; // TODO: Can we create a small crate for gathering shapes.rs, ray.rs?
@@ -47,8 +47,33 @@ pub trait EmissiveShape : Debug + Send + Sync + BBoxable + Shape {
 pub trait Shape : Debug + Send + Sync + BBoxable {
     fn intersects_with(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> Option<HitRecord>;
 
+    /// Any-hit query for shadow rays: true if there is any hit within t_interval.
+    /// Shapes should override it to skip building a HitRecord, default falls back to intersects_with( ).
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        self.intersects_with(ray, t_interval, vertex_cache).is_some()
+    }
+
+    /// Every hit within t_interval in increasing t, appended to hits. Front faces enter and back faces exit
+    /// closed shapes, which is how CSG (see csg.rs) finds where the ray is inside its children.
+    /// Default repeats intersects_with( ) just past the previous hit, shapes can override it.
+    fn all_hits(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts, hits: &mut Vec<HitRecord>) {
+        let mut interval = *t_interval;
+        for _ in 0..MAX_HITS_ALONG_RAY {
+            let Some(hit) = self.intersects_with(ray, &interval, vertex_cache) else { break; };
+            // Step is large enough to skip the same point on the neighbour triangle of a mesh edge
+            interval.min = hit.ray_t + (hit.ray_t.abs() * 1e-9).max(1e-9);
+            hits.push(hit);
+            if interval.min > interval.max {
+                break;
+            }
+        }
+    }
+
 }
 
+// Guard against endless all_hits( ) loops, e.g. if a shape keeps returning the same hit
+const MAX_HITS_ALONG_RAY: usize = 64;
+
 #[derive(Debug, Deserialize, Clone, SmartDefault)]
 pub(crate) struct CommonPrimitiveData {
     #[serde(deserialize_with = "deser_usize")]
@@ -62,6 +87,36 @@ pub(crate) struct CommonPrimitiveData {
 
     #[serde(rename = "Textures", deserialize_with = "deser_usize_vec", default)]
     pub texture_idxs: Vec<usize>,
+
+    #[serde(rename = "UVProjection", deserialize_with = "deser_uv_projection", default)]
+    pub uv_projection: Option<Arc<UvProjection>>, // Generates uv at hit time instead of the shape's own (see geometry::UvProjection)
+
+    #[serde(rename = "AlphaMask", deserialize_with = "deser_opt_usize", default)]
+    pub alpha_mask: Option<usize>, // Image texture map id, triangles are cut out where its alpha is below AlphaThreshold
+
+    #[serde(rename = "EndTransformations", default)]
+    pub end_transformation_names: Option<String>, // Transformations at the end of the shutter, see numeric::AnimatedTransform
+
+    #[serde(skip)]
+    pub motion: Option<Arc<AnimatedTransform>>, // From Transformations to EndTransformations, set in scene setup
+}
+
+impl CommonPrimitiveData {
+    /// Transform of the shape at ray time if it has EndTransformations, otherwise its (start) transform
+    pub(crate) fn transform_at<'a>(&self, time: Float, transform: Option<&'a Transform>) -> Cow<'a, Transform> {
+        match self.motion.as_deref() {
+            Some(motion) => Cow::Owned(motion.at(time)),
+            None => Cow::Borrowed(transform.unwrap_or(&Transform::IDENTITY)),
+        }
+    }
+
+    /// World space box of local_box, covering the whole motion if the shape has EndTransformations
+    pub(crate) fn world_bbox(&self, local_box: &BBox, transform: &Transform) -> BBox {
+        match self.motion.as_deref() {
+            Some(motion) => local_box.transform_animated(motion),
+            None => local_box.transform(&transform.matrix),
+        }
+    }
 }
 
 // =======================================================================================================
@@ -79,7 +134,7 @@ pub struct Triangle {
     pub vert_indices: [usize; 3],
     
     #[serde(skip)]
-    pub matrix: Option<Arc<Matrix4>>, // Arc here to share Transformations with Mesh, I didn't want to clone the same transform while creating triangles for mesh
+    pub transform: Option<Arc<Transform>>, // Arc here to share Transformations with Mesh, I didn't want to clone the same transform while creating triangles for mesh
 
     #[serde(skip)]
     #[default = false]
@@ -100,13 +155,12 @@ impl Shape for Triangle {
         
         // ---- Apply transformation --------
         //TODO: how not to copy paste the same logic for other shapes?
-        let viewmat = self.matrix.clone().unwrap_or(Arc::new(Matrix4::IDENTITY));
-        let inv_matrix = viewmat.inverse(); // TODO: better to cache inverse because of the borrow rules we keep .clone( ) and Arc::new( )
-        let ray = &ray.inverse_transform(&inv_matrix);
+        let transform = &*self._data.transform_at(ray.time, self.transform.as_deref());
+        let ray = &ray.to_local(transform);
         // ----------------------------------
 
         let verts = &vertex_cache.vertex_data;
-        if let Some((bary_beta, bary_gamma, t)) = moller_trumbore_intersection(ray, t_interval, self.vert_indices, verts) {
+        if let Some((bary_beta, bary_gamma, t)) = triangle_intersection(vertex_cache.triangle_intersection, ray, t_interval, self.vert_indices, verts) {
             
             let p = ray.at(t); // Construct hit point p // TODO: would it be faster to use barycentric u,v here? 
             let mut tri_normal = {
@@ -128,6 +182,10 @@ impl Shape for Triangle {
                 }
             };
            
+            if self.is_cut_out(p, bary_beta, bary_gamma, vertex_cache) {
+                return None; // Lets the accelerator go on with the shapes behind
+            }
+
             let front_face = ray.is_front_face(tri_normal);
             tri_normal = if front_face { tri_normal } else { -tri_normal };
 
@@ -136,7 +194,12 @@ impl Shape for Triangle {
             let mut texture_uv = None; 
             let mut tbn = None;
             let texs = self._data.texture_idxs.clone(); // TODO: any better ideas to avoid clone?
-            if !texs.is_empty() {
+            if let Some(projection) = self._data.uv_projection.as_deref().filter(|_| !texs.is_empty()) {
+                let (uv, tbn_matrix) = projection.project(p, tri_normal);
+                texture_uv = Some(uv);
+                tbn = Some(tbn_matrix);
+            }
+            else if !texs.is_empty() {
                 // See slides 06, p.20
                 let (a, b, c) = (self.texture_indices[0], self.texture_indices[1], self.texture_indices[2]);
                 
@@ -153,8 +216,7 @@ impl Shape for Triangle {
                 debug_assert!(uv_b[0] >= NEG_ZERO && uv_b[1] >= NEG_ZERO, "Failed uv_b < 0: ({}, {})", uv_b[0], uv_b[1]);
                 debug_assert!(uv_c[0] >= NEG_ZERO && uv_c[1] >= NEG_ZERO, "Failed uv_c < 0: ({}, {})", uv_c[0], uv_c[1]);
         
-                let tex_u: Float = uv_a[0] + (bary_beta * (uv_b[0] - uv_a[0])) + (bary_gamma * (uv_c[0] - uv_a[0]));
-                let tex_v: Float = uv_a[1] + (bary_beta * (uv_b[1] - uv_a[1])) + (bary_gamma * (uv_c[1] - uv_a[1]));
+                let [tex_u, tex_v] = self.interpolate_uv(bary_beta, bary_gamma, vertex_cache);
 
                 let tex_u = tex_u - tex_u.floor(); // support tiling
                 let tex_v = tex_v - tex_v.floor(); // slides 06, p.30 
@@ -200,7 +262,7 @@ impl Shape for Triangle {
                 // -------------------------------------------------------------------------------------------------------
             }
             let mut rec = HitRecord::new_from(ray.origin, p, tri_normal, t, self._data.material_idx, front_face, texs, texture_uv, tbn);
-            rec.to_world(&viewmat);
+            rec.to_world(transform);
             Some(rec) 
             // --------------------------------------------------------
         }
@@ -209,6 +271,52 @@ impl Shape for Triangle {
         }
         
     }
+
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        let verts = &vertex_cache.vertex_data;
+        let local_ray;
+        let ray = match &self.transform {
+            Some(transform) => {
+                local_ray = ray.to_local(&self._data.transform_at(ray.time, Some(transform)));
+                &local_ray
+            }
+            None => ray, // Mesh triangles are in local space already
+        };
+        match triangle_intersection(vertex_cache.triangle_intersection, ray, t_interval, self.vert_indices, verts) {
+            Some((bary_beta, bary_gamma, t)) => !self.is_cut_out(ray.at(t), bary_beta, bary_gamma, vertex_cache),
+            None => false,
+        }
+    }
+}
+
+impl Triangle {
+
+    /// Texture coordinates at barycentric coordinates (beta, gamma), not wrapped into [0, 1] yet
+    fn interpolate_uv(&self, bary_beta: Float, bary_gamma: Float, vertex_cache: &HeapAllocatedVerts) -> [Float; 2] {
+        let [uv_a, uv_b, uv_c] = self.texture_indices.map(|i| vertex_cache.uv_coords.get(i).copied().flatten().unwrap_or_default());
+        let tex_u: Float = uv_a[0] + (bary_beta * (uv_b[0] - uv_a[0])) + (bary_gamma * (uv_c[0] - uv_a[0]));
+        let tex_v: Float = uv_a[1] + (bary_beta * (uv_b[1] - uv_a[1])) + (bary_gamma * (uv_c[1] - uv_a[1]));
+        [tex_u, tex_v]
+    }
+
+    /// True if the triangle has an alpha mask that is transparent at the local hit point p.
+    /// Checked inside intersection so that both camera and shadow rays pass through cut out parts.
+    fn is_cut_out(&self, p: Vector3, bary_beta: Float, bary_gamma: Float, vertex_cache: &HeapAllocatedVerts) -> bool {
+        let Some(mask) = self._data.alpha_mask
+            .and_then(|texmap_id| texmap_id.checked_sub(1))
+            .and_then(|i| vertex_cache.alpha_masks.get(i))
+            .and_then(Option::as_ref) else { return false; };
+
+        let uv = match self._data.uv_projection.as_deref() {
+            Some(projection) => {
+                let [a, b, c] = self.vert_indices.map(|i| vertex_cache.vertex_data[i]);
+                let normal = if self.normal.norm_squared() > 0.0 { self.normal } else { get_tri_normal(&a, &b, &c) };
+                projection.project(p, normal).0
+            }
+            None => self.interpolate_uv(bary_beta, bary_gamma, vertex_cache),
+        };
+        mask.is_transparent(uv)
+    }
 }
 
 
@@ -225,8 +333,8 @@ impl BBoxable for Triangle {
 
         let local_box = BBox::new_from(&xint, &yint, &zint);
         if apply_t {
-            if let Some(matrix) = &self.matrix {
-                local_box.transform(matrix)
+            if let Some(transform) = &self.transform {
+                self._data.world_bbox(&local_box, transform)
             } else {
                 warn!("No transformation matrix found for Triangle. Returning local bounding box.");
                 local_box
@@ -254,23 +362,18 @@ pub struct Sphere {
     pub(crate) motionblur: Vector3, 
 
     #[serde(skip)]
-    pub matrix: Option<Arc<Matrix4>>, // Arc here to share Transformations with Mesh, I didn't want to clone the same transform while creating triangles for mesh
+    pub transform: Option<Arc<Transform>>, // Arc here to share Transformations with Mesh, I didn't want to clone the same transform while creating triangles for mesh
 
 }
 
 impl Sphere {
-    fn intersect(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts)
-        -> Option<HitRecord>
-    {
+    /// Returns (t in world space, hit point in local space, hit point in world space) if the ray hits the sphere
+    /// within t_interval. Shared by intersect( ) and occluded( ) so that shadow rays agree with camera rays.
+    fn hit_point(&self, ray: &Ray, t_interval: &Interval, transform: &Transform, center: Vector3) -> Option<(Float, Vector3, Vector3)> {
         // --- Transform ray into local space ---
-        let viewmat = self.matrix.clone().unwrap_or(Arc::new(Matrix4::IDENTITY));
-        let inv_matrix = viewmat.inverse();
-        let local_ray = &ray.inverse_transform(&inv_matrix);
+        let local_ray = &ray.to_local(transform);
 
         // --- Sphere intersection in local space ---
-        let verts = &vertex_cache.vertex_data;
-        let center = verts[self.center_idx];
-
         let o_minus_c = local_ray.origin - center;
         let a: Float = local_ray.direction.dot(local_ray.direction);
         let b: Float = 2.0 * local_ray.direction.dot(o_minus_c);
@@ -284,12 +387,12 @@ impl Sphere {
         let t1 = (-b - sqrt_d) / (2.0*a); 
         let t2 = (-b + sqrt_d) / (2.0*a); 
 
-        // Pick the closer t
-        let t_local = if t1 > 0.0 { t1 } else if t2 > 0.0 { t2 } else { return None; };
+        // Pick the closer t within the interval (local ray direction is not normalized, so t is the same in world space)
+        let t_local = [t1, t2].into_iter().find(|&t| t > 0.0 && t_interval.contains(t))?;
 
         // Compute hit in local space and then transform back  to world
         let p_local = local_ray.at(t_local);
-        let p_world = transform_point(&viewmat, &p_local); 
+        let p_world = transform.point(&p_local); 
 
         // Update ray t to worlds space
         let ray_dir_lensqrd = ray.direction.dot(ray.direction);
@@ -300,11 +403,19 @@ impl Sphere {
         if !t_interval.contains(t_world) || t_world <= 0.0 {
             return None;
         }
+        Some((t_world, p_local, p_world))
+    }
+
+    fn intersect(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts)
+        -> Option<HitRecord>
+    {
+        let transform = &*self._data.transform_at(ray.time, self.transform.as_deref());
+        let center = vertex_cache.vertex_data[self.center_idx];
+        let (t_world, p_local, p_world) = self.hit_point(ray, t_interval, transform, center)?;
 
         // World space normal
         let local_normal = (p_local - center).normalize();
-        let mut world_normal = transform_normal(&viewmat, &local_normal); 
-        if world_normal.norm_squared() > 0.0 { world_normal = world_normal.normalize(); }
+        let world_normal = transform.normal(&local_normal);
 
         // Check front face and build hitrecord (I was transforming hitrecord::to_world( ) but here it is already transformed.)
         let front_face = ray.is_front_face(world_normal);
@@ -313,7 +424,12 @@ impl Sphere {
         // Check texture uv coords
         let mut tbn = None;
         let mut uv = None;
-        if !self._data.texture_idxs.is_empty() { 
+        if let Some(projection) = self._data.uv_projection.as_deref().filter(|_| !self._data.texture_idxs.is_empty()) {
+            let (projected_uv, local_tbn) = projection.project(p_local, local_normal);
+            uv = Some(projected_uv);
+            tbn = Some(Matrix3::from_cols(transform.dir(&local_tbn.x_axis).normalize(), transform.dir(&local_tbn.y_axis).normalize(), world_normal));
+        }
+        else if !self._data.texture_idxs.is_empty() { 
             // See slides 06, p.6-7
             // (assumes sphere center is at origin, so we translate hitpoint by the center)
             let p = p_local - center; 
@@ -351,8 +467,8 @@ impl Sphere {
 
         let local_box = BBox::new_from(&xint, &yint, &zint);
         if apply_t {
-            if let Some(matrix) = &self.matrix {
-                local_box.transform(matrix) // return transformed bbox
+            if let Some(transform) = &self.transform {
+                self._data.world_bbox(&local_box, transform) // return transformed bbox
             } else {
                 warn!("No transformation matrix found for Sphere. Returning local bounding box.");
                 local_box
@@ -369,6 +485,12 @@ impl Shape for Sphere {
     fn intersects_with(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> Option<HitRecord> {
         self.intersect(ray, t_interval, vertex_cache)
     }
+
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        let transform = self._data.transform_at(ray.time, self.transform.as_deref());
+        let center = vertex_cache.vertex_data[self.center_idx];
+        self.hit_point(ray, t_interval, &transform, center).is_some()
+    }
 }
 
 impl BBoxable for Sphere {
@@ -404,6 +526,10 @@ impl Shape for LightSphere {
         }
         
     }
+
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        self.data.occluded(ray, t_interval, vertex_cache)
+    }
 }
 
 impl BBoxable for LightSphere {
@@ -433,10 +559,10 @@ impl EmissiveShape for LightSphere {
         let mut center_world = center_local;
         let mut radius_world = self.data.radius;
         
-        if let Some(mat) = self.data.matrix.clone() {
-            debug!("Applying the following matrix to obtain sphere center in world coordinates: \n {:?}", mat);
-            center_world = transform_point(&mat, &center_world);
-            let max_scale = numeric::max_scale(&mat, true);
+        if let Some(transform) = &self.data.transform {
+            debug!("Applying the following matrix to obtain sphere center in world coordinates: \n {:?}", transform.matrix);
+            center_world = transform.point(&center_world);
+            let max_scale = numeric::max_scale(&transform.matrix, true);
             radius_world *= max_scale;
         } 
         
@@ -489,24 +615,14 @@ pub struct Plane {
     pub(crate) motionblur: Vector3, 
 
     #[serde(skip)]
-    pub matrix: Option<Arc<Matrix4>>, // Arc here to share Transformations with Mesh, I didn't want to clone the same transform while creating triangles for mesh
+    pub transform: Option<Arc<Transform>>, // Arc here to share Transformations with Mesh, I didn't want to clone the same transform while creating triangles for mesh
 
 
 }
 
-impl Shape for Plane {
-    fn intersects_with(
-        &self,
-        ray: &Ray,
-        t_interval: &Interval,
-        vertex_cache: &HeapAllocatedVerts
-    ) -> Option<HitRecord> {
-
-        // --- Transform ray ---
-        let viewmat = self.matrix.clone().unwrap_or(Arc::new(Matrix4::IDENTITY));
-        let inv_matrix = viewmat.inverse();
-        let ray = &ray.inverse_transform(&inv_matrix);
-        // ---------------------
+impl Plane {
+    /// Ray parameter of the hit, given the ray in plane's local space
+    fn local_hit_t(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> Option<Float> {
         let verts = &vertex_cache.vertex_data;
         let p0 = verts[self.point_idx];
         let n = self.normal;
@@ -529,27 +645,50 @@ impl Shape for Plane {
         if !t_interval.contains(t) {
             return None;
         }
+        Some(t)
+    }
+}
+
+impl Shape for Plane {
+    fn intersects_with(
+        &self,
+        ray: &Ray,
+        t_interval: &Interval,
+        vertex_cache: &HeapAllocatedVerts
+    ) -> Option<HitRecord> {
+
+        // --- Transform ray ---
+        let transform = self.transform.as_deref().unwrap_or(&Transform::IDENTITY);
+        let ray = &ray.to_local(transform);
+        // ---------------------
+        let t = self.local_hit_t(ray, t_interval, vertex_cache)?;
+        let n = self.normal;
 
         // Construct Hit Record
         let front_face = ray.is_front_face(n);
         let normal = if front_face { n } else { -n };
 
         // Check texture uv coords
-        let uv = Some([-999999., -99999.]); // Dummy uv is set //todo!("Create uv for Plane hits (i guess we leave it None for planes, no?)!");
-        
-        // Compute TBN for plane
-        let n = self.normal; 
-        debug_assert!(n.is_normalized());
-        let reference = if n.x.abs() < 0.9 {
-            Vector3::X
+        let (uv, tbn) = if let Some(projection) = self._data.uv_projection.as_deref() {
+            let (uv, tbn) = projection.project(ray.at(t), self.normal);
+            (Some(uv), Some(tbn))
         } else {
-            Vector3::Y
+            let uv = Some([-999999., -99999.]); // Dummy uv is set, only noise textures work without a UVProjection
+
+            // Compute TBN for plane
+            let n = self.normal; 
+            debug_assert!(n.is_normalized());
+            let reference = if n.x.abs() < 0.9 {
+                Vector3::X
+            } else {
+                Vector3::Y
+            };
+            let t_vec = n.cross(reference).normalize();
+            let b_vec = n.cross(t_vec).normalize();
+            let tbn = Matrix3::from_cols(t_vec, b_vec, n);
+            (uv, Some(tbn))
+            //let tbn = None; // todo!("Construct TBN for plane!");
         };
-        let t_vec = n.cross(reference).normalize();
-        let b_vec = n.cross(t_vec).normalize();
-        let tbn = Matrix3::from_cols(t_vec, b_vec, n);
-        let tbn = Some(tbn);
-        //let tbn = None; // todo!("Construct TBN for plane!");
         
         
         
@@ -557,9 +696,17 @@ impl Shape for Plane {
         let mut rec = HitRecord::new_from(ray.origin, ray.at(t), normal, t, self._data.material_idx, front_face, texs, uv, tbn);
 
         // transform hitpoint and normal (04, p.53) -----
-        rec.to_world(&viewmat);
+        rec.to_world(transform);
         Some(rec)
     }
+
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        let local_ray = match &self.transform {
+            Some(transform) => ray.to_local(transform),
+            None => ray.clone(),
+        };
+        self.local_hit_t(&local_ray, t_interval, vertex_cache).is_some()
+    }
 }
 
 
@@ -571,3 +718,937 @@ impl BBoxable for Plane {
         todo!();
     }
 }
+
+// =======================================================================================================
+// Analytic shapes: Cylinder, Disk, Cone, Quad, Box, Torus (impl Shape + BBoxable through AnalyticShape)
+// =======================================================================================================
+// They are defined in their local space with y axis up (as the poles of Sphere's uv), use Transformations
+// to place and orient them. (u, v) follow Sphere's convention: u goes around y axis and v grows downwards,
+// so that B x T is the outward normal as bump mapping expects (see Textures::get_bump_mapping( )).
+
+/// Local space hit of an analytic shape
+pub(crate) struct SurfaceHit {
+    pub(crate) t: Float,
+    pub(crate) point: Vector3,
+    pub(crate) normal: Vector3, // Outward, not flipped towards the ray
+    pub(crate) tangent: Vector3, // Direction of dp/du, not necessarily normalized
+    pub(crate) bitangent: Vector3, // Direction of dp/dv, not necessarily normalized
+    pub(crate) uv: [Float; 2],
+}
+
+/// Shared intersection, occlusion and bounding box logic of the analytic shapes, each of
+/// them only implements the local space parts
+pub(crate) trait AnalyticShape: Debug + Send + Sync {
+    fn data(&self) -> &CommonPrimitiveData;
+    fn transform(&self) -> Option<&Transform>;
+    fn motionblur(&self) -> Vector3;
+    fn surface_hit(&self, ray: &Ray, t_interval: &Interval, verts: &VertexData) -> Option<SurfaceHit>;
+    fn local_bbox(&self, verts: &VertexData) -> BBox;
+
+    /// Ray in local space, moved by motion blur like Mesh::intersect( )
+    #[inline]
+    fn local_ray(&self, ray: &Ray) -> Ray {
+        let mut ray = ray.clone();
+        ray.origin += self.motionblur() * ray.time;
+        ray.to_local(&self.data().transform_at(ray.time, self.transform()))
+    }
+}
+
+impl<T: AnalyticShape> Shape for T {
+    fn intersects_with(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> Option<HitRecord> {
+        let transform = &*self.data().transform_at(ray.time, self.transform());
+        let local_ray = self.local_ray(ray);
+        // NOTE: local ray direction is not normalized, so t is the same in local and world space
+        let hit = self.surface_hit(&local_ray, t_interval, &vertex_cache.vertex_data)?;
+
+        let front_face = local_ray.is_front_face(hit.normal);
+        let normal = if front_face { hit.normal } else { -hit.normal };
+
+        let data = self.data();
+        let mut uv = None;
+        let mut tbn = None;
+        if !data.texture_idxs.is_empty() {
+            let (hit_uv, tangent, bitangent) = match data.uv_projection.as_deref() {
+                Some(projection) => {
+                    let (uv, tbn) = projection.project(hit.point, hit.normal);
+                    (uv, tbn.x_axis, tbn.y_axis)
+                },
+                None => (hit.uv, hit.tangent, hit.bitangent),
+            };
+            uv = Some(hit_uv);
+            // TBN in world space since hitrecord normal is also in world space (see slides 07, pp.10-16)
+            let t_vec = transform.dir(&tangent).normalize();
+            let b_vec = transform.dir(&bitangent).normalize();
+            tbn = Some(Matrix3::from_cols(t_vec, b_vec, transform.normal(&hit.normal)));
+        }
+
+        let mut rec = HitRecord::new_from(local_ray.origin, hit.point, normal, hit.t, data.material_idx, front_face, data.texture_idxs.clone(), uv, tbn);
+        rec.to_world(transform);
+        // Where the moving shape is at ray time, as the ray was moved instead of the shape
+        let motion = self.motionblur() * ray.time;
+        rec.entry_point -= motion;
+        rec.hit_point -= motion;
+        Some(rec)
+    }
+
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        self.surface_hit(&self.local_ray(ray), t_interval, &vertex_cache.vertex_data).is_some()
+    }
+}
+
+impl<T: AnalyticShape> BBoxable for T {
+    fn get_bbox(&self, verts: &VertexData, apply_t: bool) -> BBox {
+        let local_box = self.local_bbox(verts);
+        if !apply_t {
+            return local_box;
+        }
+        let world_box = match self.transform() {
+            Some(transform) => self.data().world_bbox(&local_box, transform),
+            None => {
+                warn!("No transformation matrix found for {:?}. Returning local bounding box.", self.data()._id);
+                local_box
+            }
+        };
+        // Ray origin is moved along motion blur, so the shape sweeps the opposite direction
+        world_box.expand_by_motion(-self.motionblur())
+    }
+}
+
+/// Returns the roots of a*t^2 + b*t + c = 0 in increasing order, degenerate (a = 0) case gives a double root
+fn solve_quadratic(a: Float, b: Float, c: Float) -> Option<(Float, Float)> {
+    if a == 0.0 {
+        if b == 0.0 {
+            return None;
+        }
+        let t = -c / b;
+        return Some((t, t));
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    // Numerically stable form, avoids cancellation when b is close to sqrt(discriminant)
+    let q = if b < 0.0 { -0.5 * (b - discriminant.sqrt()) } else { -0.5 * (b + discriminant.sqrt()) };
+    if q == 0.0 {
+        return Some((0.0, 0.0)); // b = c = 0
+    }
+    let (t0, t1) = (q / a, c / q);
+    Some((t0.min(t1), t0.max(t1)))
+}
+
+#[inline]
+pub(crate) fn valid_t(t: Float, t_interval: &Interval) -> bool {
+    t > 0.0 && t_interval.contains(t)
+}
+
+/// u coordinate around y axis given local x and z, same as Sphere
+#[inline]
+pub(crate) fn azimuth_u(x: Float, z: Float) -> Float {
+    (Float::PI - z.atan2(x)) / (2. * Float::PI)
+}
+
+// -------------------------------------------------------------------------------------------------------
+// Cylinder
+// -------------------------------------------------------------------------------------------------------
+
+/// Open cylinder (without caps, add Disks to close it) from Center to Center + Height along y axis
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Cylinder {
+    #[serde(flatten)]
+    pub(crate) _data: CommonPrimitiveData,
+
+    #[serde(rename = "Center", deserialize_with = "deser_usize")]
+    pub center_idx: usize, // Center of the bottom, refers to VertexData
+    #[serde(rename = "Radius", deserialize_with = "deser_float")]
+    pub radius: Float,
+    #[serde(rename = "Height", deserialize_with = "deser_float")]
+    pub height: Float,
+
+    #[serde(rename = "MotionBlur", deserialize_with = "deser_vec3", default)]
+    pub(crate) motionblur: Vector3,
+
+    #[serde(skip)]
+    pub transform: Option<Arc<Transform>>,
+}
+
+impl AnalyticShape for Cylinder {
+    fn data(&self) -> &CommonPrimitiveData { &self._data }
+    fn transform(&self) -> Option<&Transform> { self.transform.as_deref() }
+    fn motionblur(&self) -> Vector3 { self.motionblur }
+
+    fn surface_hit(&self, ray: &Ray, t_interval: &Interval, verts: &VertexData) -> Option<SurfaceHit> {
+        let center = verts[self.center_idx];
+        let (o, d) = (ray.origin - center, ray.direction);
+
+        // x^2 + z^2 = r^2
+        let a = d.x * d.x + d.z * d.z;
+        let b = 2.0 * (o.x * d.x + o.z * d.z);
+        let c = o.x * o.x + o.z * o.z - self.radius * self.radius;
+        let (t0, t1) = solve_quadratic(a, b, c)?;
+
+        // Closer root may be outside of the height range while the further one is not
+        for t in [t0, t1] {
+            if !valid_t(t, t_interval) {
+                continue;
+            }
+            let p = o + d * t;
+            if p.y < 0.0 || p.y > self.height {
+                continue;
+            }
+            return Some(SurfaceHit {
+                t,
+                point: center + p,
+                normal: Vector3::new(p.x, 0.0, p.z) / self.radius,
+                tangent: Vector3::new(p.z, 0.0, -p.x),
+                bitangent: -Vector3::Y,
+                uv: [azimuth_u(p.x, p.z), 1.0 - p.y / self.height],
+            });
+        }
+        None
+    }
+
+    fn local_bbox(&self, verts: &VertexData) -> BBox {
+        let center = verts[self.center_idx];
+        BBox::new_from(&Interval::new(center.x - self.radius, center.x + self.radius),
+                       &Interval::new(center.y, center.y + self.height),
+                       &Interval::new(center.z - self.radius, center.z + self.radius))
+    }
+}
+
+// -------------------------------------------------------------------------------------------------------
+// Disk
+// -------------------------------------------------------------------------------------------------------
+
+/// Disk (or annulus if InnerRadius is given) at Center facing +y
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Disk {
+    #[serde(flatten)]
+    pub(crate) _data: CommonPrimitiveData,
+
+    #[serde(rename = "Center", deserialize_with = "deser_usize")]
+    pub center_idx: usize, // Refers to VertexData
+    #[serde(rename = "Radius", deserialize_with = "deser_float")]
+    pub radius: Float,
+    #[serde(rename = "InnerRadius", deserialize_with = "deser_float", default)]
+    pub inner_radius: Float,
+
+    #[serde(rename = "MotionBlur", deserialize_with = "deser_vec3", default)]
+    pub(crate) motionblur: Vector3,
+
+    #[serde(skip)]
+    pub transform: Option<Arc<Transform>>,
+}
+
+impl AnalyticShape for Disk {
+    fn data(&self) -> &CommonPrimitiveData { &self._data }
+    fn transform(&self) -> Option<&Transform> { self.transform.as_deref() }
+    fn motionblur(&self) -> Vector3 { self.motionblur }
+
+    fn surface_hit(&self, ray: &Ray, t_interval: &Interval, verts: &VertexData) -> Option<SurfaceHit> {
+        let center = verts[self.center_idx];
+        let (o, d) = (ray.origin - center, ray.direction);
+        if d.y == 0.0 {
+            return None; // Parallel to the disk
+        }
+
+        let t = -o.y / d.y;
+        if !valid_t(t, t_interval) {
+            return None;
+        }
+        let p = o + d * t;
+        let dist2 = p.x * p.x + p.z * p.z;
+        if dist2 > self.radius * self.radius || dist2 < self.inner_radius * self.inner_radius {
+            return None;
+        }
+
+        // v grows outwards from the inner radius, radial direction is undefined at the center
+        let dist = dist2.sqrt();
+        let radial = if dist > 0.0 { Vector3::new(p.x, 0.0, p.z) / dist } else { Vector3::X };
+        Some(SurfaceHit {
+            t,
+            point: Vector3::new(center.x + p.x, center.y, center.z + p.z),
+            normal: Vector3::Y,
+            tangent: Vector3::new(radial.z, 0.0, -radial.x),
+            bitangent: radial,
+            uv: [azimuth_u(p.x, p.z), (dist - self.inner_radius) / (self.radius - self.inner_radius)],
+        })
+    }
+
+    fn local_bbox(&self, verts: &VertexData) -> BBox {
+        let center = verts[self.center_idx];
+        BBox::new_from(&Interval::new(center.x - self.radius, center.x + self.radius),
+                       &Interval::new(center.y, center.y),
+                       &Interval::new(center.z - self.radius, center.z + self.radius))
+    }
+}
+
+// -------------------------------------------------------------------------------------------------------
+// Cone
+// -------------------------------------------------------------------------------------------------------
+
+/// Open cone (without base, add a Disk to close it) with base at Center and apex at Center + Height along y axis
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Cone {
+    #[serde(flatten)]
+    pub(crate) _data: CommonPrimitiveData,
+
+    #[serde(rename = "Center", deserialize_with = "deser_usize")]
+    pub center_idx: usize, // Center of the base, refers to VertexData
+    #[serde(rename = "Radius", deserialize_with = "deser_float")]
+    pub radius: Float, // Radius of the base
+    #[serde(rename = "Height", deserialize_with = "deser_float")]
+    pub height: Float,
+
+    #[serde(rename = "MotionBlur", deserialize_with = "deser_vec3", default)]
+    pub(crate) motionblur: Vector3,
+
+    #[serde(skip)]
+    pub transform: Option<Arc<Transform>>,
+}
+
+impl AnalyticShape for Cone {
+    fn data(&self) -> &CommonPrimitiveData { &self._data }
+    fn transform(&self) -> Option<&Transform> { self.transform.as_deref() }
+    fn motionblur(&self) -> Vector3 { self.motionblur }
+
+    fn surface_hit(&self, ray: &Ray, t_interval: &Interval, verts: &VertexData) -> Option<SurfaceHit> {
+        let center = verts[self.center_idx];
+        let (o, d) = (ray.origin - center, ray.direction);
+
+        // x^2 + z^2 = k (h - y)^2 where k = (r / h)^2
+        let k = (self.radius / self.height).powi(2);
+        let h_minus_oy = self.height - o.y;
+        let a = d.x * d.x + d.z * d.z - k * d.y * d.y;
+        let b = 2.0 * (o.x * d.x + o.z * d.z + k * h_minus_oy * d.y);
+        let c = o.x * o.x + o.z * o.z - k * h_minus_oy * h_minus_oy;
+        let (t0, t1) = solve_quadratic(a, b, c)?;
+
+        // Equation above is a double cone, the range check drops the mirrored one above the apex
+        for t in [t0, t1] {
+            if !valid_t(t, t_interval) {
+                continue;
+            }
+            let p = o + d * t;
+            if p.y < 0.0 || p.y > self.height {
+                continue;
+            }
+            // Normal is the gradient of the implicit equation, it is undefined at the apex
+            let normal = Vector3::new(p.x, k * (self.height - p.y), p.z).try_normalize().unwrap_or(Vector3::Y);
+            return Some(SurfaceHit {
+                t,
+                point: center + p,
+                normal,
+                tangent: Vector3::new(p.z, 0.0, -p.x),
+                bitangent: Vector3::new(p.x, p.y - self.height, p.z), // From apex towards the base
+                uv: [azimuth_u(p.x, p.z), 1.0 - p.y / self.height],
+            });
+        }
+        None
+    }
+
+    fn local_bbox(&self, verts: &VertexData) -> BBox {
+        let center = verts[self.center_idx];
+        BBox::new_from(&Interval::new(center.x - self.radius, center.x + self.radius),
+                       &Interval::new(center.y, center.y + self.height),
+                       &Interval::new(center.z - self.radius, center.z + self.radius))
+    }
+}
+
+// -------------------------------------------------------------------------------------------------------
+// Quad
+// -------------------------------------------------------------------------------------------------------
+
+/// Parallelogram with corners Corner, Corner + EdgeU, Corner + EdgeU + EdgeV, Corner + EdgeV.
+/// Its front face is towards EdgeU x EdgeV, u grows along EdgeU and v along -EdgeV.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Quad {
+    #[serde(flatten)]
+    pub(crate) _data: CommonPrimitiveData,
+
+    #[serde(rename = "Corner", deserialize_with = "deser_usize")]
+    pub corner_idx: usize, // Refers to VertexData
+    #[serde(rename = "EdgeU", deserialize_with = "deser_vec3")]
+    pub edge_u: Vector3,
+    #[serde(rename = "EdgeV", deserialize_with = "deser_vec3")]
+    pub edge_v: Vector3,
+
+    #[serde(rename = "MotionBlur", deserialize_with = "deser_vec3", default)]
+    pub(crate) motionblur: Vector3,
+
+    #[serde(skip)]
+    pub transform: Option<Arc<Transform>>,
+}
+
+impl AnalyticShape for Quad {
+    fn data(&self) -> &CommonPrimitiveData { &self._data }
+    fn transform(&self) -> Option<&Transform> { self.transform.as_deref() }
+    fn motionblur(&self) -> Vector3 { self.motionblur }
+
+    fn surface_hit(&self, ray: &Ray, t_interval: &Interval, verts: &VertexData) -> Option<SurfaceHit> {
+        let corner = verts[self.corner_idx];
+        let n = self.edge_u.cross(self.edge_v);
+        let denom = ray.direction.dot(n);
+        if denom == 0.0 {
+            return None; // Parallel to the quad (or degenerate quad)
+        }
+
+        let t = (corner - ray.origin).dot(n) / denom;
+        if !valid_t(t, t_interval) {
+            return None;
+        }
+
+        // Coordinates of the hit point along the edges (see RTOW: The Next Week, 6.4)
+        let p = ray.at(t);
+        let w = n / n.dot(n);
+        let rel = p - corner;
+        let alpha = w.dot(rel.cross(self.edge_v));
+        let beta = w.dot(self.edge_u.cross(rel));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+
+        Some(SurfaceHit {
+            t,
+            point: p,
+            normal: n.normalize(),
+            tangent: self.edge_u,
+            bitangent: -self.edge_v,
+            uv: [alpha, 1.0 - beta],
+        })
+    }
+
+    fn local_bbox(&self, verts: &VertexData) -> BBox {
+        let corner = verts[self.corner_idx];
+        let (mut xint, mut yint, mut zint) = (Interval::EMPTY, Interval::EMPTY, Interval::EMPTY);
+        for v in [corner, corner + self.edge_u, corner + self.edge_u + self.edge_v, corner + self.edge_v] {
+            xint.expand(v.x);
+            yint.expand(v.y);
+            zint.expand(v.z);
+        }
+        BBox::new_from(&xint, &yint, &zint)
+    }
+}
+
+// -------------------------------------------------------------------------------------------------------
+// Box
+// -------------------------------------------------------------------------------------------------------
+
+/// "Box" in scene JSON (renamed to not shadow std Box), axis aligned between Min and Max corners in
+/// its local space, use Transformations to orient it. Every face has its own [0, 1] uv range.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Cuboid {
+    #[serde(flatten)]
+    pub(crate) _data: CommonPrimitiveData,
+
+    #[serde(rename = "Min", deserialize_with = "deser_usize")]
+    pub min_idx: usize, // Refers to VertexData
+    #[serde(rename = "Max", deserialize_with = "deser_usize")]
+    pub max_idx: usize, // Refers to VertexData
+
+    #[serde(rename = "MotionBlur", deserialize_with = "deser_vec3", default)]
+    pub(crate) motionblur: Vector3,
+
+    #[serde(skip)]
+    pub transform: Option<Arc<Transform>>,
+}
+
+impl Cuboid {
+    fn corners(&self, verts: &VertexData) -> (Vector3, Vector3) {
+        let (a, b) = (verts[self.min_idx], verts[self.max_idx]);
+        (a.min(b), a.max(b)) // In case Min and Max are swapped in JSON
+    }
+}
+
+impl AnalyticShape for Cuboid {
+    fn data(&self) -> &CommonPrimitiveData { &self._data }
+    fn transform(&self) -> Option<&Transform> { self.transform.as_deref() }
+    fn motionblur(&self) -> Vector3 { self.motionblur }
+
+    fn surface_hit(&self, ray: &Ray, t_interval: &Interval, verts: &VertexData) -> Option<SurfaceHit> {
+        let (min, max) = self.corners(verts);
+        let inv_dir = ray.direction.recip();
+
+        // Slab test, keeping the axes of the entry and exit faces
+        let (mut t_near, mut t_far) = (-Float::INF, Float::INF);
+        let (mut near_axis, mut far_axis) = (0, 0);
+        for axis in 0..3 {
+            let mut t0 = (min[axis] - ray.origin[axis]) * inv_dir[axis];
+            let mut t1 = (max[axis] - ray.origin[axis]) * inv_dir[axis];
+            if t0 > t1 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            // NOTE: comparisons with NaN are false, so 0 * inf cases are ignored
+            if t0 > t_near { t_near = t0; near_axis = axis; }
+            if t1 < t_far { t_far = t1; far_axis = axis; }
+        }
+        if t_near > t_far {
+            return None;
+        }
+
+        // Exit face is hit if the ray starts inside the box
+        let (t, axis, is_exit) = if valid_t(t_near, t_interval) {
+            (t_near, near_axis, false)
+        } else if valid_t(t_far, t_interval) {
+            (t_far, far_axis, true)
+        } else {
+            return None;
+        };
+
+        // Outward normal of the face, towards -direction at entry and +direction at exit
+        let sign = if (ray.direction[axis] > 0.0) == is_exit { 1.0 } else { -1.0 };
+        let mut point = ray.at(t);
+        point[axis] = if sign > 0.0 { max[axis] } else { min[axis] }; // Snap onto the face
+        let mut normal = Vector3::ZERO;
+        normal[axis] = sign;
+
+        // Face coordinates along the other two axes, oriented so that B x T is the outward normal
+        let (i, j) = ((axis + 1) % 3, (axis + 2) % 3);
+        let s = ((point - min) / (max - min)).clamp(Vector3::ZERO, Vector3::ONE);
+        let (mut tangent, mut bitangent) = (Vector3::ZERO, Vector3::ZERO);
+        tangent[j] = sign;
+        bitangent[i] = 1.0;
+        let u = if sign > 0.0 { s[j] } else { 1.0 - s[j] };
+
+        Some(SurfaceHit {
+            t,
+            point,
+            normal,
+            tangent,
+            bitangent,
+            uv: [u, s[i]],
+        })
+    }
+
+    fn local_bbox(&self, verts: &VertexData) -> BBox {
+        let (min, max) = self.corners(verts);
+        BBox::new_from(&Interval::new(min.x, max.x), &Interval::new(min.y, max.y), &Interval::new(min.z, max.z))
+    }
+}
+
+// -------------------------------------------------------------------------------------------------------
+// Torus
+// -------------------------------------------------------------------------------------------------------
+
+/// Torus around y axis through Center, MajorRadius is the distance from the center to the middle of the tube
+/// and MinorRadius is the radius of the tube. u goes around y axis and v around the tube.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Torus {
+    #[serde(flatten)]
+    pub(crate) _data: CommonPrimitiveData,
+
+    #[serde(rename = "Center", deserialize_with = "deser_usize")]
+    pub center_idx: usize, // Refers to VertexData
+    #[serde(rename = "MajorRadius", deserialize_with = "deser_float")]
+    pub major_radius: Float,
+    #[serde(rename = "MinorRadius", deserialize_with = "deser_float")]
+    pub minor_radius: Float,
+
+    #[serde(rename = "MotionBlur", deserialize_with = "deser_vec3", default)]
+    pub(crate) motionblur: Vector3,
+
+    #[serde(skip)]
+    pub transform: Option<Arc<Transform>>,
+}
+
+impl AnalyticShape for Torus {
+    fn data(&self) -> &CommonPrimitiveData { &self._data }
+    fn transform(&self) -> Option<&Transform> { self.transform.as_deref() }
+    fn motionblur(&self) -> Vector3 { self.motionblur }
+
+    fn surface_hit(&self, ray: &Ray, t_interval: &Interval, verts: &VertexData) -> Option<SurfaceHit> {
+        let center = verts[self.center_idx];
+        let (big_r, small_r) = (self.major_radius, self.minor_radius);
+        let d = ray.direction;
+
+        // Clip the ray to the bounding sphere and solve from its entry point, so that coefficients
+        // stay in the scale of the torus even if the ray starts far away (see pbrt-v4, 6.8.4 for a discussion)
+        let o = ray.origin - center;
+        let bound = big_r + small_r;
+        let (s0, s1) = solve_quadratic(d.dot(d), 2.0 * o.dot(d), o.dot(o) - bound * bound)?;
+        let t_start = s0.max(t_interval.min).max(0.0);
+        let t_end = s1.min(t_interval.max);
+        if t_start > t_end {
+            return None;
+        }
+        let o = o + d * t_start;
+
+        // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + z^2) with p = o + s d
+        let a = d.dot(d);
+        let b = 2.0 * o.dot(d);
+        let k = o.dot(o) + big_r * big_r - small_r * small_r;
+        let four_r2 = 4.0 * big_r * big_r;
+        let coeffs = [
+            k * k - four_r2 * (o.x * o.x + o.z * o.z),
+            2.0 * b * k - four_r2 * 2.0 * (o.x * d.x + o.z * d.z),
+            b * b + 2.0 * a * k - four_r2 * (d.x * d.x + d.z * d.z),
+            2.0 * a * b,
+            a * a,
+        ];
+        let mut roots = [0.0; numeric::MAX_POLYNOMIAL_DEGREE];
+        let n_roots = numeric::polynomial_roots(&coeffs, 0.0, t_end - t_start, &mut roots);
+
+        let s = roots[..n_roots].iter().copied().find(|&s| valid_t(t_start + s, t_interval))?;
+        let p = o + d * s;
+
+        // Normal points from the closest point on the tube's center circle, undefined on y axis (only for R < r)
+        let rho = (p.x * p.x + p.z * p.z).sqrt();
+        let radial = if rho > 0.0 { Vector3::new(p.x, 0.0, p.z) / rho } else { Vector3::X };
+        let normal = (p - radial * big_r).try_normalize().unwrap_or(Vector3::Y);
+
+        // Angle around the tube, zero at the outer equator, v decreases with it as v of Cylinder
+        let q = rho - big_r;
+        let tube_angle = p.y.atan2(q);
+        Some(SurfaceHit {
+            t: t_start + s,
+            point: center + p,
+            normal,
+            tangent: Vector3::new(radial.z, 0.0, -radial.x),
+            bitangent: radial * p.y - Vector3::Y * q, // -dp/d(tube_angle)
+            uv: [azimuth_u(p.x, p.z), (Float::PI - tube_angle) / (2. * Float::PI)],
+        })
+    }
+
+    fn local_bbox(&self, verts: &VertexData) -> BBox {
+        let center = verts[self.center_idx];
+        let bound = self.major_radius + self.minor_radius;
+        BBox::new_from(&Interval::new(center.x - bound, center.x + bound),
+                       &Interval::new(center.y - self.minor_radius, center.y + self.minor_radius),
+                       &Interval::new(center.z - bound, center.z + bound))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LightBox {
+    #[serde(flatten)]
+    pub data: Cuboid,
+
+    #[serde(rename = "Radiance", deserialize_with = "deser_vec3")]
+    pub radiance: Vector3,
+
+    #[serde(skip)]
+    pub nonce: u64, // Unique identifier (random large number to avoid collisions)
+}
+
+impl Shape for LightBox {
+    fn intersects_with(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> Option<HitRecord> {
+        let mut rec = self.data.intersects_with(ray, t_interval, vertex_cache)?;
+        rec.radiance = Some(self.radiance);
+        rec.emissive_ptr = Some(Arc::new(self.clone()) as Arc<dyn EmissiveShape>);
+        rec.emissive_shape_id = Some(self.shape_id());
+        Some(rec)
+    }
+
+    fn occluded(&self, ray: &Ray, t_interval: &Interval, vertex_cache: &HeapAllocatedVerts) -> bool {
+        self.data.occluded(ray, t_interval, vertex_cache)
+    }
+}
+
+impl BBoxable for LightBox {
+    fn get_bbox(&self, verts: &VertexData, apply_t: bool) -> BBox {
+        self.data.get_bbox(verts, apply_t)
+    }
+}
+
+impl EmissiveShape for LightBox {
+    fn radiance(&self) -> Vector3 {
+        self.radiance
+    }
+
+    fn shape_id(&self) -> usize {
+        self.nonce as usize
+    }
+
+    /// Samples the cone of directions towards the bounding sphere of the box (same as LightMesh)
+    fn sample_from_bsphere(&self, verts: &VertexData, point: Vector3, psi1: Float, psi2: Float) -> ShapeSample {
+        let bbox = self.data.local_bbox(verts);
+        let transform = self.data.transform.as_deref().unwrap_or(&Transform::IDENTITY);
+        let center_world = transform.point(&bbox.get_center());
+        let radius_world = bbox.get_sphere_radius() * numeric::max_scale(&transform.matrix, true);
+
+        // See slides 11 p.48 for notation
+        let distance_vec = center_world - point;
+        let sin_theta_max = (radius_world * distance_vec.length_recip()).clamp(0.0, 1.0);
+        let cos_theta_max = (1. - sin_theta_max * sin_theta_max).sqrt(); // Zero if point is inside the sphere
+
+        let theta = numeric::pdf_sphere_inv(psi1, cos_theta_max);
+        let rho = 2. * Float::PI * psi2;
+        let local_direction = Vector3::new(theta.sin() * rho.cos(), theta.sin() * rho.sin(), theta.cos());
+
+        let w = distance_vec.normalize();
+        let (u, v) = numeric::get_onb(&w);
+
+        ShapeSample {
+            direction: local_direction.x * u + local_direction.y * v + local_direction.z * w,
+            pdf: 1. / (2. * Float::PI * (1. - cos_theta_max)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use crate::scene::VertexCache;
+    use crate::image::AlphaMask;
+    use crate::acceleration::closest_hit_naive;
+
+    /// Vertex data with the given points, vertex indices start from 1
+    fn vertex_data(points: &[Vector3]) -> VertexData {
+        let mut data = vec![Vector3::ZERO];
+        data.extend_from_slice(points);
+        VertexData { _data: data, _type: String::from("xyz"), ..Default::default() }
+    }
+
+    fn ray_towards(origin: Vector3, target: Vector3) -> Ray {
+        Ray::new(origin, (target - origin).normalize(), 0.)
+    }
+
+    fn hit(shape: &impl AnalyticShape, verts: &VertexData, origin: Vector3, direction: Vector3) -> Option<SurfaceHit> {
+        shape.surface_hit(&Ray::new(origin, direction.normalize(), 0.), &Interval::new(1e-6, FloatConst::INF), verts)
+    }
+
+    /// Shoot random rays towards the local bounding box and check the frame and uv of every hit:
+    /// uv is in [0, 1] and B x T is the outward normal (as bump mapping expects)
+    fn check_random_hits(shape: &impl AnalyticShape, verts: &VertexData) {
+        let bbox = shape.local_bbox(verts);
+        let center = bbox.get_center();
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut n_hits = 0;
+        for _ in 0..2000 {
+            let random_vec = |rng: &mut StdRng| Vector3::new(rng.random_range(-1.0..1.0), rng.random_range(-1.0..1.0), rng.random_range(-1.0..1.0));
+            let origin = center + 4. * random_vec(&mut rng);
+            let target = center + 0.6 * bbox.get_sphere_radius() * random_vec(&mut rng);
+            let ray = ray_towards(origin, target);
+            let Some(hit) = shape.surface_hit(&ray, &Interval::new(1e-6, FloatConst::INF), verts) else { continue; };
+            n_hits += 1;
+
+            assert!((ray.at(hit.t) - hit.point).length() < 1e-9);
+            assert!((hit.normal.length() - 1.).abs() < 1e-9);
+            assert!(hit.uv.iter().all(|x| (0.0..=1.0).contains(x)), "uv {:?} out of range", hit.uv);
+            let bxt = hit.bitangent.cross(hit.tangent).normalize();
+            assert!(bxt.dot(hit.normal) > 1. - 1e-9, "B x T = {} is not the normal {}", bxt, hit.normal);
+        }
+        assert!(n_hits > 100, "Too few rays hit {:?}", shape);
+    }
+
+    #[test]
+    fn cylinder() {
+        let verts = vertex_data(&[Vector3::ZERO]);
+        let cylinder = Cylinder { center_idx: 1, radius: 1., height: 2., ..Default::default() };
+
+        let h = hit(&cylinder, &verts, Vector3::new(-3., 0.5, 0.), Vector3::X).unwrap();
+        assert!((h.t - 2.).abs() < 1e-12);
+        assert!(h.normal.abs_diff_eq(-Vector3::X, 1e-12));
+        assert!((h.uv[0] - 0.).abs() < 1e-12 && (h.uv[1] - 0.75).abs() < 1e-12);
+
+        // Enters above the top, so the first root is clipped and the inside of the far wall is hit
+        let direction = Vector3::new(1., -0.5, 0.);
+        let h = hit(&cylinder, &verts, Vector3::new(-2., 2.8, 0.), direction).unwrap();
+        assert!((h.t - 3. * direction.length()).abs() < 1e-12);
+        assert!(h.point.abs_diff_eq(Vector3::new(1., 1.3, 0.), 1e-12));
+        assert!(h.normal.abs_diff_eq(Vector3::X, 1e-12)); // Still outward
+
+        assert!(hit(&cylinder, &verts, Vector3::new(-3., 2.5, 0.), Vector3::X).is_none());
+        check_random_hits(&cylinder, &verts);
+    }
+
+    #[test]
+    fn disk() {
+        let verts = vertex_data(&[Vector3::new(0., 1., 0.)]);
+        let disk = Disk { center_idx: 1, radius: 1., inner_radius: 0.5, ..Default::default() };
+
+        let h = hit(&disk, &verts, Vector3::new(0.75, 3., 0.), -Vector3::Y).unwrap();
+        assert!((h.t - 2.).abs() < 1e-12);
+        assert!(h.normal.abs_diff_eq(Vector3::Y, 1e-12));
+        assert!((h.uv[0] - 0.5).abs() < 1e-12 && (h.uv[1] - 0.5).abs() < 1e-12);
+
+        assert!(hit(&disk, &verts, Vector3::new(0., 3., 0.), -Vector3::Y).is_none()); // Through the hole
+        assert!(hit(&disk, &verts, Vector3::new(1.5, 3., 0.), -Vector3::Y).is_none()); // Outside
+        assert!(hit(&disk, &verts, Vector3::new(-2., 1., 0.), Vector3::X).is_none()); // Parallel
+        check_random_hits(&disk, &verts);
+    }
+
+    #[test]
+    fn cone() {
+        let verts = vertex_data(&[Vector3::ZERO]);
+        let cone = Cone { center_idx: 1, radius: 1., height: 2., ..Default::default() };
+
+        let h = hit(&cone, &verts, Vector3::new(-3., 1., 0.), Vector3::X).unwrap();
+        assert!((h.t - 2.5).abs() < 1e-12);
+        assert!(h.normal.abs_diff_eq(Vector3::new(-2., 1., 0.).normalize(), 1e-12));
+        assert!((h.uv[1] - 0.5).abs() < 1e-12);
+
+        // First root is on the mirrored cone above the apex, the second one is on the cone
+        let direction = Vector3::new(0.1, -1., 0.);
+        let h = hit(&cone, &verts, Vector3::new(0., 4., 0.), direction).unwrap();
+        assert!((h.t - 2.5 * direction.length()).abs() < 1e-9);
+        assert!(h.point.abs_diff_eq(Vector3::new(0.25, 1.5, 0.), 1e-9));
+
+        assert!(hit(&cone, &verts, Vector3::new(-3., 3., 0.), Vector3::X).is_none()); // Only the mirrored cone
+        check_random_hits(&cone, &verts);
+    }
+
+    #[test]
+    fn quad() {
+        let verts = vertex_data(&[Vector3::ZERO]);
+        let quad = Quad { corner_idx: 1, edge_u: Vector3::new(2., 0., 0.), edge_v: Vector3::new(0., 0., -1.), ..Default::default() };
+
+        let h = hit(&quad, &verts, Vector3::new(0.5, 1., -0.25), -Vector3::Y).unwrap();
+        assert!((h.t - 1.).abs() < 1e-12);
+        assert!(h.normal.abs_diff_eq(Vector3::Y, 1e-12)); // EdgeU x EdgeV
+        assert!((h.uv[0] - 0.25).abs() < 1e-12 && (h.uv[1] - 0.75).abs() < 1e-12);
+
+        assert!(hit(&quad, &verts, Vector3::new(2.5, 1., -0.25), -Vector3::Y).is_none()); // Beyond EdgeU
+        assert!(hit(&quad, &verts, Vector3::new(0.5, 1., 0.25), -Vector3::Y).is_none()); // Behind the corner
+        assert!(hit(&quad, &verts, Vector3::new(-1., 0., -0.5), Vector3::X).is_none()); // Parallel
+        check_random_hits(&quad, &verts);
+    }
+
+    #[test]
+    fn torus() {
+        let verts = vertex_data(&[Vector3::ZERO]);
+        let torus = Torus { center_idx: 1, major_radius: 2., minor_radius: 0.5, ..Default::default() };
+
+        let h = hit(&torus, &verts, Vector3::new(-5., 0., 0.), Vector3::X).unwrap();
+        assert!((h.t - 2.5).abs() < 1e-9);
+        assert!(h.normal.abs_diff_eq(-Vector3::X, 1e-9));
+
+        // Top of the tube, a quarter turn around it from the outer equator
+        let h = hit(&torus, &verts, Vector3::new(2., 5., 0.), -Vector3::Y).unwrap();
+        assert!((h.t - 4.5).abs() < 1e-9);
+        assert!(h.normal.abs_diff_eq(Vector3::Y, 1e-9));
+        assert!((h.uv[1] - 0.25).abs() < 1e-9);
+
+        assert!(hit(&torus, &verts, Vector3::new(0., 5., 0.), -Vector3::Y).is_none()); // Through the hole
+        assert!(hit(&torus, &verts, Vector3::new(-5., 0.6, 0.), Vector3::X).is_none()); // Above the tube
+
+        // From inside the tube, leaving towards the hole with the outward normal of the tube
+        let h = hit(&torus, &verts, Vector3::new(-2., 0., 0.), Vector3::X).unwrap();
+        assert!((h.t - 0.5).abs() < 1e-9);
+        assert!(h.normal.abs_diff_eq(Vector3::X, 1e-9));
+
+        // Past the hole, the far side of the tube
+        let ray = Ray::new(Vector3::new(-5., 0., 0.), Vector3::X, 0.);
+        let h = torus.surface_hit(&ray, &Interval::new(4., FloatConst::INF), &verts).unwrap();
+        assert!((h.t - 6.5).abs() < 1e-9);
+
+        // Far origin, roots are searched from the bounding sphere so they stay accurate
+        let y = 0.1;
+        let h = hit(&torus, &verts, Vector3::new(-1e4, y, 0.), Vector3::X).unwrap();
+        let x = -(2. + (0.25 - y * y).sqrt());
+        assert!((h.t - (1e4 + x)).abs() < 1e-9);
+        assert!((h.point - Vector3::new(x, y, 0.)).length() < 1e-9);
+
+        check_random_hits(&torus, &verts);
+    }
+
+    #[test]
+    fn cuboid() {
+        let verts = vertex_data(&[Vector3::ZERO, Vector3::new(1., 2., 3.)]);
+        let cuboid = Cuboid { min_idx: 1, max_idx: 2, ..Default::default() };
+
+        let h = hit(&cuboid, &verts, Vector3::new(-1., 0.5, 0.75), Vector3::X).unwrap();
+        assert!((h.t - 1.).abs() < 1e-12);
+        assert!(h.normal.abs_diff_eq(-Vector3::X, 1e-12));
+        assert!((h.uv[0] - 0.75).abs() < 1e-12 && (h.uv[1] - 0.25).abs() < 1e-12);
+
+        // Starting inside, the exit face is hit with its outward normal
+        let h = hit(&cuboid, &verts, Vector3::new(0.5, 1., 1.5), Vector3::Y).unwrap();
+        assert!((h.t - 1.).abs() < 1e-12);
+        assert!(h.normal.abs_diff_eq(Vector3::Y, 1e-12));
+        assert!(h.point.abs_diff_eq(Vector3::new(0.5, 2., 1.5), 1e-12));
+
+        // Parallel to the x slabs, inside and outside of them
+        let h = hit(&cuboid, &verts, Vector3::new(0.5, -1., 1.5), Vector3::Y).unwrap();
+        assert!((h.t - 1.).abs() < 1e-12);
+        assert!(h.normal.abs_diff_eq(-Vector3::Y, 1e-12));
+        assert!(hit(&cuboid, &verts, Vector3::new(1.5, -1., 1.5), Vector3::Y).is_none());
+        assert!(hit(&cuboid, &verts, Vector3::new(1., 1., 5.), Vector3::Y).is_none()); // On the slab, beyond z
+
+        assert!(hit(&cuboid, &verts, Vector3::new(0.5, 1., 5.), Vector3::Z).is_none()); // Box is behind
+        check_random_hits(&cuboid, &verts);
+    }
+
+    #[test]
+    fn cuboid_face_uvs_follow_tangent_frame() {
+        let verts = vertex_data(&[Vector3::ZERO, Vector3::new(1., 2., 3.)]);
+        let cuboid = Cuboid { min_idx: 1, max_idx: 2, ..Default::default() };
+        let center = Vector3::new(0.5, 1., 1.5);
+        let eps = 1e-3;
+
+        for axis in 0..3 {
+            for sign in [-1., 1.] {
+                let mut outward = Vector3::ZERO;
+                outward[axis] = sign;
+                let origin = center + 5. * outward;
+                let h = hit(&cuboid, &verts, origin, -outward).unwrap();
+                assert!(h.normal.abs_diff_eq(outward, 1e-12));
+                assert!(h.bitangent.cross(h.tangent).abs_diff_eq(outward, 1e-12));
+
+                // Moving the hit point along T increases only u, along B increases only v
+                let along_t = hit(&cuboid, &verts, origin + eps * h.tangent, -outward).unwrap();
+                let along_b = hit(&cuboid, &verts, origin + eps * h.bitangent, -outward).unwrap();
+                assert!(along_t.uv[0] > h.uv[0] && (along_t.uv[1] - h.uv[1]).abs() < 1e-12, "face {} {}", axis, sign);
+                assert!(along_b.uv[1] > h.uv[1] && (along_b.uv[0] - h.uv[0]).abs() < 1e-12, "face {} {}", axis, sign);
+            }
+        }
+    }
+
+    #[test]
+    fn light_box_sets_emissive_shape_id() {
+        let verts = vertex_data(&[Vector3::ZERO, Vector3::ONE]);
+        let vertex_cache = Arc::new(VertexCache { vertex_data: verts, ..Default::default() });
+        let light = LightBox {
+            data: Cuboid { min_idx: 1, max_idx: 2, ..Default::default() },
+            radiance: Vector3::splat(5.),
+            nonce: 12345,
+        };
+
+        let ray = Ray::new(Vector3::new(0.5, 0.5, -2.), Vector3::Z, 0.);
+        let rec = light.intersects_with(&ray, &Interval::new(1e-6, FloatConst::INF), &vertex_cache).unwrap();
+        assert!((rec.ray_t - 2.).abs() < 1e-12);
+        assert_eq!(rec.emissive_shape_id, Some(12345));
+        assert_eq!(rec.radiance, Some(Vector3::splat(5.)));
+        assert!(light.occluded(&ray, &Interval::new(1e-6, FloatConst::INF), &vertex_cache));
+    }
+
+    #[test]
+    fn cut_out_triangle_lets_rays_through() {
+        // Front triangle at z = 0 with uv = xy, its mask is transparent where u < 0.5. Bigger triangle behind it at z = -1.
+        let vertex_data = vertex_data(&[
+            Vector3::ZERO, Vector3::X, Vector3::Y,
+            Vector3::new(-1., -1., -1.), Vector3::new(3., -1., -1.), Vector3::new(-1., 3., -1.),
+        ]);
+        let vertex_cache: HeapAllocatedVerts = Arc::new(VertexCache {
+            vertex_data,
+            uv_coords: vec![None, Some([0., 0.]), Some([1., 0.]), Some([0., 1.])],
+            alpha_masks: vec![Some(AlphaMask::new(Arc::new(vec![0., 1.]), 2, 1, 0.5))],
+            ..Default::default()
+        });
+        let mut front = Triangle { vert_indices: [1, 2, 3], texture_indices: [1, 2, 3], ..Default::default() };
+        front._data.alpha_mask = Some(1);
+        let back = Triangle { vert_indices: [4, 5, 6], ..Default::default() };
+        let shapes: Vec<HeapAllocatedShape> = vec![Arc::new(front.clone()), Arc::new(back)];
+        let t_interval = Interval::new(1e-6, FloatConst::INF);
+
+        // Through the transparent half, the triangle behind is hit by both camera and shadow rays
+        let ray = Ray::new(Vector3::new(0.25, 0.25, 1.), -Vector3::Z, 0.);
+        assert!(front.intersects_with(&ray, &t_interval, &vertex_cache).is_none());
+        assert!(!front.occluded(&ray, &t_interval, &vertex_cache));
+        let rec = closest_hit_naive(&shapes, &ray, &t_interval, &vertex_cache, false).unwrap();
+        assert!((rec.ray_t - 2.).abs() < 1e-9);
+        assert!(closest_hit_naive(&shapes, &ray, &t_interval, &vertex_cache, true).is_some_and(|rec| (rec.ray_t - 2.).abs() < 1e-9));
+        assert!(!front.occluded(&ray, &Interval::new(1e-6, 1.5), &vertex_cache));
+
+        // Through the opaque half
+        let ray = Ray::new(Vector3::new(0.75, 0.1, 1.), -Vector3::Z, 0.);
+        assert!(front.occluded(&ray, &t_interval, &vertex_cache));
+        let rec = closest_hit_naive(&shapes, &ray, &t_interval, &vertex_cache, false).unwrap();
+        assert!((rec.ray_t - 1.).abs() < 1e-9);
+
+        // Texture map ids start from 1, id 0 refers to no mask
+        front._data.alpha_mask = Some(0);
+        let ray = Ray::new(Vector3::new(0.25, 0.25, 1.), -Vector3::Z, 0.);
+        assert!(front.intersects_with(&ray, &t_interval, &vertex_cache).is_some_and(|rec| (rec.ray_t - 1.).abs() < 1e-9));
+    }
+}
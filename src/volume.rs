@@ -0,0 +1,352 @@
+/*
+
+    Heterogeneous volumes: smoke, clouds or fire density given by a voxel
+    grid that fills a box in local space. e.g.
+
+        "Volume": {
+            "_id": "1", "_gridFile": "smoke.vol",
+            "BoundsMin": "-1 0 -1", "BoundsMax": "1 2 1",
+            "DensityScale": "8", "Albedo": "0.9 0.9 0.9", "Anisotropy": "0.3",
+            "Transformations": "t1"
+        }
+
+    _gridFile is relative to the scene JSON. Files ending with .vol are read
+    as Mitsuba's binary grid volumes ("VOL" and version 3, then little endian
+    i32 encoding (1: float32, 3: uint8), resolution x y z and channel count,
+    six floats of a bounding box that is ignored, then the voxels with x
+    varying fastest, only the first channel is used). Other files are text:
+    resolution x y z followed by the densities in the same order, lines
+    starting with # are skipped.
+
+    Densities are interpolated trilinearly between voxel centers, scaled by
+    DensityScale and give the extinction per world space unit. Albedo is the
+    scattered fraction at a collision, with a Henyey-Greenstein phase function
+    of Anisotropy (see medium.rs).
+
+    Volumes are shapes in the scene BVH through their bounding box. Rays find
+    where they collide with the medium by delta tracking, so a hit is either
+    a scattering event or nothing (the ray passes through). Volumes never
+    block shadow rays, their transmittance is estimated by ratio tracking
+    instead (see renderer::shadow_transmittance( )). See Novák et al.,
+    "Monte Carlo Methods for Volumetric Light Transport Simulation", 2018.
+
+    @date: Oct, 2026
+    @author: Bartu
+*/
+
+use std::error::Error;
+use std::path::Path;
+
+use crate::bbox::{BBox, BBoxable};
+use crate::interval::Interval;
+use crate::json_structs::VertexData;
+use crate::medium::Medium;
+use crate::ray::{Ray, HitRecord};
+use crate::scene::HeapAllocatedVerts;
+use crate::shapes::Shape;
+use crate::prelude::*;
+
+// Header of Mitsuba's binary grid volume format, see the top of the file
+const VOL_HEADER_SIZE: usize = 48;
+
+#[derive(Debug, Clone, Default)]
+pub struct VoxelGrid {
+    resolution: [usize; 3],
+    densities: Vec<Float>, // x varies fastest, then y, then z
+    max_density: Float,
+}
+
+impl VoxelGrid {
+    pub fn new(resolution: [usize; 3], densities: Vec<Float>) -> Result<Self, String> {
+        let n_voxels = resolution.iter().product::<usize>();
+        if n_voxels == 0 || densities.len() != n_voxels {
+            return Err(format!("Voxel grid of resolution {:?} needs {} densities, found {}", resolution, n_voxels, densities.len()));
+        }
+        if let Some(negative) = densities.iter().find(|&&d| d < 0.) {
+            return Err(format!("Voxel grid has a negative density {}", negative));
+        }
+        let max_density = densities.iter().copied().fold(0., Float::max);
+        Ok(Self { resolution, densities, max_density })
+    }
+
+    /// Reads a grid file, binary if it ends with .vol and text otherwise (see the top of the file)
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Cannot read voxel grid {:?}: {}", path, e))?;
+        let grid = if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("vol")) {
+            Self::parse_binary(&bytes)?
+        } else {
+            Self::parse_text(&String::from_utf8(bytes)?)?
+        };
+        info!("Loaded {:?} voxel grid from {:?}, maximum density is {}", grid.resolution, path, grid.max_density);
+        Ok(grid)
+    }
+
+    fn parse_text(text: &str) -> Result<Self, String> {
+        let mut nums = text.lines()
+                           .filter(|line| !line.trim_start().starts_with('#'))
+                           .flat_map(str::split_whitespace);
+        let mut resolution = [0; 3];
+        for r in resolution.iter_mut() {
+            let token = nums.next().ok_or("Voxel grid file ended before its resolution")?;
+            *r = token.parse::<usize>().map_err(|e| format!("Voxel grid resolution '{}': {}", token, e))?;
+        }
+        let densities = nums.map(|x| x.parse::<Float>().map_err(|e| format!("Voxel grid density '{}': {}", x, e)))
+                            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(resolution, densities)
+    }
+
+    fn parse_binary(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < VOL_HEADER_SIZE || &bytes[0..3] != b"VOL" || bytes[3] != 3 {
+            return Err("Not a version 3 .vol file".to_string());
+        }
+        let read_i32 = |offset: usize| i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let (encoding, channels) = (read_i32(4), read_i32(20).max(1) as usize);
+        let resolution = [read_i32(8), read_i32(12), read_i32(16)].map(|r| r.max(0) as usize);
+
+        let data = &bytes[VOL_HEADER_SIZE..];
+        let values: Vec<Float> = match encoding {
+            1 => data.chunks_exact(4).map(|c| f32::from_le_bytes(c.try_into().unwrap()) as Float).collect(),
+            3 => data.iter().map(|&b| b as Float / 255.).collect(),
+            _ => return Err(format!("Unsupported .vol encoding {}, expected 1 (float32) or 3 (uint8)", encoding)),
+        };
+        if channels > 1 {
+            warn!("Voxel grid has {} channels, only the first one is used as density.", channels);
+        }
+        Self::new(resolution, values.into_iter().step_by(channels).collect())
+    }
+
+    #[inline]
+    fn voxel(&self, x: usize, y: usize, z: usize) -> Float {
+        let [nx, ny, _] = self.resolution;
+        self.densities[(z * ny + y) * nx + x]
+    }
+
+    /// Trilinearly interpolated density at uvw in [0, 1]^3, voxel values are at their centers
+    pub fn density(&self, uvw: Vector3) -> Float {
+        let mut lo = [0; 3];
+        let mut frac = [0.; 3];
+        for axis in 0..3 {
+            let n = self.resolution[axis];
+            let x = (uvw[axis] * n as Float - 0.5).clamp(0., (n - 1) as Float);
+            lo[axis] = (x as usize).min(n.saturating_sub(2));
+            frac[axis] = x - lo[axis] as Float;
+        }
+        let hi = [0, 1, 2].map(|axis| (lo[axis] + 1).min(self.resolution[axis] - 1));
+
+        let lerp = |a: Float, b: Float, t: Float| a + (b - a) * t;
+        let along_x = |y: usize, z: usize| lerp(self.voxel(lo[0], y, z), self.voxel(hi[0], y, z), frac[0]);
+        let along_y = |z: usize| lerp(along_x(lo[1], z), along_x(hi[1], z), frac[1]);
+        lerp(along_y(lo[2]), along_y(hi[2]), frac[2])
+    }
+}
+
+/// Random numbers of delta tracking are seeded by the ray, so that accelerators testing a volume
+/// more than once for the same ray (e.g. in several kd-tree leaves) find the same collision
+struct RaySampler(u64);
+
+impl RaySampler {
+    fn new(ray: &Ray) -> Self {
+        let bits = [ray.origin.x, ray.origin.y, ray.origin.z, ray.direction.x, ray.direction.y, ray.direction.z, ray.time];
+        Self(bits.iter().fold(0x9E37_79B9_7F4A_7C15, |seed, x| splitmix64(seed ^ x.to_bits())))
+    }
+
+    fn next(&mut self) -> Float {
+        self.0 = splitmix64(self.0);
+        (self.0 >> 11) as Float / (1u64 << 53) as Float // In [0, 1)
+    }
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[derive(Debug, Deserialize, Clone, SmartDefault)]
+#[serde(default)]
+pub struct Volume {
+    #[serde(deserialize_with = "deser_usize")]
+    pub _id: usize,
+
+    #[serde(rename = "Transformations")]
+    pub transformation_names: Option<String>,
+
+    #[serde(rename = "_gridFile")]
+    pub grid_file: String,
+
+    #[serde(rename = "BoundsMin", deserialize_with = "deser_vec3")]
+    pub bounds_min: Vector3, // Local space box the grid is stretched over
+    #[serde(rename = "BoundsMax", deserialize_with = "deser_vec3")]
+    #[default(Vector3::ONE)]
+    pub bounds_max: Vector3,
+
+    #[serde(rename = "DensityScale", deserialize_with = "deser_float")]
+    #[default = 1.0]
+    pub density_scale: Float,
+
+    #[serde(rename = "Albedo", deserialize_with = "deser_vec3")]
+    #[default(Vector3::ONE)]
+    pub albedo: Vector3,
+
+    #[serde(rename = "Anisotropy", deserialize_with = "deser_float")]
+    pub anisotropy: Float,
+
+    #[serde(skip)]
+    pub grid: Arc<VoxelGrid>,
+    #[serde(skip)]
+    medium: Option<Medium>, // Of unit density, only its albedo and phase function are used at collisions
+
+    #[serde(skip)]
+    pub transform: Option<Arc<Transform>>,
+}
+
+impl Volume {
+    /// Loads _gridFile relative to the scene JSON
+    pub fn load_grid(&mut self, json_dir: &Path) -> Result<(), Box<dyn Error>> {
+        if self.grid_file.is_empty() {
+            return Err(format!("Volume {} has no _gridFile", self._id).into());
+        }
+        self.grid = Arc::new(VoxelGrid::load(&json_dir.join(&self.grid_file))?);
+        self.medium = Some(Medium::new(Vector3::ONE - self.albedo, self.albedo, self.anisotropy));
+        Ok(())
+    }
+
+    fn local_ray(&self, ray: &Ray) -> Ray {
+        match self.transform.as_deref() {
+            Some(transform) => ray.to_local(transform),
+            None => ray.clone(),
+        }
+    }
+
+    /// Part of t_interval where the local ray is inside the bounds
+    fn bounds_interval(&self, ray: &Ray, t_interval: &Interval) -> Option<(Float, Float)> {
+        let (mut t_enter, mut t_exit) = (t_interval.min.max(0.), t_interval.max);
+        for axis in 0..3 {
+            let inv_d = 1.0 / ray.direction[axis];
+            let t0 = (self.bounds_min[axis] - ray.origin[axis]) * inv_d;
+            let t1 = (self.bounds_max[axis] - ray.origin[axis]) * inv_d;
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+        (t_enter < t_exit).then_some((t_enter, t_exit))
+    }
+
+    /// Scaled density at a local point
+    fn density(&self, p: Vector3) -> Float {
+        self.density_scale * self.grid.density((p - self.bounds_min) / (self.bounds_max - self.bounds_min))
+    }
+
+    /// Density of the densest voxel, tentative collisions are sampled as if the medium was that dense everywhere
+    fn majorant(&self) -> Float {
+        self.density_scale * self.grid.max_density
+    }
+
+    /// Local ray, where it enters and leaves the bounds within t_interval, and the step in t per unit optical
+    /// depth of the majorant. Local ray direction is not normalized but t is the same, densities are per world space unit.
+    fn tracking_range(&self, ray: &Ray, t_interval: &Interval) -> Option<(Ray, Float, Float, Float)> {
+        let majorant = self.majorant();
+        if majorant <= 0. {
+            return None;
+        }
+        let local_ray = self.local_ray(ray);
+        let (t_enter, t_exit) = self.bounds_interval(&local_ray, t_interval)?;
+        Some((local_ray, t_enter, t_exit, 1. / (majorant * ray.direction.length())))
+    }
+
+    /// Delta tracking: distance to the first real collision within t_interval, if there is any
+    fn sample_collision(&self, ray: &Ray, t_interval: &Interval) -> Option<Float> {
+        let (local_ray, mut t, t_exit, step) = self.tracking_range(ray, t_interval)?;
+        let mut sampler = RaySampler::new(ray);
+        loop {
+            t -= (1. - sampler.next()).ln() * step;
+            if t >= t_exit {
+                return None;
+            }
+            if sampler.next() * self.majorant() < self.density(local_ray.at(t)) {
+                return Some(t);
+            }
+        }
+    }
+
+    /// Ratio tracking: unbiased estimate of the fraction of light passing through the volume within t_interval
+    pub fn transmittance(&self, ray: &Ray, t_interval: &Interval) -> Float {
+        let Some((local_ray, mut t, t_exit, step)) = self.tracking_range(ray, t_interval) else { return 1.; };
+        let mut transmittance = 1.;
+        while transmittance > 0. {
+            t -= (1. - random_float()).ln() * step;
+            if t >= t_exit {
+                break;
+            }
+            transmittance *= 1. - self.density(local_ray.at(t)) / self.majorant();
+        }
+        transmittance
+    }
+}
+
+impl Shape for Volume {
+    fn intersects_with(&self, ray: &Ray, t_interval: &Interval, _: &HeapAllocatedVerts) -> Option<HitRecord> {
+        let t = self.sample_collision(ray, t_interval)?;
+        let hit_point = ray.at(t);
+        // Collisions are not on a surface, the normal faces the ray so that they are front faces. Volumes have no material.
+        let mut rec = HitRecord::new_from(ray.origin, hit_point, -ray.direction.normalize(), t, 0, true, Vec::new(), None, None);
+        rec.medium = self.medium;
+        Some(rec)
+    }
+
+    fn occluded(&self, _: &Ray, _: &Interval, _: &HeapAllocatedVerts) -> bool {
+        false
+    }
+}
+
+impl BBoxable for Volume {
+    fn get_bbox(&self, _: &VertexData, apply_t: bool) -> BBox {
+        let (min, max) = (self.bounds_min.min(self.bounds_max), self.bounds_min.max(self.bounds_max));
+        let local_box = BBox::new(min.x, max.x, min.y, max.y, min.z, max.z);
+        if !apply_t {
+            return local_box;
+        }
+        match self.transform.as_deref() {
+            Some(transform) => local_box.transform(&transform.matrix),
+            None => local_box,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grid_interpolation() {
+        let grid = VoxelGrid::parse_text("# 2x1x1 grid\n2 1 1\n0 1\n").unwrap();
+        assert_eq!(grid.max_density, 1.);
+        // Voxel centers are at u = 0.25 and 0.75, density is constant outside of them
+        assert!((grid.density(Vector3::new(0.5, 0.5, 0.5)) - 0.5).abs() < 1e-12);
+        assert_eq!(grid.density(Vector3::new(0.1, 0.2, 0.9)), 0.);
+        assert_eq!(grid.density(Vector3::new(0.9, 0.2, 0.9)), 1.);
+        assert!(VoxelGrid::parse_text("2 2 2\n1 2 3").is_err());
+    }
+
+    #[test]
+    fn test_tracking_matches_beers_law() {
+        // Constant density 2 over a unit box, both estimators converge to exp(-2) along x
+        let mut volume = Volume { density_scale: 2., ..Default::default() };
+        volume.grid = Arc::new(VoxelGrid::new([1, 1, 1], vec![1.]).unwrap());
+        let expected = (-2.0 as Float).exp();
+        let n = 20000;
+        let mut passed = 0;
+        let mut ratio_tracked = 0.;
+        for i in 0..n {
+            let ray = Ray::new(Vector3::new(-1., 0.5, (i as Float + 0.5) / n as Float), Vector3::X, 0.);
+            let interval = Interval::new(0., 10.);
+            if volume.sample_collision(&ray, &interval).is_none() {
+                passed += 1;
+            }
+            ratio_tracked += volume.transmittance(&ray, &interval);
+        }
+        let delta_tracked = passed as Float / n as Float;
+        assert!((delta_tracked - expected).abs() < 0.01, "Delta tracking {}, expected {}", delta_tracked, expected);
+        assert!((ratio_tracked / n as Float - expected).abs() < 0.01, "Ratio tracking {}, expected {}", ratio_tracked / n as Float, expected);
+    }
+}